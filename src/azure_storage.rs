@@ -1,9 +1,32 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use azure_core::request_options::Metadata;
+use azure_core::StatusCode;
 use azure_storage::prelude::*;
 use azure_storage_blobs::prelude::*;
+use bytes::Bytes;
+use chrono::{ DateTime, Utc };
+use futures::{ stream, StreamExt, TryStreamExt };
+use md5::{ Digest, Md5 };
+
+use crate::content_type::*;
+use crate::object_store::*;
+
+/// Default size of each staged block when streaming an upload.
+pub const DEFAULT_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+/// Azure rejects blocks larger than 4000 MiB.
+const MAX_BLOCK_SIZE: usize = 4000 * 1024 * 1024;
+/// Default number of blocks uploaded concurrently.
+pub const DEFAULT_PARALLELISM: usize = 4;
 
 pub struct AzureStorage {
     account_name: String,
     blob_client: BlobServiceClient,
+    block_size: usize,
+    parallelism: usize,
 }
 
 impl AzureStorage {
@@ -14,28 +37,40 @@ impl AzureStorage {
         Self {
             account_name: account,
             blob_client,
+            block_size: DEFAULT_BLOCK_SIZE,
+            parallelism: DEFAULT_PARALLELISM,
         }
     }
 
-    pub async fn upload_blob(
-        &self,
-        container: &str,
-        blob_name: &str,
-        data: Vec<u8>
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        let container_client = self.blob_client.container_client(container);
-        let blob_client = container_client.blob_client(blob_name);
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size.clamp(1, MAX_BLOCK_SIZE);
+        self
+    }
 
-        blob_client.put_block_blob(data).content_type("application/octet-stream").await?;
+    pub fn with_parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
 
-        let blob_url = format!(
+    fn blob_url(&self, container: &str, blob_name: &str) -> String {
+        format!(
             "https://{}.blob.core.windows.net/{}/{}",
             self.account_name,
             container,
             blob_name
-        );
+        )
+    }
 
-        Ok(blob_url)
+    pub async fn upload_blob(
+        &self,
+        container: &str,
+        blob_name: &str,
+        data: Vec<u8>
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let content_type = detect_content_type(blob_name, &data[..data.len().min(SNIFF_LEN)], None);
+        self.put(container, blob_name, &PutOptions::new(&content_type), Bytes::from(data)).await?;
+
+        Ok(self.blob_url(container, blob_name))
     }
 
     pub async fn download_blob(
@@ -51,3 +86,373 @@ impl AzureStorage {
         Ok(data)
     }
 }
+
+fn map_error(e: azure_core::Error, container: &str, key: &str) -> ObjectStoreError {
+    let not_found = e
+        .as_http_error()
+        .map(|http| http.status() == StatusCode::NotFound)
+        .unwrap_or(false);
+
+    if not_found {
+        ObjectStoreError::not_found(container, key)
+    } else {
+        ObjectStoreError::Backend(e.to_string())
+    }
+}
+
+fn to_utc(timestamp: i64, nanos: u32) -> DateTime<Utc> {
+    DateTime::from_timestamp(timestamp, nanos).unwrap_or_else(Utc::now)
+}
+
+fn blob_meta(blob: &Blob) -> ObjectMeta {
+    let modified = blob.properties.last_modified;
+
+    ObjectMeta {
+        key: blob.name.clone(),
+        size: blob.properties.content_length,
+        content_type: Some(blob.properties.content_type.clone()),
+        cache_control: blob.properties.cache_control.clone(),
+        metadata: blob.metadata
+            .as_ref()
+            .map(|metadata| metadata.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default(),
+        last_modified: to_utc(modified.unix_timestamp(), modified.nanosecond()),
+    }
+}
+
+fn azure_metadata(metadata: &BTreeMap<String, String>) -> Metadata {
+    let mut azure = Metadata::new();
+    for (name, value) in metadata {
+        azure.insert(name.clone(), value.clone());
+    }
+    azure
+}
+
+fn azure_tags(tags: &BTreeMap<String, String>) -> Tags {
+    let mut azure = Tags::new();
+    for (name, value) in tags {
+        azure.insert(name.clone(), value.clone());
+    }
+    azure
+}
+
+impl AzureStorage {
+    /// Put Blob and Put Block List can't set Cache-Control, so it follows in
+    /// a Set Blob Properties call. That call clears every content header it
+    /// leaves out, so they are sent again.
+    async fn set_cache_control(
+        &self,
+        blob_client: &BlobClient,
+        options: &PutOptions,
+        md5: Option<[u8; 16]>
+    ) -> azure_core::Result<()> {
+        let Some(cache_control) = &options.cache_control else {
+            return Ok(());
+        };
+
+        let mut request = blob_client
+            .set_properties()
+            .content_type(options.content_type.clone())
+            .cache_control(BlobCacheControl::from(cache_control));
+        if let Some(content_disposition) = &options.content_disposition {
+            request = request.content_disposition(BlobContentDisposition::from(content_disposition));
+        }
+        if let Some(md5) = md5 {
+            request = request.content_md5(BlobContentMD5::from(md5));
+        }
+
+        request.await.map(|_| ())
+    }
+
+    /// Turns the SDK's paged download into a byte stream. The first page is
+    /// awaited here so a missing blob is reported before streaming starts.
+    async fn stream_blob(
+        &self,
+        container: &str,
+        key: &str,
+        range: Option<Range<u64>>
+    ) -> Result<ByteStream, ObjectStoreError> {
+        let blob_client = self.blob_client.container_client(container).blob_client(key);
+
+        let mut request = blob_client.get();
+        if let Some(range) = range {
+            request = request.range(range);
+        }
+
+        let (container, key) = (container.to_string(), key.to_string());
+        let mut pages = Box::pin(
+            request
+                .into_stream()
+                .and_then(|response| async move { response.data.collect().await })
+                .map_err(move |e| map_error(e, &container, &key))
+        );
+
+        let first = match pages.next().await {
+            Some(first) => first?,
+            None => Bytes::new(),
+        };
+
+        Ok(Box::pin(stream::once(async move { Ok(first) }).chain(pages)))
+    }
+}
+
+#[async_trait(?Send)]
+impl ObjectStore for AzureStorage {
+    fn name(&self) -> &'static str {
+        "azure"
+    }
+
+    fn url(&self, container: &str, key: &str) -> String {
+        self.blob_url(container, key)
+    }
+
+    async fn put(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        data: Bytes
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        if data.len() > self.block_size {
+            let blocks: Vec<Result<Bytes, ObjectStoreError>> = (0..data.len())
+                .step_by(self.block_size)
+                .map(|start| Ok(data.slice(start..(start + self.block_size).min(data.len()))))
+                .collect();
+
+            return self.put_stream(container, key, options, Box::pin(stream::iter(blocks))).await;
+        }
+
+        validate_container(container)?;
+        validate_key(key)?;
+
+        let blob_client = self.blob_client.container_client(container).blob_client(key);
+        let size = data.len() as u64;
+        let md5: [u8; 16] = Md5::digest(&data).into();
+
+        let mut request = blob_client
+            .put_block_blob(data)
+            .content_type(options.content_type.clone())
+            .hash(Hash::MD5(md5));
+        if let Some(content_disposition) = &options.content_disposition {
+            request = request.content_disposition(BlobContentDisposition::from(content_disposition));
+        }
+        if !options.metadata.is_empty() {
+            request = request.metadata(azure_metadata(&options.metadata));
+        }
+        if !options.tags.is_empty() {
+            request = request.tags(azure_tags(&options.tags));
+        }
+
+        let response = request.await.map_err(|e| map_error(e, container, key))?;
+        self.set_cache_control(&blob_client, options, Some(md5)).await.map_err(|e| map_error(e, container, key))?;
+
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size,
+            content_type: Some(options.content_type.clone()),
+            cache_control: options.cache_control.clone(),
+            metadata: options.metadata.clone(),
+            last_modified: to_utc(response.last_modified.unix_timestamp(), response.last_modified.nanosecond()),
+        })
+    }
+
+    /// Cuts the stream into `block_size` blocks and uploads up to
+    /// `parallelism` of them at a time, so memory use is bounded by
+    /// `block_size * parallelism` regardless of the object size. Each block
+    /// carries its MD5 for Azure to verify.
+    async fn put_stream(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        data: ByteStream
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        let blob_client = self.blob_client.container_client(container).blob_client(key);
+
+        let block_ids: Vec<String> = rechunk(data, self.block_size)
+            .enumerate()
+            .map(|(index, block)| {
+                let blob_client = blob_client.clone();
+                async move {
+                    let block = block?;
+                    let id = block_id(index);
+                    let md5: [u8; 16] = Md5::digest(&block).into();
+
+                    blob_client
+                        .put_block(BlockId::new(id.clone()), block)
+                        .hash(Hash::MD5(md5)).await
+                        .map_err(|e| map_error(e, container, key))?;
+
+                    Ok::<_, ObjectStoreError>(id)
+                }
+            })
+            .buffered(self.parallelism)
+            .try_collect().await?;
+
+        self.commit_blocks(container, key, options, &block_ids).await
+    }
+
+    async fn get(&self, container: &str, key: &str) -> Result<ByteStream, ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        self.stream_blob(container, key, None).await
+    }
+
+    async fn get_range(
+        &self,
+        container: &str,
+        key: &str,
+        range: Range<u64>
+    ) -> Result<ByteStream, ObjectStoreError> {
+        let meta = self.head(container, key).await?;
+        let range = clamp_range(&range, meta.size)?;
+
+        self.stream_blob(container, key, Some(range)).await
+    }
+
+    async fn head(&self, container: &str, key: &str) -> Result<ObjectMeta, ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        let blob_client = self.blob_client.container_client(container).blob_client(key);
+        let response = blob_client.get_properties().await.map_err(|e| map_error(e, container, key))?;
+
+        Ok(blob_meta(&response.blob))
+    }
+
+    async fn delete(&self, container: &str, key: &str) -> Result<(), ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        let blob_client = self.blob_client.container_client(container).blob_client(key);
+
+        match blob_client.delete().await.map_err(|e| map_error(e, container, key)) {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_not_found() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list(&self, container: &str, prefix: &str) -> Result<Vec<ObjectMeta>, ObjectStoreError> {
+        validate_container(container)?;
+
+        let container_client = self.blob_client.container_client(container);
+        let mut pages = container_client
+            .list_blobs()
+            .prefix(prefix.to_string())
+            .include_metadata(true)
+            .into_stream();
+        let mut objects = Vec::new();
+
+        while let Some(page) = pages.next().await {
+            let page = match page.map_err(|e| map_error(e, container, prefix)) {
+                Ok(page) => page,
+                Err(e) if e.is_not_found() => {
+                    break;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+
+            objects.extend(page.blobs.blobs().map(blob_meta));
+        }
+
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(objects)
+    }
+
+    async fn stage_block(
+        &self,
+        container: &str,
+        key: &str,
+        block_id: &str,
+        data: Bytes,
+        md5: [u8; 16]
+    ) -> Result<(), ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        let blob_client = self.blob_client.container_client(container).blob_client(key);
+
+        blob_client
+            .put_block(BlockId::new(block_id.to_string()), data)
+            .hash(Hash::MD5(md5)).await
+            .map_err(|e| map_error(e, container, key))?;
+
+        Ok(())
+    }
+
+    async fn commit_blocks(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        block_ids: &[String]
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        let blob_client = self.blob_client.container_client(container).blob_client(key);
+
+        let mut block_list = BlockList::default();
+        for block_id in block_ids {
+            block_list.blocks.push(BlobBlockType::new_uncommitted(BlockId::new(block_id.clone())));
+        }
+
+        let mut request = blob_client.put_block_list(block_list).content_type(options.content_type.clone());
+        if let Some(content_disposition) = &options.content_disposition {
+            request = request.content_disposition(BlobContentDisposition::from(content_disposition));
+        }
+        if !options.metadata.is_empty() {
+            request = request.metadata(azure_metadata(&options.metadata));
+        }
+        if !options.tags.is_empty() {
+            request = request.tags(azure_tags(&options.tags));
+        }
+
+        request.await.map_err(|e| map_error(e, container, key))?;
+        self.set_cache_control(&blob_client, options, None).await.map_err(|e| map_error(e, container, key))?;
+
+        self.head(container, key).await
+    }
+
+    /// Uncommitted blocks are garbage collected by Azure after a week, so
+    /// there is nothing to clean up.
+    async fn abort_blocks(&self, _container: &str, _key: &str, _block_ids: &[String]) -> Result<(), ObjectStoreError> {
+        Ok(())
+    }
+
+    /// Issues a service SAS scoped to the single blob.
+    async fn signed_url(
+        &self,
+        container: &str,
+        key: &str,
+        permissions: LinkPermissions,
+        expires_in: Duration
+    ) -> Result<String, ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        let blob_client = self.blob_client.container_client(container).blob_client(key);
+        let sas_permissions = BlobSasPermissions {
+            read: permissions.read,
+            write: permissions.write,
+            delete: permissions.delete,
+            ..Default::default()
+        };
+        let expiry = time::OffsetDateTime::now_utc() + expires_in;
+
+        let sas = blob_client
+            .shared_access_signature(sas_permissions, expiry).await
+            .map_err(|e| map_error(e, container, key))?;
+        let url = blob_client.generate_signed_blob_url(&sas).map_err(|e| map_error(e, container, key))?;
+
+        Ok(url.to_string())
+    }
+}
@@ -0,0 +1,41 @@
+/// How many leading bytes are inspected when sniffing a content type.
+pub const SNIFF_LEN: usize = 8192;
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Picks a content type for an upload. Magic numbers win for binary formats,
+/// then the file extension (text formats have no signature), then whatever
+/// the client declared, then a plain-text check on the bytes themselves.
+pub fn detect_content_type(file_name: &str, head: &[u8], declared: Option<&str>) -> String {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_string();
+    }
+
+    if let Some(guess) = mime_guess::from_path(file_name).first_raw() {
+        return guess.to_string();
+    }
+
+    let declared = declared.map(str::trim).filter(|declared| !declared.is_empty() && !declared.starts_with(OCTET_STREAM));
+    if let Some(declared) = declared {
+        return declared.to_string();
+    }
+
+    if looks_like_text(head) {
+        return "text/plain; charset=utf-8".to_string();
+    }
+
+    OCTET_STREAM.to_string()
+}
+
+/// UTF-8 without NUL bytes. A multi-byte character cut off at the end of
+/// the sniffed window doesn't count against it.
+fn looks_like_text(head: &[u8]) -> bool {
+    if head.is_empty() || head.contains(&0) {
+        return false;
+    }
+
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
@@ -0,0 +1,588 @@
+use actix_web::{ web, HttpResponse };
+use actix_session::Session;
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::models::*;
+use crate::object_store::*;
+use crate::ollama::*;
+use crate::postgres_db::*;
+use crate::redis_client::Cache;
+use crate::response::*;
+use crate::usage::*;
+
+const DEFAULT_COLLECTION: &str = "default";
+const DEFAULT_TOP_K: usize = 5;
+const MAX_TOP_K: usize = 50;
+const DEFAULT_ASK_TOP_K: usize = 4;
+const DEFAULT_MIN_SCORE: f64 = 0.3;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS documents (
+        id UUID PRIMARY KEY,
+        collection TEXT NOT NULL,
+        title TEXT NOT NULL,
+        chunk_count INTEGER NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    ALTER TABLE documents ADD COLUMN IF NOT EXISTS blob_url TEXT;
+    CREATE INDEX IF NOT EXISTS documents_collection_idx ON documents (collection);
+    CREATE TABLE IF NOT EXISTS document_chunks (
+        id UUID PRIMARY KEY,
+        document_id UUID NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+        chunk_index INTEGER NOT NULL,
+        start_offset INTEGER NOT NULL,
+        end_offset INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        embedding REAL[] NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS document_chunks_document_idx ON document_chunks (document_id);
+";
+
+/// With pgvector, embeddings live in a `vector(n)` column behind an HNSW
+/// index. Existing `REAL[]` embeddings are converted in place.
+fn vector_schema(dimensions: usize) -> String {
+    format!(
+        "
+        DO $$
+        BEGIN
+            IF (SELECT data_type FROM information_schema.columns
+                WHERE table_name = 'document_chunks' AND column_name = 'embedding') = 'ARRAY' THEN
+                ALTER TABLE document_chunks ALTER COLUMN embedding TYPE vector({0}) USING embedding::vector({0});
+            END IF;
+        END $$;
+        CREATE INDEX IF NOT EXISTS document_chunks_embedding_idx
+            ON document_chunks USING hnsw (embedding vector_cosine_ops);
+        ",
+        dimensions
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct TextChunk {
+    pub index: usize,
+    pub start: usize,
+    pub end: usize,
+    pub content: String,
+}
+
+/// Splits `text` into chunks of roughly `chunk_size` bytes that overlap by
+/// `overlap` bytes, preferring to break on whitespace. Offsets are byte
+/// offsets into the original text and span exactly the (trimmed) content.
+pub fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<TextChunk> {
+    let chunk_size = chunk_size.max(1);
+    let overlap = overlap.min(chunk_size / 2);
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < text.len() {
+        let mut end = floor_char_boundary(text, (start + chunk_size).min(text.len()));
+
+        if end < text.len() {
+            if let Some(ws) = text[start..end].rfind(char::is_whitespace) {
+                if ws > 0 {
+                    end = start + ws;
+                }
+            }
+        }
+        if end <= start {
+            end = ceil_char_boundary(text, start + 1);
+        }
+
+        let raw = &text[start..end];
+        let content = raw.trim();
+        if !content.is_empty() {
+            let content_start = start + (raw.len() - raw.trim_start().len());
+            chunks.push(TextChunk {
+                index: chunks.len(),
+                start: content_start,
+                end: content_start + content.len(),
+                content: content.to_string(),
+            });
+        }
+
+        if end >= text.len() {
+            break;
+        }
+
+        let next = floor_char_boundary(text, end.saturating_sub(overlap));
+        start = if next > start { next } else { end };
+    }
+
+    chunks
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while index < text.len() && !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index.min(text.len())
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+
+    let mut dot = 0.0f64;
+    let mut norm_a = 0.0f64;
+    let mut norm_b = 0.0f64;
+    for (x, y) in a.iter().zip(b.iter()) {
+        dot += (*x as f64) * (*y as f64);
+        norm_a += (*x as f64) * (*x as f64);
+        norm_b += (*y as f64) * (*y as f64);
+    }
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+/// Builds a grounded prompt where every retrieved chunk is numbered so the
+/// model can cite it as `[n]`.
+pub fn build_citation_prompt(question: &str, hits: &[SearchHit]) -> String {
+    let mut prompt = String::from(
+        "Answer the question using only the numbered sources below. \
+         Cite every claim with the number of its source in square brackets, e.g. [1]. \
+         If the sources do not contain the answer, say that you don't know.\n\n"
+    );
+
+    for (i, hit) in hits.iter().enumerate() {
+        prompt.push_str(&format!(
+            "[{}] {} (document {}, offsets {}-{})\n{}\n\n",
+            i + 1,
+            hit.title,
+            hit.document_id,
+            hit.start_offset,
+            hit.end_offset,
+            hit.content
+        ));
+    }
+
+    prompt.push_str(&format!("Question: {}\nAnswer:", question));
+    prompt
+}
+
+pub struct DocumentIndex {
+    ollama: OllamaAI,
+    embedding_model: String,
+    blob_container: String,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    /// Length of the embedding model's vectors.
+    dimensions: usize,
+    pgvector: bool,
+}
+
+impl DocumentIndex {
+    pub async fn new(
+        db: &PostgresDb,
+        embedding_model: String,
+        blob_container: String,
+        chunk_size: usize,
+        chunk_overlap: usize,
+        dimensions: usize
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        db.batch_execute(SCHEMA).await?;
+
+        if let Err(e) = db.batch_execute("CREATE EXTENSION IF NOT EXISTS vector").await {
+            log::warn!("pgvector extension unavailable: {}", e);
+        }
+        let pgvector = !db
+            .query(
+                "SELECT extname FROM pg_extension WHERE extname = 'vector'",
+                &[],
+                |row| Ok(row.get::<_, String>(0))
+            ).await?
+            .is_empty();
+
+        if pgvector {
+            db.batch_execute(&vector_schema(dimensions)).await?;
+            log::info!("Using pgvector for document similarity search");
+        } else {
+            log::info!("pgvector not installed, falling back to in-process cosine search");
+        }
+
+        Ok(Self {
+            ollama: OllamaAI::new(),
+            embedding_model,
+            blob_container,
+            chunk_size,
+            chunk_overlap,
+            dimensions,
+            pgvector,
+        })
+    }
+
+    pub fn embedding_model(&self) -> &str {
+        &self.embedding_model
+    }
+
+    pub fn blob_container(&self) -> &str {
+        &self.blob_container
+    }
+
+    pub async fn generate(
+        &self,
+        model: &str,
+        prompt: &str,
+        temperature: Option<f32>,
+        max_tokens: Option<u32>
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        self.ollama.generate_completion(model, prompt, temperature, max_tokens, false).await
+    }
+
+    pub async fn embed(
+        &self,
+        model: Option<&str>,
+        inputs: Vec<String>
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let model = model.unwrap_or(&self.embedding_model);
+        self.ollama.generate_embeddings(model, inputs).await
+    }
+
+    pub async fn ingest(
+        &self,
+        db: &PostgresDb,
+        collection: &str,
+        title: &str,
+        text: &str,
+        blob_url: Option<String>
+    ) -> Result<Document, Box<dyn std::error::Error>> {
+        let chunks = chunk_text(text, self.chunk_size, self.chunk_overlap);
+        if chunks.is_empty() {
+            return Err("document has no text content".into());
+        }
+
+        let inputs = chunks.iter().map(|chunk| chunk.content.clone()).collect();
+        let embeddings = self.embed(None, inputs).await?;
+        if embeddings.len() != chunks.len() {
+            return Err(format!(
+                "embedding count mismatch: expected {}, got {}",
+                chunks.len(),
+                embeddings.len()
+            ).into());
+        }
+        if self.pgvector {
+            if let Some(embedding) = embeddings.iter().find(|embedding| embedding.len() != self.dimensions) {
+                return Err(format!(
+                    "embedding model returned {} dimensions, the index expects {}",
+                    embedding.len(),
+                    self.dimensions
+                ).into());
+            }
+        }
+
+        let document = Document {
+            id: Uuid::new_v4(),
+            collection: collection.to_string(),
+            title: title.to_string(),
+            chunk_count: chunks.len() as i32,
+            blob_url,
+            created_at: chrono::Utc::now(),
+        };
+
+        // All or nothing, so a failure never leaves a half-indexed document.
+        let transaction = db.transaction().await?;
+        transaction.execute(
+            "INSERT INTO documents (id, collection, title, chunk_count, blob_url, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                &document.id,
+                &document.collection,
+                &document.title,
+                &document.chunk_count,
+                &document.blob_url,
+                &document.created_at,
+            ]
+        ).await?;
+
+        let insert_chunk = format!(
+            "INSERT INTO document_chunks (id, document_id, chunk_index, start_offset, end_offset, content, embedding)
+             VALUES ($1, $2, $3, $4, $5, $6, {})",
+            if self.pgvector { "$7::real[]::vector" } else { "$7" }
+        );
+        for (chunk, embedding) in chunks.iter().zip(embeddings.iter()) {
+            transaction.execute(
+                &insert_chunk,
+                &[
+                    &Uuid::new_v4(),
+                    &document.id,
+                    &(chunk.index as i32),
+                    &(chunk.start as i32),
+                    &(chunk.end as i32),
+                    &chunk.content,
+                    embedding,
+                ]
+            ).await?;
+        }
+        transaction.commit().await?;
+
+        log::info!("Indexed document {} with {} chunks", document.id, document.chunk_count);
+        Ok(document)
+    }
+
+    pub async fn search(
+        &self,
+        db: &PostgresDb,
+        collection: &str,
+        query: &str,
+        top_k: usize
+    ) -> Result<Vec<SearchHit>, Box<dyn std::error::Error>> {
+        let embedding = self
+            .embed(None, vec![query.to_string()]).await?
+            .pop()
+            .ok_or("embedding model returned no vectors")?;
+
+        if self.pgvector {
+            return db.query(
+                "SELECT c.document_id, d.title, d.blob_url, c.chunk_index, c.start_offset, c.end_offset, c.content,
+                        (1 - (c.embedding <=> $2::real[]::vector))::float8 AS score
+                 FROM document_chunks c
+                 JOIN documents d ON d.id = c.document_id
+                 WHERE d.collection = $1
+                 ORDER BY c.embedding <=> $2::real[]::vector
+                 LIMIT $3",
+                &[&collection, &embedding, &(top_k as i64)],
+                map_search_hit
+            ).await;
+        }
+
+        let rows = db.query(
+            "SELECT c.document_id, d.title, d.blob_url, c.chunk_index, c.start_offset, c.end_offset, c.content, c.embedding
+             FROM document_chunks c
+             JOIN documents d ON d.id = c.document_id
+             WHERE d.collection = $1",
+            &[&collection],
+            map_chunk_with_embedding
+        ).await?;
+
+        let mut hits: Vec<SearchHit> = rows
+            .into_iter()
+            .map(|(mut hit, chunk_embedding)| {
+                hit.score = cosine_similarity(&embedding, &chunk_embedding);
+                hit
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+        hits.truncate(top_k);
+
+        Ok(hits)
+    }
+}
+
+fn map_search_hit(row: &Row) -> Result<SearchHit, Box<dyn std::error::Error>> {
+    Ok(SearchHit {
+        document_id: row.try_get("document_id")?,
+        title: row.try_get("title")?,
+        blob_url: row.try_get("blob_url")?,
+        chunk_index: row.try_get("chunk_index")?,
+        start_offset: row.try_get("start_offset")?,
+        end_offset: row.try_get("end_offset")?,
+        content: row.try_get("content")?,
+        score: row.try_get("score")?,
+    })
+}
+
+fn map_chunk_with_embedding(row: &Row) -> Result<(SearchHit, Vec<f32>), Box<dyn std::error::Error>> {
+    let hit = SearchHit {
+        document_id: row.try_get("document_id")?,
+        title: row.try_get("title")?,
+        blob_url: row.try_get("blob_url")?,
+        chunk_index: row.try_get("chunk_index")?,
+        start_offset: row.try_get("start_offset")?,
+        end_offset: row.try_get("end_offset")?,
+        content: row.try_get("content")?,
+        score: 0.0,
+    };
+    Ok((hit, row.try_get("embedding")?))
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingsRequest {
+    model: Option<String>,
+    input: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DocumentUploadRequest {
+    collection: Option<String>,
+    title: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+pub struct AskRequest {
+    question: String,
+    model: String,
+    collection: Option<String>,
+    top_k: Option<usize>,
+    min_score: Option<f64>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    collection: Option<String>,
+    top_k: Option<usize>,
+}
+
+pub async fn create_embeddings(
+    index: web::Data<DocumentIndex>,
+    req: web::Json<EmbeddingsRequest>
+) -> HttpResponse {
+    if req.input.is_empty() {
+        return response_unprocessable_entity(
+            serde_json::json!({ "input": "at least one input is required" })
+        );
+    }
+
+    match index.embed(req.model.as_deref(), req.input.clone()).await {
+        Ok(embeddings) => response_ok("embeddings generated successfully", embeddings),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn upload_document(
+    index: web::Data<DocumentIndex>,
+    db: web::Data<PostgresDb>,
+    storage: web::Data<dyn ObjectStore>,
+    req: web::Json<DocumentUploadRequest>
+) -> HttpResponse {
+    if req.content.trim().is_empty() {
+        return response_unprocessable_entity(
+            serde_json::json!({ "content": "document content must not be empty" })
+        );
+    }
+
+    let collection = req.collection.as_deref().unwrap_or(DEFAULT_COLLECTION);
+
+    let blob_name = format!("{}/{}.txt", collection, Uuid::new_v4());
+    // The text is indexed either way; the response says when the original
+    // couldn't be kept, and `blob_url` is then null.
+    let stored = storage.put(
+        index.blob_container(),
+        &blob_name,
+        &PutOptions::new("text/plain; charset=utf-8"),
+        Bytes::from(req.content.clone())
+    ).await;
+    let (blob_url, message) = match stored {
+        Ok(_) => (Some(storage.url(index.blob_container(), &blob_name)), "document indexed successfully".to_string()),
+        Err(e) => {
+            log::error!("Failed to store original document {} in blob storage: {}", blob_name, e);
+            (None, format!("document indexed, but the original could not be stored: {}", e))
+        }
+    };
+
+    let stored = blob_url.is_some();
+    match index.ingest(&db, collection, &req.title, &req.content, blob_url).await {
+        Ok(document) => response_created(&message, document),
+        Err(err) => {
+            // Nothing refers to the original without its document.
+            if stored {
+                if let Err(e) = storage.delete(index.blob_container(), &blob_name).await {
+                    log::error!("Failed to delete original document {} after a failed ingest: {}", blob_name, e);
+                }
+            }
+            response_internal_server_error(err.to_string().as_str())
+        }
+    }
+}
+
+pub async fn search_documents(
+    index: web::Data<DocumentIndex>,
+    db: web::Data<PostgresDb>,
+    query: web::Query<SearchQuery>
+) -> HttpResponse {
+    if query.q.trim().is_empty() {
+        return response_unprocessable_entity(serde_json::json!({ "q": "query must not be empty" }));
+    }
+
+    let collection = query.collection.as_deref().unwrap_or(DEFAULT_COLLECTION);
+    let top_k = query.top_k.unwrap_or(DEFAULT_TOP_K).clamp(1, MAX_TOP_K);
+
+    match index.search(&db, collection, &query.q, top_k).await {
+        Ok(hits) => response_ok("search completed successfully", hits),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn ask_documents(
+    index: web::Data<DocumentIndex>,
+    db: web::Data<PostgresDb>,
+    cache: web::Data<Cache>,
+    usage: web::Data<UsageTracker>,
+    session: Session,
+    req: web::Json<AskRequest>
+) -> HttpResponse {
+    if req.question.trim().is_empty() {
+        return response_unprocessable_entity(
+            serde_json::json!({ "question": "question must not be empty" })
+        );
+    }
+
+    let user = match enforce_quota(&usage, &cache, &db, &session).await {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    let collection = req.collection.as_deref().unwrap_or(DEFAULT_COLLECTION);
+    let top_k = req.top_k.unwrap_or(DEFAULT_ASK_TOP_K).clamp(1, MAX_TOP_K);
+    let min_score = req.min_score.unwrap_or(DEFAULT_MIN_SCORE);
+
+    let hits: Vec<SearchHit> = match index.search(&db, collection, &req.question, top_k).await {
+        Ok(hits) => hits.into_iter().filter(|hit| hit.score >= min_score).collect(),
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    if hits.is_empty() {
+        return response_not_found("no documents matched the question above the similarity threshold");
+    }
+
+    let prompt = build_citation_prompt(&req.question, &hits);
+    let completion = match index.generate(&req.model, &prompt, req.temperature, req.max_tokens).await {
+        Ok(completion) => completion,
+        Err(err) => {
+            let response = response_internal_server_error(err.to_string().as_str());
+            return finish_usage(&usage, &cache, &db, &user, &req.model, &TokenUsage::default(), response).await;
+        }
+    };
+
+    let sources = hits
+        .into_iter()
+        .enumerate()
+        .map(|(i, hit)| AnswerSource {
+            citation: i + 1,
+            document_id: hit.document_id,
+            title: hit.title,
+            blob_url: hit.blob_url,
+            chunk_index: hit.chunk_index,
+            start_offset: hit.start_offset,
+            end_offset: hit.end_offset,
+            score: hit.score,
+        })
+        .collect();
+
+    let response = response_ok("answer generated successfully", Answer {
+        answer: completion.text,
+        model: req.model.clone(),
+        sources,
+    });
+    finish_usage(&usage, &cache, &db, &user, &req.model, &completion.usage, response).await
+}
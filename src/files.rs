@@ -0,0 +1,662 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_multipart::{ Field, Multipart };
+use actix_session::Session;
+use actix_web::{
+    http::header::{ ContentDisposition, DispositionParam, DispositionType, CACHE_CONTROL, CONTENT_LENGTH },
+    web,
+    HttpRequest,
+    HttpResponse,
+};
+use bytes::BytesMut;
+use futures::{ StreamExt, TryStreamExt };
+use serde::{ Deserialize, Serialize };
+use tokio_postgres::types::Json;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::content_type::*;
+use crate::malware_scan::*;
+use crate::media::*;
+use crate::middleware::*;
+use crate::models::*;
+use crate::object_store::*;
+use crate::postgres_db::*;
+use crate::response::*;
+use crate::upload_policy::*;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+/// Upper bound for the non-file form fields of an upload.
+const MAX_FORM_FIELD_SIZE: usize = 64 * 1024;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS files (
+        id UUID PRIMARY KEY,
+        owner_id UUID NOT NULL,
+        file_name TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        size BIGINT NOT NULL,
+        container TEXT NOT NULL,
+        blob_name TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX IF NOT EXISTS files_owner_name_idx ON files (owner_id, file_name);
+    ALTER TABLE files
+        ADD COLUMN IF NOT EXISTS cache_control TEXT,
+        ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}',
+        ADD COLUMN IF NOT EXISTS tags JSONB NOT NULL DEFAULT '{}',
+        ADD COLUMN IF NOT EXISTS scan_status TEXT NOT NULL DEFAULT 'clean';
+    CREATE INDEX IF NOT EXISTS files_scan_status_idx ON files (scan_status) WHERE scan_status <> 'clean';
+    DELETE FROM files WHERE scan_status = 'infected';
+    CREATE TABLE IF NOT EXISTS quota_reservations (
+        id UUID PRIMARY KEY,
+        owner_id UUID NOT NULL,
+        bytes BIGINT NOT NULL,
+        expires_at TIMESTAMPTZ NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS quota_reservations_owner_idx ON quota_reservations (owner_id);
+";
+
+const INSERT_FILE: &str =
+    "INSERT INTO files
+        (id, owner_id, file_name, content_type, size, cache_control, metadata, tags, scan_status, container, blob_name,
+         created_at)
+     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)";
+
+pub const FILE_COLUMNS: &str =
+    "id, owner_id, file_name, content_type, size, cache_control, metadata, tags, scan_status, container, blob_name, created_at";
+
+/// Limits for signed download links.
+pub struct LinkPolicy {
+    pub default_ttl: Duration,
+    pub max_ttl: Duration,
+    /// The most a link may grant; requests may ask for less.
+    pub permissions: LinkPermissions,
+}
+
+impl Default for LinkPolicy {
+    fn default() -> Self {
+        Self {
+            default_ttl: Duration::from_secs(15 * 60),
+            max_ttl: Duration::from_secs(60 * 60),
+            permissions: LinkPermissions::READ,
+        }
+    }
+}
+
+pub struct FileStore {
+    container: String,
+    links: LinkPolicy,
+}
+
+impl FileStore {
+    pub async fn new(db: &PostgresDb, container: String, links: LinkPolicy) -> Result<Self, Box<dyn std::error::Error>> {
+        db.batch_execute(SCHEMA).await?;
+        Ok(Self { container, links })
+    }
+
+    pub fn container(&self) -> &str {
+        &self.container
+    }
+
+    /// Looks a file up by id, scoped to its owner so other users' ids 404.
+    pub async fn find(
+        &self,
+        db: &PostgresDb,
+        owner_id: &Uuid,
+        id: &Uuid
+    ) -> Result<Option<FileRecord>, Box<dyn std::error::Error>> {
+        let mut files = db.query(
+            &format!("SELECT {} FROM files WHERE id = $1 AND owner_id = $2", FILE_COLUMNS),
+            &[id, owner_id],
+            map_file
+        ).await?;
+
+        Ok(files.pop())
+    }
+
+    pub async fn insert(&self, db: &PostgresDb, file: &FileRecord) -> Result<(), Box<dyn std::error::Error>> {
+        db.execute(
+            INSERT_FILE,
+            &[
+                &file.id,
+                &file.owner_id,
+                &file.file_name,
+                &file.content_type,
+                &file.size,
+                &file.cache_control,
+                &Json(&file.metadata),
+                &Json(&file.tags),
+                &file.scan_status,
+                &file.container,
+                &file.blob_name,
+                &file.created_at,
+            ]
+        ).await
+    }
+
+    /// Like `insert`, as part of a larger transaction.
+    pub async fn insert_in(
+        &self,
+        transaction: &DbTransaction,
+        file: &FileRecord
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        transaction.execute(
+            INSERT_FILE,
+            &[
+                &file.id,
+                &file.owner_id,
+                &file.file_name,
+                &file.content_type,
+                &file.size,
+                &file.cache_control,
+                &Json(&file.metadata),
+                &Json(&file.tags),
+                &file.scan_status,
+                &file.container,
+                &file.blob_name,
+                &file.created_at,
+            ]
+        ).await?;
+        Ok(())
+    }
+}
+
+pub fn map_file(row: &Row) -> Result<FileRecord, Box<dyn std::error::Error>> {
+    Ok(FileRecord {
+        id: row.try_get("id")?,
+        owner_id: row.try_get("owner_id")?,
+        file_name: row.try_get("file_name")?,
+        content_type: row.try_get("content_type")?,
+        size: row.try_get("size")?,
+        cache_control: row.try_get("cache_control")?,
+        metadata: row.try_get::<_, Json<BTreeMap<String, String>>>("metadata")?.0,
+        tags: row.try_get::<_, Json<BTreeMap<String, String>>>("tags")?.0,
+        scan_status: row.try_get("scan_status")?,
+        container: row.try_get("container")?,
+        blob_name: row.try_get("blob_name")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+pub fn attachment_disposition(file_name: &str) -> ContentDisposition {
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(file_name.to_string())],
+    }
+}
+
+/// Reads a small, non-file multipart field as text.
+async fn read_form_field(field: &mut Field) -> Result<String, String> {
+    let mut value = BytesMut::new();
+
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        if value.len() + chunk.len() > MAX_FORM_FIELD_SIZE {
+            return Err(format!("field must be at most {} bytes", MAX_FORM_FIELD_SIZE));
+        }
+        value.extend_from_slice(&chunk);
+    }
+
+    String::from_utf8(value.to_vec()).map_err(|_| "field must be UTF-8 text".to_string())
+}
+
+#[derive(Deserialize)]
+pub struct ListFilesQuery {
+    prefix: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct FileLinkRequest {
+    expires_in: Option<u64>,
+    /// SAS-style letters, `r` by default.
+    permissions: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FilePage {
+    files: Vec<FileRecord>,
+    next_offset: Option<i64>,
+}
+
+/// Stores every file field of `payload`, pushing each stored file onto
+/// `uploaded` so a failure part-way through can discard the earlier ones.
+#[allow(clippy::too_many_arguments)]
+async fn store_uploads(
+    store: &FileStore,
+    storage: &dyn ObjectStore,
+    policy: &UploadPolicy,
+    db: &PostgresDb,
+    user: &User,
+    mut remaining_quota: Option<u64>,
+    payload: &mut Multipart,
+    uploaded: &mut Vec<FileRecord>
+) -> Result<(), HttpResponse> {
+    let mut cache_control: Option<String> = None;
+    let mut metadata = BTreeMap::new();
+    let mut tags = BTreeMap::new();
+
+    // Option fields apply to the file fields that follow them.
+    while let Some(field) = payload.next().await {
+        let mut field = match field {
+            Ok(field) => field,
+            Err(err) => {
+                return Err(response_bad_request(err.to_string().as_str()));
+            }
+        };
+
+        let file_name = match field.content_disposition().and_then(|cd| cd.get_filename()) {
+            Some(name) => name.to_string(),
+            None => {
+                let name = field.name().unwrap_or_default().to_string();
+                if !["cache_control", "metadata", "tags"].contains(&name.as_str()) {
+                    continue;
+                }
+
+                let value = match read_form_field(&mut field).await {
+                    Ok(value) => value,
+                    Err(err) => {
+                        return Err(response_unprocessable_entity(serde_json::json!({ name: err })));
+                    }
+                };
+
+                let parsed = match name.as_str() {
+                    "cache_control" => {
+                        cache_control = Some(value.trim().to_string()).filter(|value| !value.is_empty());
+                        Ok(())
+                    }
+                    "metadata" =>
+                        serde_json
+                            ::from_str(&value)
+                            .map_err(|e| e.to_string())
+                            .and_then(|value| validate_metadata(&value).map(|_| value))
+                            .map(|value| {
+                                metadata = value;
+                            }),
+                    _ =>
+                        serde_json
+                            ::from_str(&value)
+                            .map_err(|e| e.to_string())
+                            .and_then(|value| validate_tags(&value).map(|_| value))
+                            .map(|value| {
+                                tags = value;
+                            }),
+                };
+
+                if let Err(err) = parsed {
+                    return Err(response_unprocessable_entity(serde_json::json!({ name: err })));
+                }
+                continue;
+            }
+        };
+
+        let file_name = match sanitize_file_name(&file_name) {
+            Ok(file_name) => file_name,
+            Err(err) => {
+                return Err(response_unprocessable_entity(serde_json::json!({ "file_name": err })));
+            }
+        };
+
+        let declared = field.content_type().map(|mime| mime.to_string());
+        let data: ByteStream = Box::pin(field.map_err(|e| ObjectStoreError::Backend(e.to_string())));
+        let (head, data) = match peek(data, SNIFF_LEN).await {
+            Ok(peeked) => peeked,
+            Err(err) => {
+                return Err(response_bad_request(err.to_string().as_str()));
+            }
+        };
+
+        let content_type = detect_content_type(&file_name, &head, declared.as_deref());
+        let mut errors = serde_json::Map::new();
+        policy.check(&file_name, &content_type, None, &mut errors);
+        if !errors.is_empty() {
+            return Err(response_unprocessable_entity(errors));
+        }
+
+        // The size is only known once the body has streamed through.
+        let data = match [policy.max_size, remaining_quota].into_iter().flatten().min() {
+            Some(limit) => limit_stream(data, limit),
+            None => data,
+        };
+
+        let id = Uuid::new_v4();
+        let blob_name = format!("{}/{}", user.id, id);
+        let options = PutOptions {
+            content_type,
+            cache_control: cache_control.clone(),
+            content_disposition: Some(attachment_disposition(&file_name).to_string()),
+            metadata: metadata.clone(),
+            tags: tags.clone(),
+        };
+
+        let blob = match storage.put_stream(store.container(), &blob_name, &options, data).await {
+            Ok(blob) => blob,
+            Err(ObjectStoreError::TooLarge { limit }) => {
+                return Err(if policy.max_size == Some(limit) {
+                    response_unprocessable_entity(
+                        serde_json::json!({ "size": format!("file must be at most {} bytes", limit) })
+                    )
+                } else {
+                    response_unprocessable_entity(
+                        serde_json::json!({ "quota": format!("upload exceeds the remaining storage quota of {} bytes", limit) })
+                    )
+                });
+            }
+            Err(err) => {
+                return Err(response_internal_server_error(err.to_string().as_str()));
+            }
+        };
+
+        let record = FileRecord {
+            id,
+            owner_id: user.id,
+            file_name,
+            content_type: options.content_type,
+            size: blob.size as i64,
+            cache_control: options.cache_control,
+            metadata: options.metadata,
+            tags: options.tags,
+            scan_status: SCAN_PENDING.to_string(),
+            container: store.container().to_string(),
+            blob_name,
+            created_at: chrono::Utc::now(),
+        };
+
+        if let Err(err) = store.insert(db, &record).await {
+            let _ = storage.delete(&record.container, &record.blob_name).await;
+            return Err(response_internal_server_error(err.to_string().as_str()));
+        }
+
+        if let Some(remaining) = remaining_quota.as_mut() {
+            *remaining = remaining.saturating_sub(blob.size);
+        }
+
+        uploaded.push(record);
+    }
+
+    Ok(())
+}
+
+/// Removes the blobs and rows of an upload that was not completed.
+async fn discard_uploads(storage: &dyn ObjectStore, db: &PostgresDb, uploaded: &[FileRecord]) {
+    for file in uploaded {
+        if let Err(err) = storage.delete(&file.container, &file.blob_name).await {
+            log::warn!("Failed to delete blob {} for discarded file {}: {}", file.blob_name, file.id, err);
+        }
+        if let Err(err) = db.execute("DELETE FROM files WHERE id = $1", &[&file.id]).await {
+            log::warn!("Failed to delete discarded file {}: {}", file.id, err);
+        }
+    }
+}
+
+/// Files are stored quarantined and released once the malware scan passes.
+/// An upload is all or nothing: if any file fails, none are kept.
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_files(
+    store: web::Data<FileStore>,
+    storage: web::Data<dyn ObjectStore>,
+    policies: web::Data<UploadPolicies>,
+    scanner: web::Data<dyn MalwareScanner>,
+    media: web::Data<MediaPipeline>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    req: HttpRequest,
+    mut payload: Multipart
+) -> HttpResponse {
+    let user = match get_user_from_session(&session) {
+        Some(user) => user,
+        None => {
+            return response_unauthorized("unauthorized: no user in session");
+        }
+    };
+
+    // The whole request is an upper bound for the files in it.
+    let request_size = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let policy = policies.policy_for(FILES_ROUTE, &user.role);
+    let reservation = match policy.reserve_quota(&db, &user.id, request_size).await {
+        Ok(reservation) => reservation,
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    let mut uploaded = Vec::new();
+    let remaining_quota = reservation.as_ref().map(|reservation| reservation.bytes);
+    let stored = store_uploads(
+        &store,
+        storage.get_ref(),
+        policy,
+        &db,
+        &user,
+        remaining_quota,
+        &mut payload,
+        &mut uploaded
+    ).await;
+    if let Some(reservation) = reservation {
+        reservation.release(&db).await;
+    }
+    if let Err(response) = stored {
+        discard_uploads(storage.get_ref(), &db, &uploaded).await;
+        return response;
+    }
+
+    if uploaded.is_empty() {
+        return response_unprocessable_entity(serde_json::json!({ "file": "at least one file field is required" }));
+    }
+
+    for record in &uploaded {
+        spawn_scan(scanner.clone(), db.clone(), storage.clone(), media.clone(), record.clone());
+    }
+
+    response_created("files uploaded successfully", uploaded)
+}
+
+pub async fn list_files(
+    db: web::Data<PostgresDb>,
+    session: Session,
+    query: web::Query<ListFilesQuery>
+) -> HttpResponse {
+    let user = match get_user_from_session(&session) {
+        Some(user) => user,
+        None => {
+            return response_unauthorized("unauthorized: no user in session");
+        }
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let prefix = query.prefix.clone().unwrap_or_default();
+    let pattern = format!("{}%", prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+
+    let result = db.query(
+        &format!(
+            "SELECT {} FROM files WHERE owner_id = $1 AND file_name LIKE $2
+             ORDER BY created_at DESC, id LIMIT $3 OFFSET $4",
+            FILE_COLUMNS
+        ),
+        &[&user.id, &pattern, &(limit + 1), &offset],
+        map_file
+    ).await;
+
+    match result {
+        Ok(mut files) => {
+            let next_offset = if files.len() as i64 > limit {
+                files.truncate(limit as usize);
+                Some(offset + limit)
+            } else {
+                None
+            };
+            response_ok("files retrieved successfully", FilePage { files, next_offset })
+        }
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn owned_file(
+    store: &FileStore,
+    db: &PostgresDb,
+    session: &Session,
+    id: &Uuid
+) -> Result<FileRecord, HttpResponse> {
+    let user = match get_user_from_session(session) {
+        Some(user) => user,
+        None => {
+            return Err(response_unauthorized("unauthorized: no user in session"));
+        }
+    };
+
+    match store.find(db, &user.id, id).await {
+        Ok(Some(file)) => Ok(file),
+        Ok(None) => Err(response_not_found("file not found")),
+        Err(err) => Err(response_internal_server_error(err.to_string().as_str())),
+    }
+}
+
+/// Refuses access to content that hasn't passed its malware scan.
+fn check_released(file: &FileRecord) -> Result<(), HttpResponse> {
+    match file.scan_status.as_str() {
+        SCAN_CLEAN => Ok(()),
+        _ => Err(response_conflict("file is quarantined until its malware scan passes")),
+    }
+}
+
+pub async fn get_file(
+    store: web::Data<FileStore>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    id: web::Path<Uuid>
+) -> HttpResponse {
+    match owned_file(&store, &db, &session, &id).await {
+        Ok(file) => response_ok("file retrieved successfully", file),
+        Err(response) => response,
+    }
+}
+
+pub async fn download_file(
+    store: web::Data<FileStore>,
+    storage: web::Data<dyn ObjectStore>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    id: web::Path<Uuid>
+) -> HttpResponse {
+    let file = match owned_file(&store, &db, &session, &id).await {
+        Ok(file) => file,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    if let Err(response) = check_released(&file) {
+        return response;
+    }
+
+    let data = match storage.get(&file.container, &file.blob_name).await {
+        Ok(data) => data,
+        Err(err) if err.is_not_found() => {
+            return response_not_found("file content not found");
+        }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    let mut response = HttpResponse::Ok();
+    response.content_type(file.content_type.as_str()).insert_header(attachment_disposition(&file.file_name));
+    if let Some(cache_control) = &file.cache_control {
+        response.insert_header((CACHE_CONTROL, cache_control.as_str()));
+    }
+
+    response.no_chunking(file.size as u64).streaming(data)
+}
+
+/// Issues a time-limited link that works without our session, e.g. for a
+/// browser download straight from the storage service.
+pub async fn create_file_link(
+    store: web::Data<FileStore>,
+    storage: web::Data<dyn ObjectStore>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    id: web::Path<Uuid>,
+    req: web::Json<FileLinkRequest>
+) -> HttpResponse {
+    let file = match owned_file(&store, &db, &session, &id).await {
+        Ok(file) => file,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    if let Err(response) = check_released(&file) {
+        return response;
+    }
+
+    let permissions = match req.permissions.as_deref().map(LinkPermissions::parse) {
+        None => LinkPermissions::READ,
+        Some(Ok(permissions)) => permissions,
+        Some(Err(err)) => {
+            return response_unprocessable_entity(serde_json::json!({ "permissions": err }));
+        }
+    };
+    if !permissions.is_subset_of(&store.links.permissions) {
+        return response_unprocessable_entity(
+            serde_json::json!({ "permissions": format!("links may grant at most {:?}", store.links.permissions.to_string()) })
+        );
+    }
+
+    let expires_in = req.expires_in.map(Duration::from_secs).unwrap_or(store.links.default_ttl);
+    if expires_in.is_zero() || expires_in > store.links.max_ttl {
+        return response_unprocessable_entity(
+            serde_json::json!({ "expires_in": format!("must be between 1 and {} seconds", store.links.max_ttl.as_secs()) })
+        );
+    }
+
+    let expires_at = chrono::Utc::now() + chrono::Duration::seconds(expires_in.as_secs() as i64);
+
+    match storage.signed_url(&file.container, &file.blob_name, permissions, expires_in).await {
+        Ok(url) =>
+            response_created("file link created successfully", FileLink {
+                url,
+                permissions: permissions.to_string(),
+                expires_at,
+            }),
+        Err(ObjectStoreError::Unsupported(what)) => {
+            response_not_implemented(format!("{} are not available with {} storage", what, storage.name()).as_str())
+        }
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn delete_file(
+    store: web::Data<FileStore>,
+    storage: web::Data<dyn ObjectStore>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    id: web::Path<Uuid>
+) -> HttpResponse {
+    let file = match owned_file(&store, &db, &session, &id).await {
+        Ok(file) => file,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    if let Err(err) = storage.delete(&file.container, &file.blob_name).await {
+        log::warn!("Failed to delete blob {} for file {}: {}", file.blob_name, file.id, err);
+    }
+    if let Err(err) = delete_renditions(&db, storage.get_ref(), &file).await {
+        log::warn!("Failed to delete renditions for file {}: {}", file.id, err);
+    }
+
+    match db.execute("DELETE FROM files WHERE id = $1 AND owner_id = $2", &[&file.id, &file.owner_id]).await {
+        Ok(_) => response_no_content(),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
@@ -3,5 +3,22 @@ pub mod middleware;
 pub mod response;
 pub mod ollama;
 pub mod postgres_db;
+pub mod redis_client;
 
-pub mod azure_storage;
\ No newline at end of file
+pub mod azure_storage;
+pub mod content_type;
+pub mod local_storage;
+pub mod memory_storage;
+pub mod object_store;
+pub mod s3_storage;
+pub mod documents;
+pub mod prompt_templates;
+pub mod structured_output;
+pub mod usage;
+pub mod files;
+pub mod uploads;
+pub mod media;
+pub mod malware_scan;
+pub mod upload_policy;
+pub mod websocket;
+pub mod solana_h;
@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{ Path, PathBuf };
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{ DateTime, Utc };
+use futures::{ StreamExt, TryStreamExt };
+use serde::{ Deserialize, Serialize };
+use tokio::fs;
+use tokio::io::{ AsyncReadExt, AsyncSeekExt, AsyncWriteExt };
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::object_store::*;
+
+/// Sidecar kept next to each object since the filesystem has no place for
+/// the content type and other properties.
+#[derive(Serialize, Deserialize)]
+struct LocalObjectMeta {
+    content_type: String,
+    #[serde(default)]
+    cache_control: Option<String>,
+    #[serde(default)]
+    content_disposition: Option<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    last_modified: DateTime<Utc>,
+}
+
+/// Stores objects as plain files under `root`:
+///
+/// - `objects/{container}/{key}` holds the data
+/// - `meta/{container}/{key}.json` holds the content type, metadata and tags
+/// - `tmp/` holds uploads in progress, renamed into place when complete
+///
+/// Because keys map to paths, a key can't also be a "directory" of other
+/// keys (`a` and `a/b` can't both exist).
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self, std::io::Error> {
+        let root = root.into();
+        std::fs::create_dir_all(root.join("tmp"))?;
+        Ok(Self { root })
+    }
+
+    fn object_path(&self, container: &str, key: &str) -> Result<PathBuf, ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+        Ok(self.root.join("objects").join(container).join(key))
+    }
+
+    fn meta_path(&self, container: &str, key: &str) -> PathBuf {
+        self.root.join("meta").join(container).join(format!("{}.json", key))
+    }
+
+    async fn open(&self, container: &str, key: &str) -> Result<(fs::File, u64), ObjectStoreError> {
+        let path = self.object_path(container, key)?;
+
+        let file = match fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ObjectStoreError::not_found(container, key));
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        };
+        let size = file.metadata().await?.len();
+
+        Ok((file, size))
+    }
+
+    async fn read_meta(&self, container: &str, key: &str, path: &Path) -> Result<ObjectMeta, ObjectStoreError> {
+        let metadata = match fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => {
+                return Err(ObjectStoreError::not_found(container, key));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ObjectStoreError::not_found(container, key));
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        };
+
+        let sidecar = fs::read(self.meta_path(container, key)).await
+            .ok()
+            .and_then(|data| serde_json::from_slice::<LocalObjectMeta>(&data).ok());
+
+        Ok(match sidecar {
+            Some(sidecar) =>
+                ObjectMeta {
+                    key: key.to_string(),
+                    size: metadata.len(),
+                    content_type: Some(sidecar.content_type),
+                    cache_control: sidecar.cache_control,
+                    metadata: sidecar.metadata,
+                    last_modified: sidecar.last_modified,
+                },
+            None =>
+                ObjectMeta {
+                    key: key.to_string(),
+                    size: metadata.len(),
+                    content_type: None,
+                    cache_control: None,
+                    metadata: BTreeMap::new(),
+                    last_modified: metadata.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now()),
+                },
+        })
+    }
+
+    /// Moves a finished temp file into place and writes its sidecar.
+    async fn commit(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        temp_path: &Path
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        let path = self.object_path(container, key)?;
+        let meta_path = self.meta_path(container, key);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        if let Some(parent) = meta_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let sidecar = LocalObjectMeta {
+            content_type: options.content_type.clone(),
+            cache_control: options.cache_control.clone(),
+            content_disposition: options.content_disposition.clone(),
+            metadata: options.metadata.clone(),
+            tags: options.tags.clone(),
+            last_modified: Utc::now(),
+        };
+        let sidecar = serde_json::to_vec(&sidecar).map_err(|e| ObjectStoreError::Backend(e.to_string()))?;
+        fs::write(&meta_path, sidecar).await?;
+        fs::rename(temp_path, &path).await?;
+
+        self.read_meta(container, key, &path).await
+    }
+}
+
+#[async_trait(?Send)]
+impl ObjectStore for LocalStorage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn url(&self, container: &str, key: &str) -> String {
+        format!("file://{}", self.root.join("objects").join(container).join(key).display())
+    }
+
+    async fn put(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        data: Bytes
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        self.put_stream(container, key, options, Box::pin(futures::stream::once(async move { Ok(data) }))).await
+    }
+
+    async fn put_stream(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        mut data: ByteStream
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        self.object_path(container, key)?;
+
+        let temp_path = self.root.join("tmp").join(Uuid::new_v4().to_string());
+        let mut file = fs::File::create(&temp_path).await?;
+
+        let written: Result<(), ObjectStoreError> = async {
+            while let Some(chunk) = data.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await?;
+            Ok(())
+        }.await;
+        drop(file);
+
+        let result = match written {
+            Ok(()) => self.commit(container, key, options, &temp_path).await,
+            Err(e) => Err(e),
+        };
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        result
+    }
+
+    async fn get(&self, container: &str, key: &str) -> Result<ByteStream, ObjectStoreError> {
+        let (file, _) = self.open(container, key).await?;
+        Ok(Box::pin(ReaderStream::new(file).map_err(ObjectStoreError::from)))
+    }
+
+    async fn get_range(
+        &self,
+        container: &str,
+        key: &str,
+        range: Range<u64>
+    ) -> Result<ByteStream, ObjectStoreError> {
+        let (mut file, size) = self.open(container, key).await?;
+        let range = clamp_range(&range, size)?;
+
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let reader = file.take(range.end - range.start);
+
+        Ok(Box::pin(ReaderStream::new(reader).map_err(ObjectStoreError::from)))
+    }
+
+    async fn head(&self, container: &str, key: &str) -> Result<ObjectMeta, ObjectStoreError> {
+        let path = self.object_path(container, key)?;
+        self.read_meta(container, key, &path).await
+    }
+
+    async fn delete(&self, container: &str, key: &str) -> Result<(), ObjectStoreError> {
+        let path = self.object_path(container, key)?;
+
+        for path in [path, self.meta_path(container, key)] {
+            match fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn list(&self, container: &str, prefix: &str) -> Result<Vec<ObjectMeta>, ObjectStoreError> {
+        validate_container(container)?;
+
+        let base = self.root.join("objects").join(container);
+        let mut pending = vec![(base, String::new())];
+        let mut objects = Vec::new();
+
+        while let Some((dir, dir_key)) = pending.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e.into());
+                }
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let key = if dir_key.is_empty() { name } else { format!("{}/{}", dir_key, name) };
+
+                if entry.file_type().await?.is_dir() {
+                    // Only descend into directories that can still contain a match.
+                    if key.starts_with(prefix) || prefix.starts_with(&format!("{}/", key)) {
+                        pending.push((entry.path(), key));
+                    }
+                } else if key.starts_with(prefix) {
+                    objects.push(self.read_meta(container, &key, &entry.path()).await?);
+                }
+            }
+        }
+
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(objects)
+    }
+}
@@ -4,33 +4,43 @@ use actix_session::{ Session, SessionMiddleware, storage::CookieSessionStore };
 use dotenv::dotenv;
 use std::env;
 
-mod response;
-use response::*;
+use rust_api::response::*;
 
-mod redis_client;
-use redis_client::Cache;
-use serde_json;
+use rust_api::redis_client::{ Cache, CacheConfig, LocalCacheConfig };
 
-mod websocket;
-use websocket::*;
+use rust_api::websocket::*;
 
-mod solana_h;
-mod middleware;
-use crate::middleware::*;
+use rust_api::solana_h::*;
+use rust_api::middleware::*;
 
-mod models;
-use models::*;
+use rust_api::models::*;
 
-mod ollama;
-use ollama::*;
+use rust_api::ollama::*;
 
-mod postgres_db;
-use postgres_db::*;
+use rust_api::postgres_db::*;
 
-mod azure_storage;
-use azure_storage::*;
+use rust_api::object_store::*;
+
+use rust_api::documents::*;
+
+use rust_api::prompt_templates::*;
+
+use rust_api::structured_output::*;
+
+use rust_api::usage::*;
+
+use rust_api::files::*;
+
+use rust_api::uploads::*;
+
+use rust_api::media::*;
+
+use rust_api::upload_policy::*;
+
+use rust_api::malware_scan::*;
 
 use chrono::{ DateTime, Utc };
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
 
 async fn greet(_req: HttpRequest, name: web::Path<String>, session: Session) -> HttpResponse {
     let _user = get_user_from_session(&session);
@@ -40,56 +50,134 @@ async fn greet(_req: HttpRequest, name: web::Path<String>, session: Session) ->
 
 async fn current_temperature(cache: web::Data<Cache>, name: web::Path<String>) -> HttpResponse {
     let cache_key = format!("temp:{}", name);
-
-    if let Ok(Some(cached_value)) = cache.get_value(&cache_key) {
-        let cached_measurement: Temperature = serde_json
-            ::from_str(&cached_value)
-            .expect("Valid JSON in cache");
-        return response_ok("Temperature from cache", cached_measurement);
+    let location = name.into_inner();
+
+    let result = cache.get_or_compute_stale(&cache_key, 300, 60, move || async move {
+        Ok(Temperature {
+            value: 20.0,
+            location,
+            timestamp: chrono::Utc::now(),
+        })
+    }).await;
+
+    match result {
+        Ok(measurement) => response_ok("Temperature retrieved successfully", measurement),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
     }
-
-    let measurement = Temperature {
-        value: 20.0,
-        location: name.to_string(),
-        timestamp: chrono::Utc::now(),
-    };
-
-    let _ = cache.set_value(&cache_key, serde_json::to_string(&measurement).unwrap(), 300);
-
-    response_ok("Temperature retrieved successfully", measurement)
 }
 
-async fn health_check() -> HttpResponse {
+async fn health_check(cache: web::Data<Cache>) -> HttpResponse {
     let local_time: DateTime<Utc> = chrono::Utc::now();
 
     let server_health = ServerHealth {
         timestamp: local_time,
+        cache: cache.stats(),
     };
     response_ok("api is healthy", server_health)
 }
 
+async fn metrics(cache: web::Data<Cache>) -> HttpResponse {
+    let stats = cache.stats();
+    let mut tiers = vec![("l2", stats.l2)];
+    if let Some(l1) = stats.l1 {
+        tiers.insert(0, ("l1", l1));
+    }
+
+    let mut body = String::new();
+    body.push_str("# TYPE cache_hits_total counter\n");
+    for (tier, tier_stats) in &tiers {
+        body.push_str(&format!("cache_hits_total{{tier=\"{}\"}} {}\n", tier, tier_stats.hits));
+    }
+    body.push_str("# TYPE cache_misses_total counter\n");
+    for (tier, tier_stats) in &tiers {
+        body.push_str(&format!("cache_misses_total{{tier=\"{}\"}} {}\n", tier, tier_stats.misses));
+    }
+    body.push_str("# TYPE cache_entries gauge\n");
+    for (tier, tier_stats) in &tiers {
+        if let Some(entries) = tier_stats.entries {
+            body.push_str(&format!("cache_entries{{tier=\"{}\"}} {}\n", tier, entries));
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
 
 #[derive(serde::Deserialize)]
 struct PromptRequest {
     model: String,
     prompt: String,
     temperature: f32, 
-    max_tokens: u32
+    max_tokens: u32,
+    json_schema: Option<serde_json::Value>,
+    max_attempts: Option<u32>,
 }
 
-async fn generate_prompt(req: web::Json<PromptRequest>)  -> HttpResponse {
+async fn generate_prompt(
+    req: web::Json<PromptRequest>,
+    session: Session,
+    cache: web::Data<Cache>,
+    db: web::Data<PostgresDb>,
+    usage: web::Data<UsageTracker>,
+)  -> HttpResponse {
+    let user = match enforce_quota(&usage, &cache, &db, &session).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
     let ollama_client = OllamaAI::new();
 
-    let response = ollama_client.generate_text(
+    if let Some(schema) = &req.json_schema {
+        let result = generate_structured(
+            &ollama_client,
+            &req.model,
+            &req.prompt,
+            schema,
+            Some(req.temperature),
+            Some(req.max_tokens),
+            req.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS),
+        ).await;
+
+        return match result {
+            Ok((value, tokens)) => {
+                let response = response_ok("structured output generated successfully", value);
+                finish_usage(&usage, &cache, &db, &user, &req.model, &tokens, response).await
+            }
+            Err(StructuredOutputError::InvalidSchema(e)) =>
+                response_unprocessable_entity(serde_json::json!({ "json_schema": e })),
+            Err(StructuredOutputError::Invalid { attempts, violations, usage: tokens }) => {
+                let response = response_unprocessable_entity(serde_json::json!({
+                    "attempts": attempts,
+                    "violations": violations,
+                }));
+                finish_usage(&usage, &cache, &db, &user, &req.model, &tokens, response).await
+            }
+            Err(StructuredOutputError::Generation { message, usage: tokens }) => {
+                let response = response_internal_server_error(format!("generation failed: {}", message).as_str());
+                finish_usage(&usage, &cache, &db, &user, &req.model, &tokens, response).await
+            }
+        };
+    }
+
+    let response = ollama_client.generate_completion(
         &req.model,
         &req.prompt,
         Some(req.temperature),
         Some(req.max_tokens),
+        false,
     ).await;
 
     match response {
-        Ok(text) => response_ok("api is healthy", text),
-        Err(err) => response_internal_server_error(err.to_string().as_str()),
+        Ok(completion) => {
+            let response = response_ok("api is healthy", completion.text);
+            finish_usage(&usage, &cache, &db, &user, &req.model, &completion.usage, response).await
+        }
+        Err(err) => {
+            let response = response_internal_server_error(err.to_string().as_str());
+            finish_usage(&usage, &cache, &db, &user, &req.model, &TokenUsage::default(), response).await
+        }
     }
     
 }
@@ -155,28 +243,151 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let redis_url = env::var("REMOTE_REDIS_URL").unwrap();
-    let redis_client = Cache::new(&redis_url).expect("Redis connection failed");
+    let mut cache_config = CacheConfig {
+        key_prefix: env::var("REDIS_KEY_PREFIX").ok(),
+        configure_keyspace_events: env::var("REDIS_CONFIGURE_KEYSPACE_EVENTS").is_ok_and(|v| v == "true"),
+        ..CacheConfig::default()
+    };
+    if let Some(capacity) = env::var("REDIS_L1_CAPACITY").ok().and_then(|v| v.parse().ok()) {
+        let ttl_secs = env::var("REDIS_L1_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30);
+        cache_config.local = Some(LocalCacheConfig {
+            capacity,
+            max_ttl: std::time::Duration::from_secs(ttl_secs),
+        });
+    }
+    if let Some(timeout_ms) = env::var("REDIS_COMMAND_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()) {
+        cache_config.command_timeout = std::time::Duration::from_millis(timeout_ms);
+    }
+    let redis_client = Cache::with_config(&redis_url, cache_config)
+        .await
+        .expect("Invalid REMOTE_REDIS_URL");
     let cache_data = web::Data::new(redis_client);
 
     let postgresql_url = env::var("REMOTE_POSTGRESQL_URL").unwrap();
     let db = PostgresDb::new(&postgresql_url)
         .await
-        .expect("Failed to connect to database");;
+        .expect("Failed to connect to database");
+
+    let embedding_model = env::var("OLLAMA_EMBEDDING_MODEL").unwrap_or("nomic-embed-text".to_string());
+    let documents_container = env::var("STORAGE_DOCUMENTS_CONTAINER").unwrap_or("documents".to_string());
+    let embedding_dimensions = env::var("OLLAMA_EMBEDDING_DIMENSIONS").ok().and_then(|v| v.parse().ok()).unwrap_or(768);
+    let document_index = DocumentIndex::new(&db, embedding_model, documents_container, 1000, 200, embedding_dimensions)
+        .await
+        .expect("Failed to initialise document index");
+    let document_index_pool = web::Data::new(document_index);
+
+    ensure_prompt_template_schema(&db)
+        .await
+        .expect("Failed to initialise prompt templates");
+
+    let usage_limits = match env::var("LLM_QUOTAS") {
+        Ok(config) => UsageLimits::from_json(&config).expect("Invalid LLM_QUOTAS configuration"),
+        Err(_) => UsageLimits::default(),
+    };
+    let usage_tracker = UsageTracker::new(&db, usage_limits)
+        .await
+        .expect("Failed to initialise usage tracking");
+    let usage_tracker_pool = web::Data::new(usage_tracker);
+
+    let files_container = env::var("STORAGE_FILES_CONTAINER").unwrap_or("files".to_string());
+    let default_links = LinkPolicy::default();
+    let link_policy = LinkPolicy {
+        default_ttl: env::var("FILE_LINK_DEFAULT_TTL_SECS").ok().and_then(|v| v.parse().ok()).map(std::time::Duration::from_secs).unwrap_or(default_links.default_ttl),
+        max_ttl: env::var("FILE_LINK_MAX_TTL_SECS").ok().and_then(|v| v.parse().ok()).map(std::time::Duration::from_secs).unwrap_or(default_links.max_ttl),
+        permissions: match env::var("FILE_LINK_PERMISSIONS") {
+            Ok(permissions) => LinkPermissions::parse(&permissions).expect("Invalid FILE_LINK_PERMISSIONS"),
+            Err(_) => default_links.permissions,
+        },
+    };
+    let file_store = FileStore::new(&db, files_container, link_policy)
+        .await
+        .expect("Failed to initialise file storage");
+    let file_store_pool = web::Data::new(file_store);
+
+    let upload_block_size = env::var("UPLOAD_BLOCK_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_UPLOAD_BLOCK_SIZE);
+    let uploads = Uploads::new(&db, upload_block_size)
+        .await
+        .expect("Failed to initialise upload sessions");
+    let uploads_pool = web::Data::new(uploads);
+
+    let media_config = MediaConfig {
+        thumbnail_sizes: match env::var("MEDIA_THUMBNAIL_SIZES") {
+            Ok(sizes) => sizes.split(',').map(|size| size.trim().parse().expect("Invalid MEDIA_THUMBNAIL_SIZES")).collect(),
+            Err(_) => DEFAULT_THUMBNAIL_SIZES.to_vec(),
+        },
+        max_attempts: env::var("MEDIA_MAX_ATTEMPTS").ok().and_then(|v| v.parse().ok()).unwrap_or(MediaConfig::default().max_attempts),
+    };
+    let media_pipeline = MediaPipeline::new(&db, media_config)
+        .await
+        .expect("Failed to initialise media processing");
+    let media_pool = web::Data::new(media_pipeline);
+
+    let upload_policies = match env::var("UPLOAD_POLICIES") {
+        Ok(config) => UploadPolicies::from_json(&config).expect("Invalid UPLOAD_POLICIES configuration"),
+        Err(_) => UploadPolicies::default(),
+    };
+    let upload_policies_pool = web::Data::new(upload_policies);
+
+    let malware_scanner = malware_scanner_from_env().expect("Failed to initialise malware scanning");
+    let malware_scanner_pool: web::Data<dyn MalwareScanner> = web::Data::from(malware_scanner);
+
+    let wallet_store = WalletStore::new(&db)
+        .await
+        .expect("Failed to initialise wallet storage");
+    let wallet_store_pool = web::Data::new(wallet_store);
+
+    let payment_watcher = PaymentWatcher::new(&db, PaymentWatcherConfig::from_env())
+        .await
+        .expect("Failed to initialise payment tracking");
+    let payment_watcher_pool = web::Data::new(payment_watcher);
 
     let db_pool = web::Data::new(db);
 
-    let azure_account = env::var("STORAGE_ACCOUNT").unwrap();
-    let access_key = env::var("STORAGE_ACCESS_KEY").unwrap();
-    let azure_client = AzureStorage::new(azure_account, access_key);
+    let object_store = object_store_from_env().expect("Failed to initialise object storage");
+    let object_store_pool: web::Data<dyn ObjectStore> = web::Data::from(object_store);
+
+    let ws_registry_pool = web::Data::new(WsRegistry::default());
+    MediaPipeline::start(media_pool.clone(), db_pool.clone(), object_store_pool.clone(), ws_registry_pool.clone());
+    resume_scans(malware_scanner_pool.clone(), db_pool.clone(), object_store_pool.clone(), media_pool.clone());
+
+    let solana_client = solana_client_from_env().expect("Failed to initialise Solana client");
+    let solana_pool = web::Data::new(solana_client);
+    PaymentWatcher::start(payment_watcher_pool.clone(), solana_pool.clone(), db_pool.clone(), ws_registry_pool.clone());
+    let siws_pool = web::Data::new(SignInWithSolana::new(SiwsConfig::from_env()));
+
+    // Wallet sign-in sessions live in the cookie, so the key has to survive
+    // restarts and be shared by every instance.
+    let session_key = match env::var("SESSION_KEY") {
+        Ok(encoded) => {
+            let bytes = BASE64.decode(encoded.trim()).expect("SESSION_KEY must be base64");
+            Key::try_from(bytes.as_slice()).expect("SESSION_KEY must decode to at least 64 bytes")
+        }
+        Err(_) => {
+            log::warn!("SESSION_KEY not set, sessions will not survive a restart");
+            Key::generate()
+        }
+    };
 
+    let api_requests_per_minute = env::var("RATE_LIMIT_API_PER_MINUTE").ok().and_then(|v| v.parse().ok()).unwrap_or(120);
+    let api_rate_limit = RateLimit::sliding_window("api", api_requests_per_minute, std::time::Duration::from_secs(60))
+        .expect("Invalid RATE_LIMIT_API_PER_MINUTE");
 
-    let azure_storage_pool = web::Data::new(azure_client);
+    // Sign-in is unauthenticated, so it is limited per address and kept apart
+    // from the per-user API budget.
+    let auth_requests_per_minute = env::var("RATE_LIMIT_AUTH_PER_MINUTE").ok().and_then(|v| v.parse().ok()).unwrap_or(30);
+    let auth_rate_limit = RateLimit::sliding_window("auth", auth_requests_per_minute, std::time::Duration::from_secs(60))
+        .expect("Invalid RATE_LIMIT_AUTH_PER_MINUTE")
+        .key_by(RateLimitKey::Ip);
 
+    let generate_per_minute: f64 = env::var("RATE_LIMIT_GENERATE_PER_MINUTE").ok().and_then(|v| v.parse().ok()).unwrap_or(10.0);
+    let generate_burst = env::var("RATE_LIMIT_GENERATE_BURST").ok().and_then(|v| v.parse().ok()).unwrap_or(5);
+    let generate_rate_limit = RateLimit::token_bucket("ai-generate", generate_burst, generate_per_minute / 60.0)
+        .expect("RATE_LIMIT_GENERATE_PER_MINUTE must be greater than zero");
 
     HttpServer::new(move || {
         App::new()
             .wrap(
-                SessionMiddleware::builder(CookieSessionStore::default(), Key::from(&[0; 64]))
+                SessionMiddleware::builder(CookieSessionStore::default(), session_key.clone())
                     .cookie_secure(false)
                     .build()
             )
@@ -184,7 +395,19 @@ async fn main() -> std::io::Result<()> {
             // .service(fs::Files::new("/assets", "./client/dist/assets").index_file(".*"))
             .app_data(cache_data.clone())
             .app_data(db_pool.clone())
-            .app_data(azure_storage_pool.clone())
+            .app_data(object_store_pool.clone())
+            .app_data(document_index_pool.clone())
+            .app_data(usage_tracker_pool.clone())
+            .app_data(file_store_pool.clone())
+            .app_data(uploads_pool.clone())
+            .app_data(media_pool.clone())
+            .app_data(upload_policies_pool.clone())
+            .app_data(malware_scanner_pool.clone())
+            .app_data(solana_pool.clone())
+            .app_data(wallet_store_pool.clone())
+            .app_data(siws_pool.clone())
+            .app_data(payment_watcher_pool.clone())
+            .app_data(ws_registry_pool.clone())
 
             .default_service(
                 web::route().to(|| async {
@@ -193,15 +416,72 @@ async fn main() -> std::io::Result<()> {
             )
 
             .route("/health", web::get().to(health_check))
+            .route("/metrics", web::get().to(metrics))
+
+            .service(
+                web
+                    ::scope("/auth/solana")
+                    .wrap(auth_rate_limit.clone())
+                    .route("/challenge", web::post().to(create_sign_in_challenge))
+                    .route("/login", web::post().to(sign_in_with_wallet))
+                    .route("/logout", web::post().to(sign_out))
+            )
 
             .service(
                 web
                     ::scope("/api")
+                    .wrap(Idempotency::default())
                     .wrap(Auth) // Apply auth middleware only to /api routes
+                    .wrap(api_rate_limit.clone())
+                    // File routes come before "/{name}" so it does not shadow GET /files
+                    .route("/files", web::get().to(list_files))
+                    .route("/files", web::post().to(upload_files))
+                    .route("/files/{id}", web::get().to(get_file))
+                    .route("/files/{id}", web::delete().to(delete_file))
+                    .route("/files/{id}/download", web::get().to(download_file))
+                    .route("/files/{id}/link", web::post().to(create_file_link))
+                    .route("/files/{id}/renditions", web::get().to(list_renditions))
+                    .route("/files/{id}/renditions/{name}", web::get().to(download_rendition))
+                    .route("/uploads", web::post().to(create_upload))
+                    .route("/uploads/{id}", web::get().to(get_upload))
+                    .route("/uploads/{id}", web::delete().to(abort_upload))
+                    .route("/uploads/{id}/blocks/{index}", web::put().to(upload_block))
+                    .route("/uploads/{id}/complete", web::post().to(complete_upload))
                     .route("/{name}", web::get().to(greet))
                     .route("/temperature/{name}", web::get().to(current_temperature))
                     .route("/ws/", web::get().to(websocket_handler))
-                    .route("/ai/generate", web::post().to(generate_prompt))
+                    .service(
+                        web::resource("/ai/generate")
+                            .wrap(generate_rate_limit.clone())
+                            .route(web::post().to(generate_prompt))
+                    )
+                    .route("/ai/usage", web::get().to(usage_report))
+                    .route("/ai/embeddings", web::post().to(create_embeddings))
+                    .route("/ai/documents", web::post().to(upload_document))
+                    .route("/ai/search", web::get().to(search_documents))
+                    .route("/ai/ask", web::post().to(ask_documents))
+                    .route("/ai/templates", web::get().to(list_templates))
+                    .route("/ai/templates", web::post().to(create_template))
+                    .route("/ai/templates/{name}", web::get().to(get_template))
+                    .route("/ai/templates/{name}", web::put().to(update_template))
+                    .route("/ai/templates/{name}", web::delete().to(delete_template))
+                    .route("/ai/templates/{name}/render", web::post().to(render_template))
+                    .route("/ai/templates/{name}/run", web::post().to(run_template))
+                    .route("/solana/wallet/connect", web::post().to(connect_wallet))
+                    .route("/solana/wallet", web::get().to(get_wallet))
+                    .route("/solana/wallets", web::get().to(list_wallets))
+                    .route("/solana/wallets/{pubkey}", web::delete().to(unlink_wallet))
+                    .route("/solana/wallets/{pubkey}/primary", web::put().to(set_primary_wallet))
+                    .route("/solana/balance/{pubkey}", web::get().to(get_sol_balance))
+                    .route("/solana/transfer", web::post().to(send_sol_transfer))
+                    .route("/solana/tokens/accounts", web::post().to(create_token_account))
+                    .route("/solana/tokens/transfer", web::post().to(send_token_transfer))
+                    .route("/solana/tokens/{pubkey}", web::get().to(get_token_balances))
+                    .route("/solana/tx/prepare", web::post().to(prepare_wallet_transaction))
+                    .route("/solana/tx/submit", web::post().to(submit_wallet_transaction))
+                    .route("/solana/payments", web::post().to(create_payment_intent))
+                    .route("/solana/payments/{id}", web::get().to(get_payment_intent))
+                    .route("/solana/transfers", web::get().to(list_transfers))
             )
     })
         .bind("127.0.0.1:9080")?
@@ -0,0 +1,255 @@
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
+use tokio::net::{ TcpStream, UnixStream };
+
+use crate::files::*;
+use crate::media::*;
+use crate::models::*;
+use crate::object_store::*;
+use crate::postgres_db::*;
+
+/// `scan_status` values. Only `clean` files can be downloaded, linked or
+/// processed; everything else stays quarantined. Infected files are
+/// deleted, so they have no status of their own.
+pub const SCAN_PENDING: &str = "pending";
+pub const SCAN_CLEAN: &str = "clean";
+pub const SCAN_ERROR: &str = "error";
+
+const DEFAULT_CLAMAV_ADDRESS: &str = "tcp://127.0.0.1:3310";
+const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often files whose scan failed are tried again.
+const SCAN_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// clamd rejects INSTREAM chunks larger than its StreamMaxLength, so send
+/// modest ones.
+const CLAMAV_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    /// Carries the signature name reported by the scanner.
+    Infected(String),
+}
+
+/// Hook for checking uploaded content before it is released.
+#[async_trait(?Send)]
+pub trait MalwareScanner: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn scan(&self, data: ByteStream) -> Result<ScanVerdict, Box<dyn std::error::Error>>;
+}
+
+/// Passes everything; the default when no scanner is configured.
+pub struct NoopScanner;
+
+#[async_trait(?Send)]
+impl MalwareScanner for NoopScanner {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    async fn scan(&self, _data: ByteStream) -> Result<ScanVerdict, Box<dyn std::error::Error>> {
+        Ok(ScanVerdict::Clean)
+    }
+}
+
+enum ClamAvAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// Streams content to clamd with the INSTREAM command, over TCP
+/// (`tcp://host:3310`) or a Unix socket (`unix:///run/clamav/clamd.ctl`).
+pub struct ClamAvScanner {
+    address: ClamAvAddress,
+    timeout: Duration,
+}
+
+impl ClamAvScanner {
+    pub fn new(address: &str) -> Result<Self, String> {
+        let address = if let Some(path) = address.strip_prefix("unix://") {
+            ClamAvAddress::Unix(PathBuf::from(path))
+        } else {
+            let host = address.strip_prefix("tcp://").unwrap_or(address);
+            if host.is_empty() {
+                return Err("ClamAV address must not be empty".to_string());
+            }
+            ClamAvAddress::Tcp(host.to_string())
+        };
+
+        Ok(Self {
+            address,
+            timeout: DEFAULT_SCAN_TIMEOUT,
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+async fn instream<S>(mut socket: S, mut data: ByteStream) -> Result<ScanVerdict, Box<dyn std::error::Error>>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    socket.write_all(b"zINSTREAM\0").await?;
+
+    while let Some(chunk) = data.next().await {
+        for part in chunk?.chunks(CLAMAV_CHUNK_SIZE) {
+            socket.write_all(&(part.len() as u32).to_be_bytes()).await?;
+            socket.write_all(part).await?;
+        }
+    }
+    socket.write_all(&[0, 0, 0, 0]).await?;
+    socket.flush().await?;
+
+    let mut reply = Vec::new();
+    socket.read_to_end(&mut reply).await?;
+
+    parse_clamav_reply(&String::from_utf8_lossy(&reply))
+}
+
+/// Replies look like `stream: OK`, `stream: Eicar-Signature FOUND` or
+/// `INSTREAM size limit exceeded. ERROR`.
+pub fn parse_clamav_reply(reply: &str) -> Result<ScanVerdict, Box<dyn std::error::Error>> {
+    let reply = reply.trim_end_matches('\0').trim();
+    let result = reply.strip_prefix("stream:").map(str::trim).unwrap_or(reply);
+
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = result.strip_suffix("FOUND") {
+        Ok(ScanVerdict::Infected(signature.trim().to_string()))
+    } else {
+        Err(format!("unexpected ClamAV reply: {}", reply).into())
+    }
+}
+
+#[async_trait(?Send)]
+impl MalwareScanner for ClamAvScanner {
+    fn name(&self) -> &'static str {
+        "clamav"
+    }
+
+    async fn scan(&self, data: ByteStream) -> Result<ScanVerdict, Box<dyn std::error::Error>> {
+        let scan = async {
+            match &self.address {
+                ClamAvAddress::Tcp(host) => instream(TcpStream::connect(host).await?, data).await,
+                ClamAvAddress::Unix(path) => instream(UnixStream::connect(path).await?, data).await,
+            }
+        };
+
+        match tokio::time::timeout(self.timeout, scan).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("ClamAV scan timed out after {}s", self.timeout.as_secs()).into()),
+        }
+    }
+}
+
+/// Builds the scanner selected by `MALWARE_SCANNER` (`none` or `clamav`).
+pub fn malware_scanner_from_env() -> Result<Arc<dyn MalwareScanner>, Box<dyn std::error::Error>> {
+    match env::var("MALWARE_SCANNER").unwrap_or("none".to_string()).as_str() {
+        "none" => Ok(Arc::new(NoopScanner)),
+        "clamav" => {
+            let address = env::var("CLAMAV_ADDRESS").unwrap_or(DEFAULT_CLAMAV_ADDRESS.to_string());
+            let mut scanner = ClamAvScanner::new(&address)?;
+            if let Some(secs) = env::var("CLAMAV_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()) {
+                scanner = scanner.with_timeout(Duration::from_secs(secs));
+            }
+            Ok(Arc::new(scanner))
+        }
+        other => Err(format!("unknown MALWARE_SCANNER {:?}", other).into()),
+    }
+}
+
+/// Scans a stored file and releases it from quarantine when clean.
+/// Infected files are deleted along with their record, which also gives
+/// their bytes back to the owner's storage quota.
+pub async fn scan_file(
+    scanner: &dyn MalwareScanner,
+    db: &PostgresDb,
+    storage: &dyn ObjectStore,
+    media: &MediaPipeline,
+    file: &FileRecord
+) -> Result<(), Box<dyn std::error::Error>> {
+    let verdict = match storage.get(&file.container, &file.blob_name).await {
+        Ok(data) => scanner.scan(data).await,
+        Err(err) => Err(err.into()),
+    };
+
+    match verdict {
+        Ok(ScanVerdict::Clean) => {
+            db.execute("UPDATE files SET scan_status = $2 WHERE id = $1", &[&file.id, &SCAN_CLEAN]).await?;
+            media.enqueue(db, file).await?;
+        }
+        Ok(ScanVerdict::Infected(signature)) => {
+            log::warn!("File {} of user {} is infected ({}); deleting its content", file.id, file.owner_id, signature);
+            storage.delete(&file.container, &file.blob_name).await?;
+            db.execute("DELETE FROM files WHERE id = $1", &[&file.id]).await?;
+        }
+        Err(err) => {
+            log::error!("{} scan of file {} failed: {}", scanner.name(), file.id, err);
+            db.execute("UPDATE files SET scan_status = $2 WHERE id = $1", &[&file.id, &SCAN_ERROR]).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Scans `file` in the background on the current arbiter.
+pub fn spawn_scan(
+    scanner: web::Data<dyn MalwareScanner>,
+    db: web::Data<PostgresDb>,
+    storage: web::Data<dyn ObjectStore>,
+    media: web::Data<MediaPipeline>,
+    file: FileRecord
+) {
+    actix_web::rt::spawn(async move {
+        if let Err(err) = scan_file(scanner.get_ref(), &db, storage.get_ref(), &media, &file).await {
+            log::error!("Failed to record scan result for file {}: {}", file.id, err);
+        }
+    });
+}
+
+/// Retries files left pending by a restart, then keeps retrying the ones
+/// a scanner outage failed every `SCAN_RETRY_INTERVAL`.
+pub fn resume_scans(
+    scanner: web::Data<dyn MalwareScanner>,
+    db: web::Data<PostgresDb>,
+    storage: web::Data<dyn ObjectStore>,
+    media: web::Data<MediaPipeline>
+) {
+    actix_web::rt::spawn(async move {
+        // Pending files are only stale at startup; later they may be mid-scan.
+        let mut statuses = vec![SCAN_PENDING, SCAN_ERROR];
+
+        loop {
+            match quarantined_files(&db, &statuses).await {
+                Ok(files) => {
+                    for file in files {
+                        if let Err(err) = scan_file(scanner.get_ref(), &db, storage.get_ref(), &media, &file).await {
+                            log::error!("Failed to record scan result for file {}: {}", file.id, err);
+                        }
+                    }
+                }
+                Err(err) => log::error!("Failed to list quarantined files: {}", err),
+            }
+
+            statuses = vec![SCAN_ERROR];
+            actix_web::rt::time::sleep(SCAN_RETRY_INTERVAL).await;
+        }
+    });
+}
+
+async fn quarantined_files(db: &PostgresDb, statuses: &[&str]) -> Result<Vec<FileRecord>, Box<dyn std::error::Error>> {
+    db.query(
+        &format!("SELECT {} FROM files WHERE scan_status = ANY($1) ORDER BY created_at", FILE_COLUMNS),
+        &[&statuses],
+        map_file
+    ).await
+}
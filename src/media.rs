@@ -0,0 +1,524 @@
+use std::io::Cursor;
+use std::time::Duration;
+
+use actix_session::Session;
+use actix_web::{ http::header::CACHE_CONTROL, web, HttpResponse };
+use bytes::Bytes;
+use image::codecs::webp::WebPEncoder;
+use image::{ DynamicImage, ExtendedColorType, ImageDecoder, ImageReader, Limits };
+use tokio::sync::Notify;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::files::*;
+use crate::models::*;
+use crate::object_store::*;
+use crate::postgres_db::*;
+use crate::response::*;
+use crate::websocket::WsRegistry;
+
+pub const DEFAULT_THUMBNAIL_SIZES: [u32; 2] = [128, 512];
+const DEFAULT_MAX_ATTEMPTS: i32 = 5;
+/// Larger sources are not worth decoding in the API process.
+const MAX_SOURCE_BYTES: i64 = 50 * 1024 * 1024;
+/// Guards against decompression bombs.
+const MAX_DIMENSION: u32 = 16_384;
+const RETRY_BASE_SECS: i64 = 30;
+const RETRY_MAX_SECS: i64 = 60 * 60;
+/// A job still `processing` after this long is assumed to belong to a
+/// crashed worker and is picked up again.
+const STALE_JOB_MINUTES: i32 = 15;
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Content types the pipeline can decode.
+const SUPPORTED_TYPES: [&str; 6] = ["image/jpeg", "image/png", "image/gif", "image/webp", "image/bmp", "image/tiff"];
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS media_jobs (
+        file_id UUID PRIMARY KEY REFERENCES files (id) ON DELETE CASCADE,
+        owner_id UUID NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        attempts INT NOT NULL DEFAULT 0,
+        last_error TEXT,
+        next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX IF NOT EXISTS media_jobs_next_idx ON media_jobs (status, next_attempt_at);
+    CREATE TABLE IF NOT EXISTS file_renditions (
+        file_id UUID NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        width INT NOT NULL,
+        height INT NOT NULL,
+        size BIGINT NOT NULL,
+        blob_name TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        PRIMARY KEY (file_id, name)
+    );
+";
+
+const RENDITION_COLUMNS: &str = "name, content_type, width, height, size, blob_name, created_at";
+
+pub struct MediaConfig {
+    /// Longest edge of each thumbnail, in pixels.
+    pub thumbnail_sizes: Vec<u32>,
+    pub max_attempts: i32,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            thumbnail_sizes: DEFAULT_THUMBNAIL_SIZES.to_vec(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+}
+
+/// Derives WebP renditions of uploaded images in the background. Jobs live
+/// in `media_jobs`, so they survive restarts and are retried with backoff.
+pub struct MediaPipeline {
+    config: MediaConfig,
+    wakeup: Notify,
+}
+
+#[derive(Debug)]
+struct ClaimedJob {
+    file_id: Uuid,
+    owner_id: Uuid,
+    attempts: i32,
+}
+
+#[derive(Debug)]
+struct SourceFile {
+    container: String,
+    blob_name: String,
+    size: i64,
+}
+
+enum JobError {
+    /// Worth another attempt, e.g. storage or database hiccups.
+    Transient(String),
+    /// Retrying can't help, e.g. a corrupt image.
+    Permanent(String),
+}
+
+impl From<ObjectStoreError> for JobError {
+    fn from(e: ObjectStoreError) -> Self {
+        JobError::Transient(e.to_string())
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for JobError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        JobError::Transient(e.to_string())
+    }
+}
+
+pub struct RenderedImage {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl MediaPipeline {
+    pub async fn new(db: &PostgresDb, config: MediaConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        db.batch_execute(SCHEMA).await?;
+        Ok(Self {
+            config,
+            wakeup: Notify::new(),
+        })
+    }
+
+    pub fn accepts(content_type: &str) -> bool {
+        SUPPORTED_TYPES.contains(&content_type)
+    }
+
+    /// Queues processing for an uploaded file. Returns false for files that
+    /// aren't images we can decode.
+    pub async fn enqueue(&self, db: &PostgresDb, file: &FileRecord) -> Result<bool, Box<dyn std::error::Error>> {
+        if !Self::accepts(&file.content_type) {
+            return Ok(false);
+        }
+
+        db.execute(
+            "INSERT INTO media_jobs (file_id, owner_id) VALUES ($1, $2)
+             ON CONFLICT (file_id) DO UPDATE
+             SET status = 'pending', attempts = 0, last_error = NULL, next_attempt_at = now(), updated_at = now()",
+            &[&file.id, &file.owner_id]
+        ).await?;
+        self.wakeup.notify_one();
+
+        Ok(true)
+    }
+
+    /// Runs the worker on the current arbiter; storage futures aren't `Send`.
+    pub fn start(
+        pipeline: web::Data<MediaPipeline>,
+        db: web::Data<PostgresDb>,
+        storage: web::Data<dyn ObjectStore>,
+        sockets: web::Data<WsRegistry>
+    ) {
+        actix_web::rt::spawn(async move {
+            loop {
+                match pipeline.run_next(&db, storage.get_ref(), &sockets).await {
+                    Ok(true) => {
+                        continue;
+                    }
+                    Ok(false) => {}
+                    Err(err) => log::error!("Media pipeline failed to claim a job: {}", err),
+                }
+
+                // Sleep until new work is queued or a retry may be due.
+                let _ = tokio::time::timeout(POLL_INTERVAL, pipeline.wakeup.notified()).await;
+            }
+        });
+    }
+
+    /// Processes one due job. Returns false when there was nothing to do.
+    async fn run_next(
+        &self,
+        db: &PostgresDb,
+        storage: &dyn ObjectStore,
+        sockets: &WsRegistry
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut claimed = db.query(
+            "UPDATE media_jobs SET status = 'processing', attempts = attempts + 1, updated_at = now()
+             WHERE file_id = (
+                 SELECT file_id FROM media_jobs
+                 WHERE (status = 'pending' AND next_attempt_at <= now())
+                    OR (status = 'processing' AND updated_at < now() - make_interval(mins => $1))
+                 ORDER BY next_attempt_at
+                 LIMIT 1
+                 FOR UPDATE SKIP LOCKED
+             )
+             RETURNING file_id, owner_id, attempts",
+            &[&STALE_JOB_MINUTES],
+            map_claimed_job
+        ).await?;
+
+        let job = match claimed.pop() {
+            Some(job) => job,
+            None => {
+                return Ok(false);
+            }
+        };
+
+        notify(sockets, &job, "processing", serde_json::json!({ "attempt": job.attempts }));
+
+        match self.process(db, storage, sockets, &job).await {
+            Ok(count) => {
+                db.execute(
+                    "UPDATE media_jobs SET status = 'done', last_error = NULL, updated_at = now() WHERE file_id = $1",
+                    &[&job.file_id]
+                ).await?;
+                notify(sockets, &job, "done", serde_json::json!({ "renditions": count }));
+            }
+            Err(JobError::Transient(err)) if job.attempts < self.config.max_attempts => {
+                let delay = (RETRY_BASE_SECS << (job.attempts - 1).min(16)).min(RETRY_MAX_SECS) as f64;
+                log::warn!("Media job for file {} failed (attempt {}), retrying in {}s: {}", job.file_id, job.attempts, delay, err);
+
+                db.execute(
+                    "UPDATE media_jobs
+                     SET status = 'pending', last_error = $2, next_attempt_at = now() + make_interval(secs => $3), updated_at = now()
+                     WHERE file_id = $1",
+                    &[&job.file_id, &err, &delay]
+                ).await?;
+                notify(sockets, &job, "retrying", serde_json::json!({ "error": err, "retry_in": delay }));
+            }
+            Err(JobError::Transient(err) | JobError::Permanent(err)) => {
+                log::error!("Media job for file {} failed permanently: {}", job.file_id, err);
+
+                db.execute(
+                    "UPDATE media_jobs SET status = 'failed', last_error = $2, updated_at = now() WHERE file_id = $1",
+                    &[&job.file_id, &err]
+                ).await?;
+                notify(sockets, &job, "failed", serde_json::json!({ "error": err }));
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn process(
+        &self,
+        db: &PostgresDb,
+        storage: &dyn ObjectStore,
+        sockets: &WsRegistry,
+        job: &ClaimedJob
+    ) -> Result<usize, JobError> {
+        let source = match
+            db.query(
+                "SELECT container, blob_name, size FROM files WHERE id = $1",
+                &[&job.file_id],
+                map_source_file
+            ).await?
+            .pop()
+        {
+            Some(source) => source,
+            None => {
+                return Err(JobError::Permanent("file no longer exists".to_string()));
+            }
+        };
+
+        if source.size > MAX_SOURCE_BYTES {
+            return Err(JobError::Permanent(format!("images over {} bytes are not processed", MAX_SOURCE_BYTES)));
+        }
+
+        let data = collect_bytes(storage.get(&source.container, &source.blob_name).await?).await?;
+        let sizes = self.config.thumbnail_sizes.clone();
+
+        // Decoding and encoding are CPU bound, so keep them off the event loop.
+        let rendered = web
+            ::block(move || render_renditions(&data, &sizes)).await
+            .map_err(|e| JobError::Transient(e.to_string()))?
+            .map_err(JobError::Permanent)?;
+
+        let total = rendered.len();
+        for (index, rendition) in rendered.into_iter().enumerate() {
+            let blob_name = rendition_blob_name(&source.blob_name, &rendition.name);
+            let size = rendition.data.len() as i64;
+
+            storage.put(&source.container, &blob_name, &PutOptions::new("image/webp"), Bytes::from(rendition.data)).await?;
+
+            db.execute(
+                "INSERT INTO file_renditions (file_id, name, content_type, width, height, size, blob_name)
+                 VALUES ($1, $2, 'image/webp', $3, $4, $5, $6)
+                 ON CONFLICT (file_id, name) DO UPDATE
+                 SET width = EXCLUDED.width, height = EXCLUDED.height, size = EXCLUDED.size,
+                     blob_name = EXCLUDED.blob_name, created_at = now()",
+                &[&job.file_id, &rendition.name, &(rendition.width as i32), &(rendition.height as i32), &size, &blob_name]
+            ).await?;
+
+            notify(
+                sockets,
+                job,
+                "processing",
+                serde_json::json!({ "rendition": rendition.name, "completed": index + 1, "total": total })
+            );
+        }
+
+        Ok(total)
+    }
+}
+
+fn map_claimed_job(row: &Row) -> Result<ClaimedJob, Box<dyn std::error::Error>> {
+    Ok(ClaimedJob {
+        file_id: row.try_get("file_id")?,
+        owner_id: row.try_get("owner_id")?,
+        attempts: row.try_get("attempts")?,
+    })
+}
+
+fn map_source_file(row: &Row) -> Result<SourceFile, Box<dyn std::error::Error>> {
+    Ok(SourceFile {
+        container: row.try_get("container")?,
+        blob_name: row.try_get("blob_name")?,
+        size: row.try_get("size")?,
+    })
+}
+
+fn map_rendition(row: &Row) -> Result<Rendition, Box<dyn std::error::Error>> {
+    Ok(Rendition {
+        name: row.try_get("name")?,
+        content_type: row.try_get("content_type")?,
+        width: row.try_get("width")?,
+        height: row.try_get("height")?,
+        size: row.try_get("size")?,
+        blob_name: row.try_get("blob_name")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+fn map_media_status(row: &Row) -> Result<MediaStatus, Box<dyn std::error::Error>> {
+    Ok(MediaStatus {
+        status: row.try_get("status")?,
+        attempts: row.try_get("attempts")?,
+        last_error: row.try_get("last_error")?,
+        renditions: Vec::new(),
+        updated_at: row.try_get("updated_at")?,
+    })
+}
+
+fn notify(sockets: &WsRegistry, job: &ClaimedJob, status: &str, mut details: serde_json::Value) {
+    details["file_id"] = serde_json::json!(job.file_id);
+    details["status"] = serde_json::json!(status);
+    sockets.send_to_user(&job.owner_id, "media_progress", details);
+}
+
+/// Renditions sit next to the original, e.g. `{owner}/{id}.thumb-128.webp`.
+pub fn rendition_blob_name(blob_name: &str, name: &str) -> String {
+    format!("{}.{}.webp", blob_name, name)
+}
+
+/// Decodes `source` and returns a full-size WebP copy (`full`) plus one
+/// `thumb-{size}` per size. Re-encoding drops EXIF and other metadata, so
+/// the orientation tag is applied to the pixels first.
+pub fn render_renditions(source: &[u8], sizes: &[u32]) -> Result<Vec<RenderedImage>, String> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+
+    let mut reader = ImageReader::new(Cursor::new(source)).with_guessed_format().map_err(|e| e.to_string())?;
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().map_err(|e| e.to_string())?;
+    let orientation = decoder.orientation().map_err(|e| e.to_string())?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    image.apply_orientation(orientation);
+
+    let mut renditions = vec![encode_webp("full".to_string(), &image)?];
+    for &size in sizes {
+        let name = format!("thumb-{}", size);
+        // Never upscale; small images get a same-size thumbnail.
+        if image.width() > size || image.height() > size {
+            renditions.push(encode_webp(name, &image.thumbnail(size, size))?);
+        } else {
+            renditions.push(encode_webp(name, &image)?);
+        }
+    }
+
+    Ok(renditions)
+}
+
+/// The `image` crate's WebP encoder is lossless and takes 8-bit RGB(A).
+fn encode_webp(name: String, image: &DynamicImage) -> Result<RenderedImage, String> {
+    let mut data = Vec::new();
+    let encoder = WebPEncoder::new_lossless(&mut data);
+
+    let encoded = if image.color().has_alpha() {
+        let pixels = image.to_rgba8();
+        encoder.encode(pixels.as_raw(), pixels.width(), pixels.height(), ExtendedColorType::Rgba8)
+    } else {
+        let pixels = image.to_rgb8();
+        encoder.encode(pixels.as_raw(), pixels.width(), pixels.height(), ExtendedColorType::Rgb8)
+    };
+    encoded.map_err(|e| e.to_string())?;
+
+    Ok(RenderedImage {
+        name,
+        width: image.width(),
+        height: image.height(),
+        data,
+    })
+}
+
+/// Removes a file's stored renditions; their rows go with the file.
+pub async fn delete_renditions(
+    db: &PostgresDb,
+    storage: &dyn ObjectStore,
+    file: &FileRecord
+) -> Result<(), Box<dyn std::error::Error>> {
+    let renditions = db.query(
+        &format!("SELECT {} FROM file_renditions WHERE file_id = $1", RENDITION_COLUMNS),
+        &[&file.id],
+        map_rendition
+    ).await?;
+
+    for rendition in renditions {
+        storage.delete(&file.container, &rendition.blob_name).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn list_renditions(
+    store: web::Data<FileStore>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    id: web::Path<Uuid>
+) -> HttpResponse {
+    let file = match owned_file(&store, &db, &session, &id).await {
+        Ok(file) => file,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    let status = db.query(
+        "SELECT status, attempts, last_error, updated_at FROM media_jobs WHERE file_id = $1",
+        &[&file.id],
+        map_media_status
+    ).await;
+
+    let mut status = match status {
+        Ok(mut status) =>
+            match status.pop() {
+                Some(status) => status,
+                None => {
+                    return response_not_found("file has no renditions");
+                }
+            }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    let renditions = db.query(
+        &format!("SELECT {} FROM file_renditions WHERE file_id = $1 ORDER BY name", RENDITION_COLUMNS),
+        &[&file.id],
+        map_rendition
+    ).await;
+
+    match renditions {
+        Ok(renditions) => {
+            status.renditions = renditions;
+            response_ok("renditions retrieved successfully", status)
+        }
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn download_rendition(
+    store: web::Data<FileStore>,
+    storage: web::Data<dyn ObjectStore>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    path: web::Path<(Uuid, String)>
+) -> HttpResponse {
+    let (id, name) = path.into_inner();
+    let file = match owned_file(&store, &db, &session, &id).await {
+        Ok(file) => file,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    let rendition = db.query(
+        &format!("SELECT {} FROM file_renditions WHERE file_id = $1 AND name = $2", RENDITION_COLUMNS),
+        &[&file.id, &name],
+        map_rendition
+    ).await;
+
+    let rendition = match rendition {
+        Ok(mut renditions) =>
+            match renditions.pop() {
+                Some(rendition) => rendition,
+                None => {
+                    return response_not_found("rendition not found");
+                }
+            }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    let data = match storage.get(&file.container, &rendition.blob_name).await {
+        Ok(data) => data,
+        Err(err) if err.is_not_found() => {
+            return response_not_found("rendition content not found");
+        }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    let mut response = HttpResponse::Ok();
+    response.content_type(rendition.content_type.as_str());
+    if let Some(cache_control) = &file.cache_control {
+        response.insert_header((CACHE_CONTROL, cache_control.as_str()));
+    }
+
+    response.no_chunking(rendition.size as u64).streaming(data)
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{ DateTime, Utc };
+use futures::stream;
+
+use crate::object_store::*;
+
+struct StoredObject {
+    data: Bytes,
+    options: PutOptions,
+    last_modified: DateTime<Utc>,
+}
+
+impl StoredObject {
+    fn meta(&self, key: &str) -> ObjectMeta {
+        ObjectMeta {
+            key: key.to_string(),
+            size: self.data.len() as u64,
+            content_type: Some(self.options.content_type.clone()),
+            cache_control: self.options.cache_control.clone(),
+            metadata: self.options.metadata.clone(),
+            last_modified: self.last_modified,
+        }
+    }
+}
+
+/// Keeps every object in process memory. Meant for tests and local runs;
+/// nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: RwLock<BTreeMap<(String, String), StoredObject>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read<T>(&self, container: &str, key: &str, f: impl FnOnce(&StoredObject) -> T) -> Result<T, ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        let objects = self.objects.read().unwrap();
+        objects
+            .get(&(container.to_string(), key.to_string()))
+            .map(f)
+            .ok_or_else(|| ObjectStoreError::not_found(container, key))
+    }
+}
+
+fn single_chunk(data: Bytes) -> ByteStream {
+    Box::pin(stream::once(async move { Ok(data) }))
+}
+
+#[async_trait(?Send)]
+impl ObjectStore for MemoryStorage {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn url(&self, container: &str, key: &str) -> String {
+        format!("memory://{}/{}", container, key)
+    }
+
+    async fn put(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        data: Bytes
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        let object = StoredObject {
+            data,
+            options: options.clone(),
+            last_modified: Utc::now(),
+        };
+        let meta = object.meta(key);
+
+        self.objects.write().unwrap().insert((container.to_string(), key.to_string()), object);
+
+        Ok(meta)
+    }
+
+    async fn get(&self, container: &str, key: &str) -> Result<ByteStream, ObjectStoreError> {
+        let data = self.read(container, key, |object| object.data.clone())?;
+        Ok(single_chunk(data))
+    }
+
+    async fn get_range(
+        &self,
+        container: &str,
+        key: &str,
+        range: Range<u64>
+    ) -> Result<ByteStream, ObjectStoreError> {
+        let data = self.read(container, key, |object| object.data.clone())?;
+        let range = clamp_range(&range, data.len() as u64)?;
+        Ok(single_chunk(data.slice(range.start as usize..range.end as usize)))
+    }
+
+    async fn head(&self, container: &str, key: &str) -> Result<ObjectMeta, ObjectStoreError> {
+        self.read(container, key, |object| object.meta(key))
+    }
+
+    async fn delete(&self, container: &str, key: &str) -> Result<(), ObjectStoreError> {
+        validate_container(container)?;
+        validate_key(key)?;
+
+        self.objects.write().unwrap().remove(&(container.to_string(), key.to_string()));
+        Ok(())
+    }
+
+    async fn list(&self, container: &str, prefix: &str) -> Result<Vec<ObjectMeta>, ObjectStoreError> {
+        validate_container(container)?;
+
+        let objects = self.objects.read().unwrap();
+        let start = (container.to_string(), prefix.to_string());
+
+        Ok(
+            objects
+                .range(start..)
+                .take_while(|((c, key), _)| c == container && key.starts_with(prefix))
+                .map(|((_, key), object)| object.meta(key))
+                .collect()
+        )
+    }
+}
@@ -1,9 +1,20 @@
 use actix_web::{
-    dev::{ forward_ready, Service, ServiceRequest, ServiceResponse, Transform },
+    body,
+    dev::{ forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform },
+    error::{ ErrorInternalServerError, PayloadError },
+    http::{ header::{ HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER }, Method, StatusCode },
+    web::{ self, Bytes },
     Error,
+    HttpMessage,
+    HttpResponse,
 };
 
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
 use futures::future::LocalBoxFuture;
+use futures::StreamExt;
 use futures::future::{ ready, Ready };
 
 use crate::response::*;
@@ -11,6 +22,16 @@ use crate::response::*;
 use actix_session::{Session, SessionExt};
 
 use crate::models::*;
+use crate::redis_client::Cache;
+use crate::solana_h::WALLET_AUTH_METHOD;
+
+use uuid::Uuid;
+
+use std::collections::{ HashMap, VecDeque };
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
 
 
 pub struct Auth;
@@ -53,24 +74,27 @@ impl<S> Service<ServiceRequest>
 
         if let Some(auth_header) = req.headers().get("Authorization") {
             if let Ok(auth_str) = auth_header.to_str() {
-                if auth_str.starts_with("Bearer ") {
-                    let token = &auth_str[7..];
+                if let Some(token) = auth_str.strip_prefix("Bearer ") {
                     if validate_token(token) {
 
-                        let user = User::new("admin".to_string(), "admin".to_string());
+                        let user = user_for_token(token);
 
                         let session = req.get_session();
-                        session.insert("user_id", &user.id).expect("Failed to insert user_id into session");
+                        session.insert("user_id", user.id).expect("Failed to insert user_id into session");
+                        session.insert("username", &user.username).expect("Failed to insert username into session");
+                        session.insert("user_role", &user.role).expect("Failed to insert user_role into session");
+                        session.insert("auth_method", "bearer").expect("Failed to insert auth_method into session");
                         
-                        let fut = self.service.call(req);
-                        return Box::pin(async move {
-                            fut.await
-                        });
+                        return Box::pin(self.service.call(req));
                     }
                 }
             }
         }
 
+        if wallet_session_user(&req.get_session()).is_some() {
+            return Box::pin(self.service.call(req));
+        }
+
         Box::pin(async move {
             Ok(req.into_response(response_unauthorized("unauthorized: invalid or missing token")))
         })
@@ -81,15 +105,668 @@ pub fn validate_token(token: &str) -> bool {
     token == "valid_token"
 }
 
+/// Tokens map to a stable user id so per-user state survives across requests.
+/// A token grants the ordinary `user` role; nothing is elevated by holding one.
+pub fn user_for_token(token: &str) -> User {
+    User::with_id(
+        Uuid::new_v5(&Uuid::NAMESPACE_OID, token.as_bytes()),
+        "api".to_string(),
+        "user".to_string()
+    )
+}
+
+/// The session's user if it was opened by wallet sign-in. Only those
+/// sessions authenticate on their own; bearer sessions need the token on
+/// every request.
+pub fn wallet_session_user(session: &Session) -> Option<User> {
+    let method = session.get::<String>("auth_method").ok().flatten();
+    if method.as_deref() != Some(WALLET_AUTH_METHOD) {
+        return None;
+    }
+    get_user_from_session(session)
+}
+
+/// The authenticated caller, if any, from a valid bearer token or a wallet
+/// session, for middleware that runs before `Auth`.
+pub fn request_user(req: &ServiceRequest) -> Option<User> {
+    match bearer_token(req) {
+        Some(token) if validate_token(token) => Some(user_for_token(token)),
+        _ => wallet_session_user(&req.get_session()),
+    }
+}
+
 pub fn get_user_from_session(session: &Session) -> Option<User> {
     match (
-        session.get::<String>("user_id").ok()?,
+        session.get::<Uuid>("user_id").ok()?,
+        session.get::<String>("username").ok()?,
         session.get::<String>("user_role").ok()?
     ) {
-        (Some(id), Some(role)) => Some(User::new(
+        (Some(id), Some(username), Some(role)) => Some(User::with_id(
             id,
+            username,
             role
         )),
         _ => None
     }
-}
\ No newline at end of file
+}
+
+const SLIDING_WINDOW_SCRIPT: &str = r"
+    local now = tonumber(ARGV[1])
+    local window = tonumber(ARGV[2])
+    local limit = tonumber(ARGV[3])
+    redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, now - window)
+    local count = redis.call('ZCARD', KEYS[1])
+    if count < limit then
+        redis.call('ZADD', KEYS[1], now, ARGV[4])
+        redis.call('PEXPIRE', KEYS[1], window)
+        return {1, limit - count - 1, window}
+    end
+    local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+    return {0, 0, window - (now - tonumber(oldest[2]))}
+";
+
+const TOKEN_BUCKET_SCRIPT: &str = r"
+    local now = tonumber(ARGV[1])
+    local capacity = tonumber(ARGV[2])
+    local refill_per_ms = tonumber(ARGV[3])
+    local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+    local tokens = tonumber(bucket[1]) or capacity
+    local ts = tonumber(bucket[2]) or now
+    tokens = math.min(capacity, tokens + math.max(0, now - ts) * refill_per_ms)
+    local allowed = 0
+    local wait = 0
+    if tokens >= 1 then
+        tokens = tokens - 1
+        allowed = 1
+    else
+        wait = math.ceil((1 - tokens) / refill_per_ms)
+    end
+    local full_in = math.ceil((capacity - tokens) / refill_per_ms)
+    redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+    redis.call('PEXPIRE', KEYS[1], math.max(full_in, 1))
+    if allowed == 1 then
+        return {1, math.floor(tokens), full_in}
+    end
+    return {0, 0, wait}
+";
+
+/// Entries kept by the in-memory fallback before stale keys are swept.
+const MEMORY_LIMITER_SWEEP_AT: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimitAlgorithm {
+    /// At most `limit` requests in any rolling `window`.
+    SlidingWindow { limit: u64, window: Duration },
+    /// Bursts of up to `capacity`, refilled at `refill_per_sec`.
+    TokenBucket { capacity: u64, refill_per_sec: f64 },
+}
+
+/// What identifies a caller. `User` falls back to the client IP for
+/// requests without a valid bearer token or wallet session.
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimitKey {
+    User,
+    Ip,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RateLimitDecision {
+    allowed: bool,
+    limit: u64,
+    remaining: u64,
+    /// Time until a request would be allowed (when denied) or until the
+    /// window or bucket fully resets (when allowed).
+    wait: Duration,
+}
+
+enum MemoryBucket {
+    Window(VecDeque<Instant>),
+    Tokens { tokens: f64, updated: Instant },
+}
+
+/// Per-process limiter used while Redis is unreachable.
+#[derive(Default)]
+struct MemoryLimiter {
+    buckets: Mutex<HashMap<String, MemoryBucket>>,
+}
+
+impl MemoryLimiter {
+    fn check(&self, key: &str, algorithm: RateLimitAlgorithm) -> RateLimitDecision {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MEMORY_LIMITER_SWEEP_AT {
+            buckets.retain(|_, bucket| match bucket {
+                MemoryBucket::Window(hits) => hits.back().is_some_and(|t| now.duration_since(*t) < Duration::from_secs(3600)),
+                MemoryBucket::Tokens { updated, .. } => now.duration_since(*updated) < Duration::from_secs(3600),
+            });
+        }
+
+        match algorithm {
+            RateLimitAlgorithm::SlidingWindow { limit, window } => {
+                let bucket = buckets
+                    .entry(key.to_string())
+                    .or_insert_with(|| MemoryBucket::Window(VecDeque::new()));
+                if !matches!(bucket, MemoryBucket::Window(_)) {
+                    *bucket = MemoryBucket::Window(VecDeque::new());
+                }
+                let MemoryBucket::Window(hits) = bucket else { unreachable!() };
+
+                while hits.front().is_some_and(|t| now.duration_since(*t) >= window) {
+                    hits.pop_front();
+                }
+
+                if (hits.len() as u64) < limit {
+                    hits.push_back(now);
+                    RateLimitDecision {
+                        allowed: true,
+                        limit,
+                        remaining: limit - hits.len() as u64,
+                        wait: window,
+                    }
+                } else {
+                    let oldest = hits.front().copied().unwrap_or(now);
+                    RateLimitDecision {
+                        allowed: false,
+                        limit,
+                        remaining: 0,
+                        wait: window.saturating_sub(now.duration_since(oldest)),
+                    }
+                }
+            }
+            RateLimitAlgorithm::TokenBucket { capacity, refill_per_sec } => {
+                let bucket = buckets
+                    .entry(key.to_string())
+                    .or_insert(MemoryBucket::Tokens { tokens: capacity as f64, updated: now });
+                if !matches!(bucket, MemoryBucket::Tokens { .. }) {
+                    *bucket = MemoryBucket::Tokens { tokens: capacity as f64, updated: now };
+                }
+
+                let MemoryBucket::Tokens { tokens, updated } = bucket else { unreachable!() };
+                *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * refill_per_sec).min(capacity as f64);
+                *updated = now;
+
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    RateLimitDecision {
+                        allowed: true,
+                        limit: capacity,
+                        remaining: tokens.floor() as u64,
+                        wait: Duration::from_secs_f64((capacity as f64 - *tokens) / refill_per_sec),
+                    }
+                } else {
+                    RateLimitDecision {
+                        allowed: false,
+                        limit: capacity,
+                        remaining: 0,
+                        wait: Duration::from_secs_f64((1.0 - *tokens) / refill_per_sec),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Redis-backed rate limiting. Wrap a scope or a single resource; each
+/// `RateLimit` keeps its own counters under `ratelimit:{name}:...`.
+///
+/// Build it once outside `HttpServer::new` and clone it into the app so the
+/// in-memory fallback is shared between workers.
+#[derive(Clone)]
+pub struct RateLimit {
+    name: String,
+    algorithm: RateLimitAlgorithm,
+    key: RateLimitKey,
+    script: Arc<redis::Script>,
+    fallback: Arc<MemoryLimiter>,
+}
+
+impl RateLimit {
+    pub fn sliding_window(name: &str, limit: u64, window: Duration) -> Result<Self, String> {
+        if window.as_millis() == 0 {
+            return Err(format!("rate limit {}: window must be at least 1ms", name));
+        }
+        Ok(Self::new(name, RateLimitAlgorithm::SlidingWindow { limit, window }, SLIDING_WINDOW_SCRIPT))
+    }
+
+    pub fn token_bucket(name: &str, capacity: u64, refill_per_sec: f64) -> Result<Self, String> {
+        if !(refill_per_sec.is_finite() && refill_per_sec > 0.0) {
+            return Err(format!("rate limit {}: refill rate must be greater than zero", name));
+        }
+        Ok(Self::new(name, RateLimitAlgorithm::TokenBucket { capacity, refill_per_sec }, TOKEN_BUCKET_SCRIPT))
+    }
+
+    fn new(name: &str, algorithm: RateLimitAlgorithm, script: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            algorithm,
+            key: RateLimitKey::User,
+            script: Arc::new(redis::Script::new(script)),
+            fallback: Arc::new(MemoryLimiter::default()),
+        }
+    }
+
+    pub fn key_by(mut self, key: RateLimitKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Only authenticated users get their own bucket; anyone else is keyed
+    /// by the connecting address, since forwarded headers can be forged.
+    fn client_key(&self, req: &ServiceRequest) -> String {
+        if let RateLimitKey::User = self.key {
+            if let Some(user) = request_user(req) {
+                return format!("user:{}", user.id);
+            }
+        }
+
+        match req.peer_addr() {
+            Some(addr) => format!("ip:{}", addr.ip()),
+            None => "ip:unknown".to_string(),
+        }
+    }
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+}
+
+async fn check_rate_limit(
+    cache: Option<&Cache>,
+    limit: &RateLimit,
+    key: &str
+) -> RateLimitDecision {
+    let algorithm = limit.algorithm;
+    if let Some(cache) = cache {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let (max, args) = match algorithm {
+            RateLimitAlgorithm::SlidingWindow { limit, window } =>
+                (
+                    limit,
+                    vec![
+                        now_ms.to_string(),
+                        (window.as_millis() as u64).to_string(),
+                        limit.to_string(),
+                        format!("{}-{}", now_ms, Uuid::new_v4()),
+                    ],
+                ),
+            RateLimitAlgorithm::TokenBucket { capacity, refill_per_sec } =>
+                (
+                    capacity,
+                    vec![now_ms.to_string(), capacity.to_string(), (refill_per_sec / 1000.0).to_string()],
+                ),
+        };
+
+        let result: redis::RedisResult<Vec<i64>> = cache.run_script(&limit.script, &[key], &args).await;
+
+        match result {
+            Ok(values) if values.len() == 3 => {
+                return RateLimitDecision {
+                    allowed: values[0] == 1,
+                    limit: max,
+                    remaining: values[1].max(0) as u64,
+                    wait: Duration::from_millis(values[2].max(0) as u64),
+                };
+            }
+            Ok(values) => log::warn!("Unexpected rate limit script reply: {:?}", values),
+            Err(e) => log::warn!("Rate limiter falling back to memory, Redis unavailable: {}", e),
+        }
+    }
+
+    limit.fallback.check(key, algorithm)
+}
+
+fn apply_rate_limit_headers(headers: &mut HeaderMap, decision: &RateLimitDecision) {
+    let wait_secs = decision.wait.as_secs() + u64::from(decision.wait.subsec_nanos() > 0);
+
+    headers.insert(HeaderName::from_static("x-ratelimit-limit"), HeaderValue::from(decision.limit));
+    headers.insert(HeaderName::from_static("x-ratelimit-remaining"), HeaderValue::from(decision.remaining));
+    headers.insert(HeaderName::from_static("x-ratelimit-reset"), HeaderValue::from(wait_secs));
+    if !decision.allowed {
+        headers.insert(RETRY_AFTER, HeaderValue::from(wait_secs.max(1)));
+    }
+}
+
+impl<S> Transform<S, ServiceRequest>
+    for RateLimit
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+        S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service: Rc::new(service), limit: self.clone() }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limit: RateLimit,
+}
+
+impl<S> Service<ServiceRequest>
+    for RateLimitMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+        S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limit = self.limit.clone();
+        let key = format!("ratelimit:{}:{}", limit.name, limit.client_key(&req));
+        let cache = req.app_data::<web::Data<Cache>>().cloned();
+
+        Box::pin(async move {
+            let decision = check_rate_limit(cache.as_ref().map(|cache| cache.get_ref()), &limit, &key).await;
+
+            if !decision.allowed {
+                let mut response = response_too_many_requests("too many requests, slow down");
+                apply_rate_limit_headers(response.headers_mut(), &decision);
+                return Ok(req.into_response(response));
+            }
+
+            let mut res = service.call(req).await?;
+            apply_rate_limit_headers(res.headers_mut(), &decision);
+            Ok(res)
+        })
+    }
+}
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+const IDEMPOTENCY_KEY_MAX_LEN: usize = 255;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum IdempotencyRecord {
+    InProgress {
+        fingerprint: String,
+    },
+    Completed {
+        fingerprint: String,
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    },
+}
+
+impl IdempotencyRecord {
+    fn fingerprint(&self) -> &str {
+        match self {
+            IdempotencyRecord::InProgress { fingerprint } => fingerprint,
+            IdempotencyRecord::Completed { fingerprint, .. } => fingerprint,
+        }
+    }
+}
+
+/// Bodies up to this size are hashed into the request fingerprint.
+const IDEMPOTENCY_BODY_LIMIT: usize = 64 * 1024;
+
+/// Replays the stored response for POST requests that repeat an
+/// `Idempotency-Key`. Requests without the header pass straight through.
+#[derive(Clone)]
+pub struct Idempotency {
+    /// How long a completed response is kept for replay.
+    ttl: Duration,
+    /// How long a request may stay in progress before the key is released.
+    lock_ttl: Duration,
+    /// Larger bodies, and multipart ones, are fingerprinted by their
+    /// content type and length instead of being buffered.
+    body_limit: usize,
+}
+
+impl Idempotency {
+    pub fn new(ttl: Duration, lock_ttl: Duration) -> Self {
+        Self { ttl, lock_ttl, body_limit: IDEMPOTENCY_BODY_LIMIT }
+    }
+
+    pub fn body_limit(mut self, body_limit: usize) -> Self {
+        self.body_limit = body_limit;
+        self
+    }
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(24 * 60 * 60), Duration::from_secs(5 * 60))
+    }
+}
+
+/// The media type without parameters, so a new multipart boundary on retry
+/// still matches.
+fn content_type_essence(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+/// Hashes the request line, content type and length, plus the body when it
+/// was small enough to buffer.
+fn request_fingerprint(req: &ServiceRequest, body: Option<&[u8]>) -> String {
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+
+    let mut hasher = Sha256::new();
+    for part in [
+        req.method().as_str(),
+        req.path(),
+        req.query_string(),
+        &content_type_essence(req),
+        content_length,
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\n");
+    }
+    match body {
+        Some(body) => {
+            hasher.update(b"body\n");
+            hasher.update(body);
+        }
+        None => hasher.update(b"unbuffered"),
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Reads at most `limit` bytes of the body. Returns the whole body if it fit,
+/// and always puts what was read back in front of the rest of the stream.
+async fn buffer_small_body(req: &mut ServiceRequest, limit: usize) -> Result<Option<Bytes>, Error> {
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_type_essence(req).starts_with("multipart/") || declared.is_some_and(|len| len > limit) {
+        return Ok(None);
+    }
+
+    let mut payload = req.take_payload();
+    let mut chunks = Vec::new();
+    let mut read = 0;
+    let mut complete = true;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        read += chunk.len();
+        chunks.push(chunk);
+        if read > limit {
+            complete = false;
+            break;
+        }
+    }
+
+    let body = complete.then(|| chunks.concat().into());
+    let stream: Pin<Box<dyn futures::Stream<Item = Result<Bytes, PayloadError>>>> = Box::pin(
+        futures::stream::iter(chunks.into_iter().map(Ok)).chain(payload)
+    );
+    req.set_payload(Payload::from(stream));
+    Ok(body)
+}
+
+fn replay_response(status: u16, headers: &[(String, String)], body: &str) -> HttpResponse {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+    let mut response = HttpResponse::build(status);
+    for (name, value) in headers {
+        response.insert_header((name.as_str(), value.as_str()));
+    }
+    response.insert_header((IDEMPOTENT_REPLAYED_HEADER, "true"));
+    response.body(BASE64.decode(body).unwrap_or_default())
+}
+
+impl<S> Transform<S, ServiceRequest>
+    for Idempotency
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+        S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IdempotencyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyMiddleware { service: Rc::new(service), config: self.clone() }))
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<S>,
+    config: Idempotency,
+}
+
+impl<S> Service<ServiceRequest>
+    for IdempotencyMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+        S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let config = self.config.clone();
+
+        let idempotency_key = req
+            .headers()
+            .get(IDEMPOTENCY_KEY_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string);
+        let cache = req.app_data::<web::Data<Cache>>().cloned();
+        let is_post = req.method() == Method::POST;
+
+        let (idempotency_key, cache) = match (is_post, idempotency_key, cache) {
+            (true, Some(key), Some(cache)) => (key, cache),
+            _ => {
+                return Box::pin(service.call(req));
+            }
+        };
+
+        Box::pin(async move {
+            if idempotency_key.is_empty() || idempotency_key.len() > IDEMPOTENCY_KEY_MAX_LEN {
+                let response = response_unprocessable_entity(serde_json::json!({
+                    IDEMPOTENCY_KEY_HEADER: format!("must be between 1 and {} characters", IDEMPOTENCY_KEY_MAX_LEN)
+                }));
+                return Ok(req.into_response(response));
+            }
+
+            let request_body = buffer_small_body(&mut req, config.body_limit).await?;
+            let fingerprint = request_fingerprint(&req, request_body.as_deref());
+
+            // Runs inside `Auth`, so the session holds the validated caller.
+            let caller = match get_user_from_session(&req.get_session()) {
+                Some(user) => user.id.to_string(),
+                None =>
+                    req
+                        .peer_addr()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_else(|| "unknown".to_string()),
+            };
+            let key = format!("idempotency:{}:{}", caller, idempotency_key);
+
+            let pending = IdempotencyRecord::InProgress { fingerprint: fingerprint.clone() };
+            let pending = serde_json::to_string(&pending).map_err(ErrorInternalServerError)?;
+
+            match cache.set_if_absent(&key, pending, config.lock_ttl.as_secs()).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    let response = match cache.get_json::<IdempotencyRecord>(&key).await {
+                        Ok(Some(record)) if record.fingerprint() != fingerprint =>
+                            response_unprocessable_entity(serde_json::json!({
+                                IDEMPOTENCY_KEY_HEADER: "key was already used with a different request body"
+                            })),
+                        Ok(Some(IdempotencyRecord::Completed { status, headers, body: stored, .. })) =>
+                            replay_response(status, &headers, &stored),
+                        _ => response_conflict("a request with this idempotency key is already in progress"),
+                    };
+                    return Ok(req.into_response(response));
+                }
+                Err(e) => {
+                    log::warn!("Idempotency disabled for this request, Redis unavailable: {}", e);
+                    return service.call(req).await;
+                }
+            }
+
+            let res = service.call(req).await;
+            let res = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    let _ = cache.delete(&key).await;
+                    return Err(e);
+                }
+            };
+
+            let (http_req, response) = res.into_parts();
+            let status = response.status();
+            let headers = response.headers().clone();
+            let response_body = body::to_bytes(response.into_body()).await.map_err(ErrorInternalServerError)?;
+
+            if status.is_server_error() {
+                let _ = cache.delete(&key).await;
+            } else {
+                let record = IdempotencyRecord::Completed {
+                    fingerprint,
+                    status: status.as_u16(),
+                    headers: headers
+                        .iter()
+                        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                        .collect(),
+                    body: BASE64.encode(&response_body),
+                };
+                if let Err(e) = cache.set_json(&key, &record, config.ttl.as_secs()).await {
+                    log::warn!("Failed to store idempotent response for {}: {}", key, e);
+                }
+            }
+
+            let mut rebuilt = HttpResponse::with_body(status, response_body).map_into_boxed_body();
+            *rebuilt.headers_mut() = headers;
+            Ok(ServiceResponse::new(http_req, rebuilt))
+        })
+    }
+}
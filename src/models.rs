@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -19,6 +21,21 @@ pub struct Temperature {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerHealth {
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub cache: CacheStats,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TierStats {
+    pub hits: u64,
+    pub misses: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheStats {
+    pub l1: Option<TierStats>,
+    pub l2: TierStats,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,11 +47,301 @@ pub struct Settings {
 
 impl User {
     pub fn new(username: String, role: String) -> Self {
+        Self::with_id(Uuid::new_v4(), username, role)
+    }
+
+    pub fn with_id(id: Uuid, username: String, role: String) -> Self {
         Self {
-            id: Uuid::new_v4(),
+            id,
             username,
             role,
             created_at: chrono::Utc::now(),
         }
     }
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Document {
+    pub id: Uuid,
+    pub collection: String,
+    pub title: String,
+    pub chunk_count: i32,
+    pub blob_url: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchHit {
+    pub document_id: Uuid,
+    pub title: String,
+    pub blob_url: Option<String>,
+    pub chunk_index: i32,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub content: String,
+    pub score: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnswerSource {
+    pub citation: usize,
+    pub document_id: Uuid,
+    pub title: String,
+    pub blob_url: Option<String>,
+    pub chunk_index: i32,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub score: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Answer {
+    pub answer: String,
+    pub model: String,
+    pub sources: Vec<AnswerSource>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VariableType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TemplateVariable {
+    pub name: String,
+    #[serde(rename = "type", default = "default_variable_type")]
+    pub kind: VariableType,
+    #[serde(default = "default_true")]
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+}
+
+fn default_variable_type() -> VariableType {
+    VariableType::String
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PromptTemplate {
+    pub id: Uuid,
+    pub name: String,
+    pub version: i32,
+    pub body: String,
+    pub variables: Vec<TemplateVariable>,
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<i32>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileRecord {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: i64,
+    pub cache_control: Option<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// `pending`, `clean` or `error`; only clean files are served.
+    pub scan_status: String,
+    #[serde(skip_serializing, default)]
+    pub container: String,
+    #[serde(skip_serializing, default)]
+    pub blob_name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UploadSession {
+    pub id: Uuid,
+    #[serde(skip_serializing, default)]
+    pub owner_id: Uuid,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: i64,
+    pub block_size: i64,
+    pub block_count: i32,
+    /// Base64 MD5 of the whole file, checked when the upload completes.
+    pub content_md5: Option<String>,
+    pub cache_control: Option<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    pub received_blocks: Vec<i32>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileLink {
+    pub url: String,
+    pub permissions: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Rendition {
+    pub name: String,
+    pub content_type: String,
+    pub width: i32,
+    pub height: i32,
+    pub size: i64,
+    #[serde(skip_serializing, default)]
+    pub blob_name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MediaStatus {
+    /// `pending`, `processing`, `done` or `failed`.
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub renditions: Vec<Rendition>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WalletBalance {
+    pub pubkey: String,
+    pub lamports: u64,
+    pub sol: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LinkedWallet {
+    pub pubkey: String,
+    pub is_primary: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConnectedWallet {
+    pub pubkey: String,
+    pub is_primary: bool,
+    pub lamports: u64,
+    pub sol: f64,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SignInChallenge {
+    pub nonce: String,
+    /// Exact text the wallet must sign.
+    pub message: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferReceipt {
+    pub signature: String,
+    pub from: String,
+    pub to: String,
+    pub lamports: u64,
+    pub sol: f64,
+    pub fee: u64,
+    pub commitment: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PreparedTransaction {
+    pub id: String,
+    /// `transfer`, `token_transfer` or `memo`.
+    pub kind: String,
+    pub wallet: String,
+    /// Base64 of the bincode-serialized, unsigned transaction.
+    pub transaction: String,
+    pub fee: u64,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransactionStatusUpdate {
+    pub id: String,
+    pub signature: String,
+    /// `submitted`, `processed`, `confirmed`, `failed` or `expired`.
+    pub status: String,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PaymentIntent {
+    pub id: Uuid,
+    /// Must appear in the paying transaction's memo.
+    pub reference: String,
+    pub recipient: String,
+    pub lamports: u64,
+    pub sol: f64,
+    /// `pending`, `paid` or `expired`.
+    pub status: String,
+    pub signature: Option<String>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub paid_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SolanaTransfer {
+    pub signature: String,
+    /// The watched account that received the transfer.
+    pub address: String,
+    pub sender: String,
+    pub lamports: u64,
+    pub sol: f64,
+    pub slot: u64,
+    pub memo: Option<String>,
+    /// `confirmed`, `finalized`, `failed` or `dropped`.
+    pub status: String,
+    pub block_time: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenAccount {
+    pub address: String,
+    pub mint: String,
+    pub owner: String,
+    /// Raw amount in the mint's base units.
+    pub amount: u64,
+    pub decimals: u8,
+    /// `amount` in whole tokens, as a decimal string.
+    pub ui_amount: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AssociatedTokenAccount {
+    pub address: String,
+    pub owner: String,
+    pub mint: String,
+    /// Set when the account had to be created.
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenTransferReceipt {
+    pub signature: String,
+    pub from: String,
+    pub to: String,
+    pub mint: String,
+    /// The recipient's associated token account.
+    pub destination: String,
+    pub amount: u64,
+    pub decimals: u8,
+    pub ui_amount: String,
+    pub fee: u64,
+    pub created_account: bool,
+    pub commitment: String,
+}
@@ -0,0 +1,460 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::{ Bytes, BytesMut };
+use chrono::{ DateTime, Utc };
+use futures::{ stream, Stream, StreamExt, TryStreamExt };
+use serde::Serialize;
+
+use crate::azure_storage::*;
+use crate::local_storage::*;
+use crate::memory_storage::*;
+use crate::s3_storage::*;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, ObjectStoreError>>>>;
+
+/// Azure allows at most 10 tags per blob, S3 at most 10 per object.
+pub const MAX_TAGS: usize = 10;
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    /// Listings may leave this and `metadata` empty; S3 returns neither.
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Properties stored with an object when it is written.
+#[derive(Debug, Clone, Default)]
+pub struct PutOptions {
+    pub content_type: String,
+    pub cache_control: Option<String>,
+    pub content_disposition: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl PutOptions {
+    pub fn new(content_type: &str) -> Self {
+        Self {
+            content_type: content_type.to_string(),
+            ..Self::default()
+        }
+    }
+}
+
+/// What a signed link allows its holder to do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct LinkPermissions {
+    pub read: bool,
+    pub write: bool,
+    pub delete: bool,
+}
+
+impl LinkPermissions {
+    pub const READ: LinkPermissions = LinkPermissions { read: true, write: false, delete: false };
+
+    /// Parses SAS-style letters, e.g. `r` or `rwd`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut permissions = Self::default();
+
+        for letter in value.chars() {
+            match letter {
+                'r' => permissions.read = true,
+                'w' => permissions.write = true,
+                'd' => permissions.delete = true,
+                other => {
+                    return Err(format!("unknown permission {:?}; use r, w and d", other));
+                }
+            }
+        }
+
+        if permissions == Self::default() {
+            return Err("at least one permission is required".to_string());
+        }
+
+        Ok(permissions)
+    }
+
+    pub fn is_subset_of(&self, other: &LinkPermissions) -> bool {
+        (!self.read || other.read) && (!self.write || other.write) && (!self.delete || other.delete)
+    }
+}
+
+impl std::fmt::Display for LinkPermissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (enabled, letter) in [(self.read, "r"), (self.write, "w"), (self.delete, "d")] {
+            if enabled {
+                f.write_str(letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ObjectStoreError {
+    NotFound {
+        container: String,
+        key: String,
+    },
+    InvalidKey(String),
+    InvalidRange {
+        start: u64,
+        end: u64,
+        size: u64,
+    },
+    /// A streamed body went past the allowed size.
+    TooLarge {
+        limit: u64,
+    },
+    Io(std::io::Error),
+    Unsupported(&'static str),
+    Backend(String),
+}
+
+impl std::fmt::Display for ObjectStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectStoreError::NotFound { container, key } => write!(f, "object {}/{} not found", container, key),
+            ObjectStoreError::InvalidKey(key) => write!(f, "invalid object key: {:?}", key),
+            ObjectStoreError::InvalidRange { start, end, size } =>
+                write!(f, "range {}..{} is not satisfiable for an object of {} bytes", start, end, size),
+            ObjectStoreError::TooLarge { limit } => write!(f, "object exceeds the limit of {} bytes", limit),
+            ObjectStoreError::Io(e) => write!(f, "storage I/O error: {}", e),
+            ObjectStoreError::Unsupported(what) => write!(f, "{} is not supported by this storage backend", what),
+            ObjectStoreError::Backend(e) => write!(f, "storage backend error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ObjectStoreError {}
+
+impl From<std::io::Error> for ObjectStoreError {
+    fn from(e: std::io::Error) -> Self {
+        ObjectStoreError::Io(e)
+    }
+}
+
+impl ObjectStoreError {
+    pub fn not_found(container: &str, key: &str) -> Self {
+        ObjectStoreError::NotFound {
+            container: container.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, ObjectStoreError::NotFound { .. })
+    }
+}
+
+/// Common interface over the blob backends. Objects are addressed by a
+/// container (bucket) and a `/`-separated key.
+#[async_trait(?Send)]
+pub trait ObjectStore: Send + Sync {
+    /// Short backend name used in logs and health output.
+    fn name(&self) -> &'static str;
+
+    /// Location of an object, suitable for storing alongside a record.
+    fn url(&self, container: &str, key: &str) -> String;
+
+    async fn put(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        data: Bytes
+    ) -> Result<ObjectMeta, ObjectStoreError>;
+
+    /// Stores a streamed body. The default buffers the stream and calls
+    /// `put`; backends that can write incrementally override it.
+    async fn put_stream(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        data: ByteStream
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        let data = collect_bytes(data).await?;
+        self.put(container, key, options, data).await
+    }
+
+    async fn get(&self, container: &str, key: &str) -> Result<ByteStream, ObjectStoreError>;
+
+    /// Returns the bytes in `range`. The end is clamped to the object size;
+    /// a range that starts at or past the end is an `InvalidRange` error.
+    async fn get_range(
+        &self,
+        container: &str,
+        key: &str,
+        range: Range<u64>
+    ) -> Result<ByteStream, ObjectStoreError>;
+
+    async fn head(&self, container: &str, key: &str) -> Result<ObjectMeta, ObjectStoreError>;
+
+    /// Removes an object. Deleting a missing object is not an error.
+    async fn delete(&self, container: &str, key: &str) -> Result<(), ObjectStoreError>;
+
+    /// Lists the objects whose key starts with `prefix`, ordered by key.
+    async fn list(&self, container: &str, prefix: &str) -> Result<Vec<ObjectMeta>, ObjectStoreError>;
+
+    /// Stages one block of a multi-request upload under `block_id`. Staging
+    /// the same id again replaces it. `md5` is the digest of `data`, already
+    /// checked by the caller; backends that can verify it in transit do.
+    ///
+    /// The default keeps each block as a temporary object.
+    async fn stage_block(
+        &self,
+        container: &str,
+        key: &str,
+        block_id: &str,
+        data: Bytes,
+        _md5: [u8; 16]
+    ) -> Result<(), ObjectStoreError> {
+        let options = PutOptions::new("application/octet-stream");
+        self.put(container, &staged_block_key(key, block_id)?, &options, data).await?;
+        Ok(())
+    }
+
+    /// Assembles the staged blocks, in the given order, into `key`.
+    async fn commit_blocks(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        block_ids: &[String]
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        let mut blocks = Vec::with_capacity(block_ids.len());
+        for block_id in block_ids {
+            blocks.push(self.get(container, &staged_block_key(key, block_id)?).await?);
+        }
+
+        let meta = self.put_stream(container, key, options, Box::pin(stream::iter(blocks).flatten())).await?;
+        self.abort_blocks(container, key, block_ids).await?;
+
+        Ok(meta)
+    }
+
+    /// Discards staged blocks that will not be committed.
+    async fn abort_blocks(&self, container: &str, key: &str, block_ids: &[String]) -> Result<(), ObjectStoreError> {
+        for block_id in block_ids {
+            self.delete(container, &staged_block_key(key, block_id)?).await?;
+        }
+        Ok(())
+    }
+
+    /// A time-limited URL that grants `permissions` on the object without
+    /// further authentication.
+    async fn signed_url(
+        &self,
+        _container: &str,
+        _key: &str,
+        _permissions: LinkPermissions,
+        _expires_in: Duration
+    ) -> Result<String, ObjectStoreError> {
+        Err(ObjectStoreError::Unsupported("signed links"))
+    }
+}
+
+/// Where the default `stage_block` keeps a block, out of the way of
+/// listings under the object's own prefix.
+/// Where the default `stage_block` keeps a block until it is committed.
+pub fn staged_block_key(key: &str, block_id: &str) -> Result<String, ObjectStoreError> {
+    let staged = format!(".blocks/{}/{}", key, block_id);
+    validate_key(&staged)?;
+    Ok(staged)
+}
+
+/// Block ids must all have the same length within a blob on Azure, so they
+/// are zero padded.
+pub fn block_id(index: usize) -> String {
+    format!("{:06}", index)
+}
+
+/// Reads up to `len` bytes from the front of `data`, returning them along
+/// with a stream that still yields the whole body.
+pub async fn peek(data: ByteStream, len: usize) -> Result<(Bytes, ByteStream), ObjectStoreError> {
+    // Fused so a body shorter than `len` isn't polled again once it ended.
+    let mut data = data.fuse();
+    let mut head = BytesMut::new();
+
+    while head.len() < len {
+        match data.next().await {
+            Some(chunk) => head.extend_from_slice(&chunk?),
+            None => {
+                break;
+            }
+        }
+    }
+
+    let head = head.freeze();
+    let first = head.clone();
+
+    Ok((head, Box::pin(stream::once(async move { Ok(first) }).chain(data))))
+}
+
+/// Regroups `data` into blocks of `block_size` bytes; only the last block
+/// may be shorter.
+pub fn rechunk(data: ByteStream, block_size: usize) -> ByteStream {
+    let state = (data, BytesMut::new(), false);
+
+    Box::pin(
+        stream::try_unfold(state, move |(mut data, mut buffer, mut finished)| async move {
+            while !finished && buffer.len() < block_size {
+                match data.next().await {
+                    Some(chunk) => buffer.extend_from_slice(&chunk?),
+                    None => {
+                        finished = true;
+                    }
+                }
+            }
+
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+
+            let block = buffer.split_to(buffer.len().min(block_size)).freeze();
+            Ok(Some((block, (data, buffer, finished))))
+        })
+    )
+}
+
+pub async fn collect_bytes(stream: ByteStream) -> Result<Bytes, ObjectStoreError> {
+    let data = stream
+        .try_fold(BytesMut::new(), |mut buffer, chunk| async move {
+            buffer.extend_from_slice(&chunk);
+            Ok(buffer)
+        }).await?;
+
+    Ok(data.freeze())
+}
+
+/// Rejects keys that would escape the container on a filesystem backend or
+/// mean different things to different backends.
+pub fn validate_key(key: &str) -> Result<(), ObjectStoreError> {
+    let invalid =
+        key.is_empty() ||
+        key.len() > 1024 ||
+        key.contains('\\') ||
+        key.contains('\0') ||
+        key.split('/').any(|segment| segment.is_empty() || segment == "." || segment == "..");
+
+    if invalid {
+        return Err(ObjectStoreError::InvalidKey(key.to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn validate_container(container: &str) -> Result<(), ObjectStoreError> {
+    if container.contains('/') {
+        return Err(ObjectStoreError::InvalidKey(container.to_string()));
+    }
+
+    validate_key(container)
+}
+
+/// Checks metadata against the strictest backend rules: names must be
+/// identifiers (Azure) and values printable ASCII (HTTP headers).
+pub fn validate_metadata(metadata: &BTreeMap<String, String>) -> Result<(), String> {
+    for (name, value) in metadata {
+        let mut chars = name.chars();
+        let identifier =
+            chars.next().map(|c| c.is_ascii_alphabetic() || c == '_').unwrap_or(false) &&
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+        if !identifier || name.len() > 64 {
+            return Err(format!("metadata name {:?} must be an identifier of at most 64 characters", name));
+        }
+        if value.len() > 1024 || !value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+            return Err(format!("metadata value for {:?} must be printable ASCII of at most 1024 characters", name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks tags against the limits shared by Azure and S3.
+pub fn validate_tags(tags: &BTreeMap<String, String>) -> Result<(), String> {
+    if tags.len() > MAX_TAGS {
+        return Err(format!("at most {} tags are allowed", MAX_TAGS));
+    }
+
+    let allowed = |c: char| c.is_ascii_alphanumeric() || " +-./:=_".contains(c);
+
+    for (name, value) in tags {
+        if name.is_empty() || name.len() > 128 || !name.chars().all(allowed) {
+            return Err(format!("tag name {:?} must be 1-128 letters, digits, spaces or + - . / : = _", name));
+        }
+        if value.len() > 256 || !value.chars().all(allowed) {
+            return Err(format!("tag value for {:?} must be at most 256 letters, digits, spaces or + - . / : = _", name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Clamps `range` to an object of `size` bytes.
+pub fn clamp_range(range: &Range<u64>, size: u64) -> Result<Range<u64>, ObjectStoreError> {
+    if range.start >= range.end || range.start >= size {
+        return Err(ObjectStoreError::InvalidRange {
+            start: range.start,
+            end: range.end,
+            size,
+        });
+    }
+
+    Ok(range.start..range.end.min(size))
+}
+
+/// Builds the backend selected by `STORAGE_BACKEND` (`azure`, `local`,
+/// `memory` or `s3`; defaults to `azure`). Azure also reads
+/// `STORAGE_BLOCK_SIZE` (bytes) and `STORAGE_UPLOAD_PARALLELISM`.
+pub fn object_store_from_env() -> Result<Arc<dyn ObjectStore>, Box<dyn std::error::Error>> {
+    let backend = env::var("STORAGE_BACKEND").unwrap_or("azure".to_string());
+
+    let store: Arc<dyn ObjectStore> = match backend.as_str() {
+        "azure" => {
+            let account = env::var("STORAGE_ACCOUNT")?;
+            let access_key = env::var("STORAGE_ACCESS_KEY")?;
+            let mut storage = AzureStorage::new(account, access_key);
+
+            if let Ok(block_size) = env::var("STORAGE_BLOCK_SIZE") {
+                storage = storage.with_block_size(block_size.parse()?);
+            }
+            if let Ok(parallelism) = env::var("STORAGE_UPLOAD_PARALLELISM") {
+                storage = storage.with_parallelism(parallelism.parse()?);
+            }
+
+            Arc::new(storage)
+        }
+        "local" => {
+            let root = env::var("STORAGE_LOCAL_ROOT").unwrap_or("./storage".to_string());
+            Arc::new(LocalStorage::new(root)?)
+        }
+        "memory" => Arc::new(MemoryStorage::new()),
+        "s3" => {
+            let endpoint = env::var("S3_ENDPOINT")?;
+            let region = env::var("S3_REGION").unwrap_or("us-east-1".to_string());
+            let access_key = env::var("S3_ACCESS_KEY")?;
+            let secret_key = env::var("S3_SECRET_KEY")?;
+            Arc::new(S3Storage::new(endpoint, region, access_key, secret_key)?)
+        }
+        other => {
+            return Err(format!("unknown STORAGE_BACKEND {:?}", other).into());
+        }
+    };
+
+    log::info!("Using {} object storage", store.name());
+
+    Ok(store)
+}
@@ -1,13 +1,50 @@
 use ollama_rs::{
-    generation::{completion::request::GenerationRequest, options::GenerationOptions},
+    generation::{
+        completion::{request::GenerationRequest, GenerationResponse},
+        embeddings::request::{EmbeddingsInput, GenerateEmbeddingsRequest},
+        options::GenerationOptions,
+        parameters::FormatType,
+    },
     Ollama,
 };
+use serde::Serialize;
 
 
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub duration_ms: u64,
+}
+
+impl TokenUsage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    pub fn add(&mut self, other: &TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.duration_ms += other.duration_ms;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub text: String,
+    pub usage: TokenUsage,
+}
+
 pub struct OllamaAI {
     client: Ollama,
 }
 
+impl Default for OllamaAI {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl OllamaAI {
     pub fn new() -> Self {
         Self {
@@ -22,16 +59,91 @@ impl OllamaAI {
         temperature: Option<f32>,
         max_tokens: Option<u32>,
     ) -> Result<String, Box<dyn std::error::Error>> {
-        let temperature = temperature.unwrap_or(2.7);
-        let max_tokens = max_tokens.unwrap_or(9);
-        
-        let options = GenerationOptions::default().temperature(temperature).top_k(max_tokens);
-        let request = GenerationRequest::new(model.to_string(), prompt.to_string()).options(options);
-    
+        let completion = self.generate_completion(model, prompt, temperature, max_tokens, false).await?;
+        Ok(completion.text)
+    }
+
+    pub async fn generate_json(
+        &self,
+        model: &str,
+        prompt: &str,
+        temperature: Option<f32>,
+        max_tokens: Option<u32>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let completion = self.generate_completion(model, prompt, temperature, max_tokens, true).await?;
+        Ok(completion.text)
+    }
+
+    pub async fn generate_completion(
+        &self,
+        model: &str,
+        prompt: &str,
+        temperature: Option<f32>,
+        max_tokens: Option<u32>,
+        json: bool,
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        let request = generation_request(model, prompt, temperature, max_tokens, json);
+
+        let started = std::time::Instant::now();
         match self.client.generate(request).await {
-            Ok(response) => Ok(response.response),
+            Ok(response) => {
+                let usage = token_usage(&response, started.elapsed().as_millis() as u64);
+                Ok(Completion {
+                    text: response.response,
+                    usage,
+                })
+            }
+            Err(e) => Err(Box::new(e))
+        }
+    }
+
+    pub async fn generate_embeddings(
+        &self,
+        model: &str,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let request = GenerateEmbeddingsRequest::new(
+            model.to_string(),
+            EmbeddingsInput::Multiple(inputs),
+        );
+
+        match self.client.generate_embeddings(request).await {
+            Ok(response) => Ok(response.embeddings),
             Err(e) => Err(Box::new(e))
         }
     }
     
-}
\ No newline at end of file
+}
+
+/// Builds a generation request. Settings left as `None` use the model's
+/// defaults; `max_tokens` caps the number of tokens generated.
+fn generation_request(
+    model: &str,
+    prompt: &str,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    json: bool,
+) -> GenerationRequest {
+    let mut options = GenerationOptions::default();
+    if let Some(temperature) = temperature {
+        options = options.temperature(temperature);
+    }
+    if let Some(max_tokens) = max_tokens {
+        options = options.num_predict(i32::try_from(max_tokens).unwrap_or(i32::MAX));
+    }
+
+    let request = GenerationRequest::new(model.to_string(), prompt.to_string()).options(options);
+    if json {
+        request.format(FormatType::Json)
+    } else {
+        request
+    }
+}
+
+fn token_usage(response: &GenerationResponse, elapsed_ms: u64) -> TokenUsage {
+    TokenUsage {
+        prompt_tokens: response.prompt_eval_count.unwrap_or(0) as u64,
+        completion_tokens: response.eval_count.unwrap_or(0) as u64,
+        duration_ms: response.total_duration.map_or(elapsed_ms, |nanos| nanos / 1_000_000),
+    }
+}
@@ -0,0 +1,481 @@
+use actix_web::{ web, HttpResponse };
+use actix_session::Session;
+use serde::Deserialize;
+use serde_json::{ json, Map, Value };
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use std::collections::{ BTreeSet, HashMap };
+
+use crate::models::*;
+use crate::ollama::*;
+use crate::postgres_db::*;
+use crate::redis_client::Cache;
+use crate::response::*;
+use crate::usage::*;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS prompt_templates (
+        id UUID PRIMARY KEY,
+        name TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        body TEXT NOT NULL,
+        variables JSONB NOT NULL,
+        model TEXT NOT NULL,
+        temperature REAL,
+        max_tokens INTEGER,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        UNIQUE (name, version)
+    );
+";
+
+const TEMPLATE_COLUMNS: &str =
+    "id, name, version, body, variables, model, temperature, max_tokens, created_at";
+
+pub async fn ensure_prompt_template_schema(db: &PostgresDb) -> Result<(), Box<dyn std::error::Error>> {
+    db.batch_execute(SCHEMA).await
+}
+
+/// Returns the distinct `{{variable}}` names used in a template body.
+pub fn placeholders(body: &str) -> Result<BTreeSet<String>, String> {
+    let mut names = BTreeSet::new();
+    let mut rest = body;
+
+    while let Some(open) = rest.find("{{") {
+        let after = &rest[open + 2..];
+        let close = after
+            .find("}}")
+            .ok_or_else(|| "unterminated placeholder, expected `}}`".to_string())?;
+        let name = after[..close].trim();
+
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid placeholder name `{}`", name));
+        }
+
+        names.insert(name.to_string());
+        rest = &after[close + 2..];
+    }
+
+    Ok(names)
+}
+
+/// Checks that the declared variables and the placeholders in `body` agree.
+pub fn validate_definition(body: &str, variables: &[TemplateVariable]) -> Result<(), Map<String, Value>> {
+    let mut errors = Map::new();
+
+    let used = match placeholders(body) {
+        Ok(used) => used,
+        Err(e) => {
+            errors.insert("body".to_string(), json!(e));
+            return Err(errors);
+        }
+    };
+
+    let mut declared = BTreeSet::new();
+    for variable in variables {
+        if !declared.insert(variable.name.clone()) {
+            errors.insert(variable.name.clone(), json!("variable is declared more than once"));
+            continue;
+        }
+        if let Some(default) = &variable.default {
+            if !matches_type(default, variable.kind) {
+                errors.insert(
+                    variable.name.clone(),
+                    json!(format!("default value is not of type {:?}", variable.kind).to_lowercase())
+                );
+            }
+        }
+        if !used.contains(&variable.name) {
+            errors.insert(variable.name.clone(), json!("variable is declared but never used in body"));
+        }
+    }
+
+    for name in used.difference(&declared) {
+        errors.insert(name.clone(), json!("placeholder is used in body but not declared"));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn matches_type(value: &Value, kind: VariableType) -> bool {
+    match kind {
+        VariableType::String => value.is_string(),
+        VariableType::Number => value.is_number(),
+        VariableType::Integer => value.is_i64() || value.is_u64(),
+        VariableType::Boolean => value.is_boolean(),
+    }
+}
+
+/// Substitutes `values` into the template, reporting missing, extra and
+/// mistyped variables by name.
+pub fn render(template: &PromptTemplate, values: &Map<String, Value>) -> Result<String, Map<String, Value>> {
+    let mut errors = Map::new();
+    let mut resolved: HashMap<&str, String> = HashMap::new();
+
+    for variable in &template.variables {
+        let value = match values.get(&variable.name).or(variable.default.as_ref()) {
+            Some(value) => value,
+            None => {
+                if variable.required {
+                    errors.insert(variable.name.clone(), json!("variable is required"));
+                } else {
+                    resolved.insert(&variable.name, String::new());
+                }
+                continue;
+            }
+        };
+
+        if !matches_type(value, variable.kind) {
+            errors.insert(
+                variable.name.clone(),
+                json!(format!("expected a value of type {:?}", variable.kind).to_lowercase())
+            );
+            continue;
+        }
+
+        let text = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        resolved.insert(&variable.name, text);
+    }
+
+    for name in values.keys() {
+        if !template.variables.iter().any(|v| &v.name == name) {
+            errors.insert(name.clone(), json!("variable is not defined by this template"));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut output = String::with_capacity(template.body.len());
+    let mut rest = template.body.as_str();
+    while let Some(open) = rest.find("{{") {
+        output.push_str(&rest[..open]);
+        let after = &rest[open + 2..];
+        let close = after.find("}}").unwrap_or(after.len());
+        let name = after[..close].trim();
+        output.push_str(resolved.get(name).map(String::as_str).unwrap_or(""));
+        rest = &after[(close + 2).min(after.len())..];
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+fn map_template(row: &Row) -> Result<PromptTemplate, Box<dyn std::error::Error>> {
+    let variables: Value = row.try_get("variables")?;
+
+    Ok(PromptTemplate {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        version: row.try_get("version")?,
+        body: row.try_get("body")?,
+        variables: serde_json::from_value(variables)?,
+        model: row.try_get("model")?,
+        temperature: row.try_get("temperature")?,
+        max_tokens: row.try_get("max_tokens")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+async fn find_template(
+    db: &PostgresDb,
+    name: &str,
+    version: Option<i32>
+) -> Result<Option<PromptTemplate>, Box<dyn std::error::Error>> {
+    let mut templates = match version {
+        Some(version) =>
+            db.query(
+                &format!("SELECT {} FROM prompt_templates WHERE name = $1 AND version = $2", TEMPLATE_COLUMNS),
+                &[&name, &version],
+                map_template
+            ).await?,
+        None =>
+            db.query(
+                &format!(
+                    "SELECT {} FROM prompt_templates WHERE name = $1 ORDER BY version DESC LIMIT 1",
+                    TEMPLATE_COLUMNS
+                ),
+                &[&name],
+                map_template
+            ).await?,
+    };
+
+    Ok(templates.pop())
+}
+
+/// Concurrent writers of the same template race on UNIQUE (name, version).
+const MAX_VERSION_ATTEMPTS: usize = 5;
+
+/// Inserts `req` as version 1 of `name` when `first` is set, otherwise as
+/// the version after the latest. The number is computed by the INSERT
+/// itself; when a concurrent writer takes it first, a new version retries
+/// while a first version fails with the unique violation.
+async fn insert_version(
+    db: &PostgresDb,
+    name: &str,
+    req: &TemplateRequest,
+    first: bool
+) -> Result<PromptTemplate, Box<dyn std::error::Error>> {
+    let sql = format!(
+        "INSERT INTO prompt_templates (id, name, version, body, variables, model, temperature, max_tokens)
+         SELECT $1::uuid, $2::text, {}, $3::text, $4::jsonb, $5::text, $6::real, $7::integer
+         FROM (SELECT COALESCE(MAX(version), 0) + 1 AS next FROM prompt_templates WHERE name = $2) latest
+         RETURNING {}",
+        if first { "1" } else { "latest.next" },
+        TEMPLATE_COLUMNS
+    );
+    let variables = serde_json::to_value(&req.variables)?;
+
+    let mut attempt = 1;
+    loop {
+        let inserted = db.query(
+            &sql,
+            &[&Uuid::new_v4(), &name, &req.body, &variables, &req.model, &req.temperature, &req.max_tokens],
+            map_template
+        ).await;
+
+        match inserted {
+            Ok(mut templates) => {
+                return templates.pop().ok_or_else(|| "template version was not created".into());
+            }
+            Err(err) if !first && attempt < MAX_VERSION_ATTEMPTS && is_unique_violation(&*err) => {
+                attempt += 1;
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// `validate_definition` plus the generation settings.
+fn validate_request(req: &TemplateRequest) -> Result<(), Map<String, Value>> {
+    let mut errors = match validate_definition(&req.body, &req.variables) {
+        Ok(()) => Map::new(),
+        Err(errors) => errors,
+    };
+    if req.max_tokens.is_some_and(|max_tokens| max_tokens < 0) {
+        errors.insert("max_tokens".to_string(), json!("max_tokens must not be negative"));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TemplateRequest {
+    name: Option<String>,
+    body: String,
+    #[serde(default)]
+    variables: Vec<TemplateVariable>,
+    model: String,
+    temperature: Option<f32>,
+    max_tokens: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct VersionQuery {
+    version: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct RenderRequest {
+    version: Option<i32>,
+    #[serde(default)]
+    variables: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+pub struct RunRequest {
+    version: Option<i32>,
+    #[serde(default)]
+    variables: Map<String, Value>,
+    model: Option<String>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+}
+
+pub async fn list_templates(db: web::Data<PostgresDb>) -> HttpResponse {
+    let result = db.query(
+        &format!(
+            "SELECT DISTINCT ON (name) {} FROM prompt_templates ORDER BY name, version DESC",
+            TEMPLATE_COLUMNS
+        ),
+        &[],
+        map_template
+    ).await;
+
+    match result {
+        Ok(templates) => response_ok("templates retrieved successfully", templates),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn create_template(db: web::Data<PostgresDb>, req: web::Json<TemplateRequest>) -> HttpResponse {
+    let name = match req.name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => {
+            return response_unprocessable_entity(json!({ "name": "template name is required" }));
+        }
+    };
+
+    if let Err(errors) = validate_request(&req) {
+        return response_unprocessable_entity(errors);
+    }
+
+    match insert_version(&db, &name, &req, true).await {
+        Ok(template) => response_created("template created successfully", template),
+        Err(err) if is_unique_violation(&*err) =>
+            response_unprocessable_entity(
+                json!({ "name": "a template with this name already exists, use PUT to add a version" })
+            ),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn get_template(
+    db: web::Data<PostgresDb>,
+    name: web::Path<String>,
+    query: web::Query<VersionQuery>
+) -> HttpResponse {
+    match find_template(&db, &name, query.version).await {
+        Ok(Some(template)) => response_ok("template retrieved successfully", template),
+        Ok(None) => response_not_found("template not found"),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn update_template(
+    db: web::Data<PostgresDb>,
+    name: web::Path<String>,
+    req: web::Json<TemplateRequest>
+) -> HttpResponse {
+    if let Err(errors) = validate_request(&req) {
+        return response_unprocessable_entity(errors);
+    }
+
+    match find_template(&db, &name, None).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return response_not_found("template not found");
+        }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    }
+
+    match insert_version(&db, &name, &req, false).await {
+        Ok(template) => response_ok("template version created successfully", template),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn delete_template(db: web::Data<PostgresDb>, name: web::Path<String>) -> HttpResponse {
+    match find_template(&db, &name, None).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return response_not_found("template not found");
+        }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    }
+
+    match db.execute("DELETE FROM prompt_templates WHERE name = $1", &[&name.as_str()]).await {
+        Ok(_) => response_no_content(),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn render_template(
+    db: web::Data<PostgresDb>,
+    name: web::Path<String>,
+    req: web::Json<RenderRequest>
+) -> HttpResponse {
+    let template = match find_template(&db, &name, req.version).await {
+        Ok(Some(template)) => template,
+        Ok(None) => {
+            return response_not_found("template not found");
+        }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    match render(&template, &req.variables) {
+        Ok(prompt) =>
+            response_ok(
+                "template rendered successfully",
+                json!({ "name": template.name, "version": template.version, "prompt": prompt })
+            ),
+        Err(errors) => response_unprocessable_entity(errors),
+    }
+}
+
+pub async fn run_template(
+    db: web::Data<PostgresDb>,
+    cache: web::Data<Cache>,
+    usage: web::Data<UsageTracker>,
+    session: Session,
+    name: web::Path<String>,
+    req: web::Json<RunRequest>
+) -> HttpResponse {
+    let user = match enforce_quota(&usage, &cache, &db, &session).await {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    let template = match find_template(&db, &name, req.version).await {
+        Ok(Some(template)) => template,
+        Ok(None) => {
+            return response_not_found("template not found");
+        }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    let prompt = match render(&template, &req.variables) {
+        Ok(prompt) => prompt,
+        Err(errors) => {
+            return response_unprocessable_entity(errors);
+        }
+    };
+
+    let model = req.model.clone().unwrap_or(template.model.clone());
+    let temperature = req.temperature.or(template.temperature);
+    let max_tokens = req.max_tokens.or(template.max_tokens.and_then(|t| u32::try_from(t).ok()));
+
+    let ollama_client = OllamaAI::new();
+    match ollama_client.generate_completion(&model, &prompt, temperature, max_tokens, false).await {
+        Ok(completion) => {
+            let response = response_ok(
+                "template run successfully",
+                json!({
+                    "name": template.name,
+                    "version": template.version,
+                    "model": model,
+                    "text": completion.text,
+                })
+            );
+            finish_usage(&usage, &cache, &db, &user, &model, &completion.usage, response).await
+        }
+        Err(err) => {
+            let response = response_internal_server_error(err.to_string().as_str());
+            finish_usage(&usage, &cache, &db, &user, &model, &TokenUsage::default(), response).await
+        }
+    }
+}
@@ -1,67 +1,876 @@
-use redis::{ Client, Commands, RedisResult };
-use std::{thread, time::Duration};
+use redis::{
+    aio::{ ConnectionManager, ConnectionManagerConfig },
+    AsyncCommands,
+    AsyncConnectionConfig,
+    Client,
+    ErrorKind,
+    FromRedisValue,
+    Pipeline,
+    RedisError,
+    RedisResult,
+};
+use futures::StreamExt;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::num::NonZeroUsize;
+use serde::{ de::DeserializeOwned, Serialize };
+use std::future::Future;
+use std::sync::{ atomic::{ AtomicU64, Ordering }, Arc, Mutex, OnceLock };
+use std::time::{ Duration, Instant };
 
+use crate::models::{ CacheStats, TierStats };
+
+const LOCK_TTL: Duration = Duration::from_secs(10);
+const LOCK_WAIT: Duration = Duration::from_secs(5);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Keyspace event classes the L1 tier needs: keyspace channels (`K`) for
+/// generic (`g`), string (`$`), expired (`x`) and evicted (`e`) events.
+const KEYSPACE_EVENTS: &str = "Kg$xe";
+const SUBSCRIBER_RETRY_DELAY: Duration = Duration::from_secs(5);
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(3);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(2);
+/// Invalidation counters for L1; keys share one by hash.
+const LOCAL_GENERATION_SLOTS: usize = 1024;
+
+const RELEASE_LOCK_SCRIPT: &str = r"
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        return redis.call('DEL', KEYS[1])
+    end
+    return 0
+";
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub connection_timeout: Duration,
+    pub command_timeout: Duration,
+    pub reconnect_retries: usize,
+    /// Prepended to every key as `{prefix}:` so several environments can
+    /// share one Redis.
+    pub key_prefix: Option<String>,
+    /// Enables the in-process L1 tier in front of Redis.
+    pub local: Option<LocalCacheConfig>,
+    /// Lets the L1 tier turn on the keyspace notifications it needs with
+    /// `CONFIG SET`. Off by default, since that changes the server for
+    /// every client; the setting is only checked and a warning logged.
+    pub configure_keyspace_events: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalCacheConfig {
+    pub capacity: usize,
+    /// Upper bound for an L1 entry; the key's remaining Redis TTL is used
+    /// when it is shorter.
+    pub max_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            connection_timeout: Duration::from_secs(5),
+            command_timeout: Duration::from_secs(2),
+            reconnect_retries: 6,
+            key_prefix: None,
+            local: None,
+            configure_keyspace_events: false,
+        }
+    }
+}
+
+struct LocalEntry {
+    value: String,
+    expires_at: Instant,
+}
+
+/// Bounded LRU of plain string values keyed by the un-prefixed cache key.
+/// Every removal bumps the key's generation, so a read from Redis that
+/// started before an invalidation can't put its stale value back after it.
+struct LocalCache {
+    entries: Mutex<LruCache<String, LocalEntry>>,
+    generations: Vec<AtomicU64>,
+    max_ttl: Duration,
+}
+
+impl LocalCache {
+    fn new(config: &LocalCacheConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            generations: (0..LOCAL_GENERATION_SLOTS).map(|_| AtomicU64::new(0)).collect(),
+            max_ttl: config.max_ttl,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn slot(&self, key: &str) -> &AtomicU64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.generations[hasher.finish() as usize % self.generations.len()]
+    }
+
+    /// Taken before reading `key` from Redis and handed to `put_if_current`.
+    fn generation(&self, key: &str) -> u64 {
+        self.slot(key).load(Ordering::SeqCst)
+    }
+
+    fn put(&self, key: &str, value: String, redis_ttl: Option<Duration>) {
+        self.put_if_current(key, value, redis_ttl, None);
+    }
+
+    /// Stores `value` unless `key` was invalidated since `generation` was
+    /// taken. Generations only change under the entries lock, so the check
+    /// and the write can't interleave with a removal.
+    fn put_if_current(&self, key: &str, value: String, redis_ttl: Option<Duration>, generation: Option<u64>) {
+        let ttl = match redis_ttl {
+            Some(ttl) => ttl.min(self.max_ttl),
+            None => self.max_ttl,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if generation.is_some_and(|generation| generation != self.generation(key)) {
+            return;
+        }
+        entries.put(key.to_string(), LocalEntry {
+            value,
+            expires_at: Instant::now() + ttl,
+        });
+    }
+
+    fn remove(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        self.slot(key).fetch_add(1, Ordering::SeqCst);
+        entries.pop(key);
+    }
+
+    fn bump_all(&self) {
+        for generation in &self.generations {
+            generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn remove_matching(&self, pattern: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        self.bump_all();
+        let matching: Vec<String> = entries
+            .iter()
+            .filter(|(key, _)| glob_match(pattern, key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in matching {
+            entries.pop(&key);
+        }
+    }
+
+    fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        self.bump_all();
+        entries.clear();
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+/// Redis-style glob matching supporting `*` and `?`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    while p < pattern.len() && pattern[p] == '*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+/// `current` notify-keyspace-events flags plus the ones in `KEYSPACE_EVENTS`,
+/// e.g. `"Ex"` becomes `"ExKg$e"`. `A` already covers every event class.
+pub fn keyspace_event_flags(current: &str) -> String {
+    let mut flags = current.to_string();
+    for flag in KEYSPACE_EVENTS.chars() {
+        let covered = flags.contains(flag) || (flag != 'K' && flags.contains('A'));
+        if !covered {
+            flags.push(flag);
+        }
+    }
+    flags
+}
+
+/// Checks that the keyspace notifications the L1 tier listens for are on.
+/// With `configure` set they are turned on, keeping whatever is enabled
+/// already; otherwise a missing flag is only logged.
+async fn check_keyspace_events(client: &Client, configure: bool) -> RedisResult<()> {
+    let mut connection = client.get_multiplexed_async_connection().await?;
+    let (_, current): (String, String) = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query_async(&mut connection).await?;
+
+    let flags = keyspace_event_flags(&current);
+    if flags == current {
+        return Ok(());
+    }
+    if configure {
+        redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("notify-keyspace-events")
+            .arg(&flags)
+            .query_async::<()>(&mut connection).await?;
+    } else {
+        log::warn!(
+            "notify-keyspace-events is \"{}\"; set it to \"{}\" so L1 entries changed by other instances are dropped",
+            current,
+            flags
+        );
+    }
+    Ok(())
+}
+
+fn fresh_key(key: &str) -> String {
+    format!("fresh:{}", key)
+}
+
+#[derive(Default)]
+struct Counters {
+    l1_hits: AtomicU64,
+    l1_misses: AtomicU64,
+    l2_hits: AtomicU64,
+    l2_misses: AtomicU64,
+}
+
+/// Redis access over a single multiplexed connection. `ConnectionManager`
+/// is cheap to clone and reconnects on its own after the server goes away.
+/// If Redis is down at startup the cache is built anyway: commands fail
+/// fast, so callers fall back, until a background task has connected.
+#[derive(Clone)]
 pub struct Cache {
-    client: Client,
+    connection: Arc<OnceLock<ConnectionManager>>,
+    key_prefix: String,
+    local: Option<Arc<LocalCache>>,
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyTtl {
+    Missing,
+    Persistent,
+    Expires(Duration),
 }
 
 impl Cache {
-    pub fn new(redis_url: &str) -> RedisResult<Self> {
-        const MAX_RETRIES: u32 = 2;
-        const RETRY_DELAY: Duration = Duration::from_secs(3);
-        
-        let mut retry_count = 0;
-        
-        loop {
-            match Client::open(redis_url) {
-                Ok(client) => {
-                    log::info!("Redis connection established successfully");
-                    return Ok(Cache { client });
+    pub async fn new(redis_url: &str) -> RedisResult<Self> {
+        Self::with_config(redis_url, CacheConfig::default()).await
+    }
+
+    /// Only fails for an invalid `redis_url`. When Redis can't be reached,
+    /// connecting is retried in the background.
+    pub async fn with_config(redis_url: &str, config: CacheConfig) -> RedisResult<Self> {
+        let client = Client::open(redis_url)?;
+        let cache = Self::build(&client, &config);
+
+        match Self::connect(client.clone(), &config).await {
+            Ok(connection) => {
+                log::info!("Redis connection established successfully");
+                let _ = cache.connection.set(connection);
+            }
+            Err(e) => {
+                log::error!("Redis connection failed, retrying in the background: {}", e);
+                cache.spawn_connect(client, config);
+            }
+        }
+        Ok(cache)
+    }
+
+    /// Tries the server once first: `ConnectionManager` retries its first
+    /// connection with the reconnect backoff, which holds startup for
+    /// minutes while Redis is down.
+    async fn connect(client: Client, config: &CacheConfig) -> RedisResult<ConnectionManager> {
+        let probe = AsyncConnectionConfig::new().set_connection_timeout(config.connection_timeout);
+        client.get_multiplexed_async_connection_with_config(&probe).await?;
+
+        let manager_config = ConnectionManagerConfig::new()
+            .set_connection_timeout(config.connection_timeout)
+            .set_response_timeout(config.command_timeout)
+            .set_number_of_retries(config.reconnect_retries)
+            .set_max_delay(RECONNECT_MAX_DELAY.as_millis() as u64);
+
+        ConnectionManager::new_with_config(client, manager_config).await
+    }
+
+    fn spawn_connect(&self, client: Client, config: CacheConfig) {
+        let connection = self.connection.clone();
+        tokio::spawn(async move {
+            let mut attempt = 1;
+            loop {
+                tokio::time::sleep(CONNECT_RETRY_DELAY).await;
+                attempt += 1;
+                match Self::connect(client.clone(), &config).await {
+                    Ok(manager) => {
+                        log::info!("Redis connection established after {} attempts", attempt);
+                        let _ = connection.set(manager);
+                        return;
+                    }
+                    Err(e) => log::warn!("Redis connection attempt {} failed: {}", attempt, e),
                 }
-                Err(e) => {
-                    retry_count += 1;
-                    log::warn!("Redis connection attempt {} failed: {}", retry_count, e);
-                    
-                    if retry_count >= MAX_RETRIES {
-                        log::error!("Max retries reached. Using fallback mechanism");
-                        return Ok(Cache { client: Client::open("redis://127.0.0.1:6379")? });
+            }
+        });
+    }
+
+    fn build(client: &Client, config: &CacheConfig) -> Self {
+        let cache = Cache {
+            connection: Arc::new(OnceLock::new()),
+            key_prefix: Self::prefix(config),
+            local: config.local.as_ref().map(|local| Arc::new(LocalCache::new(local))),
+            counters: Arc::new(Counters::default()),
+        };
+
+        if cache.local.is_some() {
+            cache.spawn_invalidation_listener(client.clone(), config.configure_keyspace_events);
+        }
+        cache
+    }
+
+    /// Drops L1 entries whose keys change in Redis, whoever changed them,
+    /// by listening to keyspace notifications for this prefix. L1 is cleared
+    /// whenever the subscription is (re)established, since events sent while
+    /// it was down are lost.
+    fn spawn_invalidation_listener(&self, client: Client, configure_keyspace_events: bool) {
+        let local = match &self.local {
+            Some(local) => local.clone(),
+            None => return,
+        };
+        let channel_prefix = format!("__keyspace@{}__:{}", client.get_connection_info().redis.db, self.key_prefix);
+        let pattern = format!("{}*", channel_prefix);
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = check_keyspace_events(&client, configure_keyspace_events).await {
+                    log::warn!(
+                        "Could not check keyspace notifications ({}); set notify-keyspace-events to include {} for L1 invalidation",
+                        e,
+                        KEYSPACE_EVENTS
+                    );
+                }
+
+                let subscribed = async {
+                    let mut pubsub = client.get_async_pubsub().await?;
+                    pubsub.psubscribe(&pattern).await?;
+                    Ok::<_, redis::RedisError>(pubsub)
+                }.await;
+
+                match subscribed {
+                    Ok(mut pubsub) => {
+                        log::info!("Listening for cache invalidations on {}", pattern);
+                        local.clear();
+                        let mut messages = pubsub.on_message();
+                        while let Some(message) = messages.next().await {
+                            if let Some(key) = message.get_channel_name().strip_prefix(&channel_prefix) {
+                                local.remove(key);
+                            }
+                        }
+                        log::warn!("Cache invalidation subscription closed, resubscribing");
                     }
-                    
-                    thread::sleep(RETRY_DELAY);
+                    Err(e) => log::warn!("Cache invalidation subscription failed: {}", e),
                 }
+
+                tokio::time::sleep(SUBSCRIBER_RETRY_DELAY).await;
             }
+        });
+    }
+
+    /// Removes `key` from this instance's L1 right away; other instances
+    /// drop it when the keyspace notification arrives.
+    fn invalidate_local(&self, key: &str) {
+        if let Some(local) = &self.local {
+            local.remove(key);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let l1 = self.local.as_ref().map(|local| TierStats {
+            hits: self.counters.l1_hits.load(Ordering::Relaxed),
+            misses: self.counters.l1_misses.load(Ordering::Relaxed),
+            entries: Some(local.len() as u64),
+        });
+
+        CacheStats {
+            l1,
+            l2: TierStats {
+                hits: self.counters.l2_hits.load(Ordering::Relaxed),
+                misses: self.counters.l2_misses.load(Ordering::Relaxed),
+                entries: None,
+            },
+        }
+    }
+
+    fn prefix(config: &CacheConfig) -> String {
+        match config.key_prefix.as_deref() {
+            Some(prefix) if !prefix.is_empty() => format!("{}:", prefix),
+            _ => String::new(),
         }
     }
 
-    pub fn set_value<T: ToString>(&self, key: &str, value: T, expiry_secs: u64) -> RedisResult<()> {
-        let result: RedisResult<()> = self.client.get_connection()
-            .and_then(|mut con| con.set_ex(key, value.to_string(), expiry_secs));
+    fn connection(&self) -> RedisResult<ConnectionManager> {
+        self.connection
+            .get()
+            .cloned()
+            .ok_or_else(|| RedisError::from((ErrorKind::IoError, "Redis is not connected yet")))
+    }
+
+    /// Returns `key` with this environment's prefix. Callers building their
+    /// own pipelines or scripts must pass keys through this.
+    pub fn namespaced(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
+
+    pub async fn set_value<T: ToString>(&self, key: &str, value: T, expiry_secs: u64) -> RedisResult<()> {
+        let value = value.to_string();
+        let result: RedisResult<()> = self.connection()?.set_ex(self.namespaced(key), &value, expiry_secs).await;
 
         match result {
             Ok(_) => {
                 log::info!("Value set successfully for key: {}", key);
+                if let Some(local) = &self.local {
+                    self.invalidate_local(key);
+                    local.put(key, value, Some(Duration::from_secs(expiry_secs)));
+                }
                 Ok(())
             }
             Err(e) => {
                 log::error!("Failed to set value for key {}: {}", key, e);
-                Ok(())
+                Err(e)
             }
         }
     }
     
-    pub fn get_value(&self, key: &str) -> RedisResult<Option<String>> {
-        let result = self.client.get_connection()
-            .and_then(|mut con| con.get(key));
+    pub async fn get_value(&self, key: &str) -> RedisResult<Option<String>> {
+        if let Some(local) = &self.local {
+            if let Some(value) = local.get(key) {
+                self.counters.l1_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(value));
+            }
+            self.counters.l1_misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let generation = self.local.as_ref().map(|local| local.generation(key));
+        let namespaced = self.namespaced(key);
+        let result: RedisResult<(Option<String>, i64)> = if self.local.is_some() {
+            self.pipeline(redis::pipe().get(&namespaced).pttl(&namespaced)).await
+        } else {
+            self.pipeline(redis::pipe().get(&namespaced)).await.map(|(value,)| (value, -1))
+        };
 
         match result {
-            Ok(value) => {
+            Ok((value, pttl)) => {
                 log::info!("Value retrieved successfully for key: {}", key);
+                match (&value, &self.local) {
+                    (Some(found), Some(local)) => {
+                        self.counters.l2_hits.fetch_add(1, Ordering::Relaxed);
+                        match pttl {
+                            -1 => local.put_if_current(key, found.clone(), None, generation),
+                            ms if ms > 0 => {
+                                let ttl = Some(Duration::from_millis(ms as u64));
+                                local.put_if_current(key, found.clone(), ttl, generation);
+                            }
+                            _ => {}
+                        }
+                    }
+                    (Some(_), None) => {
+                        self.counters.l2_hits.fetch_add(1, Ordering::Relaxed);
+                    }
+                    (None, _) => {
+                        self.counters.l2_misses.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
                 Ok(value)
             }
             Err(e) => {
                 log::error!("Failed to get value for key {}: {}", key, e);
+                Err(e)
+            }
+        }
+    }
+
+    /// SET NX with an expiry; returns whether the key was written.
+    pub async fn set_if_absent<T: ToString>(&self, key: &str, value: T, expiry_secs: u64) -> RedisResult<bool> {
+        let written: Option<String> = redis::cmd("SET")
+            .arg(self.namespaced(key))
+            .arg(value.to_string())
+            .arg("NX")
+            .arg("EX")
+            .arg(expiry_secs)
+            .query_async(&mut self.connection()?).await?;
+
+        if written.is_some() {
+            self.invalidate_local(key);
+        }
+        Ok(written.is_some())
+    }
+
+    /// Sends every command in `pipe` in one round-trip.
+    pub async fn pipeline<T: FromRedisValue>(&self, pipe: &Pipeline) -> RedisResult<T> {
+        pipe.query_async(&mut self.connection()?).await
+    }
+
+    /// Runs a Lua script atomically. `keys` are namespaced before they are
+    /// passed in as `KEYS`.
+    pub async fn run_script<T: FromRedisValue>(
+        &self,
+        script: &redis::Script,
+        keys: &[&str],
+        args: &[String]
+    ) -> RedisResult<T> {
+        let mut invocation = script.prepare_invoke();
+        for key in keys {
+            invocation.key(self.namespaced(key));
+        }
+        for arg in args {
+            invocation.arg(arg);
+        }
+        invocation.invoke_async(&mut self.connection()?).await
+    }
+
+    pub async fn incr_by(&self, key: &str, delta: i64, expiry_secs: u64) -> RedisResult<i64> {
+        let namespaced = self.namespaced(key);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .incr(&namespaced, delta)
+            .expire(&namespaced, expiry_secs as i64).ignore();
+
+        match self.pipeline::<(i64,)>(&pipe).await {
+            Ok((value,)) => {
+                self.invalidate_local(key);
+                Ok(value)
+            }
+            Err(e) => {
+                log::error!("Failed to increment key {}: {}", key, e);
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn decr_by(&self, key: &str, delta: i64, expiry_secs: u64) -> RedisResult<i64> {
+        self.incr_by(key, -delta, expiry_secs).await
+    }
+
+    pub async fn delete(&self, key: &str) -> RedisResult<bool> {
+        let removed: u64 = self.connection()?.del(self.namespaced(key)).await?;
+        self.invalidate_local(key);
+        Ok(removed > 0)
+    }
+
+    /// Removes every key matching a glob `pattern` (within this namespace)
+    /// using SCAN, so large keyspaces are not blocked the way KEYS would.
+    pub async fn delete_pattern(&self, pattern: &str) -> RedisResult<u64> {
+        let mut keys: Vec<String> = Vec::new();
+        {
+            let mut con = self.connection()?;
+            let mut iter = con.scan_match::<_, String>(self.namespaced(pattern)).await?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+        }
+
+        let mut removed = 0;
+        for batch in keys.chunks(500) {
+            let count: u64 = self.connection()?.unlink(batch).await?;
+            removed += count;
+        }
+
+        if let Some(local) = &self.local {
+            local.remove_matching(pattern);
+        }
+        log::info!("Deleted {} keys matching pattern: {}", removed, pattern);
+        Ok(removed)
+    }
+
+    pub async fn ttl(&self, key: &str) -> RedisResult<KeyTtl> {
+        let ttl: i64 = self.connection()?.ttl(self.namespaced(key)).await?;
+        Ok(match ttl {
+            -2 => KeyTtl::Missing,
+            -1 => KeyTtl::Persistent,
+            secs => KeyTtl::Expires(Duration::from_secs(secs.max(0) as u64)),
+        })
+    }
+
+    pub async fn expire(&self, key: &str, expiry_secs: u64) -> RedisResult<bool> {
+        let updated = self.connection()?.expire(self.namespaced(key), expiry_secs as i64).await?;
+        self.invalidate_local(key);
+        Ok(updated)
+    }
+
+    pub async fn hash_set<T: ToString>(&self, key: &str, field: &str, value: T) -> RedisResult<()> {
+        self.connection()?.hset(self.namespaced(key), field, value.to_string()).await
+    }
+
+    pub async fn hash_get(&self, key: &str, field: &str) -> RedisResult<Option<String>> {
+        self.connection()?.hget(self.namespaced(key), field).await
+    }
+
+    pub async fn hash_get_all(&self, key: &str) -> RedisResult<HashMap<String, String>> {
+        self.connection()?.hgetall(self.namespaced(key)).await
+    }
+
+    pub async fn hash_delete(&self, key: &str, field: &str) -> RedisResult<bool> {
+        let removed: u64 = self.connection()?.hdel(self.namespaced(key), field).await?;
+        Ok(removed > 0)
+    }
+
+    pub async fn hash_incr_by(&self, key: &str, field: &str, delta: i64) -> RedisResult<i64> {
+        self.connection()?.hincr(self.namespaced(key), field, delta).await
+    }
+
+    pub async fn sorted_add(&self, key: &str, member: &str, score: f64) -> RedisResult<()> {
+        let _: u64 = self.connection()?.zadd(self.namespaced(key), member, score).await?;
+        Ok(())
+    }
+
+    pub async fn sorted_incr_by(&self, key: &str, member: &str, delta: f64) -> RedisResult<f64> {
+        self.connection()?.zincr(self.namespaced(key), member, delta).await
+    }
+
+    pub async fn sorted_remove(&self, key: &str, member: &str) -> RedisResult<bool> {
+        let removed: u64 = self.connection()?.zrem(self.namespaced(key), member).await?;
+        Ok(removed > 0)
+    }
+
+    /// Highest-scoring members first, e.g. for leaderboards.
+    pub async fn sorted_top(&self, key: &str, count: usize) -> RedisResult<Vec<(String, f64)>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        self.connection()?.zrevrange_withscores(self.namespaced(key), 0, count as isize - 1).await
+    }
+
+    /// Members with `min <= score <= max`, e.g. events inside a time window.
+    pub async fn sorted_range_by_score(&self, key: &str, min: f64, max: f64) -> RedisResult<Vec<(String, f64)>> {
+        self.connection()?.zrangebyscore_withscores(self.namespaced(key), min, max).await
+    }
+
+    pub async fn sorted_remove_by_score(&self, key: &str, min: f64, max: f64) -> RedisResult<u64> {
+        self.connection()?.zrembyscore(self.namespaced(key), min, max).await
+    }
+
+    pub async fn sorted_len(&self, key: &str) -> RedisResult<u64> {
+        self.connection()?.zcard(self.namespaced(key)).await
+    }
+
+    /// Appends to the tail of a list used as a FIFO queue and returns its length.
+    pub async fn queue_push<T: ToString>(&self, key: &str, value: T) -> RedisResult<u64> {
+        self.connection()?.rpush(self.namespaced(key), value.to_string()).await
+    }
+
+    pub async fn queue_pop(&self, key: &str) -> RedisResult<Option<String>> {
+        self.connection()?.lpop(self.namespaced(key), None::<NonZeroUsize>).await
+    }
+
+    pub async fn queue_len(&self, key: &str) -> RedisResult<u64> {
+        self.connection()?.llen(self.namespaced(key)).await
+    }
+
+    pub async fn queue_range(&self, key: &str, start: isize, stop: isize) -> RedisResult<Vec<String>> {
+        self.connection()?.lrange(self.namespaced(key), start, stop).await
+    }
+
+    /// Reads and deserializes a JSON value. Entries that fail to parse are
+    /// logged and treated as a miss.
+    pub async fn get_json<T: DeserializeOwned>(&self, key: &str) -> RedisResult<Option<T>> {
+        let raw = match self.get_value(key).await? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+
+        match serde_json::from_str(&raw) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                log::warn!("Ignoring corrupt cache entry for key {}: {}", key, e);
                 Ok(None)
             }
         }
     }
+
+    pub async fn set_json<T: Serialize>(&self, key: &str, value: &T, expiry_secs: u64) -> RedisResult<()> {
+        let raw = serde_json::to_string(value).map_err(|e| {
+            redis::RedisError::from((redis::ErrorKind::TypeError, "failed to serialize cache value", e.to_string()))
+        })?;
+        self.set_value(key, raw, expiry_secs).await
+    }
+
+    /// Returns the cached value for `key`, or runs `compute` and caches its
+    /// result for `ttl_secs`. Only one caller recomputes a missing key at a time.
+    /// Values are stored as plain JSON, so `get_json` reads them too.
+    pub async fn get_or_compute<T, F, Fut>(
+        &self,
+        key: &str,
+        ttl_secs: u64,
+        compute: F
+    ) -> Result<T, Box<dyn std::error::Error>>
+        where
+            T: Serialize + DeserializeOwned + 'static,
+            F: FnOnce() -> Fut + 'static,
+            Fut: Future<Output = Result<T, Box<dyn std::error::Error>>> + 'static
+    {
+        self.get_or_compute_stale(key, ttl_secs, 0, compute).await
+    }
+
+    /// Like `get_or_compute`, but keeps serving an expired value for up to
+    /// `stale_secs` while a single background task refreshes it. The value
+    /// lives for `ttl_secs + stale_secs`; a `fresh:{key}` marker that expires
+    /// after `ttl_secs` tells fresh values from stale ones.
+    pub async fn get_or_compute_stale<T, F, Fut>(
+        &self,
+        key: &str,
+        ttl_secs: u64,
+        stale_secs: u64,
+        compute: F
+    ) -> Result<T, Box<dyn std::error::Error>>
+        where
+            T: Serialize + DeserializeOwned + 'static,
+            F: FnOnce() -> Fut + 'static,
+            Fut: Future<Output = Result<T, Box<dyn std::error::Error>>> + 'static
+    {
+        if let Ok(Some(value)) = self.get_json::<T>(key).await {
+            if stale_secs == 0 || self.is_fresh(key).await {
+                return Ok(value);
+            }
+
+            if let Ok(Some(token)) = self.try_lock(key).await {
+                let cache = self.clone();
+                let key = key.to_string();
+                actix_web::rt::spawn(async move {
+                    if let Err(e) = cache.refresh(&key, ttl_secs, stale_secs, compute).await {
+                        log::warn!("Background refresh of key {} failed: {}", key, e);
+                    }
+                    cache.unlock(&key, &token).await;
+                });
+            }
+            return Ok(value);
+        }
+
+        let token = match self.try_lock(key).await {
+            Ok(Some(token)) => Some(token),
+            Ok(None) => {
+                if let Some(value) = self.wait_for_entry::<T>(key).await {
+                    return Ok(value);
+                }
+                None
+            }
+            Err(e) => {
+                log::warn!("Could not take cache lock for key {}: {}", key, e);
+                None
+            }
+        };
+
+        let result = self.refresh(key, ttl_secs, stale_secs, compute).await;
+        if let Some(token) = token {
+            self.unlock(key, &token).await;
+        }
+        result
+    }
+
+    async fn refresh<T, F, Fut>(
+        &self,
+        key: &str,
+        ttl_secs: u64,
+        stale_secs: u64,
+        compute: F
+    ) -> Result<T, Box<dyn std::error::Error>>
+        where
+            T: Serialize,
+            F: FnOnce() -> Fut,
+            Fut: Future<Output = Result<T, Box<dyn std::error::Error>>>
+    {
+        let value = compute().await?;
+
+        if let Err(e) = self.set_json(key, &value, ttl_secs + stale_secs).await {
+            log::warn!("Computed value for key {} could not be cached: {}", key, e);
+        } else if stale_secs > 0 {
+            let marker = self.namespaced(&fresh_key(key));
+            if let Err(e) = self.pipeline::<()>(redis::pipe().set_ex(marker, 1, ttl_secs).ignore()).await {
+                log::warn!("Freshness of key {} could not be recorded: {}", key, e);
+            }
+        }
+        Ok(value)
+    }
+
+    /// Whether a value written by `get_or_compute_stale` is still fresh.
+    /// When Redis can't tell, the value is served as it is.
+    async fn is_fresh(&self, key: &str) -> bool {
+        let marker = self.namespaced(&fresh_key(key));
+        match self.pipeline::<(bool,)>(redis::pipe().exists(marker)).await {
+            Ok((fresh,)) => fresh,
+            Err(_) => true,
+        }
+    }
+
+    async fn wait_for_entry<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let deadline = tokio::time::Instant::now() + LOCK_WAIT;
+
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+            if let Ok(Some(value)) = self.get_json::<T>(key).await {
+                return Some(value);
+            }
+        }
+
+        log::warn!("Timed out waiting for another worker to fill key {}", key);
+        None
+    }
+
+    async fn try_lock(&self, key: &str) -> RedisResult<Option<String>> {
+        let token = uuid::Uuid::new_v4().to_string();
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(self.namespaced(&format!("lock:{}", key)))
+            .arg(&token)
+            .arg("NX")
+            .arg("PX")
+            .arg(LOCK_TTL.as_millis() as u64)
+            .query_async(&mut self.connection()?).await?;
+
+        Ok(acquired.map(|_| token))
+    }
+
+    async fn unlock(&self, key: &str, token: &str) {
+        let result: RedisResult<i64> = self
+            .run_script(&redis::Script::new(RELEASE_LOCK_SCRIPT), &[&format!("lock:{}", key)], &[token.to_string()]).await;
+
+        if let Err(e) = result {
+            log::warn!("Failed to release cache lock for key {}: {}", key, e);
+        }
+    }
 }
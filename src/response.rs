@@ -1,4 +1,4 @@
-use actix_web::{HttpResponse, http::StatusCode};
+use actix_web::HttpResponse;
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize)]
@@ -90,6 +90,42 @@ pub fn response_forbidden(message: &str) -> HttpResponse {
     })
 }
 
+pub fn response_conflict(message: &str) -> HttpResponse {
+    HttpResponse::Conflict().json(Response::<()> {
+        status: false,
+        message: message.to_string(),
+        data: None,
+        errors: None,
+    })
+}
+
+pub fn response_too_many_requests(message: &str) -> HttpResponse {
+    HttpResponse::TooManyRequests().json(Response::<()> {
+        status: false,
+        message: message.to_string(),
+        data: None,
+        errors: None,
+    })
+}
+
+pub fn response_not_implemented(message: &str) -> HttpResponse {
+    HttpResponse::NotImplemented().json(Response::<()> {
+        status: false,
+        message: message.to_string(),
+        data: None,
+        errors: None,
+    })
+}
+
+pub fn response_bad_gateway(message: &str) -> HttpResponse {
+    HttpResponse::BadGateway().json(Response::<()> {
+        status: false,
+        message: message.to_string(),
+        data: None,
+        errors: None,
+    })
+}
+
 pub fn response_redirect(url: &str) -> HttpResponse {
     HttpResponse::Found()
         .append_header(("Location", url))
@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::{ Bytes, BytesMut };
+use chrono::{ DateTime, Utc };
+use futures::{ stream, Stream, StreamExt, TryStreamExt };
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::{ Bucket, Region };
+
+use crate::object_store::*;
+
+/// Streamed uploads are sent as multipart uploads in parts of this size. S3
+/// needs every part but the last to be at least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+/// Parts grow by `PART_SIZE` every this many parts, so the 10,000 part
+/// limit still allows objects of several hundred GiB.
+const PARTS_PER_SIZE_STEP: usize = 1000;
+
+/// Driver for S3-compatible services (AWS, MinIO, Ceph, ...). Buckets play
+/// the role of containers and are addressed path-style, so any endpoint
+/// works without wildcard DNS.
+pub struct S3Storage {
+    endpoint: String,
+    region: Region,
+    credentials: Credentials,
+}
+
+impl S3Storage {
+    pub fn new(
+        endpoint: String,
+        region: String,
+        access_key: String,
+        secret_key: String
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let credentials = Credentials::new(Some(&access_key), Some(&secret_key), None, None, None)?;
+        let region = Region::Custom {
+            region,
+            endpoint: endpoint.clone(),
+        };
+
+        Ok(Self {
+            endpoint,
+            region,
+            credentials,
+        })
+    }
+
+    fn bucket(&self, container: &str) -> Result<Box<Bucket>, ObjectStoreError> {
+        validate_container(container)?;
+
+        Bucket::new(container, self.region.clone(), self.credentials.clone())
+            .map(|bucket| bucket.with_path_style())
+            .map_err(|e| ObjectStoreError::Backend(e.to_string()))
+    }
+
+    /// A bucket whose requests carry the object headers from `options`.
+    fn bucket_with_options(&self, container: &str, options: &PutOptions) -> Result<Box<Bucket>, ObjectStoreError> {
+        let mut bucket = self.bucket(container)?;
+
+        if let Some(cache_control) = &options.cache_control {
+            bucket.add_header("Cache-Control", cache_control);
+        }
+        if let Some(content_disposition) = &options.content_disposition {
+            bucket.add_header("Content-Disposition", content_disposition);
+        }
+        for (name, value) in &options.metadata {
+            bucket.add_header(&format!("x-amz-meta-{}", name), value);
+        }
+        if !options.tags.is_empty() {
+            let tagging: Vec<String> = options.tags
+                .iter()
+                .map(|(name, value)| format!("{}={}", url_encode(name), url_encode(value)))
+                .collect();
+            bucket.add_header("x-amz-tagging", &tagging.join("&"));
+        }
+
+        Ok(bucket)
+    }
+
+    /// Stores a stream holding at most one part in memory. Anything that
+    /// fits in a single part is sent with a plain `put`.
+    async fn upload_stream<S>(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        mut data: S
+    ) -> Result<ObjectMeta, ObjectStoreError>
+        where S: Stream<Item = Result<Bytes, ObjectStoreError>> + Unpin
+    {
+        validate_key(key)?;
+
+        let mut buffer = BytesMut::new();
+        let mut finished = fill_part(&mut data, &mut buffer, PART_SIZE).await?;
+        if finished {
+            return self.put(container, key, options, buffer.freeze()).await;
+        }
+
+        // Object headers belong on the initiating request only.
+        let upload = self
+            .bucket_with_options(container, options)?
+            .initiate_multipart_upload(key, &options.content_type).await
+            .map_err(|e| map_error(e, container, key))?;
+        let bucket = self.bucket(container)?;
+
+        let uploaded = async {
+            let mut parts = Vec::new();
+            let mut size = 0;
+            loop {
+                size += buffer.len() as u64;
+                let part = bucket
+                    .put_multipart_chunk(buffer.split().to_vec(), key, parts.len() as u32 + 1, &upload.upload_id, &options.content_type).await
+                    .map_err(|e| map_error(e, container, key))?;
+                parts.push(part);
+
+                if finished {
+                    break;
+                }
+                let part_size = PART_SIZE * (1 + parts.len() / PARTS_PER_SIZE_STEP);
+                finished = fill_part(&mut data, &mut buffer, part_size).await?;
+                if buffer.is_empty() {
+                    break;
+                }
+            }
+
+            let response = bucket
+                .complete_multipart_upload(key, &upload.upload_id, parts).await
+                .map_err(|e| map_error(e, container, key))?;
+            check_status(response.status_code(), container, key)?;
+            Ok(size)
+        }.await;
+
+        let size = match uploaded {
+            Ok(size) => size,
+            Err(e) => {
+                if let Err(abort_error) = bucket.abort_upload(key, &upload.upload_id).await {
+                    log::warn!("Failed to abort multipart upload of {}/{}: {}", container, key, abort_error);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size,
+            content_type: Some(options.content_type.clone()),
+            cache_control: options.cache_control.clone(),
+            metadata: options.metadata.clone(),
+            last_modified: Utc::now(),
+        })
+    }
+}
+
+/// Reads from `data` until `buffer` holds `part_size` bytes. Returns whether
+/// the stream ended.
+async fn fill_part<S>(data: &mut S, buffer: &mut BytesMut, part_size: usize) -> Result<bool, ObjectStoreError>
+    where S: Stream<Item = Result<Bytes, ObjectStoreError>> + Unpin
+{
+    while buffer.len() < part_size {
+        match data.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk?),
+            None => {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn map_error(e: S3Error, container: &str, key: &str) -> ObjectStoreError {
+    match e {
+        S3Error::HttpFailWithBody(404, _) => ObjectStoreError::not_found(container, key),
+        e => ObjectStoreError::Backend(e.to_string()),
+    }
+}
+
+/// Fails on non-2xx responses for builds where the client doesn't.
+fn check_status(status: u16, container: &str, key: &str) -> Result<(), ObjectStoreError> {
+    match status {
+        200..=299 => Ok(()),
+        404 => Err(ObjectStoreError::not_found(container, key)),
+        status => Err(ObjectStoreError::Backend(format!("unexpected status {}", status))),
+    }
+}
+
+/// Percent-encodes everything outside the RFC 3986 unreserved set.
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                (byte as char).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect()
+}
+
+fn parse_time(value: Option<&str>) -> DateTime<Utc> {
+    value
+        .and_then(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .or_else(|_| DateTime::parse_from_rfc2822(value))
+                .ok()
+        })
+        .map(|time| time.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now)
+}
+
+fn single_chunk(data: Bytes) -> ByteStream {
+    Box::pin(stream::once(async move { Ok(data) }))
+}
+
+#[async_trait(?Send)]
+impl ObjectStore for S3Storage {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    fn url(&self, container: &str, key: &str) -> String {
+        format!("{}/{}/{}", self.endpoint.trim_end_matches('/'), container, key)
+    }
+
+    async fn put(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        data: Bytes
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        validate_key(key)?;
+        let bucket = self.bucket_with_options(container, options)?;
+
+        let response = bucket
+            .put_object_with_content_type(key, &data, &options.content_type).await
+            .map_err(|e| map_error(e, container, key))?;
+        check_status(response.status_code(), container, key)?;
+
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size: data.len() as u64,
+            content_type: Some(options.content_type.clone()),
+            cache_control: options.cache_control.clone(),
+            metadata: options.metadata.clone(),
+            last_modified: Utc::now(),
+        })
+    }
+
+    async fn put_stream(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        data: ByteStream
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        self.upload_stream(container, key, options, data).await
+    }
+
+    async fn get(&self, container: &str, key: &str) -> Result<ByteStream, ObjectStoreError> {
+        validate_key(key)?;
+        let bucket = self.bucket(container)?;
+
+        let response = bucket.get_object_stream(key).await.map_err(|e| map_error(e, container, key))?;
+        check_status(response.status_code, container, key)?;
+
+        let (container, key) = (container.to_string(), key.to_string());
+        Ok(Box::pin(response.bytes.map_err(move |e| map_error(e, &container, &key))))
+    }
+
+    async fn get_range(
+        &self,
+        container: &str,
+        key: &str,
+        range: Range<u64>
+    ) -> Result<ByteStream, ObjectStoreError> {
+        let meta = self.head(container, key).await?;
+        let range = clamp_range(&range, meta.size)?;
+        let bucket = self.bucket(container)?;
+
+        // S3 ranges are inclusive.
+        let response = bucket
+            .get_object_range(key, range.start, Some(range.end - 1)).await
+            .map_err(|e| map_error(e, container, key))?;
+        check_status(response.status_code(), container, key)?;
+
+        Ok(single_chunk(response.bytes().clone()))
+    }
+
+    async fn head(&self, container: &str, key: &str) -> Result<ObjectMeta, ObjectStoreError> {
+        validate_key(key)?;
+        let bucket = self.bucket(container)?;
+
+        let (head, status) = bucket.head_object(key).await.map_err(|e| map_error(e, container, key))?;
+        check_status(status, container, key)?;
+
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size: head.content_length.unwrap_or(0).max(0) as u64,
+            content_type: head.content_type,
+            cache_control: head.cache_control,
+            metadata: head.metadata.map(|metadata| metadata.into_iter().collect()).unwrap_or_default(),
+            last_modified: parse_time(head.last_modified.as_deref()),
+        })
+    }
+
+    async fn delete(&self, container: &str, key: &str) -> Result<(), ObjectStoreError> {
+        validate_key(key)?;
+        let bucket = self.bucket(container)?;
+
+        match bucket.delete_object(key).await.map_err(|e| map_error(e, container, key)) {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_not_found() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list(&self, container: &str, prefix: &str) -> Result<Vec<ObjectMeta>, ObjectStoreError> {
+        let bucket = self.bucket(container)?;
+
+        let pages = bucket
+            .list(prefix.to_string(), None).await
+            .map_err(|e| map_error(e, container, prefix))?;
+
+        let mut objects: Vec<ObjectMeta> = pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| ObjectMeta {
+                last_modified: parse_time(Some(&object.last_modified)),
+                key: object.key,
+                size: object.size,
+                content_type: None,
+                cache_control: None,
+                metadata: BTreeMap::new(),
+            })
+            .collect();
+
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(objects)
+    }
+
+    /// Streams the staged blocks, opening each only once the previous one
+    /// has been read, into a multipart upload.
+    async fn commit_blocks(
+        &self,
+        container: &str,
+        key: &str,
+        options: &PutOptions,
+        block_ids: &[String]
+    ) -> Result<ObjectMeta, ObjectStoreError> {
+        let block_keys = block_ids
+            .iter()
+            .map(|block_id| staged_block_key(key, block_id))
+            .collect::<Result<Vec<String>, ObjectStoreError>>()?;
+
+        let blocks = stream
+            ::iter(block_keys)
+            .then(|block_key| async move { self.get(container, &block_key).await })
+            .try_flatten();
+        let meta = self.upload_stream(container, key, options, Box::pin(blocks)).await?;
+        self.abort_blocks(container, key, block_ids).await?;
+
+        Ok(meta)
+    }
+
+    /// Presigned URLs cover a single operation, so exactly one permission
+    /// must be requested.
+    async fn signed_url(
+        &self,
+        container: &str,
+        key: &str,
+        permissions: LinkPermissions,
+        expires_in: Duration
+    ) -> Result<String, ObjectStoreError> {
+        validate_key(key)?;
+        let bucket = self.bucket(container)?;
+        let expiry = expires_in.as_secs().clamp(1, 7 * 24 * 3600) as u32;
+
+        let url = match (permissions.read, permissions.write, permissions.delete) {
+            (true, false, false) => bucket.presign_get(key, expiry, None).await,
+            (false, true, false) => bucket.presign_put(key, expiry, None, None).await,
+            (false, false, true) => bucket.presign_delete(key, expiry).await,
+            _ => {
+                return Err(ObjectStoreError::Unsupported("combining permissions in one S3 presigned link"));
+            }
+        };
+
+        url.map_err(|e| map_error(e, container, key))
+    }
+}
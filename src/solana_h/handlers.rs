@@ -0,0 +1,714 @@
+use actix_session::Session;
+use actix_web::{ web, HttpResponse };
+use serde::Deserialize;
+use solana_sdk::commitment_config::CommitmentConfig;
+use uuid::Uuid;
+
+use crate::middleware::*;
+use crate::models::*;
+use crate::postgres_db::*;
+use crate::redis_client::Cache;
+use crate::response::*;
+use crate::websocket::WsRegistry;
+
+use super::*;
+
+#[derive(Deserialize)]
+pub struct SignInChallengeRequest {
+    pubkey: String,
+}
+
+#[derive(Deserialize)]
+pub struct WalletSignInRequest {
+    pubkey: String,
+    nonce: String,
+    /// Base58 ed25519 signature over the challenge message.
+    signature: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConnectWalletRequest {
+    pubkey: String,
+    nonce: String,
+    signature: String,
+    /// Make this the primary wallet; the first linked wallet always is.
+    #[serde(default)]
+    primary: bool,
+}
+
+#[derive(Deserialize)]
+pub struct TransferRequest {
+    to: String,
+    lamports: u64,
+    /// `processed`, `confirmed` (default) or `finalized`.
+    commitment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenAccountRequest {
+    owner: String,
+    mint: String,
+    commitment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenTransferRequest {
+    mint: String,
+    /// The recipient wallet; its associated token account is derived.
+    to: String,
+    /// In the mint's base units.
+    amount: u64,
+    decimals: u8,
+    commitment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PaymentIntentRequest {
+    lamports: u64,
+}
+
+#[derive(Deserialize)]
+pub struct TransferHistoryQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct SubmitTransactionRequest {
+    id: String,
+    /// Base64 of the transaction as signed by the wallet.
+    transaction: String,
+}
+
+fn wallet_balance(pubkey: &Pubkey, lamports: u64) -> WalletBalance {
+    WalletBalance {
+        pubkey: pubkey.to_string(),
+        lamports,
+        sol: lamports_to_sol(lamports),
+    }
+}
+
+fn connected_wallet(wallet: LinkedWallet, lamports: u64) -> ConnectedWallet {
+    ConnectedWallet {
+        pubkey: wallet.pubkey,
+        is_primary: wallet.is_primary,
+        lamports,
+        sol: lamports_to_sol(lamports),
+        created_at: wallet.created_at,
+    }
+}
+
+fn session_user(session: &Session) -> Result<User, HttpResponse> {
+    get_user_from_session(session).ok_or_else(|| response_unauthorized("unauthorized: no user in session"))
+}
+
+fn path_pubkey(value: &str) -> Result<Pubkey, HttpResponse> {
+    parse_pubkey(value).map_err(|err| response_unprocessable_entity(serde_json::json!({ "pubkey": err })))
+}
+
+/// Spending server funds takes an explicit grant, not a role: wallet
+/// sessions get theirs from `SIWS_ROLE`.
+fn spending_user(solana: &SolanaClient, session: &Session, action: &str) -> Result<User, HttpResponse> {
+    let user = session_user(session)?;
+    if !solana.can_spend(&user.id) {
+        return Err(response_forbidden(format!("only users in SOLANA_SPENDERS can {}", action).as_str()));
+    }
+    Ok(user)
+}
+
+fn request_commitment(commitment: &Option<String>) -> Result<(CommitmentConfig, String), HttpResponse> {
+    let name = commitment.as_deref().unwrap_or(DEFAULT_TRANSFER_COMMITMENT).trim();
+    parse_commitment(name)
+        .map(|commitment| (commitment, name.to_string()))
+        .map_err(|err| response_unprocessable_entity(serde_json::json!({ "commitment": err })))
+}
+
+fn transfer_error_response(err: TransferError, field: &str) -> HttpResponse {
+    match err {
+        TransferError::Rpc(_) | TransferError::Expired => response_bad_gateway(err.to_string().as_str()),
+        TransferError::Signing(_) => response_internal_server_error(err.to_string().as_str()),
+        TransferError::Failed(_) => response_unprocessable_entity(serde_json::json!({ "transaction": err.to_string() })),
+        err => response_unprocessable_entity(serde_json::json!({ field: err.to_string() })),
+    }
+}
+
+fn token_error_response(err: TokenError) -> HttpResponse {
+    match err {
+        TokenError::Transfer(err) => transfer_error_response(err, "amount"),
+        err => response_unprocessable_entity(serde_json::json!({ err.field(): err.to_string() })),
+    }
+}
+
+fn siws_error_response(err: SiwsError) -> HttpResponse {
+    match err {
+        SiwsError::Cache(e) => response_internal_server_error(e.as_str()),
+        SiwsError::UnknownNonce => response_unprocessable_entity(serde_json::json!({ "nonce": err.to_string() })),
+        SiwsError::WrongWallet | SiwsError::InvalidSignature => response_unauthorized(format!("unauthorized: {}", err).as_str()),
+    }
+}
+
+/// A wallet can belong to one user only; losing that race is a conflict.
+fn wallet_error_response(err: Box<dyn std::error::Error>) -> HttpResponse {
+    if is_unique_violation(err.as_ref()) {
+        return response_conflict("wallet is linked to another account");
+    }
+    response_internal_server_error(err.to_string().as_str())
+}
+
+/// Issues a message for the wallet to sign, for either sign-in or linking.
+pub async fn create_sign_in_challenge(
+    siws: web::Data<SignInWithSolana>,
+    cache: web::Data<Cache>,
+    req: web::Json<SignInChallengeRequest>
+) -> HttpResponse {
+    let pubkey = match path_pubkey(&req.pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match siws.issue(&cache, &pubkey).await {
+        Ok(challenge) => response_created("challenge issued successfully", challenge),
+        Err(err) => siws_error_response(err),
+    }
+}
+
+/// Opens a session for the owner of a signed challenge. A wallet nobody has
+/// linked yet becomes the primary wallet of a new wallet-only user.
+pub async fn sign_in_with_wallet(
+    siws: web::Data<SignInWithSolana>,
+    wallets: web::Data<WalletStore>,
+    db: web::Data<PostgresDb>,
+    cache: web::Data<Cache>,
+    session: Session,
+    req: web::Json<WalletSignInRequest>
+) -> HttpResponse {
+    let pubkey = match path_pubkey(&req.pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    if let Err(err) = siws.verify(&cache, &pubkey, &req.nonce, &req.signature).await {
+        return siws_error_response(err);
+    }
+
+    let user_id = match wallets.owner(&db, &pubkey).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            let user_id = Uuid::new_v5(&Uuid::NAMESPACE_OID, pubkey.as_ref());
+            if let Err(err) = wallets.link(&db, &cache, &user_id, &pubkey, true).await {
+                return wallet_error_response(err);
+            }
+            user_id
+        }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    let user = User::with_id(user_id, pubkey.to_string(), siws.role().to_string());
+
+    // A fresh session id on login, so a planted cookie can't be promoted.
+    session.renew();
+    let inserted = session
+        .insert("user_id", user.id)
+        .and_then(|_| session.insert("username", &user.username))
+        .and_then(|_| session.insert("user_role", &user.role))
+        .and_then(|_| session.insert("auth_method", WALLET_AUTH_METHOD));
+    if let Err(err) = inserted {
+        return response_internal_server_error(err.to_string().as_str());
+    }
+
+    response_ok("signed in successfully", user)
+}
+
+pub async fn sign_out(session: Session) -> HttpResponse {
+    session.purge();
+    response_no_content()
+}
+
+/// Links a wallet to the current user once it has signed a challenge, after
+/// checking it on the cluster.
+pub async fn connect_wallet(
+    solana: web::Data<SolanaClient>,
+    siws: web::Data<SignInWithSolana>,
+    wallets: web::Data<WalletStore>,
+    db: web::Data<PostgresDb>,
+    cache: web::Data<Cache>,
+    session: Session,
+    req: web::Json<ConnectWalletRequest>
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+    let pubkey = match path_pubkey(&req.pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    if let Err(err) = siws.verify(&cache, &pubkey, &req.nonce, &req.signature).await {
+        return siws_error_response(err);
+    }
+
+    match wallets.owner(&db, &pubkey).await {
+        Ok(Some(owner)) if owner != user.id => {
+            return response_conflict("wallet is linked to another account");
+        }
+        Ok(_) => {}
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    }
+
+    let balance = match solana.handle_wallet_connection(pubkey).await {
+        Ok(balance) => balance,
+        Err(err) => {
+            return response_bad_gateway(format!("solana rpc error: {}", err).as_str());
+        }
+    };
+
+    match wallets.link(&db, &cache, &user.id, &pubkey, req.primary).await {
+        Ok(wallet) => response_ok("wallet connected successfully", connected_wallet(wallet, balance)),
+        Err(err) => wallet_error_response(err),
+    }
+}
+
+/// Returns the user's primary wallet with a freshly fetched balance.
+pub async fn get_wallet(
+    solana: web::Data<SolanaClient>,
+    wallets: web::Data<WalletStore>,
+    db: web::Data<PostgresDb>,
+    cache: web::Data<Cache>,
+    session: Session
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    let wallet = match wallets.primary(&db, &cache, &user.id).await {
+        Ok(Some(wallet)) => wallet,
+        Ok(None) => {
+            return response_not_found("no wallet connected");
+        }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+    let pubkey = match parse_pubkey(&wallet.pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(err) => {
+            return response_internal_server_error(err.as_str());
+        }
+    };
+
+    match solana.get_balance(&pubkey).await {
+        Ok(balance) => response_ok("wallet retrieved successfully", connected_wallet(wallet, balance)),
+        Err(err) => response_bad_gateway(format!("solana rpc error: {}", err).as_str()),
+    }
+}
+
+pub async fn list_wallets(
+    wallets: web::Data<WalletStore>,
+    db: web::Data<PostgresDb>,
+    cache: web::Data<Cache>,
+    session: Session
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match wallets.list(&db, &cache, &user.id).await {
+        Ok(wallets) => response_ok("wallets retrieved successfully", wallets),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn set_primary_wallet(
+    wallets: web::Data<WalletStore>,
+    db: web::Data<PostgresDb>,
+    cache: web::Data<Cache>,
+    session: Session,
+    pubkey: web::Path<String>
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+    let pubkey = match path_pubkey(&pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match wallets.set_primary(&db, &cache, &user.id, &pubkey).await {
+        Ok(Some(wallet)) => response_ok("primary wallet updated successfully", wallet),
+        Ok(None) => response_not_found("wallet not linked"),
+        Err(err) => wallet_error_response(err),
+    }
+}
+
+pub async fn unlink_wallet(
+    wallets: web::Data<WalletStore>,
+    db: web::Data<PostgresDb>,
+    cache: web::Data<Cache>,
+    session: Session,
+    pubkey: web::Path<String>
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+    let pubkey = match path_pubkey(&pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match wallets.unlink(&db, &cache, &user.id, &pubkey).await {
+        Ok(true) => response_no_content(),
+        Ok(false) => response_not_found("wallet not linked"),
+        Err(err) => wallet_error_response(err),
+    }
+}
+
+pub async fn get_sol_balance(solana: web::Data<SolanaClient>, pubkey: web::Path<String>) -> HttpResponse {
+    let pubkey = match path_pubkey(&pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match solana.get_balance(&pubkey).await {
+        Ok(lamports) => response_ok("balance retrieved successfully", wallet_balance(&pubkey, lamports)),
+        Err(err) => response_bad_gateway(format!("solana rpc error: {}", err).as_str()),
+    }
+}
+
+fn prepared_tx_error_response(err: PreparedTxError) -> HttpResponse {
+    match err {
+        PreparedTxError::Invalid { field, message } => response_unprocessable_entity(serde_json::json!({ field: message })),
+        PreparedTxError::UnknownTransaction => response_not_found(err.to_string().as_str()),
+        PreparedTxError::Mismatch(_) => response_unprocessable_entity(serde_json::json!({ "transaction": err.to_string() })),
+        PreparedTxError::Transfer(err) => response_bad_gateway(err.to_string().as_str()),
+        PreparedTxError::Cache(e) => response_internal_server_error(e.as_str()),
+    }
+}
+
+/// Returns an unsigned transfer or memo transaction paid for by the user's
+/// primary wallet, for the wallet to sign. The server never sees its key.
+pub async fn prepare_wallet_transaction(
+    solana: web::Data<SolanaClient>,
+    wallets: web::Data<WalletStore>,
+    db: web::Data<PostgresDb>,
+    cache: web::Data<Cache>,
+    session: Session,
+    req: web::Json<UnsignedTransactionRequest>
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    let wallet = match wallets.primary(&db, &cache, &user.id).await {
+        Ok(Some(wallet)) => wallet,
+        Ok(None) => {
+            return response_not_found("no wallet connected");
+        }
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+    let wallet = match parse_pubkey(&wallet.pubkey) {
+        Ok(wallet) => wallet,
+        Err(err) => {
+            return response_internal_server_error(err.as_str());
+        }
+    };
+
+    match prepare_unsigned_transaction(&solana, &cache, &user.id, &wallet, &req).await {
+        Ok(prepared) => response_created("transaction prepared successfully", prepared),
+        Err(err) => prepared_tx_error_response(err),
+    }
+}
+
+/// Broadcasts a wallet-signed copy of a prepared transaction. Progress
+/// until confirmation is pushed over the websocket as `solana_tx_status`.
+pub async fn submit_wallet_transaction(
+    solana: web::Data<SolanaClient>,
+    cache: web::Data<Cache>,
+    sockets: web::Data<WsRegistry>,
+    session: Session,
+    req: web::Json<SubmitTransactionRequest>
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    let (transaction, last_valid_block_height) = match redeem_signed_transaction(&cache, &user.id, &req.id, &req.transaction).await {
+        Ok(redeemed) => redeemed,
+        Err(err) => {
+            return prepared_tx_error_response(err);
+        }
+    };
+
+    let signature = match solana.broadcast(&transaction).await {
+        Ok(signature) => signature,
+        Err(err) => {
+            // Not sent, so the wallet may retry with the same signature.
+            if let Err(e) = restore_signed_transaction(&cache, &user.id, &req.id, &transaction, last_valid_block_height).await {
+                log::warn!("Failed to restore prepared transaction {}: {}", req.id, e);
+            }
+            return response_bad_gateway(err.to_string().as_str());
+        }
+    };
+
+    track_confirmation(solana, sockets, user.id, req.id.clone(), signature, last_valid_block_height);
+
+    response_ok("transaction submitted", TransactionStatusUpdate {
+        id: req.id.clone(),
+        signature: signature.to_string(),
+        status: "submitted".to_string(),
+        error: None,
+    })
+}
+
+/// Sends SOL from the server's keypair. Only for `SOLANA_SPENDERS`, since
+/// it spends server funds.
+pub async fn send_sol_transfer(
+    solana: web::Data<SolanaClient>,
+    session: Session,
+    req: web::Json<TransferRequest>
+) -> HttpResponse {
+    if let Err(response) = spending_user(&solana, &session, "send transfers") {
+        return response;
+    }
+
+    let to = match parse_pubkey(&req.to) {
+        Ok(to) => to,
+        Err(err) => {
+            return response_unprocessable_entity(serde_json::json!({ "to": err }));
+        }
+    };
+    let (commitment, commitment_name) = match request_commitment(&req.commitment) {
+        Ok(commitment) => commitment,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match solana.transfer_sol(&to, req.lamports, commitment).await {
+        Ok((signature, fee)) =>
+            response_ok("transfer confirmed", TransferReceipt {
+                signature: signature.to_string(),
+                from: solana.payer().to_string(),
+                to: to.to_string(),
+                lamports: req.lamports,
+                sol: lamports_to_sol(req.lamports),
+                fee,
+                commitment: commitment_name,
+            }),
+        Err(err) => transfer_error_response(err, "lamports"),
+    }
+}
+
+/// Lists the SPL Token accounts a wallet holds.
+pub async fn get_token_balances(solana: web::Data<SolanaClient>, pubkey: web::Path<String>) -> HttpResponse {
+    let pubkey = match path_pubkey(&pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match solana.token_accounts(&pubkey).await {
+        Ok(accounts) => response_ok("token accounts retrieved successfully", accounts),
+        Err(err) => response_bad_gateway(format!("solana rpc error: {}", err).as_str()),
+    }
+}
+
+/// Creates the associated token account of `owner` for `mint` unless it
+/// exists. Only for `SOLANA_SPENDERS`, since the server pays the rent.
+pub async fn create_token_account(
+    solana: web::Data<SolanaClient>,
+    session: Session,
+    req: web::Json<TokenAccountRequest>
+) -> HttpResponse {
+    if let Err(response) = spending_user(&solana, &session, "create token accounts") {
+        return response;
+    }
+
+    let owner = match parse_pubkey(&req.owner) {
+        Ok(owner) => owner,
+        Err(err) => {
+            return response_unprocessable_entity(serde_json::json!({ "owner": err }));
+        }
+    };
+    let mint = match parse_pubkey(&req.mint) {
+        Ok(mint) => mint,
+        Err(err) => {
+            return response_unprocessable_entity(serde_json::json!({ "mint": err }));
+        }
+    };
+    let (commitment, _) = match request_commitment(&req.commitment) {
+        Ok(commitment) => commitment,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match solana.ensure_associated_token_account(&owner, &mint, commitment).await {
+        Ok((address, signature)) => {
+            let account = AssociatedTokenAccount {
+                address: address.to_string(),
+                owner: owner.to_string(),
+                mint: mint.to_string(),
+                signature: signature.map(|signature| signature.to_string()),
+            };
+            match signature {
+                Some(_) => response_created("token account created successfully", account),
+                None => response_ok("token account already exists", account),
+            }
+        }
+        Err(err) => token_error_response(err),
+    }
+}
+
+/// Sends SPL tokens from the server keypair's token account. Only for
+/// `SOLANA_SPENDERS`, like SOL transfers.
+pub async fn send_token_transfer(
+    solana: web::Data<SolanaClient>,
+    session: Session,
+    req: web::Json<TokenTransferRequest>
+) -> HttpResponse {
+    if let Err(response) = spending_user(&solana, &session, "send transfers") {
+        return response;
+    }
+
+    let mint = match parse_pubkey(&req.mint) {
+        Ok(mint) => mint,
+        Err(err) => {
+            return response_unprocessable_entity(serde_json::json!({ "mint": err }));
+        }
+    };
+    let to = match parse_pubkey(&req.to) {
+        Ok(to) => to,
+        Err(err) => {
+            return response_unprocessable_entity(serde_json::json!({ "to": err }));
+        }
+    };
+    let (commitment, commitment_name) = match request_commitment(&req.commitment) {
+        Ok(commitment) => commitment,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match solana.transfer_tokens(&mint, &to, req.amount, req.decimals, commitment).await {
+        Ok((signature, fee, created_account)) =>
+            response_ok("token transfer confirmed", TokenTransferReceipt {
+                signature: signature.to_string(),
+                from: solana.payer().to_string(),
+                to: to.to_string(),
+                mint: mint.to_string(),
+                destination: associated_token_address(&to, &mint).to_string(),
+                amount: req.amount,
+                decimals: req.decimals,
+                ui_amount: token_ui_amount(req.amount, req.decimals),
+                fee,
+                created_account,
+                commitment: commitment_name,
+            }),
+        Err(err) => token_error_response(err),
+    }
+}
+
+/// Opens a payment to the server's receiver. The payer includes the
+/// returned `reference` in the transfer's memo.
+pub async fn create_payment_intent(
+    solana: web::Data<SolanaClient>,
+    watcher: web::Data<PaymentWatcher>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    req: web::Json<PaymentIntentRequest>
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+    if req.lamports == 0 || req.lamports > i64::MAX as u64 {
+        return response_unprocessable_entity(serde_json::json!({ "lamports": "lamports must be a positive amount" }));
+    }
+
+    match watcher.create_intent(&db, &user.id, &solana.receiver(), req.lamports).await {
+        Ok(intent) => response_created("payment intent created successfully", intent),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+pub async fn get_payment_intent(
+    watcher: web::Data<PaymentWatcher>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    id: web::Path<Uuid>
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match watcher.find_intent(&db, &user.id, &id).await {
+        Ok(Some(intent)) => response_ok("payment intent retrieved successfully", intent),
+        Ok(None) => response_not_found("payment intent not found"),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
+
+/// Incoming transfers to the user's wallets and their payments, newest first.
+pub async fn list_transfers(
+    watcher: web::Data<PaymentWatcher>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    query: web::Query<TransferHistoryQuery>
+) -> HttpResponse {
+    let user = match session_user(&session) {
+        Ok(user) => user,
+        Err(response) => {
+            return response;
+        }
+    };
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+
+    match watcher.transfers_for_user(&db, &user.id, limit).await {
+        Ok(transfers) => response_ok("transfers retrieved successfully", transfers),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
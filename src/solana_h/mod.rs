@@ -1,70 +1,157 @@
-use solana_sdk::{signature::Keypair, system_instruction, message::Message};
+use solana_sdk::{signature::Keypair, message::Message};
+#[allow(deprecated)]
+use solana_sdk::system_instruction;
 use solana_program::instruction::Instruction;
-use solana_client::rpc_client::RpcClient;
-use solana_sdk::signature::Signer;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::signature::{read_keypair_file, Signer};
 use solana_sdk::transaction::Transaction;
 use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
+use std::env;
+use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use uuid::Uuid;
 
-const LAMPORTS_PER_SOL: u64 = 1_000_000_000;
-const TX_FEE: u64 = 5000;
-const RENT_EXEMPT_MIN: u64 = 53;
+mod handlers;
+pub use handlers::*;
 
-#[derive(Debug, Clone)]
-pub struct WalletState {
-    pub connected_wallet: Option<Pubkey>,
-    pub balance: u64,
-}
+mod payments;
+pub use payments::*;
+
+mod prepared;
+pub use prepared::*;
+
+mod rpc;
+pub use rpc::*;
+
+mod siws;
+pub use siws::*;
+
+mod tokens;
+pub use tokens::*;
+
+mod transfer;
+pub use transfer::*;
+
+mod wallets;
+pub use wallets::*;
+
+pub const DEFAULT_RPC_URL: &str = "https://api.devnet.solana.com";
+
+const LAMPORTS_PER_SOL: u64 = 1_000_000_000;
 
 pub struct SolanaClient {
-    rpc_client: RpcClient,
+    rpc: RpcPool,
     receiver_keypair: Keypair,
-    sender_publicKey: Pubkey,
-    wallet_state: Arc<RwLock<WalletState>>,
+    /// Users allowed to spend the keypair's funds, by id.
+    spenders: HashSet<Uuid>,
 }
 
 impl SolanaClient {
-    pub fn new(rpc_url: &str, receiver_keypair: Keypair, sender_publicKey: Pubkey) -> Self {
-        let rpc_client = RpcClient::new(rpc_url.to_string());
-        let wallet_state = Arc::new(
-            RwLock::new(WalletState {
-                connected_wallet: None,
-                balance: 0,
-            })
-        );
+    pub fn new(rpc_url: &str, receiver_keypair: Keypair) -> Self {
+        let config = SolanaConfig {
+            endpoints: vec![rpc_url.to_string()],
+            ..SolanaConfig::default()
+        };
+        Self::with_config(&config, receiver_keypair)
+    }
 
+    pub fn with_config(config: &SolanaConfig, receiver_keypair: Keypair) -> Self {
         SolanaClient {
-            rpc_client,
+            rpc: RpcPool::new(config),
             receiver_keypair,
-            sender_publicKey,
-            wallet_state,
+            spenders: HashSet::new(),
         }
     }
 
-    pub async fn handle_wallet_connection(&self, wallet_pubkey: Pubkey) -> Result<(), Box<dyn std::error::Error>> {
-        log::info!("Processing wallet connection: {}", wallet_pubkey);
-        let balance = self.rpc_client.get_balance(&wallet_pubkey)?;
-        
-        self.update_wallet_state(wallet_pubkey, balance).await?;
-        
-        log::info!("Wallet connected successfully with balance: {} SOL", balance as f64 / LAMPORTS_PER_SOL as f64);
-        Ok(())
+    /// Wraps an existing RPC client, e.g. `RpcClient::new_mock` in tests.
+    pub fn with_rpc_client(rpc_client: RpcClient, receiver_keypair: Keypair) -> Self {
+        Self::with_rpc_clients(vec![rpc_client], &SolanaConfig::default(), receiver_keypair)
+    }
+
+    /// Fails over between existing clients in order; `config.endpoints` and
+    /// `config.commitment` are ignored.
+    pub fn with_rpc_clients(
+        rpc_clients: Vec<RpcClient>,
+        config: &SolanaConfig,
+        receiver_keypair: Keypair
+    ) -> Self {
+        SolanaClient {
+            rpc: RpcPool::with_clients(rpc_clients.into_iter().map(Arc::new).collect(), config),
+            receiver_keypair,
+            spenders: HashSet::new(),
+        }
     }
 
-    async fn update_wallet_state(&self, pubkey: Pubkey, balance: u64) -> Result<(), Box<dyn std::error::Error>> {
-        let mut state = self.wallet_state.write().await;
-        state.connected_wallet = Some(pubkey);
-        state.balance = balance;
-        Ok(())
+    /// Lets these users send transfers and create accounts paid for by the
+    /// server keypair. Nobody can by default.
+    pub fn with_spenders(mut self, spenders: impl IntoIterator<Item = Uuid>) -> Self {
+        self.spenders = spenders.into_iter().collect();
+        self
     }
 
-    pub async fn get_connected_wallet(&self) -> Option<Pubkey> {
-        self.wallet_state.read().await.connected_wallet
+    pub fn can_spend(&self, user_id: &Uuid) -> bool {
+        self.spenders.contains(user_id)
     }
 
-    pub fn check_balance(&self) -> u64 {
-        self.rpc_client.get_balance(&self.receiver_keypair.pubkey()).unwrap_or(0)
+    /// The endpoint requests currently go to.
+    pub fn rpc_url(&self) -> String {
+        self.rpc.active().url()
     }
 
+    pub async fn get_balance(&self, pubkey: &Pubkey) -> Result<u64, SolanaError> {
+        self.rpc.call(|rpc| async move { rpc.get_balance(pubkey).await }).await
+    }
+
+    /// Checks a wallet against the cluster before it is linked and returns
+    /// its balance. Callers prove ownership first with `SignInWithSolana`;
+    /// which user it belongs to is kept by `WalletStore`.
+    pub async fn handle_wallet_connection(&self, wallet_pubkey: Pubkey) -> Result<u64, SolanaError> {
+        log::info!("Processing wallet connection: {}", wallet_pubkey);
+        let balance = self.get_balance(&wallet_pubkey).await?;
+
+        log::info!("Wallet connected successfully with balance: {} SOL", lamports_to_sol(balance));
+        Ok(balance)
+    }
+
+    /// The receiver's balance. An RPC failure is an error, never `0`.
+    pub async fn check_balance(&self) -> Result<u64, SolanaError> {
+        self.get_balance(&self.receiver_keypair.pubkey()).await
+    }
+}
+
+pub fn lamports_to_sol(lamports: u64) -> f64 {
+    lamports as f64 / LAMPORTS_PER_SOL as f64
+}
+
+/// Parses a base58 public key, with a message fit for a validation error.
+pub fn parse_pubkey(value: &str) -> Result<Pubkey, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err("pubkey is required".to_string());
+    }
+
+    Pubkey::from_str(value).map_err(|_| "pubkey must be a base58 encoded 32 byte public key".to_string())
+}
+
+/// Builds the client from `SolanaConfig::from_env`, the
+/// `SOLANA_RECEIVER_KEYPAIR` keyfile and `SOLANA_SPENDERS`, a comma
+/// separated list of the user ids allowed to spend from it. The keyfile is
+/// required: its keypair receives payments and pays for outgoing
+/// transfers, so a throwaway one would lose funds.
+pub fn solana_client_from_env() -> Result<SolanaClient, Box<dyn std::error::Error>> {
+    let config = SolanaConfig::from_env()?;
+
+    let path = env::var("SOLANA_RECEIVER_KEYPAIR").map_err(|_| "SOLANA_RECEIVER_KEYPAIR must name a keypair file")?;
+    let receiver_keypair = read_keypair_file(&path).map_err(|e| format!("failed to read keypair {}: {}", path, e))?;
+
+    let spenders = env::var("SOLANA_SPENDERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| Uuid::parse_str(id).map_err(|_| format!("SOLANA_SPENDERS entry {:?} is not a user id", id)))
+        .collect::<Result<Vec<Uuid>, String>>()?;
+
+    Ok(SolanaClient::with_config(&config, receiver_keypair).with_spenders(spenders))
 }
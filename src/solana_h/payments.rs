@@ -0,0 +1,585 @@
+use std::env;
+use std::time::Duration;
+
+use actix_web::web;
+use solana_client::rpc_client::GetConfirmedSignaturesForAddress2Config;
+use solana_client::rpc_config::RpcTransactionConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::signature::Signature;
+use solana_transaction_status::UiTransactionEncoding;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::models::*;
+use crate::postgres_db::*;
+use crate::websocket::WsRegistry;
+
+use super::*;
+
+/// Websocket action carrying a `PaymentIntent` whose status changed.
+pub const PAYMENT_STATUS_ACTION: &str = "payment_status";
+
+/// The RPC's page size limit for `getSignaturesForAddress`.
+const SIGNATURE_PAGE_SIZE: usize = 1000;
+/// Most signatures `getSignatureStatuses` takes at once.
+const STATUS_BATCH_SIZE: i64 = 256;
+/// A transaction the cluster hasn't seen this many slots after it was
+/// recorded was on a dropped fork. Blockhashes expire after 150 blocks.
+const DROPPED_AFTER_SLOTS: u64 = 300;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS solana_watch_cursors (
+        address TEXT PRIMARY KEY,
+        last_signature TEXT,
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE TABLE IF NOT EXISTS solana_transfers (
+        signature TEXT NOT NULL,
+        address TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        lamports BIGINT NOT NULL,
+        slot BIGINT NOT NULL,
+        memo TEXT,
+        status TEXT NOT NULL,
+        block_time TIMESTAMPTZ,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        PRIMARY KEY (signature, address)
+    );
+    CREATE INDEX IF NOT EXISTS solana_transfers_address_idx ON solana_transfers (address, slot DESC);
+    CREATE INDEX IF NOT EXISTS solana_transfers_status_idx ON solana_transfers (status) WHERE status = 'confirmed';
+    CREATE TABLE IF NOT EXISTS payment_intents (
+        id UUID PRIMARY KEY,
+        user_id UUID NOT NULL,
+        reference TEXT NOT NULL UNIQUE,
+        recipient TEXT NOT NULL,
+        lamports BIGINT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        signature TEXT,
+        expires_at TIMESTAMPTZ NOT NULL,
+        paid_at TIMESTAMPTZ,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX IF NOT EXISTS payment_intents_pending_idx ON payment_intents (recipient) WHERE status = 'pending';
+";
+
+const TRANSFER_COLUMNS: &str = "signature, address, sender, lamports, slot, memo, status, block_time, created_at";
+const INTENT_COLUMNS: &str = "id, reference, recipient, lamports, status, signature, expires_at, paid_at, created_at";
+
+pub struct PaymentWatcherConfig {
+    pub poll_interval: Duration,
+    /// How long a payment intent waits for its transfer.
+    pub intent_ttl: Duration,
+}
+
+impl Default for PaymentWatcherConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(15),
+            intent_ttl: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+impl PaymentWatcherConfig {
+    /// Reads `SOLANA_WATCH_INTERVAL_SECS` and `SOLANA_PAYMENT_TTL_SECS`.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let secs = |name: &str| env::var(name).ok().and_then(|v| v.parse().ok()).map(Duration::from_secs);
+
+        Self {
+            poll_interval: secs("SOLANA_WATCH_INTERVAL_SECS").unwrap_or(default.poll_interval),
+            intent_ttl: secs("SOLANA_PAYMENT_TTL_SECS").unwrap_or(default.intent_ttl),
+        }
+    }
+}
+
+/// A transfer into a watched account, read from the cluster.
+#[derive(Debug, Clone)]
+pub struct IncomingTransfer {
+    pub signature: String,
+    pub address: Pubkey,
+    pub sender: Pubkey,
+    pub lamports: u64,
+    pub slot: u64,
+    pub memo: Option<String>,
+    pub block_time: Option<i64>,
+}
+
+/// New activity on a watched account since the cursor.
+struct AddressActivity {
+    /// Newest signature seen, successful or not.
+    newest_signature: Option<String>,
+    /// Incoming transfers, oldest first.
+    transfers: Vec<IncomingTransfer>,
+}
+
+/// Where a recorded signature stands on the cluster now.
+enum ObservedStatus {
+    Finalized,
+    Confirmed,
+    Failed,
+    Unknown,
+}
+
+impl SolanaClient {
+    /// The account payment intents are paid to.
+    pub fn receiver(&self) -> Pubkey {
+        self.receiver_keypair.pubkey()
+    }
+
+    /// Reads confirmed activity on `address` newer than `until`. Without a
+    /// cursor only the latest page is read rather than the whole history.
+    async fn address_activity(&self, address: &Pubkey, until: Option<Signature>) -> Result<AddressActivity, SolanaError> {
+        let mut signatures = Vec::new();
+        let mut before = None;
+        loop {
+            let page = self.rpc.call(|rpc| async move {
+                rpc.get_signatures_for_address_with_config(address, GetConfirmedSignaturesForAddress2Config {
+                    before,
+                    until,
+                    limit: Some(SIGNATURE_PAGE_SIZE),
+                    commitment: Some(CommitmentConfig::confirmed()),
+                }).await
+            }).await?;
+            let full = page.len() == SIGNATURE_PAGE_SIZE;
+            before = page.last().and_then(|status| status.signature.parse().ok());
+            signatures.extend(page);
+
+            if !full || until.is_none() || before.is_none() {
+                break;
+            }
+        }
+
+        let newest_signature = signatures.first().map(|status| status.signature.clone());
+        let mut transfers = Vec::new();
+
+        // Oldest first, so the cursor never skips past unrecorded work.
+        for status in signatures.into_iter().rev() {
+            if status.err.is_some() {
+                continue;
+            }
+            let Ok(signature) = status.signature.parse::<Signature>() else {
+                continue;
+            };
+            let signature = &signature;
+
+            let transaction = self.rpc.call(|rpc| async move {
+                rpc.get_transaction_with_config(signature, RpcTransactionConfig {
+                    encoding: Some(UiTransactionEncoding::Base64),
+                    commitment: Some(CommitmentConfig::confirmed()),
+                    max_supported_transaction_version: Some(0),
+                }).await
+            }).await?;
+            let (Some(decoded), Some(meta)) = (transaction.transaction.transaction.decode(), transaction.transaction.meta) else {
+                continue;
+            };
+
+            // Balances list static keys first. An account that only
+            // appears through a lookup table is not matched.
+            let keys = decoded.message.static_account_keys();
+            let delta = |index: usize| -> i128 {
+                meta.post_balances.get(index).copied().unwrap_or(0) as i128 -
+                    meta.pre_balances.get(index).copied().unwrap_or(0) as i128
+            };
+            let Some(index) = keys.iter().position(|key| key == address) else {
+                continue;
+            };
+            let received = delta(index);
+            if received <= 0 {
+                continue;
+            }
+
+            // Whoever lost the most paid for it.
+            let sender = (0..keys.len())
+                .filter(|i| *i != index)
+                .min_by_key(|i| delta(*i))
+                .map(|i| keys[i])
+                .unwrap_or(keys[0]);
+
+            transfers.push(IncomingTransfer {
+                signature: status.signature,
+                address: *address,
+                sender,
+                lamports: received as u64,
+                slot: status.slot,
+                memo: status.memo,
+                block_time: status.block_time,
+            });
+        }
+
+        Ok(AddressActivity { newest_signature, transfers })
+    }
+
+    /// Current status of each signature, searching past the status cache.
+    async fn observed_statuses(&self, signatures: &[Signature]) -> Result<(u64, Vec<ObservedStatus>), SolanaError> {
+        let slot = self.rpc.call(|rpc| async move { rpc.get_slot_with_commitment(CommitmentConfig::confirmed()).await }).await?;
+        let statuses = self.rpc.call(|rpc| async move { rpc.get_signature_statuses_with_history(signatures).await }).await?.value;
+
+        let statuses = statuses
+            .into_iter()
+            .map(|status| match status {
+                None => ObservedStatus::Unknown,
+                Some(status) if status.err.is_some() => ObservedStatus::Failed,
+                Some(status) if status.satisfies_commitment(CommitmentConfig::finalized()) => ObservedStatus::Finalized,
+                Some(_) => ObservedStatus::Confirmed,
+            })
+            .collect();
+
+        Ok((slot, statuses))
+    }
+}
+
+/// Polls the receiver and every linked wallet for incoming transfers,
+/// records them in `solana_transfers` and settles payment intents whose
+/// reference appears in a transfer's memo. Transfers are recorded at
+/// `confirmed` and followed until finalized or dropped.
+pub struct PaymentWatcher {
+    config: PaymentWatcherConfig,
+}
+
+impl PaymentWatcher {
+    pub async fn new(db: &PostgresDb, config: PaymentWatcherConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        db.batch_execute(SCHEMA).await?;
+        Ok(Self { config })
+    }
+
+    /// Runs the watcher on the current arbiter.
+    pub fn start(
+        watcher: web::Data<PaymentWatcher>,
+        solana: web::Data<SolanaClient>,
+        db: web::Data<PostgresDb>,
+        sockets: web::Data<WsRegistry>
+    ) {
+        actix_web::rt::spawn(async move {
+            loop {
+                if let Err(err) = watcher.poll(&solana, &db, &sockets).await {
+                    log::error!("Payment watcher poll failed: {}", err);
+                }
+                tokio::time::sleep(watcher.config.poll_interval).await;
+            }
+        });
+    }
+
+    /// One pass: new activity on every watched address, then status
+    /// follow-up on recorded transfers and expiry of stale intents.
+    pub async fn poll(&self, solana: &SolanaClient, db: &PostgresDb, sockets: &WsRegistry) -> Result<(), Box<dyn std::error::Error>> {
+        let mut addresses = vec![solana.receiver().to_string()];
+        for address in db.query("SELECT DISTINCT pubkey FROM user_wallets", &[], map_pubkey).await? {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+
+        for address in addresses {
+            let Ok(address) = parse_pubkey(&address) else {
+                continue;
+            };
+            if let Err(err) = self.poll_address(solana, db, sockets, &address).await {
+                log::warn!("Failed to poll transfers for {}: {}", address, err);
+            }
+        }
+
+        self.reconcile(solana, db, sockets).await?;
+        self.expire_intents(db, sockets).await
+    }
+
+    async fn poll_address(
+        &self,
+        solana: &SolanaClient,
+        db: &PostgresDb,
+        sockets: &WsRegistry,
+        address: &Pubkey
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let cursor = db
+            .query("SELECT last_signature FROM solana_watch_cursors WHERE address = $1", &[&address.to_string()], map_cursor).await?
+            .pop()
+            .flatten()
+            .and_then(|signature| signature.parse().ok());
+
+        let activity = solana.address_activity(address, cursor).await?;
+        for transfer in &activity.transfers {
+            self.record(db, sockets, transfer).await?;
+        }
+
+        if let Some(newest) = activity.newest_signature {
+            db.execute(
+                "INSERT INTO solana_watch_cursors (address, last_signature) VALUES ($1, $2)
+                 ON CONFLICT (address) DO UPDATE SET last_signature = $2, updated_at = now()",
+                &[&address.to_string(), &newest]
+            ).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores a confirmed incoming transfer and settles the payment intent
+    /// its memo references, if any. A transfer pays for one intent at most. Recording the same transfer twice is a
+    /// no-op, unless it had been dropped and reappeared on the main fork.
+    pub async fn record(&self, db: &PostgresDb, sockets: &WsRegistry, transfer: &IncomingTransfer) -> Result<(), Box<dyn std::error::Error>> {
+        let block_time = transfer.block_time.and_then(|secs| chrono::DateTime::from_timestamp(secs, 0));
+
+        db.execute(
+            "INSERT INTO solana_transfers (signature, address, sender, lamports, slot, memo, status, block_time)
+             VALUES ($1, $2, $3, $4, $5, $6, 'confirmed', $7)
+             ON CONFLICT (signature, address) DO UPDATE
+             SET status = 'confirmed', slot = excluded.slot, updated_at = now()
+             WHERE solana_transfers.status = 'dropped'",
+            &[
+                &transfer.signature,
+                &transfer.address.to_string(),
+                &transfer.sender.to_string(),
+                &(transfer.lamports as i64),
+                &(transfer.slot as i64),
+                &transfer.memo,
+                &block_time,
+            ]
+        ).await?;
+
+        let Some(reference) = transfer.memo.as_deref().and_then(payment_reference) else {
+            return Ok(());
+        };
+
+        let paid = db.query(
+            &format!(
+                "UPDATE payment_intents SET status = 'paid', signature = $1, paid_at = now()
+                 WHERE status = 'pending' AND expires_at > now()
+                   AND recipient = $2 AND lamports <= $3 AND reference = $4
+                 RETURNING user_id, {}",
+                INTENT_COLUMNS
+            ),
+            &[&transfer.signature, &transfer.address.to_string(), &(transfer.lamports as i64), &reference],
+            map_owned_intent
+        ).await?;
+
+        for (user_id, intent) in paid {
+            log::info!("Payment intent {} paid by {}", intent.id, transfer.signature);
+            notify_intent(sockets, &user_id, &intent);
+        }
+
+        Ok(())
+    }
+
+    /// Follows recorded transfers to finalization. Ones the cluster has
+    /// forgotten were on a dropped fork: their intents reopen and the
+    /// cursor rewinds so the address is read again.
+    async fn reconcile(&self, solana: &SolanaClient, db: &PostgresDb, sockets: &WsRegistry) -> Result<(), Box<dyn std::error::Error>> {
+        let pending = db.query(
+            &format!("SELECT {} FROM solana_transfers WHERE status = 'confirmed' ORDER BY slot LIMIT $1", TRANSFER_COLUMNS),
+            &[&STATUS_BATCH_SIZE],
+            map_transfer
+        ).await?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let signatures = pending
+            .iter()
+            .map(|transfer| transfer.signature.parse())
+            .collect::<Result<Vec<Signature>, _>>()?;
+        let (slot, statuses) = solana.observed_statuses(&signatures).await?;
+
+        for (transfer, status) in pending.iter().zip(statuses) {
+            let status = match status {
+                ObservedStatus::Finalized => "finalized",
+                ObservedStatus::Failed => "failed",
+                ObservedStatus::Unknown if slot > transfer.slot + DROPPED_AFTER_SLOTS => "dropped",
+                ObservedStatus::Confirmed | ObservedStatus::Unknown => {
+                    continue;
+                }
+            };
+
+            self.set_status(db, sockets, &transfer.signature, &transfer.address, status).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves a recorded transfer to `status`. A failed or dropped transfer
+    /// no longer pays anything, so its intents go back to pending.
+    pub async fn set_status(
+        &self,
+        db: &PostgresDb,
+        sockets: &WsRegistry,
+        signature: &str,
+        address: &str,
+        status: &str
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        db.execute(
+            "UPDATE solana_transfers SET status = $3, updated_at = now() WHERE signature = $1 AND address = $2",
+            &[&signature, &address, &status]
+        ).await?;
+        if status == "finalized" {
+            return Ok(());
+        }
+
+        log::warn!("Transfer {} to {} was {}", signature, address, status);
+
+        let reopened = db.query(
+            &format!(
+                "UPDATE payment_intents SET status = 'pending', signature = NULL, paid_at = NULL
+                 WHERE signature = $1 AND recipient = $2 AND status = 'paid'
+                 RETURNING user_id, {}",
+                INTENT_COLUMNS
+            ),
+            &[&signature, &address],
+            map_owned_intent
+        ).await?;
+        for (user_id, intent) in reopened {
+            notify_intent(sockets, &user_id, &intent);
+        }
+
+        // Rewind to the newest transfer still known to be good.
+        db.execute(
+            "UPDATE solana_watch_cursors SET updated_at = now(), last_signature = (
+                 SELECT signature FROM solana_transfers
+                 WHERE address = $1 AND status = 'finalized'
+                 ORDER BY slot DESC LIMIT 1
+             )
+             WHERE address = $1",
+            &[&address]
+        ).await
+    }
+
+    async fn expire_intents(&self, db: &PostgresDb, sockets: &WsRegistry) -> Result<(), Box<dyn std::error::Error>> {
+        let expired = db.query(
+            &format!(
+                "UPDATE payment_intents SET status = 'expired' WHERE status = 'pending' AND expires_at <= now() RETURNING user_id, {}",
+                INTENT_COLUMNS
+            ),
+            &[],
+            map_owned_intent
+        ).await?;
+        for (user_id, intent) in expired {
+            notify_intent(sockets, &user_id, &intent);
+        }
+
+        Ok(())
+    }
+
+    /// Opens an intent for `lamports` to the receiver. The payer puts the
+    /// returned reference in the transfer's memo.
+    pub async fn create_intent(
+        &self,
+        db: &PostgresDb,
+        user_id: &Uuid,
+        recipient: &Pubkey,
+        lamports: u64
+    ) -> Result<PaymentIntent, Box<dyn std::error::Error>> {
+        let id = Uuid::new_v4();
+        let reference = format!("pay:{}", id.simple());
+        let expires_at = chrono::Utc::now() + chrono::Duration::seconds(self.config.intent_ttl.as_secs() as i64);
+
+        let mut created = db.query(
+            &format!(
+                "INSERT INTO payment_intents (id, user_id, reference, recipient, lamports, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 RETURNING {}",
+                INTENT_COLUMNS
+            ),
+            &[&id, user_id, &reference, &recipient.to_string(), &(lamports as i64), &expires_at],
+            map_intent
+        ).await?;
+
+        created.pop().ok_or_else(|| "payment intent was not created".into())
+    }
+
+    pub async fn find_intent(&self, db: &PostgresDb, user_id: &Uuid, id: &Uuid) -> Result<Option<PaymentIntent>, Box<dyn std::error::Error>> {
+        let mut intents = db.query(
+            &format!("SELECT {} FROM payment_intents WHERE id = $1 AND user_id = $2", INTENT_COLUMNS),
+            &[id, user_id],
+            map_intent
+        ).await?;
+
+        Ok(intents.pop())
+    }
+
+    /// Transfers into the user's wallets and those paying their intents,
+    /// newest first.
+    pub async fn transfers_for_user(&self, db: &PostgresDb, user_id: &Uuid, limit: i64) -> Result<Vec<SolanaTransfer>, Box<dyn std::error::Error>> {
+        db.query(
+            &format!(
+                "SELECT {} FROM solana_transfers
+                 WHERE address IN (SELECT pubkey FROM user_wallets WHERE user_id = $1)
+                    OR signature IN (SELECT signature FROM payment_intents WHERE user_id = $1 AND signature IS NOT NULL)
+                 ORDER BY slot DESC, signature
+                 LIMIT $2",
+                TRANSFER_COLUMNS
+            ),
+            &[user_id, &limit],
+            map_transfer
+        ).await
+    }
+}
+
+/// The one `pay:<id>` reference in a memo. Memos naming several intents,
+/// or none, settle nothing.
+pub fn payment_reference(memo: &str) -> Option<String> {
+    let mut found: Option<String> = None;
+    for (start, _) in memo.match_indices("pay:") {
+        let id = &memo[start + 4..];
+        let id = &id[..id.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(id.len())];
+        if id.len() != 32 {
+            continue;
+        }
+
+        let reference = format!("pay:{}", id.to_ascii_lowercase());
+        match &found {
+            Some(other) if *other != reference => {
+                return None;
+            }
+            _ => found = Some(reference),
+        }
+    }
+    found
+}
+
+fn notify_intent(sockets: &WsRegistry, user_id: &Uuid, intent: &PaymentIntent) {
+    sockets.send_to_user(user_id, PAYMENT_STATUS_ACTION, serde_json::to_value(intent).unwrap_or_default());
+}
+
+fn map_pubkey(row: &Row) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(row.try_get("pubkey")?)
+}
+
+fn map_cursor(row: &Row) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    Ok(row.try_get("last_signature")?)
+}
+
+fn map_transfer(row: &Row) -> Result<SolanaTransfer, Box<dyn std::error::Error>> {
+    let lamports = row.try_get::<_, i64>("lamports")? as u64;
+
+    Ok(SolanaTransfer {
+        signature: row.try_get("signature")?,
+        address: row.try_get("address")?,
+        sender: row.try_get("sender")?,
+        lamports,
+        sol: lamports_to_sol(lamports),
+        slot: row.try_get::<_, i64>("slot")? as u64,
+        memo: row.try_get("memo")?,
+        status: row.try_get("status")?,
+        block_time: row.try_get("block_time")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+fn map_intent(row: &Row) -> Result<PaymentIntent, Box<dyn std::error::Error>> {
+    let lamports = row.try_get::<_, i64>("lamports")? as u64;
+
+    Ok(PaymentIntent {
+        id: row.try_get("id")?,
+        reference: row.try_get("reference")?,
+        recipient: row.try_get("recipient")?,
+        lamports,
+        sol: lamports_to_sol(lamports),
+        status: row.try_get("status")?,
+        signature: row.try_get("signature")?,
+        expires_at: row.try_get("expires_at")?,
+        paid_at: row.try_get("paid_at")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+fn map_owned_intent(row: &Row) -> Result<(Uuid, PaymentIntent), Box<dyn std::error::Error>> {
+    Ok((row.try_get("user_id")?, map_intent(row)?))
+}
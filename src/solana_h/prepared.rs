@@ -0,0 +1,323 @@
+use std::time::{ Duration, Instant };
+
+use actix_web::web;
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
+use serde::{ Deserialize, Serialize };
+use solana_program::instruction::AccountMeta;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::signature::Signature;
+use solana_sdk::transaction::TransactionError;
+use uuid::Uuid;
+
+use crate::models::*;
+use crate::redis_client::Cache;
+use crate::websocket::WsRegistry;
+
+use super::*;
+
+/// SPL Memo program v2.
+pub const MEMO_PROGRAM_ID: &str = "MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr";
+pub const MAX_MEMO_LEN: usize = 512;
+
+/// Websocket action carrying `TransactionStatusUpdate`s.
+pub const TX_STATUS_ACTION: &str = "solana_tx_status";
+
+/// A blockhash stays valid for roughly 60-90 seconds, so a prepared
+/// transaction is useless long before this.
+const PREPARED_TX_TTL_SECS: u64 = 120;
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Tracking gives up after this even if the RPC never answers.
+const MAX_TRACKING: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UnsignedTransactionRequest {
+    Transfer { to: String, lamports: u64 },
+    /// `amount` in the mint's base units; `decimals` must match the mint.
+    TokenTransfer { mint: String, to: String, amount: u64, decimals: u8 },
+    Memo { memo: String },
+}
+
+#[derive(Debug)]
+pub enum PreparedTxError {
+    /// A request field failed validation.
+    Invalid { field: &'static str, message: String },
+    /// Unknown, expired, already submitted or prepared for someone else.
+    UnknownTransaction,
+    /// The signed transaction isn't the one that was prepared.
+    Mismatch(String),
+    Transfer(TransferError),
+    Cache(String),
+}
+
+impl std::fmt::Display for PreparedTxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreparedTxError::Invalid { field, message } => write!(f, "{}: {}", field, message),
+            PreparedTxError::UnknownTransaction => write!(f, "prepared transaction is unknown, expired or already submitted"),
+            PreparedTxError::Mismatch(e) => write!(f, "signed transaction does not match the prepared one: {}", e),
+            PreparedTxError::Transfer(e) => write!(f, "{}", e),
+            PreparedTxError::Cache(e) => write!(f, "prepared transaction store error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PreparedTxError {}
+
+/// What Redis keeps for a prepared transaction until it is submitted.
+#[derive(Serialize, Deserialize)]
+struct PendingTransaction {
+    user_id: Uuid,
+    /// Base64 of the serialized message the wallet must sign.
+    message: String,
+    last_valid_block_height: u64,
+}
+
+fn cache_key(id: &str) -> String {
+    format!("solana:tx:{}", id)
+}
+
+/// A memo signed by `signer`, so the memo is attributable to the wallet.
+pub fn memo_instruction(signer: &Pubkey, memo: &str) -> Instruction {
+    Instruction {
+        program_id: Pubkey::from_str(MEMO_PROGRAM_ID).expect("memo program id is valid"),
+        accounts: vec![AccountMeta::new_readonly(*signer, true)],
+        data: memo.as_bytes().to_vec(),
+    }
+}
+
+impl SolanaClient {
+    async fn quote_memo(&self, wallet: &Pubkey, memo: &str) -> Result<(Message, u64, u64), TransferError> {
+        let (blockhash, last_valid_block_height) = self.rpc
+            .call(|rpc| async move { rpc.get_latest_blockhash_with_commitment(rpc.commitment()).await }).await?;
+        let message = Message::new_with_blockhash(&[memo_instruction(wallet, memo)], Some(wallet), &blockhash);
+        let message_ref = &message;
+
+        let fee = self.rpc.call(|rpc| async move { rpc.get_fee_for_message(message_ref).await }).await?;
+        let balance = self.get_balance(wallet).await?;
+        if balance < fee {
+            return Err(TransferError::InsufficientFunds { balance, required: fee });
+        }
+
+        Ok((message, fee, last_valid_block_height))
+    }
+
+    /// Sends an already signed transaction without waiting for it. Sending
+    /// it again on retry is harmless; the signature stays the same.
+    pub async fn broadcast(&self, transaction: &Transaction) -> Result<Signature, SolanaError> {
+        self.rpc.call(|rpc| async move { rpc.send_transaction(transaction).await }).await
+    }
+
+    /// Whether the signature reached `confirmed`, and its error, or `None`
+    /// while the cluster hasn't seen it.
+    async fn signature_status(&self, signature: Signature) -> Result<Option<(bool, Option<TransactionError>)>, SolanaError> {
+        let signatures = [signature];
+        let signatures = &signatures;
+        let statuses = self.rpc.call(|rpc| async move { rpc.get_signature_statuses(signatures).await }).await?;
+
+        Ok(
+            statuses.value
+                .into_iter()
+                .next()
+                .flatten()
+                .map(|status| (status.satisfies_commitment(CommitmentConfig::confirmed()), status.err))
+        )
+    }
+
+    async fn block_height(&self) -> Result<u64, SolanaError> {
+        self.rpc.call(|rpc| async move { rpc.get_block_height().await }).await
+    }
+}
+
+/// Builds an unsigned transaction paid for and signed by `wallet`, and
+/// remembers it so the signed copy can be checked on submission.
+pub async fn prepare_unsigned_transaction(
+    solana: &SolanaClient,
+    cache: &Cache,
+    user_id: &Uuid,
+    wallet: &Pubkey,
+    request: &UnsignedTransactionRequest
+) -> Result<PreparedTransaction, PreparedTxError> {
+    let (kind, message, fee, last_valid_block_height) = match request {
+        UnsignedTransactionRequest::Transfer { to, lamports } => {
+            let to = parse_pubkey(to).map_err(|message| PreparedTxError::Invalid { field: "to", message })?;
+            let quote = solana.quote_transfer(wallet, &to, *lamports).await.map_err(|err| match err {
+                TransferError::Rpc(_) => PreparedTxError::Transfer(err),
+                err => PreparedTxError::Invalid { field: "lamports", message: err.to_string() },
+            })?;
+            ("transfer", quote.message, quote.fee, quote.last_valid_block_height)
+        }
+        UnsignedTransactionRequest::TokenTransfer { mint, to, amount, decimals } => {
+            let mint = parse_pubkey(mint).map_err(|message| PreparedTxError::Invalid { field: "mint", message })?;
+            let to = parse_pubkey(to).map_err(|message| PreparedTxError::Invalid { field: "to", message })?;
+            let quote = solana.quote_token_transfer(wallet, &mint, &to, *amount, *decimals).await.map_err(|err| match err {
+                TokenError::Transfer(err @ TransferError::Rpc(_)) => PreparedTxError::Transfer(err),
+                err => PreparedTxError::Invalid { field: err.field(), message: err.to_string() },
+            })?;
+            ("token_transfer", quote.message, quote.fee, quote.last_valid_block_height)
+        }
+        UnsignedTransactionRequest::Memo { memo } => {
+            if memo.is_empty() || memo.len() > MAX_MEMO_LEN {
+                return Err(PreparedTxError::Invalid {
+                    field: "memo",
+                    message: format!("memo must be between 1 and {} bytes", MAX_MEMO_LEN),
+                });
+            }
+            let (message, fee, last_valid_block_height) = solana.quote_memo(wallet, memo).await.map_err(|err| match err {
+                TransferError::Rpc(_) => PreparedTxError::Transfer(err),
+                err => PreparedTxError::Invalid { field: "memo", message: err.to_string() },
+            })?;
+            ("memo", message, fee, last_valid_block_height)
+        }
+    };
+
+    let id = Uuid::new_v4().to_string();
+    let transaction = Transaction::new_unsigned(message);
+    let serialized = bincode::serialize(&transaction).map_err(|e| PreparedTxError::Cache(e.to_string()))?;
+
+    let pending = PendingTransaction {
+        user_id: *user_id,
+        message: BASE64.encode(transaction.message_data()),
+        last_valid_block_height,
+    };
+    cache
+        .set_json(&cache_key(&id), &pending, PREPARED_TX_TTL_SECS).await
+        .map_err(|e| PreparedTxError::Cache(e.to_string()))?;
+
+    Ok(PreparedTransaction {
+        id,
+        kind: kind.to_string(),
+        wallet: wallet.to_string(),
+        transaction: BASE64.encode(serialized),
+        fee,
+        expires_at: chrono::Utc::now() + chrono::Duration::seconds(PREPARED_TX_TTL_SECS as i64),
+    })
+}
+
+/// Checks a signed transaction against what was prepared as `id` for the
+/// user and redeems it, so it can be submitted once. Returns it with the
+/// block height its blockhash expires at.
+pub async fn redeem_signed_transaction(
+    cache: &Cache,
+    user_id: &Uuid,
+    id: &str,
+    signed: &str
+) -> Result<(Transaction, u64), PreparedTxError> {
+    let invalid = |message: &str| PreparedTxError::Invalid { field: "transaction", message: message.to_string() };
+
+    let bytes = BASE64.decode(signed.trim()).map_err(|_| invalid("transaction must be base64"))?;
+    let transaction: Transaction = bincode
+        ::deserialize(&bytes)
+        .map_err(|_| invalid("transaction is not a serialized Solana transaction"))?;
+
+    let key = cache_key(id);
+    let pending = cache
+        .get_json::<PendingTransaction>(&key).await
+        .map_err(|e| PreparedTxError::Cache(e.to_string()))?
+        .filter(|pending| pending.user_id == *user_id)
+        .ok_or(PreparedTxError::UnknownTransaction)?;
+
+    if BASE64.encode(transaction.message_data()) != pending.message {
+        return Err(PreparedTxError::Mismatch("message was changed".to_string()));
+    }
+    if !transaction.is_signed() || transaction.verify().is_err() {
+        return Err(PreparedTxError::Mismatch("signatures are missing or invalid".to_string()));
+    }
+
+    match cache.delete(&key).await {
+        Ok(true) => Ok((transaction, pending.last_valid_block_height)),
+        Ok(false) => Err(PreparedTxError::UnknownTransaction),
+        Err(e) => Err(PreparedTxError::Cache(e.to_string())),
+    }
+}
+
+/// Puts back a transaction redeemed by `redeem_signed_transaction` whose
+/// broadcast failed, so the wallet can submit it again.
+pub async fn restore_signed_transaction(
+    cache: &Cache,
+    user_id: &Uuid,
+    id: &str,
+    transaction: &Transaction,
+    last_valid_block_height: u64
+) -> Result<(), PreparedTxError> {
+    let pending = PendingTransaction {
+        user_id: *user_id,
+        message: BASE64.encode(transaction.message_data()),
+        last_valid_block_height,
+    };
+    cache
+        .set_json(&cache_key(id), &pending, PREPARED_TX_TTL_SECS).await
+        .map_err(|e| PreparedTxError::Cache(e.to_string()))
+}
+
+fn push_status(sockets: &WsRegistry, user_id: &Uuid, id: &str, signature: &Signature, status: &str, error: Option<String>) {
+    let update = TransactionStatusUpdate {
+        id: id.to_string(),
+        signature: signature.to_string(),
+        status: status.to_string(),
+        error,
+    };
+    sockets.send_to_user(user_id, TX_STATUS_ACTION, serde_json::to_value(update).unwrap_or_default());
+}
+
+/// Follows a broadcast transaction in the background until it is
+/// confirmed, fails, or its blockhash expires, pushing each change to the
+/// user's websockets. Gives up with `unknown` after `MAX_TRACKING`.
+pub fn track_confirmation(
+    solana: web::Data<SolanaClient>,
+    sockets: web::Data<WsRegistry>,
+    user_id: Uuid,
+    id: String,
+    signature: Signature,
+    last_valid_block_height: u64
+) {
+    actix_web::rt::spawn(async move {
+        let started = Instant::now();
+        let mut last_status = "submitted";
+
+        loop {
+            if started.elapsed() > MAX_TRACKING {
+                log::warn!("Gave up tracking transaction {} for user {}", signature, user_id);
+                push_status(&sockets, &user_id, &id, &signature, "unknown", None);
+                return;
+            }
+            tokio::time::sleep(STATUS_POLL_INTERVAL).await;
+
+            let status = match solana.signature_status(signature).await {
+                Ok(status) => status,
+                Err(e) => {
+                    log::warn!("Failed to poll transaction {}: {}", signature, e);
+                    continue;
+                }
+            };
+
+            match status {
+                Some((_, Some(err))) => {
+                    push_status(&sockets, &user_id, &id, &signature, "failed", Some(err.to_string()));
+                    return;
+                }
+                Some((true, None)) => {
+                    push_status(&sockets, &user_id, &id, &signature, "confirmed", None);
+                    return;
+                }
+                Some(_) => {
+                    if last_status != "processed" {
+                        last_status = "processed";
+                        push_status(&sockets, &user_id, &id, &signature, last_status, None);
+                    }
+                }
+                None => {
+                    // Unseen after its blockhash expired means it was dropped.
+                    match solana.block_height().await {
+                        Ok(height) if height > last_valid_block_height => {
+                            push_status(&sockets, &user_id, &id, &signature, "expired", None);
+                            return;
+                        }
+                        Ok(_) => {}
+                        Err(e) => log::warn!("Failed to read block height: {}", e),
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,192 @@
+use std::env;
+use std::future::Future;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::Duration;
+
+use solana_client::client_error::{ ClientError, ClientErrorKind };
+use solana_client::rpc_request::RpcError;
+use solana_sdk::commitment_config::CommitmentConfig;
+
+use super::*;
+
+/// JSON-RPC codes some providers use instead of HTTP 429.
+const RATE_LIMIT_CODES: [i64; 2] = [429, -32429];
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct SolanaConfig {
+    /// Tried in order; the next one takes over when the current one fails.
+    pub endpoints: Vec<String>,
+    pub commitment: CommitmentConfig,
+    /// Per request, on each endpoint.
+    pub timeout: Duration,
+    /// Extra rounds over every endpoint before giving up.
+    pub max_retries: u32,
+    /// Wait before the first retry round; doubles each round.
+    pub retry_backoff: Duration,
+}
+
+impl Default for SolanaConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: vec![DEFAULT_RPC_URL.to_string()],
+            commitment: CommitmentConfig::confirmed(),
+            timeout: Duration::from_secs(30),
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl SolanaConfig {
+    /// Reads `SOLANA_RPC_URL` (comma separated for failover),
+    /// `SOLANA_COMMITMENT`, `SOLANA_RPC_TIMEOUT_SECS`,
+    /// `SOLANA_RPC_MAX_RETRIES` and `SOLANA_RPC_BACKOFF_MS`.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let default = Self::default();
+
+        let endpoints = match env::var("SOLANA_RPC_URL") {
+            Ok(urls) => urls.split(',').map(str::trim).filter(|url| !url.is_empty()).map(str::to_string).collect(),
+            Err(_) => default.endpoints,
+        };
+        if endpoints.is_empty() {
+            return Err("SOLANA_RPC_URL must name at least one endpoint".into());
+        }
+        let commitment = match env::var("SOLANA_COMMITMENT") {
+            Ok(commitment) => parse_commitment(&commitment)?,
+            Err(_) => default.commitment,
+        };
+
+        Ok(Self {
+            endpoints,
+            commitment,
+            timeout: env::var("SOLANA_RPC_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).map(Duration::from_secs).unwrap_or(default.timeout),
+            max_retries: env::var("SOLANA_RPC_MAX_RETRIES").ok().and_then(|v| v.parse().ok()).unwrap_or(default.max_retries),
+            retry_backoff: env::var("SOLANA_RPC_BACKOFF_MS").ok().and_then(|v| v.parse().ok()).map(Duration::from_millis).unwrap_or(default.retry_backoff),
+        })
+    }
+}
+
+/// RPC failures, split so callers can tell "the cluster said no" from "we
+/// couldn't ask".
+#[derive(Debug, Clone)]
+pub enum SolanaError {
+    /// No endpoint could be reached.
+    Network(String),
+    Timeout,
+    /// Every endpoint kept rate limiting us.
+    RateLimited,
+    /// The node answered with an error, e.g. a failed preflight.
+    Rpc(String),
+}
+
+impl SolanaError {
+    /// Whether another endpoint or a later attempt might succeed.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, SolanaError::Rpc(_))
+    }
+}
+
+impl std::fmt::Display for SolanaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SolanaError::Network(e) => write!(f, "rpc endpoint unreachable: {}", e),
+            SolanaError::Timeout => write!(f, "rpc request timed out"),
+            SolanaError::RateLimited => write!(f, "rpc endpoints are rate limiting requests"),
+            SolanaError::Rpc(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SolanaError {}
+
+impl From<ClientError> for SolanaError {
+    fn from(err: ClientError) -> Self {
+        match err.kind() {
+            ClientErrorKind::Reqwest(e) if e.status().map(|status| status.as_u16()) == Some(429) => SolanaError::RateLimited,
+            ClientErrorKind::Reqwest(e) if e.is_timeout() => SolanaError::Timeout,
+            ClientErrorKind::Reqwest(_) | ClientErrorKind::Io(_) => SolanaError::Network(err.to_string()),
+            ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) if RATE_LIMIT_CODES.contains(code) =>
+                SolanaError::RateLimited,
+            _ => SolanaError::Rpc(err.to_string()),
+        }
+    }
+}
+
+/// The configured endpoints and which one is currently preferred.
+pub(super) struct RpcPool {
+    clients: Vec<Arc<RpcClient>>,
+    active: AtomicUsize,
+    timeout: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+}
+
+impl RpcPool {
+    pub(super) fn new(config: &SolanaConfig) -> Self {
+        let clients = config.endpoints
+            .iter()
+            .map(|url| Arc::new(RpcClient::new_with_timeout_and_commitment(url.clone(), config.timeout, config.commitment)))
+            .collect();
+        Self::with_clients(clients, config)
+    }
+
+    pub(super) fn with_clients(clients: Vec<Arc<RpcClient>>, config: &SolanaConfig) -> Self {
+        Self {
+            clients,
+            active: AtomicUsize::new(0),
+            timeout: config.timeout,
+            max_retries: config.max_retries,
+            retry_backoff: config.retry_backoff,
+        }
+    }
+
+    pub(super) fn active(&self) -> &Arc<RpcClient> {
+        &self.clients[self.active.load(Ordering::Relaxed) % self.clients.len()]
+    }
+
+    /// Runs `call` against the preferred endpoint, moving to the next one
+    /// on network failures, timeouts and rate limits. After a full round
+    /// fails it backs off and goes round again, up to `max_retries` times.
+    /// Errors the node returns are final.
+    pub(super) async fn call<T, F, Fut>(&self, call: F) -> Result<T, SolanaError>
+        where F: Fn(Arc<RpcClient>) -> Fut, Fut: Future<Output = Result<T, ClientError>>
+    {
+        let mut backoff = self.retry_backoff;
+        let mut last_error = SolanaError::Network("no rpc endpoints configured".to_string());
+
+        for round in 0..=self.max_retries {
+            if round > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+
+            for _ in 0..self.clients.len() {
+                let index = self.active.load(Ordering::Relaxed) % self.clients.len();
+                let client = self.clients[index].clone();
+
+                let result = match tokio::time::timeout(self.timeout, call(client.clone())).await {
+                    Ok(result) => result.map_err(SolanaError::from),
+                    Err(_) => Err(SolanaError::Timeout),
+                };
+                let err = match result {
+                    Ok(value) => {
+                        return Ok(value);
+                    }
+                    Err(err) if !err.is_retryable() => {
+                        return Err(err);
+                    }
+                    Err(err) => err,
+                };
+
+                log::warn!("Solana rpc {} failed: {}", client.url(), err);
+                if self.clients.len() > 1 {
+                    // Only the first caller to see the failure moves on.
+                    let _ = self.active.compare_exchange(index, (index + 1) % self.clients.len(), Ordering::Relaxed, Ordering::Relaxed);
+                }
+                last_error = err;
+            }
+        }
+
+        Err(last_error)
+    }
+}
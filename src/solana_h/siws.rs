@@ -0,0 +1,170 @@
+use std::env;
+use std::time::Duration;
+
+use serde::{ Deserialize, Serialize };
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use uuid::Uuid;
+
+use crate::models::*;
+use crate::redis_client::Cache;
+
+/// Marks sessions established by wallet sign-in; see `Auth`.
+pub const WALLET_AUTH_METHOD: &str = "siws";
+
+pub struct SiwsConfig {
+    /// Host the client is signing in to, e.g. `app.example.com`.
+    pub domain: String,
+    pub uri: String,
+    pub statement: String,
+    pub chain_id: String,
+    pub ttl: Duration,
+    /// Role given to sessions opened by wallet sign-in.
+    pub role: String,
+}
+
+impl Default for SiwsConfig {
+    fn default() -> Self {
+        Self {
+            domain: "localhost:9080".to_string(),
+            uri: "http://localhost:9080".to_string(),
+            statement: "Sign in with your Solana wallet.".to_string(),
+            chain_id: "devnet".to_string(),
+            ttl: Duration::from_secs(5 * 60),
+            role: "user".to_string(),
+        }
+    }
+}
+
+impl SiwsConfig {
+    /// Reads `SIWS_DOMAIN`, `SIWS_URI`, `SIWS_STATEMENT`, `SIWS_CHAIN_ID`,
+    /// `SIWS_TTL_SECS` and `SIWS_ROLE`, keeping defaults for unset ones.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        Self {
+            domain: env::var("SIWS_DOMAIN").unwrap_or(default.domain),
+            uri: env::var("SIWS_URI").unwrap_or(default.uri),
+            statement: env::var("SIWS_STATEMENT").unwrap_or(default.statement),
+            chain_id: env::var("SIWS_CHAIN_ID").unwrap_or(default.chain_id),
+            ttl: env::var("SIWS_TTL_SECS").ok().and_then(|v| v.parse().ok()).map(Duration::from_secs).unwrap_or(default.ttl),
+            role: env::var("SIWS_ROLE").unwrap_or(default.role),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SiwsError {
+    /// The nonce expired, was already used or never existed.
+    UnknownNonce,
+    /// The challenge was issued for another wallet.
+    WrongWallet,
+    InvalidSignature,
+    Cache(String),
+}
+
+impl std::fmt::Display for SiwsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SiwsError::UnknownNonce => write!(f, "challenge is unknown, expired or already used"),
+            SiwsError::WrongWallet => write!(f, "challenge was issued for a different wallet"),
+            SiwsError::InvalidSignature => write!(f, "signature does not match the challenge and wallet"),
+            SiwsError::Cache(e) => write!(f, "challenge store error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SiwsError {}
+
+/// What Redis keeps for an issued challenge.
+#[derive(Serialize, Deserialize)]
+struct PendingChallenge {
+    pubkey: String,
+    message: String,
+}
+
+/// Challenge–response proof of wallet ownership, in the style of
+/// Sign-In With Ethereum (EIP-4361): the server issues a one-time message,
+/// the wallet signs it, and the ed25519 signature is checked here.
+pub struct SignInWithSolana {
+    config: SiwsConfig,
+}
+
+impl SignInWithSolana {
+    pub fn new(config: SiwsConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn role(&self) -> &str {
+        &self.config.role
+    }
+
+    fn cache_key(nonce: &str) -> String {
+        format!("solana:siws:{}", nonce)
+    }
+
+    fn message(&self, pubkey: &Pubkey, nonce: &str, issued_at: &str, expires_at: &str) -> String {
+        format!(
+            "{domain} wants you to sign in with your Solana account:\n{pubkey}\n\n{statement}\n\n\
+             URI: {uri}\nVersion: 1\nChain ID: {chain_id}\nNonce: {nonce}\nIssued At: {issued_at}\nExpiration Time: {expires_at}",
+            domain = self.config.domain,
+            pubkey = pubkey,
+            statement = self.config.statement,
+            uri = self.config.uri,
+            chain_id = self.config.chain_id,
+            nonce = nonce,
+            issued_at = issued_at,
+            expires_at = expires_at
+        )
+    }
+
+    /// Issues a one-time message for `pubkey` to sign. It expires with its
+    /// Redis entry.
+    pub async fn issue(&self, cache: &Cache, pubkey: &Pubkey) -> Result<SignInChallenge, SiwsError> {
+        let nonce = Uuid::new_v4().simple().to_string();
+        let issued_at = chrono::Utc::now();
+        let expires_at = issued_at + chrono::Duration::seconds(self.config.ttl.as_secs() as i64);
+        let message = self.message(pubkey, &nonce, &issued_at.to_rfc3339(), &expires_at.to_rfc3339());
+
+        let pending = PendingChallenge {
+            pubkey: pubkey.to_string(),
+            message: message.clone(),
+        };
+        cache
+            .set_json(&Self::cache_key(&nonce), &pending, self.config.ttl.as_secs().max(1)).await
+            .map_err(|e| SiwsError::Cache(e.to_string()))?;
+
+        Ok(SignInChallenge {
+            nonce,
+            message,
+            expires_at,
+        })
+    }
+
+    /// Checks a base58 `signature` over the challenge `nonce` by `pubkey`.
+    /// A challenge can be redeemed once; failed attempts leave it in place.
+    pub async fn verify(&self, cache: &Cache, pubkey: &Pubkey, nonce: &str, signature: &str) -> Result<(), SiwsError> {
+        let key = Self::cache_key(nonce);
+        let pending = cache
+            .get_json::<PendingChallenge>(&key).await
+            .map_err(|e| SiwsError::Cache(e.to_string()))?
+            .ok_or(SiwsError::UnknownNonce)?;
+
+        if pending.pubkey != pubkey.to_string() {
+            return Err(SiwsError::WrongWallet);
+        }
+
+        let signature = signature.trim().parse::<Signature>().map_err(|_| SiwsError::InvalidSignature)?;
+        if !signature.verify(pubkey.as_ref(), pending.message.as_bytes()) {
+            return Err(SiwsError::InvalidSignature);
+        }
+
+        // Whoever deletes the entry redeems it, so a replay racing this
+        // request fails.
+        match cache.delete(&key).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(SiwsError::UnknownNonce),
+            Err(e) => Err(SiwsError::Cache(e.to_string())),
+        }
+    }
+}
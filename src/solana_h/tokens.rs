@@ -0,0 +1,277 @@
+use solana_account_decoder::parse_token::UiTokenAmount;
+use solana_account_decoder::UiAccountData;
+use solana_client::rpc_request::TokenAccountsFilter;
+use solana_program::program_pack::Pack;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::signature::Signature;
+use spl_associated_token_account::get_associated_token_address;
+use spl_associated_token_account::instruction::create_associated_token_account_idempotent;
+use spl_token::state::{ Account as TokenAccountState, Mint };
+
+use crate::models::*;
+
+use super::*;
+
+#[derive(Debug)]
+pub enum TokenError {
+    InvalidAmount(String),
+    /// Not an initialized SPL Token mint.
+    InvalidMint(String),
+    /// The decimals given don't match the mint's.
+    DecimalsMismatch { expected: u8, actual: u8 },
+    InsufficientTokens { balance: u64, required: u64 },
+    /// Fee, rent, signing, submission and RPC failures.
+    Transfer(TransferError),
+}
+
+impl From<TransferError> for TokenError {
+    fn from(err: TransferError) -> Self {
+        TokenError::Transfer(err)
+    }
+}
+
+impl From<SolanaError> for TokenError {
+    fn from(err: SolanaError) -> Self {
+        TokenError::Transfer(TransferError::Rpc(err))
+    }
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::InvalidAmount(e) => write!(f, "{}", e),
+            TokenError::InvalidMint(e) => write!(f, "{}", e),
+            TokenError::DecimalsMismatch { expected, actual } =>
+                write!(f, "mint has {} decimals, {} given", expected, actual),
+            TokenError::InsufficientTokens { balance, required } =>
+                write!(f, "insufficient tokens: balance is {}, {} required", balance, required),
+            TokenError::Transfer(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+impl TokenError {
+    /// The request field a validation error is reported on.
+    pub fn field(&self) -> &'static str {
+        match self {
+            TokenError::InvalidMint(_) => "mint",
+            TokenError::DecimalsMismatch { .. } => "decimals",
+            TokenError::Transfer(TransferError::Failed(_)) => "transaction",
+            _ => "amount",
+        }
+    }
+}
+
+/// The associated token account of `owner` for `mint`.
+pub fn associated_token_address(owner: &Pubkey, mint: &Pubkey) -> Pubkey {
+    get_associated_token_address(owner, mint)
+}
+
+/// `amount` in whole tokens, e.g. `1.5` for 1500000 at 6 decimals.
+pub fn token_ui_amount(amount: u64, decimals: u8) -> String {
+    spl_token::amount_to_ui_amount_string_trimmed(amount, decimals)
+}
+
+/// Reads a `jsonParsed` SPL Token account as returned by
+/// `getTokenAccountsByOwner`.
+fn parse_token_account(address: &str, data: &UiAccountData) -> Option<TokenAccount> {
+    let UiAccountData::Json(parsed) = data else {
+        return None;
+    };
+    let info = parsed.parsed.get("info")?;
+    let amount: UiTokenAmount = serde_json::from_value(info.get("tokenAmount")?.clone()).ok()?;
+
+    Some(TokenAccount {
+        address: address.to_string(),
+        mint: info.get("mint")?.as_str()?.to_string(),
+        owner: info.get("owner")?.as_str()?.to_string(),
+        amount: amount.amount.parse().ok()?,
+        decimals: amount.decimals,
+        ui_amount: amount.ui_amount_string,
+    })
+}
+
+/// Everything fetched from the cluster to build and check a token transfer.
+pub(super) struct TokenTransferQuote {
+    pub(super) message: Message,
+    pub(super) fee: u64,
+    /// The recipient's token account is created by the transfer.
+    pub(super) creates_account: bool,
+    pub(super) last_valid_block_height: u64,
+}
+
+impl SolanaClient {
+    /// Every SPL Token account `owner` holds, with its mint and decimals.
+    pub async fn token_accounts(&self, owner: &Pubkey) -> Result<Vec<TokenAccount>, SolanaError> {
+        let program = spl_token::id();
+        let accounts = self.rpc
+            .call(|rpc| async move { rpc.get_token_accounts_by_owner(owner, TokenAccountsFilter::ProgramId(program)).await }).await?;
+
+        Ok(
+            accounts
+                .iter()
+                .filter_map(|keyed| parse_token_account(&keyed.pubkey, &keyed.account.data))
+                .collect()
+        )
+    }
+
+    async fn account_data(&self, address: &Pubkey) -> Result<Option<(Pubkey, Vec<u8>)>, SolanaError> {
+        let account = self.rpc
+            .call(|rpc| async move { rpc.get_account_with_commitment(address, rpc.commitment()).await }).await?;
+        Ok(account.value.map(|account| (account.owner, account.data)))
+    }
+
+    /// The decimals of an SPL Token mint.
+    pub async fn mint_decimals(&self, mint: &Pubkey) -> Result<u8, TokenError> {
+        let (owner, data) = self
+            .account_data(mint).await?
+            .ok_or_else(|| TokenError::InvalidMint("mint account does not exist".to_string()))?;
+        if owner != spl_token::id() {
+            return Err(TokenError::InvalidMint("account is not an SPL Token mint".to_string()));
+        }
+
+        Mint::unpack(&data)
+            .map(|mint| mint.decimals)
+            .map_err(|_| TokenError::InvalidMint("account is not an initialized SPL Token mint".to_string()))
+    }
+
+    /// Raw balance of a token account; `0` when it doesn't exist.
+    async fn token_balance(&self, address: &Pubkey) -> Result<u64, SolanaError> {
+        Ok(
+            self
+                .account_data(address).await?
+                .and_then(|(_, data)| TokenAccountState::unpack(&data).ok())
+                .map(|account| account.amount)
+                .unwrap_or(0)
+        )
+    }
+
+    /// Fetches the fee for `message` and checks `payer` can cover it plus
+    /// `rent` for accounts it creates.
+    async fn quote_fee(&self, payer: &Pubkey, message: &Message, rent: u64) -> Result<u64, TransferError> {
+        let fee = self.rpc.call(|rpc| async move { rpc.get_fee_for_message(message).await }).await?;
+        let balance = self.get_balance(payer).await?;
+
+        let required = fee.saturating_add(rent);
+        if balance < required {
+            return Err(TransferError::InsufficientFunds { balance, required });
+        }
+        Ok(fee)
+    }
+
+    async fn token_account_rent(&self) -> Result<u64, SolanaError> {
+        self.rpc.call(|rpc| async move { rpc.get_minimum_balance_for_rent_exemption(TokenAccountState::LEN).await }).await
+    }
+
+    fn sign_as_payer(&self, message: Message) -> Result<Transaction, TransferError> {
+        let blockhash = message.recent_blockhash;
+        let mut transaction = Transaction::new_unsigned(message);
+        transaction
+            .try_sign(&[&self.receiver_keypair], blockhash)
+            .map_err(|e| TransferError::Signing(e.to_string()))?;
+        Ok(transaction)
+    }
+
+    /// Derives `owner`'s associated token account for `mint` and creates it,
+    /// paid for by the server keypair, if it doesn't exist yet. Returns the
+    /// address and the creating signature, if one was needed.
+    pub async fn ensure_associated_token_account(
+        &self,
+        owner: &Pubkey,
+        mint: &Pubkey,
+        commitment: CommitmentConfig
+    ) -> Result<(Pubkey, Option<Signature>), TokenError> {
+        self.mint_decimals(mint).await?;
+
+        let address = associated_token_address(owner, mint);
+        if self.account_data(&address).await?.is_some() {
+            return Ok((address, None));
+        }
+
+        let payer = self.payer();
+        let instruction = create_associated_token_account_idempotent(&payer, owner, mint, &spl_token::id());
+        let (blockhash, _) = self.rpc
+            .call(|rpc| async move { rpc.get_latest_blockhash_with_commitment(rpc.commitment()).await }).await?;
+        let message = Message::new_with_blockhash(&[instruction], Some(&payer), &blockhash);
+
+        let rent = self.token_account_rent().await?;
+        self.quote_fee(&payer, &message, rent).await?;
+
+        log::info!("Creating token account {} for {} (mint {})", address, owner, mint);
+        let signature = self.submit_transaction(self.sign_as_payer(message)?, commitment).await?;
+        Ok((address, Some(signature)))
+    }
+
+    /// Builds a checked transfer of `amount` base units of `mint` from
+    /// `authority`'s associated token account to `to`'s, creating the latter
+    /// if needed. `decimals` must match the mint's, so a caller can't be off
+    /// by orders of magnitude. `authority` also pays the fee and any rent.
+    pub(super) async fn quote_token_transfer(
+        &self,
+        authority: &Pubkey,
+        mint: &Pubkey,
+        to: &Pubkey,
+        amount: u64,
+        decimals: u8
+    ) -> Result<TokenTransferQuote, TokenError> {
+        if amount == 0 {
+            return Err(TokenError::InvalidAmount("amount must be greater than zero".to_string()));
+        }
+
+        let mint_decimals = self.mint_decimals(mint).await?;
+        if decimals != mint_decimals {
+            return Err(TokenError::DecimalsMismatch { expected: mint_decimals, actual: decimals });
+        }
+
+        let source = associated_token_address(authority, mint);
+        let balance = self.token_balance(&source).await?;
+        if balance < amount {
+            return Err(TokenError::InsufficientTokens { balance, required: amount });
+        }
+
+        let destination = associated_token_address(to, mint);
+        let creates_account = self.account_data(&destination).await?.is_none();
+
+        let mut instructions = Vec::new();
+        let mut rent = 0;
+        if creates_account {
+            instructions.push(create_associated_token_account_idempotent(authority, to, mint, &spl_token::id()));
+            rent = self.token_account_rent().await?;
+        }
+        instructions.push(
+            spl_token::instruction
+                ::transfer_checked(&spl_token::id(), &source, mint, &destination, authority, &[], amount, decimals)
+                .map_err(|e| TokenError::InvalidAmount(e.to_string()))?
+        );
+
+        let (blockhash, last_valid_block_height) = self.rpc
+            .call(|rpc| async move { rpc.get_latest_blockhash_with_commitment(rpc.commitment()).await }).await?;
+        let message = Message::new_with_blockhash(&instructions, Some(authority), &blockhash);
+        let fee = self.quote_fee(authority, &message, rent).await?;
+
+        Ok(TokenTransferQuote { message, fee, creates_account, last_valid_block_height })
+    }
+
+    /// Sends `amount` base units of `mint` from the payer to `to` and returns
+    /// the confirmed signature, the fee paid and whether `to`'s token
+    /// account had to be created.
+    pub async fn transfer_tokens(
+        &self,
+        mint: &Pubkey,
+        to: &Pubkey,
+        amount: u64,
+        decimals: u8,
+        commitment: CommitmentConfig
+    ) -> Result<(Signature, u64, bool), TokenError> {
+        let quote = self.quote_token_transfer(&self.payer(), mint, to, amount, decimals).await?;
+        let transaction = self.sign_as_payer(quote.message)?;
+
+        log::info!("Sending {} of mint {} from {} to {}", token_ui_amount(amount, decimals), mint, self.payer(), to);
+        let signature = self.submit_transaction(transaction, commitment).await?;
+        log::info!("Token transfer {} reached {:?}", signature, commitment.commitment);
+
+        Ok((signature, quote.fee, quote.creates_account))
+    }
+}
@@ -0,0 +1,186 @@
+use solana_sdk::commitment_config::{ CommitmentConfig, CommitmentLevel };
+use solana_sdk::signature::Signature;
+use std::time::Duration;
+
+use super::*;
+
+/// Commitment used when a transfer doesn't ask for one.
+pub const DEFAULT_TRANSFER_COMMITMENT: &str = "confirmed";
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum TransferError {
+    InvalidAmount(String),
+    /// The payer can't cover the amount and fee and stay rent exempt.
+    InsufficientFunds { balance: u64, required: u64 },
+    /// A new account would be created below the rent-exempt minimum.
+    BelowRentExemption { minimum: u64 },
+    Signing(String),
+    /// The transaction landed but its execution failed.
+    Failed(String),
+    /// The blockhash expired before the transaction was confirmed.
+    Expired,
+    Rpc(SolanaError),
+}
+
+impl From<SolanaError> for TransferError {
+    fn from(err: SolanaError) -> Self {
+        TransferError::Rpc(err)
+    }
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::InvalidAmount(e) => write!(f, "{}", e),
+            TransferError::InsufficientFunds { balance, required } =>
+                write!(f, "insufficient funds: balance is {} lamports, {} required", balance, required),
+            TransferError::BelowRentExemption { minimum } =>
+                write!(f, "recipient account does not exist and must be funded with at least {} lamports", minimum),
+            TransferError::Signing(e) => write!(f, "failed to sign transaction: {}", e),
+            TransferError::Failed(e) => write!(f, "transaction failed: {}", e),
+            TransferError::Expired => write!(f, "transaction expired before it was confirmed"),
+            TransferError::Rpc(e) => write!(f, "solana rpc error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+/// Parses `processed`, `confirmed` or `finalized`.
+pub fn parse_commitment(value: &str) -> Result<CommitmentConfig, String> {
+    CommitmentLevel::from_str(value.trim())
+        .map(|commitment| CommitmentConfig { commitment })
+        .map_err(|_| "commitment must be one of processed, confirmed or finalized".to_string())
+}
+
+/// Everything fetched from the cluster to build and check a transfer.
+pub(super) struct TransferQuote {
+    pub(super) message: Message,
+    pub(super) fee: u64,
+    rent_exempt_minimum: u64,
+    payer_balance: u64,
+    recipient_balance: u64,
+    /// Last block height at which the message's blockhash is accepted.
+    pub(super) last_valid_block_height: u64,
+}
+
+impl SolanaClient {
+    /// The server-held keypair that pays for transfers.
+    pub fn payer(&self) -> Pubkey {
+        self.receiver_keypair.pubkey()
+    }
+
+    /// Fetches a recent blockhash, the fee and the balances a transfer of
+    /// `lamports` from `payer` to `to` depends on, and checks `payer` can
+    /// cover the amount and fee and keep its own rent exemption.
+    pub(super) async fn quote_transfer(&self, payer: &Pubkey, to: &Pubkey, lamports: u64) -> Result<TransferQuote, TransferError> {
+        if lamports == 0 {
+            return Err(TransferError::InvalidAmount("lamports must be greater than zero".to_string()));
+        }
+
+        let (blockhash, last_valid_block_height) = self.rpc
+            .call(|rpc| async move { rpc.get_latest_blockhash_with_commitment(rpc.commitment()).await }).await?;
+        let instruction = system_instruction::transfer(payer, to, lamports);
+        let message = Message::new_with_blockhash(&[instruction], Some(payer), &blockhash);
+        let message_ref = &message;
+
+        let quote = TransferQuote {
+            fee: self.rpc.call(|rpc| async move { rpc.get_fee_for_message(message_ref).await }).await?,
+            // Plain system accounts hold no data.
+            rent_exempt_minimum: self.rpc.call(|rpc| async move { rpc.get_minimum_balance_for_rent_exemption(0).await }).await?,
+            payer_balance: self.get_balance(payer).await?,
+            recipient_balance: self.get_balance(to).await?,
+            last_valid_block_height,
+            message,
+        };
+
+        if quote.recipient_balance == 0 && lamports < quote.rent_exempt_minimum {
+            return Err(TransferError::BelowRentExemption { minimum: quote.rent_exempt_minimum });
+        }
+
+        let required = lamports
+            .checked_add(quote.fee)
+            .and_then(|total| total.checked_add(quote.rent_exempt_minimum))
+            .ok_or_else(|| TransferError::InvalidAmount("lamports is too large".to_string()))?;
+        if quote.payer_balance < required {
+            return Err(TransferError::InsufficientFunds { balance: quote.payer_balance, required });
+        }
+
+        Ok(quote)
+    }
+
+    /// Builds and signs a transfer of `lamports` from the payer to `to`
+    /// against a recent blockhash. Returns the transaction and its fee.
+    pub async fn build_transfer(&self, to: &Pubkey, lamports: u64) -> Result<(Transaction, u64), TransferError> {
+        let quote = self.quote_transfer(&self.payer(), to, lamports).await?;
+
+        let blockhash = quote.message.recent_blockhash;
+        let mut transaction = Transaction::new_unsigned(quote.message);
+        transaction
+            .try_sign(&[&self.receiver_keypair], blockhash)
+            .map_err(|e| TransferError::Signing(e.to_string()))?;
+
+        Ok((transaction, quote.fee))
+    }
+
+    /// Submits a signed transaction and waits until it reaches `commitment`
+    /// or its blockhash expires.
+    pub async fn submit_transaction(
+        &self,
+        transaction: Transaction,
+        commitment: CommitmentConfig
+    ) -> Result<Signature, TransferError> {
+        let signature = self.broadcast(&transaction).await?;
+        let signature_ref = &signature;
+        let blockhash = &transaction.message.recent_blockhash;
+        let status = || async {
+            self.rpc
+                .call(|rpc| async move { rpc.get_signature_status_with_commitment(signature_ref, commitment).await }).await
+        };
+
+        loop {
+            match status().await? {
+                Some(Ok(())) => {
+                    return Ok(signature);
+                }
+                Some(Err(err)) => {
+                    return Err(TransferError::Failed(err.to_string()));
+                }
+                None => {}
+            }
+
+            // Checked at `processed` so a fresh blockhash isn't mistaken for
+            // an expired one while waiting for `finalized`.
+            let valid = self.rpc
+                .call(|rpc| async move { rpc.is_blockhash_valid(blockhash, CommitmentConfig::processed()).await }).await?;
+            if !valid {
+                // It may have landed just before the blockhash expired.
+                return match status().await? {
+                    Some(Ok(())) => Ok(signature),
+                    Some(Err(err)) => Err(TransferError::Failed(err.to_string())),
+                    None => Err(TransferError::Expired),
+                };
+            }
+
+            tokio::time::sleep(CONFIRM_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Sends `lamports` from the payer to `to` and returns the confirmed
+    /// signature and the fee paid.
+    pub async fn transfer_sol(
+        &self,
+        to: &Pubkey,
+        lamports: u64,
+        commitment: CommitmentConfig
+    ) -> Result<(Signature, u64), TransferError> {
+        let (transaction, fee) = self.build_transfer(to, lamports).await?;
+
+        log::info!("Sending {} lamports from {} to {}", lamports, self.payer(), to);
+        let signature = self.submit_transaction(transaction, commitment).await?;
+        log::info!("Transfer {} reached {:?}", signature, commitment.commitment);
+
+        Ok((signature, fee))
+    }
+}
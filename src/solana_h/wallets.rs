@@ -0,0 +1,243 @@
+use solana_sdk::pubkey::Pubkey;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::models::*;
+use crate::postgres_db::*;
+use crate::redis_client::Cache;
+
+/// Cached wallet lists are dropped on every change, so this only bounds
+/// drift from writes made outside this service.
+const WALLET_CACHE_TTL_SECS: u64 = 300;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS user_wallets (
+        user_id UUID NOT NULL,
+        pubkey TEXT NOT NULL,
+        is_primary BOOLEAN NOT NULL DEFAULT false,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        PRIMARY KEY (user_id, pubkey)
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS user_wallets_primary_idx ON user_wallets (user_id) WHERE is_primary;
+    CREATE UNIQUE INDEX IF NOT EXISTS user_wallets_pubkey_idx ON user_wallets (pubkey);
+";
+
+const WALLET_COLUMNS: &str = "pubkey, is_primary, created_at";
+
+/// Wallets linked to each user. PostgreSQL is the source of truth; Redis
+/// holds each user's list for fast lookups.
+pub struct WalletStore;
+
+impl WalletStore {
+    pub async fn new(db: &PostgresDb) -> Result<Self, Box<dyn std::error::Error>> {
+        db.batch_execute(SCHEMA).await?;
+        Ok(Self)
+    }
+
+    fn cache_key(user_id: &Uuid) -> String {
+        format!("solana:wallets:{}", user_id)
+    }
+
+    async fn invalidate(&self, cache: &Cache, user_id: &Uuid) {
+        if let Err(e) = cache.delete(&Self::cache_key(user_id)).await {
+            log::warn!("Failed to invalidate wallet cache for user {}: {}", user_id, e);
+        }
+    }
+
+    /// The user's wallets, primary first, then oldest first.
+    pub async fn list(
+        &self,
+        db: &PostgresDb,
+        cache: &Cache,
+        user_id: &Uuid
+    ) -> Result<Vec<LinkedWallet>, Box<dyn std::error::Error>> {
+        let key = Self::cache_key(user_id);
+        if let Ok(Some(wallets)) = cache.get_json::<Vec<LinkedWallet>>(&key).await {
+            return Ok(wallets);
+        }
+
+        let wallets = db.query(
+            &format!(
+                "SELECT {} FROM user_wallets WHERE user_id = $1 ORDER BY is_primary DESC, created_at, pubkey",
+                WALLET_COLUMNS
+            ),
+            &[user_id],
+            map_linked_wallet
+        ).await?;
+
+        if let Err(e) = cache.set_json(&key, &wallets, WALLET_CACHE_TTL_SECS).await {
+            log::warn!("Failed to cache wallets for user {}: {}", user_id, e);
+        }
+
+        Ok(wallets)
+    }
+
+    pub async fn find(
+        &self,
+        db: &PostgresDb,
+        cache: &Cache,
+        user_id: &Uuid,
+        pubkey: &Pubkey
+    ) -> Result<Option<LinkedWallet>, Box<dyn std::error::Error>> {
+        let pubkey = pubkey.to_string();
+        Ok(self.list(db, cache, user_id).await?.into_iter().find(|wallet| wallet.pubkey == pubkey))
+    }
+
+    pub async fn primary(
+        &self,
+        db: &PostgresDb,
+        cache: &Cache,
+        user_id: &Uuid
+    ) -> Result<Option<LinkedWallet>, Box<dyn std::error::Error>> {
+        Ok(self.list(db, cache, user_id).await?.into_iter().find(|wallet| wallet.is_primary))
+    }
+
+    /// The user a wallet is linked to, if any.
+    pub async fn owner(&self, db: &PostgresDb, pubkey: &Pubkey) -> Result<Option<Uuid>, Box<dyn std::error::Error>> {
+        let mut owners = db.query(
+            "SELECT user_id FROM user_wallets WHERE pubkey = $1",
+            &[&pubkey.to_string()],
+            map_user_id
+        ).await?;
+
+        Ok(owners.pop())
+    }
+
+    /// Links `pubkey` to the user. The first wallet becomes primary, as does
+    /// any wallet linked with `primary` set. Linking twice is a no-op; a
+    /// wallet linked to another user fails with a unique violation.
+    pub async fn link(
+        &self,
+        db: &PostgresDb,
+        cache: &Cache,
+        user_id: &Uuid,
+        pubkey: &Pubkey,
+        primary: bool
+    ) -> Result<LinkedWallet, Box<dyn std::error::Error>> {
+        let pubkey = pubkey.to_string();
+        let transaction = lock_user_wallets(db, user_id).await?;
+        transaction.execute(
+            "INSERT INTO user_wallets (user_id, pubkey, is_primary)
+             VALUES ($1, $2, NOT EXISTS (SELECT 1 FROM user_wallets WHERE user_id = $1 AND is_primary))
+             ON CONFLICT (user_id, pubkey) DO NOTHING",
+            &[user_id, &pubkey]
+        ).await?;
+        let mut linked = if primary {
+            make_primary(&transaction, user_id, &pubkey).await?
+        } else {
+            transaction.query(
+                &format!("SELECT {} FROM user_wallets WHERE user_id = $1 AND pubkey = $2", WALLET_COLUMNS),
+                &[user_id, &pubkey],
+                map_linked_wallet
+            ).await?
+        };
+        transaction.commit().await?;
+        self.invalidate(cache, user_id).await;
+
+        linked.pop().ok_or_else(|| "linked wallet disappeared".into())
+    }
+
+    /// Makes `pubkey` the user's primary wallet. Returns `None` when it
+    /// isn't linked to the user.
+    pub async fn set_primary(
+        &self,
+        db: &PostgresDb,
+        cache: &Cache,
+        user_id: &Uuid,
+        pubkey: &Pubkey
+    ) -> Result<Option<LinkedWallet>, Box<dyn std::error::Error>> {
+        let transaction = lock_user_wallets(db, user_id).await?;
+        let mut updated = make_primary(&transaction, user_id, &pubkey.to_string()).await?;
+        transaction.commit().await?;
+        self.invalidate(cache, user_id).await;
+
+        Ok(updated.pop())
+    }
+
+    /// Removes a wallet. When it was primary, the oldest remaining wallet
+    /// takes over. Returns false when it wasn't linked.
+    pub async fn unlink(
+        &self,
+        db: &PostgresDb,
+        cache: &Cache,
+        user_id: &Uuid,
+        pubkey: &Pubkey
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let transaction = lock_user_wallets(db, user_id).await?;
+        let removed = transaction.query(
+            &format!("DELETE FROM user_wallets WHERE user_id = $1 AND pubkey = $2 RETURNING {}", WALLET_COLUMNS),
+            &[user_id, &pubkey.to_string()],
+            map_linked_wallet
+        ).await?;
+
+        let removed = match removed.first() {
+            Some(wallet) => wallet,
+            None => {
+                return Ok(false);
+            }
+        };
+
+        if removed.is_primary {
+            transaction.execute(
+                "UPDATE user_wallets SET is_primary = true
+                 WHERE user_id = $1 AND pubkey = (
+                     SELECT pubkey FROM user_wallets WHERE user_id = $1 ORDER BY created_at, pubkey LIMIT 1
+                 )",
+                &[user_id]
+            ).await?;
+        }
+        transaction.commit().await?;
+        self.invalidate(cache, user_id).await;
+
+        Ok(true)
+    }
+}
+
+/// Starts a transaction holding the user's wallet lock, so concurrent
+/// changes from any instance can't both pick a primary.
+async fn lock_user_wallets(db: &PostgresDb, user_id: &Uuid) -> Result<DbTransaction, Box<dyn std::error::Error>> {
+    let transaction = db.transaction().await?;
+    transaction.execute("SELECT pg_advisory_xact_lock(hashtext('user_wallets:' || $1::uuid::text))", &[user_id]).await?;
+    Ok(transaction)
+}
+
+/// Returns the wallet, or nothing when it isn't linked to the user.
+async fn make_primary(
+    transaction: &DbTransaction,
+    user_id: &Uuid,
+    pubkey: &str
+) -> Result<Vec<LinkedWallet>, Box<dyn std::error::Error>> {
+    let linked = transaction.execute(
+        "SELECT 1 FROM user_wallets WHERE user_id = $1 AND pubkey = $2",
+        &[user_id, &pubkey]
+    ).await?;
+    if linked == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Cleared first so the one-primary index never sees two.
+    transaction.execute(
+        "UPDATE user_wallets SET is_primary = false WHERE user_id = $1 AND is_primary AND pubkey <> $2",
+        &[user_id, &pubkey]
+    ).await?;
+    transaction.query(
+        &format!(
+            "UPDATE user_wallets SET is_primary = true WHERE user_id = $1 AND pubkey = $2 RETURNING {}",
+            WALLET_COLUMNS
+        ),
+        &[user_id, &pubkey],
+        map_linked_wallet
+    ).await
+}
+
+fn map_user_id(row: &Row) -> Result<Uuid, Box<dyn std::error::Error>> {
+    Ok(row.try_get("user_id")?)
+}
+
+fn map_linked_wallet(row: &Row) -> Result<LinkedWallet, Box<dyn std::error::Error>> {
+    Ok(LinkedWallet {
+        pubkey: row.try_get("pubkey")?,
+        is_primary: row.try_get("is_primary")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
@@ -0,0 +1,157 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::ollama::*;
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const MAX_ATTEMPTS_LIMIT: u32 = 5;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SchemaViolation {
+    pub path: String,
+    pub schema_path: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum StructuredOutputError {
+    InvalidSchema(String),
+    /// The model call failed; `usage` covers the attempts before it.
+    Generation {
+        message: String,
+        usage: TokenUsage,
+    },
+    Invalid {
+        attempts: u32,
+        violations: Vec<SchemaViolation>,
+        usage: TokenUsage,
+    },
+}
+
+impl std::fmt::Display for StructuredOutputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StructuredOutputError::InvalidSchema(e) => write!(f, "invalid JSON schema: {}", e),
+            StructuredOutputError::Generation { message, .. } => write!(f, "generation failed: {}", message),
+            StructuredOutputError::Invalid { attempts, violations, .. } =>
+                write!(
+                    f,
+                    "model output did not match the schema after {} attempts ({} violations)",
+                    attempts,
+                    violations.len()
+                ),
+        }
+    }
+}
+
+impl std::error::Error for StructuredOutputError {}
+
+/// Parses `output` as JSON and validates it, returning every failing path.
+pub fn parse_and_validate(
+    validator: &jsonschema::Validator,
+    output: &str
+) -> Result<Value, Vec<SchemaViolation>> {
+    let value: Value = match serde_json::from_str(output.trim()) {
+        Ok(value) => value,
+        Err(e) => {
+            return Err(
+                vec![SchemaViolation {
+                    path: "".to_string(),
+                    schema_path: "".to_string(),
+                    message: format!("output is not valid JSON: {}", e),
+                }]
+            );
+        }
+    };
+
+    let violations: Vec<SchemaViolation> = validator
+        .iter_errors(&value)
+        .map(|error| SchemaViolation {
+            path: error.instance_path.to_string(),
+            schema_path: error.schema_path.to_string(),
+            message: error.to_string(),
+        })
+        .collect();
+
+    if violations.is_empty() {
+        Ok(value)
+    } else {
+        Err(violations)
+    }
+}
+
+fn schema_prompt(prompt: &str, schema: &Value) -> String {
+    format!(
+        "{}\n\nRespond only with a JSON value that conforms to this JSON Schema:\n{}",
+        prompt,
+        schema
+    )
+}
+
+fn retry_prompt(prompt: &str, schema: &Value, previous: &str, violations: &[SchemaViolation]) -> String {
+    let problems: Vec<String> = violations
+        .iter()
+        .map(|v| {
+            let path = if v.path.is_empty() { "/" } else { v.path.as_str() };
+            format!("- {}: {}", path, v.message)
+        })
+        .collect();
+
+    format!(
+        "{}\n\nYour previous response was:\n{}\n\nIt was rejected for these reasons:\n{}\n\nRespond again with only corrected JSON.",
+        schema_prompt(prompt, schema),
+        previous,
+        problems.join("\n")
+    )
+}
+
+/// Asks the model for JSON output and re-prompts with the validation errors
+/// until the result matches `schema` or `max_attempts` is exhausted.
+pub async fn generate_structured(
+    ollama: &OllamaAI,
+    model: &str,
+    prompt: &str,
+    schema: &Value,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    max_attempts: u32
+) -> Result<(Value, TokenUsage), StructuredOutputError> {
+    let validator = jsonschema
+        ::validator_for(schema)
+        .map_err(|e| StructuredOutputError::InvalidSchema(e.to_string()))?;
+
+    let max_attempts = max_attempts.clamp(1, MAX_ATTEMPTS_LIMIT);
+    let mut request_prompt = schema_prompt(prompt, schema);
+    let mut violations = Vec::new();
+    let mut usage = TokenUsage::default();
+
+    for attempt in 1..=max_attempts {
+        let completion = ollama
+            .generate_completion(model, &request_prompt, temperature, max_tokens, true).await
+            .map_err(|e| StructuredOutputError::Generation { message: e.to_string(), usage })?;
+        usage.add(&completion.usage);
+        let output = completion.text;
+
+        match parse_and_validate(&validator, &output) {
+            Ok(value) => {
+                return Ok((value, usage));
+            }
+            Err(errors) => {
+                log::warn!(
+                    "Structured output attempt {}/{} failed schema validation with {} errors",
+                    attempt,
+                    max_attempts,
+                    errors.len()
+                );
+                request_prompt = retry_prompt(prompt, schema, &output, &errors);
+                violations = errors;
+            }
+        }
+    }
+
+    Err(StructuredOutputError::Invalid {
+        attempts: max_attempts,
+        violations,
+        usage,
+    })
+}
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use futures::StreamExt;
+use serde::{ Deserialize, Serialize };
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::object_store::*;
+use crate::postgres_db::*;
+
+/// Route names policies can be configured for.
+pub const FILES_ROUTE: &str = "files";
+pub const UPLOADS_ROUTE: &str = "uploads";
+
+/// A reservation left by a crashed request stops counting after this long.
+const RESERVATION_TTL_HOURS: i32 = 6;
+
+/// Characters Windows and most shells treat specially in file names.
+const FORBIDDEN_NAME_CHARS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Upload limits for one role on one route. `None` means unlimited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadPolicy {
+    pub max_size: Option<u64>,
+    /// Exact types or `type/*` wildcards; empty allows any type.
+    pub allowed_types: Vec<String>,
+    pub max_file_name_length: usize,
+    /// Total bytes a user may keep, counting uploads in progress.
+    pub quota_bytes: Option<u64>,
+}
+
+impl Default for UploadPolicy {
+    fn default() -> Self {
+        Self {
+            max_size: Some(5 * 1024 * 1024 * 1024),
+            allowed_types: Vec::new(),
+            max_file_name_length: 255,
+            quota_bytes: Some(20 * 1024 * 1024 * 1024),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StorageUsage {
+    bytes: i64,
+}
+
+impl UploadPolicy {
+    /// Compares the essence of `content_type`, ignoring parameters such as
+    /// `charset`.
+    pub fn allows_type(&self, content_type: &str) -> bool {
+        if self.allowed_types.is_empty() {
+            return true;
+        }
+
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.allowed_types.iter().any(|allowed| {
+            let allowed = allowed.trim().to_ascii_lowercase();
+            match allowed.strip_suffix("/*") {
+                Some(kind) => essence.split('/').next() == Some(kind),
+                None => essence == allowed,
+            }
+        })
+    }
+
+    /// Adds a field-level reason to `errors` for every rule the upload
+    /// breaks. `size` is `None` when it isn't known up front.
+    pub fn check(
+        &self,
+        file_name: &str,
+        content_type: &str,
+        size: Option<u64>,
+        errors: &mut serde_json::Map<String, serde_json::Value>
+    ) {
+        if file_name.chars().count() > self.max_file_name_length {
+            errors.insert(
+                "file_name".to_string(),
+                format!("file name must be at most {} characters", self.max_file_name_length).into()
+            );
+        }
+        if !self.allows_type(content_type) {
+            errors.insert(
+                "content_type".to_string(),
+                format!("{} is not allowed; allowed types are {}", content_type, self.allowed_types.join(", ")).into()
+            );
+        }
+        if let (Some(size), Some(max_size)) = (size, self.max_size) {
+            if size > max_size {
+                errors.insert("size".to_string(), format!("file must be at most {} bytes", max_size).into());
+            }
+        }
+    }
+
+    /// Sets aside up to `wanted` bytes of the remaining quota for one upload,
+    /// or all of it when the size isn't known up front. `None` without a
+    /// quota.
+    pub async fn reserve_quota(
+        &self,
+        db: &PostgresDb,
+        owner_id: &Uuid,
+        wanted: Option<u64>
+    ) -> Result<Option<QuotaReservation>, Box<dyn std::error::Error>> {
+        let quota = match self.quota_bytes {
+            Some(quota) => quota,
+            None => {
+                return Ok(None);
+            }
+        };
+
+        let transaction = db.transaction().await?;
+        // Serialises reservations per owner, across processes too.
+        transaction.execute("SELECT pg_advisory_xact_lock(hashtext($1::uuid::text))", &[owner_id]).await?;
+        transaction.execute(
+            "DELETE FROM quota_reservations WHERE owner_id = $1 AND expires_at <= now()",
+            &[owner_id]
+        ).await?;
+
+        let usage = transaction.query(
+            "SELECT (
+                 (SELECT COALESCE(SUM(size), 0) FROM files WHERE owner_id = $1) +
+                 (SELECT COALESCE(SUM(size), 0) FROM upload_sessions WHERE owner_id = $1 AND expires_at > now()) +
+                 (SELECT COALESCE(SUM(bytes), 0) FROM quota_reservations WHERE owner_id = $1)
+             )::BIGINT AS bytes",
+            &[owner_id],
+            map_storage_usage
+        ).await?;
+        let used = usage.first().map(|usage| usage.bytes.max(0) as u64).unwrap_or(0);
+        let remaining = quota.saturating_sub(used);
+        let bytes = wanted.map_or(remaining, |wanted| wanted.min(remaining));
+
+        let id = Uuid::new_v4();
+        transaction.execute(
+            "INSERT INTO quota_reservations (id, owner_id, bytes, expires_at)
+             VALUES ($1, $2, $3, now() + make_interval(hours => $4))",
+            &[&id, owner_id, &(bytes as i64), &RESERVATION_TTL_HOURS]
+        ).await?;
+        transaction.commit().await?;
+
+        Ok(Some(QuotaReservation { id, bytes }))
+    }
+}
+
+/// Quota held for an upload in progress. It counts as used until released,
+/// so concurrent uploads can't all spend the same remaining bytes.
+#[derive(Debug)]
+pub struct QuotaReservation {
+    id: Uuid,
+    /// The most the upload may store.
+    pub bytes: u64,
+}
+
+impl QuotaReservation {
+    /// Call once the upload is recorded or abandoned.
+    pub async fn release(self, db: &PostgresDb) {
+        if let Err(err) = db.execute("DELETE FROM quota_reservations WHERE id = $1", &[&self.id]).await {
+            log::warn!("Failed to release quota reservation {}: {}", self.id, err);
+        }
+    }
+}
+
+fn map_storage_usage(row: &Row) -> Result<StorageUsage, Box<dyn std::error::Error>> {
+    Ok(StorageUsage {
+        bytes: row.try_get("bytes")?,
+    })
+}
+
+/// Policies by route and role.
+#[derive(Default)]
+pub struct UploadPolicies {
+    routes: HashMap<String, HashMap<String, UploadPolicy>>,
+    default: UploadPolicy,
+}
+
+impl UploadPolicies {
+    /// Parses a route → role → policy map such as
+    /// `{"default": {"default": {"max_size": 10485760}}, "files": {"admin": {"max_size": null}}}`.
+    /// Lookups fall back from the route's role to the route's `default`,
+    /// then to the same pair under the `default` route.
+    pub fn from_json(config: &str) -> Result<Self, serde_json::Error> {
+        let mut routes: HashMap<String, HashMap<String, UploadPolicy>> = serde_json::from_str(config)?;
+        let default = routes
+            .get_mut("default")
+            .and_then(|roles| roles.remove("default"))
+            .unwrap_or_default();
+        Ok(Self { routes, default })
+    }
+
+    pub fn policy_for(&self, route: &str, role: &str) -> &UploadPolicy {
+        let lookup = |route: &str| {
+            self.routes.get(route).and_then(|roles| roles.get(role).or_else(|| roles.get("default")))
+        };
+
+        lookup(route).or_else(|| lookup("default")).unwrap_or(&self.default)
+    }
+}
+
+/// Reduces a client supplied name to a safe final path component: control
+/// and shell-special characters are dropped, whitespace is collapsed, and
+/// leading or trailing dots and spaces are trimmed.
+pub fn sanitize_file_name(name: &str) -> Result<String, String> {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control() && !FORBIDDEN_NAME_CHARS.contains(c))
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let cleaned = cleaned.trim_matches(|c| c == '.' || c == ' ');
+
+    if cleaned.is_empty() {
+        return Err("file name must contain at least one usable character".to_string());
+    }
+
+    let stem = cleaned.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) {
+        return Ok(format!("_{}", cleaned));
+    }
+
+    Ok(cleaned.to_string())
+}
+
+/// Fails the stream with `TooLarge` once more than `limit` bytes pass
+/// through, so oversized uploads stop without being buffered.
+pub fn limit_stream(data: ByteStream, limit: u64) -> ByteStream {
+    let mut seen = 0u64;
+
+    Box::pin(
+        data.map(move |chunk| {
+            let chunk = chunk?;
+            seen += chunk.len() as u64;
+            if seen > limit {
+                return Err(ObjectStoreError::TooLarge { limit });
+            }
+            Ok(chunk)
+        })
+    )
+}
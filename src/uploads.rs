@@ -0,0 +1,618 @@
+use std::collections::BTreeMap;
+
+use actix_session::Session;
+use actix_web::{ web, HttpRequest, HttpResponse };
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use bytes::BytesMut;
+use futures::StreamExt;
+use md5::{ Digest, Md5 };
+use serde::{ Deserialize, Serialize };
+use tokio_postgres::types::Json;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::content_type::*;
+use crate::files::*;
+use crate::malware_scan::*;
+use crate::media::*;
+use crate::middleware::*;
+use crate::models::*;
+use crate::object_store::*;
+use crate::postgres_db::*;
+use crate::response::*;
+use crate::upload_policy::*;
+
+pub const DEFAULT_UPLOAD_BLOCK_SIZE: i64 = 8 * 1024 * 1024;
+const MIN_BLOCK_SIZE: i64 = 256 * 1024;
+const MAX_BLOCK_SIZE: i64 = 100 * 1024 * 1024;
+/// Azure allows at most 50,000 blocks per blob.
+const MAX_BLOCK_COUNT: i64 = 50_000;
+const MAX_UPLOAD_SIZE: i64 = MAX_BLOCK_SIZE * MAX_BLOCK_COUNT;
+/// Sessions expire this long after their last block.
+const SESSION_TTL_HOURS: i32 = 24;
+const PURGE_BATCH: i64 = 20;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS upload_sessions (
+        id UUID PRIMARY KEY,
+        owner_id UUID NOT NULL,
+        file_id UUID NOT NULL,
+        container TEXT NOT NULL,
+        blob_name TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        size BIGINT NOT NULL,
+        block_size BIGINT NOT NULL,
+        block_count INT NOT NULL,
+        content_md5 TEXT,
+        expires_at TIMESTAMPTZ NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE TABLE IF NOT EXISTS upload_blocks (
+        session_id UUID NOT NULL REFERENCES upload_sessions (id) ON DELETE CASCADE,
+        block_index INT NOT NULL,
+        size BIGINT NOT NULL,
+        md5 TEXT NOT NULL,
+        PRIMARY KEY (session_id, block_index)
+    );
+    CREATE INDEX IF NOT EXISTS upload_sessions_expires_idx ON upload_sessions (expires_at);
+    ALTER TABLE upload_sessions
+        ADD COLUMN IF NOT EXISTS cache_control TEXT,
+        ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}',
+        ADD COLUMN IF NOT EXISTS tags JSONB NOT NULL DEFAULT '{}';
+";
+
+const SESSION_COLUMNS: &str =
+    "s.id, s.owner_id, s.file_id, s.container, s.blob_name, s.file_name, s.content_type, s.size,
+     s.block_size, s.block_count, s.content_md5, s.cache_control, s.metadata, s.tags, s.expires_at, s.created_at,
+     ARRAY(SELECT b.block_index FROM upload_blocks b WHERE b.session_id = s.id ORDER BY b.block_index) AS received_blocks";
+
+pub struct Uploads {
+    block_size: i64,
+}
+
+impl Uploads {
+    pub async fn new(db: &PostgresDb, block_size: i64) -> Result<Self, Box<dyn std::error::Error>> {
+        db.batch_execute(SCHEMA).await?;
+        Ok(Self {
+            block_size: block_size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE),
+        })
+    }
+}
+
+/// A session plus the storage location, which clients never see.
+#[derive(Debug)]
+struct SessionRow {
+    session: UploadSession,
+    file_id: Uuid,
+    container: String,
+    blob_name: String,
+}
+
+impl SessionRow {
+    /// Size the block at `index` must have; only the last may be short.
+    fn expected_block_size(&self, index: i32) -> i64 {
+        let session = &self.session;
+        if index == session.block_count - 1 {
+            session.size - session.block_size * (session.block_count as i64 - 1)
+        } else {
+            session.block_size
+        }
+    }
+
+    fn put_options(&self) -> PutOptions {
+        let session = &self.session;
+
+        PutOptions {
+            content_type: session.content_type.clone(),
+            cache_control: session.cache_control.clone(),
+            content_disposition: Some(attachment_disposition(&session.file_name).to_string()),
+            metadata: session.metadata.clone(),
+            tags: session.tags.clone(),
+        }
+    }
+
+    fn received_block_ids(&self) -> Vec<String> {
+        self.session.received_blocks
+            .iter()
+            .map(|index| block_id(*index as usize))
+            .collect()
+    }
+}
+
+fn map_session(row: &Row) -> Result<SessionRow, Box<dyn std::error::Error>> {
+    Ok(SessionRow {
+        session: UploadSession {
+            id: row.try_get("id")?,
+            owner_id: row.try_get("owner_id")?,
+            file_name: row.try_get("file_name")?,
+            content_type: row.try_get("content_type")?,
+            size: row.try_get("size")?,
+            block_size: row.try_get("block_size")?,
+            block_count: row.try_get("block_count")?,
+            content_md5: row.try_get("content_md5")?,
+            cache_control: row.try_get("cache_control")?,
+            metadata: row.try_get::<_, Json<BTreeMap<String, String>>>("metadata")?.0,
+            tags: row.try_get::<_, Json<BTreeMap<String, String>>>("tags")?.0,
+            received_blocks: row.try_get("received_blocks")?,
+            expires_at: row.try_get("expires_at")?,
+            created_at: row.try_get("created_at")?,
+        },
+        file_id: row.try_get("file_id")?,
+        container: row.try_get("container")?,
+        blob_name: row.try_get("blob_name")?,
+    })
+}
+
+fn decode_md5(value: &str) -> Option<[u8; 16]> {
+    STANDARD.decode(value.trim()).ok()?.try_into().ok()
+}
+
+/// Drops a few expired sessions and their staged blocks.
+async fn purge_expired(db: &PostgresDb, storage: &dyn ObjectStore) -> Result<(), Box<dyn std::error::Error>> {
+    let expired = db.query(
+        &format!("SELECT {} FROM upload_sessions s WHERE s.expires_at <= now() LIMIT $1", SESSION_COLUMNS),
+        &[&PURGE_BATCH],
+        map_session
+    ).await?;
+
+    for row in expired {
+        if let Err(e) = storage.abort_blocks(&row.container, &row.blob_name, &row.received_block_ids()).await {
+            log::warn!("Failed to discard blocks of expired upload {}: {}", row.session.id, e);
+        }
+        db.execute("DELETE FROM upload_sessions WHERE id = $1", &[&row.session.id]).await?;
+    }
+
+    Ok(())
+}
+
+async fn owned_session(db: &PostgresDb, session: &Session, id: &Uuid) -> Result<SessionRow, HttpResponse> {
+    let user = match get_user_from_session(session) {
+        Some(user) => user,
+        None => {
+            return Err(response_unauthorized("unauthorized: no user in session"));
+        }
+    };
+
+    let result = db.query(
+        &format!(
+            "SELECT {} FROM upload_sessions s WHERE s.id = $1 AND s.owner_id = $2 AND s.expires_at > now()",
+            SESSION_COLUMNS
+        ),
+        &[id, &user.id],
+        map_session
+    ).await;
+
+    match result {
+        Ok(mut rows) =>
+            match rows.pop() {
+                Some(row) => Ok(row),
+                None => Err(response_not_found("upload session not found or expired")),
+            }
+        Err(err) => Err(response_internal_server_error(err.to_string().as_str())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateUploadRequest {
+    file_name: String,
+    content_type: Option<String>,
+    size: i64,
+    block_size: Option<i64>,
+    /// Base64 MD5 of the whole file.
+    content_md5: Option<String>,
+    cache_control: Option<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct BlockReceipt {
+    index: i32,
+    size: i64,
+    content_md5: String,
+}
+
+/// Blocks needed for `size` bytes; both must already be validated as
+/// positive.
+fn block_count(size: i64, block_size: i64) -> i64 {
+    (size as u64).div_ceil(block_size as u64) as i64
+}
+
+pub async fn create_upload(
+    uploads: web::Data<Uploads>,
+    files: web::Data<FileStore>,
+    storage: web::Data<dyn ObjectStore>,
+    policies: web::Data<UploadPolicies>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    req: web::Json<CreateUploadRequest>
+) -> HttpResponse {
+    let user = match get_user_from_session(&session) {
+        Some(user) => user,
+        None => {
+            return response_unauthorized("unauthorized: no user in session");
+        }
+    };
+
+    let mut errors = serde_json::Map::new();
+    let block_size = req.block_size.unwrap_or(uploads.block_size);
+    let policy = policies.policy_for(UPLOADS_ROUTE, &user.role);
+
+    let file_name = match sanitize_file_name(&req.file_name) {
+        Ok(file_name) => file_name,
+        Err(err) => {
+            errors.insert("file_name".to_string(), err.into());
+            String::new()
+        }
+    };
+    // Refined from the first block's bytes once it arrives.
+    let content_type = detect_content_type(&file_name, &[], req.content_type.as_deref());
+
+    let size_is_valid = (1..=MAX_UPLOAD_SIZE).contains(&req.size);
+    if !size_is_valid {
+        errors.insert(
+            "size".to_string(),
+            format!("size must be between 1 and {} bytes", MAX_UPLOAD_SIZE).into()
+        );
+    }
+    if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+        errors.insert(
+            "block_size".to_string(),
+            format!("block size must be between {} and {} bytes", MIN_BLOCK_SIZE, MAX_BLOCK_SIZE).into()
+        );
+    } else if size_is_valid && block_count(req.size, block_size) > MAX_BLOCK_COUNT {
+        errors.insert(
+            "size".to_string(),
+            format!("file needs more than {} blocks; use a larger block size", MAX_BLOCK_COUNT).into()
+        );
+    }
+    if let Some(md5) = &req.content_md5 {
+        if decode_md5(md5).is_none() {
+            errors.insert("content_md5".to_string(), "content_md5 must be a base64 encoded MD5 digest".into());
+        }
+    }
+    if let Err(err) = validate_metadata(&req.metadata) {
+        errors.insert("metadata".to_string(), err.into());
+    }
+    if let Err(err) = validate_tags(&req.tags) {
+        errors.insert("tags".to_string(), err.into());
+    }
+    if !errors.is_empty() {
+        return response_unprocessable_entity(errors);
+    }
+
+    policy.check(&file_name, &content_type, Some(req.size as u64), &mut errors);
+    if !errors.is_empty() {
+        return response_unprocessable_entity(errors);
+    }
+
+    if let Err(e) = purge_expired(&db, storage.get_ref()).await {
+        log::warn!("Failed to purge expired upload sessions: {}", e);
+    }
+
+    let reservation = match policy.reserve_quota(&db, &user.id, Some(req.size as u64)).await {
+        Ok(reservation) => reservation,
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+    let remaining = reservation.as_ref().map(|reservation| reservation.bytes);
+    if let Some(remaining) = remaining.filter(|&remaining| (req.size as u64) > remaining) {
+        if let Some(reservation) = reservation {
+            reservation.release(&db).await;
+        }
+        return response_unprocessable_entity(
+            serde_json::json!({ "quota": format!("upload exceeds the remaining storage quota of {} bytes", remaining) })
+        );
+    }
+
+    let id = Uuid::new_v4();
+    let file_id = Uuid::new_v4();
+    let blob_name = format!("{}/{}", user.id, file_id);
+    let block_count = block_count(req.size, block_size) as i32;
+
+    let result = db.execute(
+        "INSERT INTO upload_sessions
+            (id, owner_id, file_id, container, blob_name, file_name, content_type, size, block_size, block_count,
+             content_md5, cache_control, metadata, tags, expires_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, now() + make_interval(hours => $15))",
+        &[
+            &id,
+            &user.id,
+            &file_id,
+            &files.container(),
+            &blob_name,
+            &file_name,
+            &content_type,
+            &req.size,
+            &block_size,
+            &block_count,
+            &req.content_md5,
+            &req.cache_control,
+            &Json(&req.metadata),
+            &Json(&req.tags),
+            &SESSION_TTL_HOURS,
+        ]
+    ).await;
+
+    // From here on the session row holds the quota.
+    if let Some(reservation) = reservation {
+        reservation.release(&db).await;
+    }
+    if let Err(err) = result {
+        return response_internal_server_error(err.to_string().as_str());
+    }
+
+    match owned_session(&db, &session, &id).await {
+        Ok(row) => response_created("upload session created successfully", row.session),
+        Err(response) => response,
+    }
+}
+
+pub async fn get_upload(db: web::Data<PostgresDb>, session: Session, id: web::Path<Uuid>) -> HttpResponse {
+    match owned_session(&db, &session, &id).await {
+        Ok(row) => response_ok("upload session retrieved successfully", row.session),
+        Err(response) => response,
+    }
+}
+
+/// Stores one block. The body must be exactly the block's size; a
+/// `Content-MD5` header, when sent, must match it. Re-sending a block
+/// replaces it, so clients resume by sending whatever is missing from
+/// `received_blocks`.
+pub async fn upload_block(
+    storage: web::Data<dyn ObjectStore>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    req: HttpRequest,
+    path: web::Path<(Uuid, i32)>,
+    mut payload: web::Payload
+) -> HttpResponse {
+    let (id, index) = path.into_inner();
+
+    let row = match owned_session(&db, &session, &id).await {
+        Ok(row) => row,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    if index < 0 || index >= row.session.block_count {
+        return response_unprocessable_entity(
+            serde_json::json!({ "index": format!("block index must be between 0 and {}", row.session.block_count - 1) })
+        );
+    }
+
+    let expected = row.expected_block_size(index);
+    let mut body = BytesMut::with_capacity(expected as usize);
+
+    while let Some(chunk) = payload.next().await {
+        match chunk {
+            Ok(chunk) => body.extend_from_slice(&chunk),
+            Err(err) => {
+                return response_bad_request(err.to_string().as_str());
+            }
+        }
+        if body.len() as i64 > expected {
+            break;
+        }
+    }
+
+    if body.len() as i64 != expected {
+        return response_unprocessable_entity(
+            serde_json::json!({ "block": format!("block {} must be exactly {} bytes", index, expected) })
+        );
+    }
+
+    let body = body.freeze();
+    let md5: [u8; 16] = Md5::digest(&body).into();
+
+    if let Some(header) = req.headers().get("Content-MD5") {
+        let matches = header
+            .to_str()
+            .ok()
+            .and_then(decode_md5)
+            .map(|declared| declared == md5)
+            .unwrap_or(false);
+
+        if !matches {
+            return response_bad_request("Content-MD5 does not match the block body");
+        }
+    }
+
+    if index == 0 {
+        let head = &body[..body.len().min(SNIFF_LEN)];
+        let content_type = detect_content_type(&row.session.file_name, head, Some(&row.session.content_type));
+
+        if content_type != row.session.content_type {
+            let result = db.execute(
+                "UPDATE upload_sessions SET content_type = $2 WHERE id = $1",
+                &[&id, &content_type]
+            ).await;
+            if let Err(err) = result {
+                return response_internal_server_error(err.to_string().as_str());
+            }
+        }
+    }
+
+    if let Err(err) = storage.stage_block(&row.container, &row.blob_name, &block_id(index as usize), body, md5).await {
+        return response_internal_server_error(err.to_string().as_str());
+    }
+
+    let md5 = STANDARD.encode(md5);
+    let result = db.execute(
+        "INSERT INTO upload_blocks (session_id, block_index, size, md5) VALUES ($1, $2, $3, $4)
+         ON CONFLICT (session_id, block_index) DO UPDATE SET size = EXCLUDED.size, md5 = EXCLUDED.md5",
+        &[&id, &index, &expected, &md5]
+    ).await;
+    if let Err(err) = result {
+        return response_internal_server_error(err.to_string().as_str());
+    }
+
+    let _ = db.execute(
+        "UPDATE upload_sessions SET expires_at = now() + make_interval(hours => $2) WHERE id = $1",
+        &[&id, &SESSION_TTL_HOURS]
+    ).await;
+
+    response_ok("block stored successfully", BlockReceipt {
+        index,
+        size: expected,
+        content_md5: md5,
+    })
+}
+
+/// Commits the blocks into the final object, checks the whole-file MD5 if
+/// one was declared, and registers the result as a file.
+#[allow(clippy::too_many_arguments)]
+pub async fn complete_upload(
+    files: web::Data<FileStore>,
+    storage: web::Data<dyn ObjectStore>,
+    policies: web::Data<UploadPolicies>,
+    scanner: web::Data<dyn MalwareScanner>,
+    media: web::Data<MediaPipeline>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    id: web::Path<Uuid>
+) -> HttpResponse {
+    let row = match owned_session(&db, &session, &id).await {
+        Ok(row) => row,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    let missing: Vec<i32> = (0..row.session.block_count)
+        .filter(|index| !row.session.received_blocks.contains(index))
+        .collect();
+    if !missing.is_empty() {
+        return response_unprocessable_entity(serde_json::json!({ "missing_blocks": missing }));
+    }
+
+    let block_ids = row.received_block_ids();
+    let options = row.put_options();
+
+    // The first block may have revealed a type the policy doesn't allow.
+    let role = get_user_from_session(&session).map(|user| user.role).unwrap_or_default();
+    let policy = policies.policy_for(UPLOADS_ROUTE, &role);
+    if !policy.allows_type(&options.content_type) {
+        if let Err(err) = storage.abort_blocks(&row.container, &row.blob_name, &block_ids).await {
+            log::warn!("Failed to discard blocks of upload {}: {}", row.session.id, err);
+        }
+        if let Err(err) = db.execute("DELETE FROM upload_sessions WHERE id = $1", &[&row.session.id]).await {
+            log::warn!("Failed to remove rejected upload session {}: {}", row.session.id, err);
+        }
+
+        let mut errors = serde_json::Map::new();
+        policy.check(&row.session.file_name, &options.content_type, None, &mut errors);
+        return response_unprocessable_entity(errors);
+    }
+
+    let meta = match storage.commit_blocks(&row.container, &row.blob_name, &options, &block_ids).await {
+        Ok(meta) => meta,
+        Err(err) => {
+            return response_internal_server_error(err.to_string().as_str());
+        }
+    };
+
+    let mut problem = None;
+    if meta.size as i64 != row.session.size {
+        problem = Some(("size", format!("stored object is {} bytes, expected {}", meta.size, row.session.size)));
+    } else if let Some(declared) = row.session.content_md5.as_deref().and_then(decode_md5) {
+        // Block MD5s don't compose, so the final object is read back once.
+        match object_md5(storage.get_ref(), &row.container, &row.blob_name).await {
+            Ok(actual) if actual == declared => {}
+            Ok(actual) => {
+                problem = Some((
+                    "content_md5",
+                    format!("content MD5 is {}, expected {}", STANDARD.encode(actual), STANDARD.encode(declared)),
+                ));
+            }
+            Err(err) => {
+                return response_internal_server_error(err.to_string().as_str());
+            }
+        }
+    }
+
+    if let Some((field, problem)) = problem {
+        let _ = storage.delete(&row.container, &row.blob_name).await;
+        if let Err(err) = db.execute("DELETE FROM upload_sessions WHERE id = $1", &[&row.session.id]).await {
+            log::warn!("Failed to remove rejected upload session {}: {}", row.session.id, err);
+        }
+        return response_unprocessable_entity(serde_json::json!({ field: problem }));
+    }
+
+    let record = FileRecord {
+        id: row.file_id,
+        owner_id: row.session.owner_id,
+        file_name: row.session.file_name.clone(),
+        content_type: options.content_type,
+        size: row.session.size,
+        cache_control: options.cache_control,
+        metadata: options.metadata,
+        tags: options.tags,
+        scan_status: SCAN_PENDING.to_string(),
+        container: row.container.clone(),
+        blob_name: row.blob_name.clone(),
+        created_at: chrono::Utc::now(),
+    };
+
+    // The session is only removed together with the file row, so a failed
+    // insert leaves it to count against the quota until it is aborted.
+    if let Err(err) = record_completed_upload(&files, &db, &record, &row.session.id).await {
+        let _ = storage.delete(&record.container, &record.blob_name).await;
+        return response_internal_server_error(err.to_string().as_str());
+    }
+
+    spawn_scan(scanner.clone(), db.clone(), storage.clone(), media.clone(), record.clone());
+
+    response_created("upload completed successfully", record)
+}
+
+async fn record_completed_upload(
+    files: &FileStore,
+    db: &PostgresDb,
+    record: &FileRecord,
+    session_id: &Uuid
+) -> Result<(), Box<dyn std::error::Error>> {
+    let transaction = db.transaction().await?;
+    files.insert_in(&transaction, record).await?;
+    transaction.execute("DELETE FROM upload_sessions WHERE id = $1", &[session_id]).await?;
+    transaction.commit().await
+}
+
+async fn object_md5(storage: &dyn ObjectStore, container: &str, key: &str) -> Result<[u8; 16], ObjectStoreError> {
+    let mut data = storage.get(container, key).await?;
+    let mut hasher = Md5::new();
+
+    while let Some(chunk) = data.next().await {
+        hasher.update(&chunk?);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+pub async fn abort_upload(
+    storage: web::Data<dyn ObjectStore>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    id: web::Path<Uuid>
+) -> HttpResponse {
+    let row = match owned_session(&db, &session, &id).await {
+        Ok(row) => row,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    if let Err(err) = storage.abort_blocks(&row.container, &row.blob_name, &row.received_block_ids()).await {
+        log::warn!("Failed to discard blocks of upload {}: {}", row.session.id, err);
+    }
+
+    match db.execute("DELETE FROM upload_sessions WHERE id = $1", &[&row.session.id]).await {
+        Ok(_) => response_no_content(),
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
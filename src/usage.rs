@@ -0,0 +1,515 @@
+use actix_web::{ http::header::{ HeaderName, HeaderValue }, web, HttpResponse };
+use actix_session::Session;
+use chrono::{ DateTime, Datelike, TimeZone, Utc };
+use serde::{ Deserialize, Serialize };
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+
+use crate::middleware::*;
+use crate::models::*;
+use crate::ollama::*;
+use crate::postgres_db::*;
+use crate::redis_client::Cache;
+use crate::response::*;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS llm_usage (
+        id UUID PRIMARY KEY,
+        user_id UUID NOT NULL,
+        role TEXT NOT NULL,
+        model TEXT NOT NULL,
+        prompt_tokens BIGINT NOT NULL,
+        completion_tokens BIGINT NOT NULL,
+        duration_ms BIGINT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX IF NOT EXISTS llm_usage_user_created_idx ON llm_usage (user_id, created_at);
+";
+
+const DEFAULT_REPORT_DAYS: i64 = 30;
+const MAX_REPORT_DAYS: i64 = 366;
+
+/// Limits for one role. `None` means unlimited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaPolicy {
+    pub daily_requests: Option<u64>,
+    pub daily_tokens: Option<u64>,
+    pub monthly_requests: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+}
+
+impl Default for QuotaPolicy {
+    fn default() -> Self {
+        Self {
+            daily_requests: Some(200),
+            daily_tokens: Some(200_000),
+            monthly_requests: Some(4_000),
+            monthly_tokens: Some(4_000_000),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct UsageLimits {
+    roles: HashMap<String, QuotaPolicy>,
+    default: QuotaPolicy,
+}
+
+impl UsageLimits {
+    /// Parses a role → policy map such as
+    /// `{"default": {"daily_tokens": 100000}, "admin": {"daily_tokens": null}}`.
+    pub fn from_json(config: &str) -> Result<Self, serde_json::Error> {
+        let mut roles: HashMap<String, QuotaPolicy> = serde_json::from_str(config)?;
+        let default = roles.remove("default").unwrap_or_default();
+        Ok(Self { roles, default })
+    }
+
+    pub fn policy_for(&self, role: &str) -> &QuotaPolicy {
+        self.roles.get(role).unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    fn key(&self, now: DateTime<Utc>) -> String {
+        match self {
+            Period::Day => now.format("%Y%m%d").to_string(),
+            Period::Month => now.format("%Y%m").to_string(),
+        }
+    }
+
+    fn start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let day = match self {
+            Period::Day => now.day(),
+            Period::Month => 1,
+        };
+        Utc.with_ymd_and_hms(now.year(), now.month(), day, 0, 0, 0).unwrap()
+    }
+
+    fn ttl_secs(&self) -> u64 {
+        match self {
+            Period::Day => 2 * 24 * 60 * 60,
+            Period::Month => 32 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct UsageCounters {
+    pub requests: u64,
+    pub tokens: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaStatus {
+    pub role: String,
+    pub policy: QuotaPolicy,
+    pub day: UsageCounters,
+    pub month: UsageCounters,
+}
+
+fn remaining(limit: Option<u64>, used: u64) -> Option<u64> {
+    limit.map(|limit| limit.saturating_sub(used))
+}
+
+impl QuotaStatus {
+    pub fn daily_requests_remaining(&self) -> Option<u64> {
+        remaining(self.policy.daily_requests, self.day.requests)
+    }
+
+    pub fn daily_tokens_remaining(&self) -> Option<u64> {
+        remaining(self.policy.daily_tokens, self.day.tokens)
+    }
+
+    pub fn monthly_requests_remaining(&self) -> Option<u64> {
+        remaining(self.policy.monthly_requests, self.month.requests)
+    }
+
+    pub fn monthly_tokens_remaining(&self) -> Option<u64> {
+        remaining(self.policy.monthly_tokens, self.month.tokens)
+    }
+
+    /// Returns which quota is used up, if any.
+    pub fn exceeded(&self) -> Option<&'static str> {
+        if self.daily_requests_remaining() == Some(0) {
+            Some("daily request quota exceeded")
+        } else if self.daily_tokens_remaining() == Some(0) {
+            Some("daily token quota exceeded")
+        } else if self.monthly_requests_remaining() == Some(0) {
+            Some("monthly request quota exceeded")
+        } else if self.monthly_tokens_remaining() == Some(0) {
+            Some("monthly token quota exceeded")
+        } else {
+            None
+        }
+    }
+
+    pub fn apply_headers(&self, response: &mut HttpResponse) {
+        let headers = [
+            ("x-quota-daily-requests-remaining", self.daily_requests_remaining()),
+            ("x-quota-daily-tokens-remaining", self.daily_tokens_remaining()),
+            ("x-quota-monthly-requests-remaining", self.monthly_requests_remaining()),
+            ("x-quota-monthly-tokens-remaining", self.monthly_tokens_remaining()),
+        ];
+
+        for (name, value) in headers {
+            if let Some(value) = value {
+                response.headers_mut().insert(HeaderName::from_static(name), HeaderValue::from(value));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReportRow {
+    pub day: DateTime<Utc>,
+    pub model: String,
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub duration_ms: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub user_id: Uuid,
+    pub quota: QuotaStatus,
+    pub since: DateTime<Utc>,
+    pub daily: Vec<UsageReportRow>,
+}
+
+/// Reads a user's four counters (day requests, day tokens, month requests,
+/// month tokens) and, when ARGV[1] is 1 and no quota is used up, counts a
+/// request in the same step so concurrent requests can't overshoot.
+/// ARGV[2..5] are the limits (-1 for none) and ARGV[6..7] the day and month
+/// TTLs. Missing counters are seeded from ARGV[8..11] when given, with
+/// SET NX so a counter another call just seeded is kept; without seeds a
+/// missing counter returns {-1}.
+const COUNTERS_SCRIPT: &str = r#"
+local ttls = { ARGV[6], ARGV[6], ARGV[7], ARGV[7] }
+local counts = {}
+for i = 1, 4 do
+    if ARGV[7 + i] then
+        redis.call('SET', KEYS[i], ARGV[7 + i], 'NX', 'EX', ttls[i])
+    end
+    local count = redis.call('GET', KEYS[i])
+    if not count then
+        return { -1 }
+    end
+    counts[i] = tonumber(count)
+end
+
+for i = 1, 4 do
+    local limit = tonumber(ARGV[1 + i])
+    if limit >= 0 and counts[i] >= limit then
+        return { 0, counts[1], counts[2], counts[3], counts[4] }
+    end
+end
+
+if ARGV[1] == '1' then
+    counts[1] = redis.call('INCR', KEYS[1])
+    counts[3] = redis.call('INCR', KEYS[3])
+end
+return { 1, counts[1], counts[2], counts[3], counts[4] }
+"#;
+
+/// Adds ARGV[1] tokens to the counters in KEYS that exist. Missing ones are
+/// seeded later from `llm_usage`, which already has this call.
+const ADD_TOKENS_SCRIPT: &str = r#"
+for i = 1, #KEYS do
+    if redis.call('EXISTS', KEYS[i]) == 1 then
+        redis.call('INCRBY', KEYS[i], ARGV[1])
+    end
+end
+return 0
+"#;
+
+pub struct UsageTracker {
+    limits: UsageLimits,
+    counters_script: redis::Script,
+    add_tokens_script: redis::Script,
+}
+
+impl UsageTracker {
+    pub async fn new(db: &PostgresDb, limits: UsageLimits) -> Result<Self, Box<dyn std::error::Error>> {
+        db.batch_execute(SCHEMA).await?;
+        Ok(Self {
+            limits,
+            counters_script: redis::Script::new(COUNTERS_SCRIPT),
+            add_tokens_script: redis::Script::new(ADD_TOKENS_SCRIPT),
+        })
+    }
+
+    fn counter_key(user_id: &Uuid, period: Period, now: DateTime<Utc>, counter: &str) -> String {
+        format!("usage:{}:{}:{}", user_id, period.key(now), counter)
+    }
+
+    /// The counters as recorded in `llm_usage` since the period started.
+    async fn recorded(
+        &self,
+        db: &PostgresDb,
+        user_id: &Uuid,
+        period: Period,
+        now: DateTime<Utc>
+    ) -> Result<UsageCounters, Box<dyn std::error::Error>> {
+        let mut totals = db.query(
+            "SELECT COUNT(*), COALESCE(SUM(prompt_tokens + completion_tokens), 0)::BIGINT
+             FROM llm_usage WHERE user_id = $1 AND created_at >= $2",
+            &[user_id, &period.start(now)],
+            |row| Ok((row.try_get::<_, i64>(0)?, row.try_get::<_, i64>(1)?))
+        ).await?;
+        let (requests, tokens) = totals.pop().unwrap_or((0, 0));
+
+        Ok(UsageCounters {
+            requests: requests as u64,
+            tokens: tokens as u64,
+        })
+    }
+
+    /// Runs `COUNTERS_SCRIPT`, seeding the counters from `llm_usage` when
+    /// Redis has lost them. Returns whether the request was admitted. While
+    /// Redis is unreachable the check falls back to `llm_usage` alone.
+    async fn counters(
+        &self,
+        cache: &Cache,
+        db: &PostgresDb,
+        user: &User,
+        count: bool
+    ) -> Result<(bool, QuotaStatus), Box<dyn std::error::Error>> {
+        let now = Utc::now();
+        let policy = self.limits.policy_for(&user.role).clone();
+        let keys = [
+            Self::counter_key(&user.id, Period::Day, now, "requests"),
+            Self::counter_key(&user.id, Period::Day, now, "tokens"),
+            Self::counter_key(&user.id, Period::Month, now, "requests"),
+            Self::counter_key(&user.id, Period::Month, now, "tokens"),
+        ];
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+        let limit = |limit: Option<u64>| limit.map_or("-1".to_string(), |limit| limit.to_string());
+        let mut args = vec![
+            if count { "1" } else { "0" }.to_string(),
+            limit(policy.daily_requests),
+            limit(policy.daily_tokens),
+            limit(policy.monthly_requests),
+            limit(policy.monthly_tokens),
+            Period::Day.ttl_secs().to_string(),
+            Period::Month.ttl_secs().to_string(),
+        ];
+
+        let mut reply: redis::RedisResult<Vec<i64>> = cache.run_script(&self.counters_script, &keys, &args).await;
+        if matches!(reply.as_deref(), Ok([-1])) {
+            let day = self.recorded(db, &user.id, Period::Day, now).await?;
+            let month = self.recorded(db, &user.id, Period::Month, now).await?;
+            args.extend([day.requests, day.tokens, month.requests, month.tokens].map(|count| count.to_string()));
+            reply = cache.run_script(&self.counters_script, &keys, &args).await;
+        }
+
+        let status = |day, month| QuotaStatus { role: user.role.clone(), policy: policy.clone(), day, month };
+        match reply.as_deref() {
+            Ok(&[admitted, day_requests, day_tokens, month_requests, month_tokens]) => {
+                let counters = |requests: i64, tokens: i64| UsageCounters {
+                    requests: requests.max(0) as u64,
+                    tokens: tokens.max(0) as u64,
+                };
+                Ok((admitted == 1, status(counters(day_requests, day_tokens), counters(month_requests, month_tokens))))
+            }
+            other => {
+                if let Err(e) = other {
+                    log::warn!("Usage counters for user {} fall back to PostgreSQL until Redis recovers: {}", user.id, e);
+                }
+                let status = status(
+                    self.recorded(db, &user.id, Period::Day, now).await?,
+                    self.recorded(db, &user.id, Period::Month, now).await?
+                );
+                Ok((status.exceeded().is_none(), status))
+            }
+        }
+    }
+
+    pub async fn status(
+        &self,
+        cache: &Cache,
+        db: &PostgresDb,
+        user: &User
+    ) -> Result<QuotaStatus, Box<dyn std::error::Error>> {
+        Ok(self.counters(cache, db, user, false).await?.1)
+    }
+
+    /// Counts a new request unless one of the user's quotas is used up.
+    /// Returns whether it was admitted, with the counters.
+    pub async fn admit(
+        &self,
+        cache: &Cache,
+        db: &PostgresDb,
+        user: &User
+    ) -> Result<(bool, QuotaStatus), Box<dyn std::error::Error>> {
+        self.counters(cache, db, user, true).await
+    }
+
+    /// Stores an admitted request's usage and adds its tokens to the
+    /// counters; `admit` has already counted the request itself.
+    pub async fn record(
+        &self,
+        cache: &Cache,
+        db: &PostgresDb,
+        user: &User,
+        model: &str,
+        usage: &TokenUsage
+    ) -> Result<QuotaStatus, Box<dyn std::error::Error>> {
+        let now = Utc::now();
+        db.execute(
+            "INSERT INTO llm_usage (id, user_id, role, model, prompt_tokens, completion_tokens, duration_ms, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[
+                &Uuid::new_v4(),
+                &user.id,
+                &user.role,
+                &model,
+                &(usage.prompt_tokens as i64),
+                &(usage.completion_tokens as i64),
+                &(usage.duration_ms as i64),
+                &now,
+            ]
+        ).await?;
+
+        let tokens = usage.total_tokens();
+        if tokens > 0 {
+            let keys = [
+                Self::counter_key(&user.id, Period::Day, now, "tokens"),
+                Self::counter_key(&user.id, Period::Month, now, "tokens"),
+            ];
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            let added: redis::RedisResult<i64> = cache.run_script(&self.add_tokens_script, &keys, &[tokens.to_string()]).await;
+            if let Err(e) = added {
+                log::warn!("Usage counters for user {} are stale until Redis recovers: {}", user.id, e);
+            }
+        }
+
+        self.status(cache, db, user).await
+    }
+
+    pub async fn report(
+        &self,
+        cache: &Cache,
+        db: &PostgresDb,
+        user: &User,
+        days: i64
+    ) -> Result<UsageReport, Box<dyn std::error::Error>> {
+        let since = Period::Day.start(Utc::now()) - chrono::Duration::days(days - 1);
+
+        let daily = db.query(
+            "SELECT date_trunc('day', created_at) AS day, model, COUNT(*) AS requests,
+                    SUM(prompt_tokens)::BIGINT AS prompt_tokens,
+                    SUM(completion_tokens)::BIGINT AS completion_tokens,
+                    SUM(duration_ms)::BIGINT AS duration_ms
+             FROM llm_usage
+             WHERE user_id = $1 AND created_at >= $2
+             GROUP BY 1, 2
+             ORDER BY 1 DESC, 2",
+            &[&user.id, &since],
+            map_report_row
+        ).await?;
+
+        Ok(UsageReport {
+            user_id: user.id,
+            quota: self.status(cache, db, user).await?,
+            since,
+            daily,
+        })
+    }
+}
+
+fn map_report_row(row: &Row) -> Result<UsageReportRow, Box<dyn std::error::Error>> {
+    Ok(UsageReportRow {
+        day: row.try_get("day")?,
+        model: row.try_get("model")?,
+        requests: row.try_get("requests")?,
+        prompt_tokens: row.try_get("prompt_tokens")?,
+        completion_tokens: row.try_get("completion_tokens")?,
+        duration_ms: row.try_get("duration_ms")?,
+    })
+}
+
+/// Resolves the caller and counts the request, or rejects it with 429 when
+/// any of the role's quotas is used up.
+pub async fn enforce_quota(
+    tracker: &UsageTracker,
+    cache: &Cache,
+    db: &PostgresDb,
+    session: &Session
+) -> Result<User, HttpResponse> {
+    let user = match get_user_from_session(session) {
+        Some(user) => user,
+        None => {
+            return Err(response_unauthorized("unauthorized: no user in session"));
+        }
+    };
+
+    match tracker.admit(cache, db, &user).await {
+        Ok((true, _)) => Ok(user),
+        Ok((false, status)) => {
+            let mut response = response_too_many_requests(status.exceeded().unwrap_or("quota exceeded"));
+            status.apply_headers(&mut response);
+            Err(response)
+        }
+        Err(err) => Err(response_internal_server_error(err.to_string().as_str())),
+    }
+}
+
+/// Records the usage of a finished generation and attaches the remaining
+/// quota headers to its response. Failed generations count as a request,
+/// with whatever tokens they used before failing.
+pub async fn finish_usage(
+    tracker: &UsageTracker,
+    cache: &Cache,
+    db: &PostgresDb,
+    user: &User,
+    model: &str,
+    usage: &TokenUsage,
+    mut response: HttpResponse
+) -> HttpResponse {
+    match tracker.record(cache, db, user, model, usage).await {
+        Ok(status) => status.apply_headers(&mut response),
+        Err(err) => log::error!("Failed to record LLM usage for user {}: {}", user.id, err),
+    }
+    response
+}
+
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    days: Option<i64>,
+}
+
+pub async fn usage_report(
+    tracker: web::Data<UsageTracker>,
+    cache: web::Data<Cache>,
+    db: web::Data<PostgresDb>,
+    session: Session,
+    query: web::Query<UsageQuery>
+) -> HttpResponse {
+    let user = match get_user_from_session(&session) {
+        Some(user) => user,
+        None => {
+            return response_unauthorized("unauthorized: no user in session");
+        }
+    };
+
+    let days = query.days.unwrap_or(DEFAULT_REPORT_DAYS).clamp(1, MAX_REPORT_DAYS);
+
+    match tracker.report(&cache, &db, &user, days).await {
+        Ok(report) => {
+            let mut response = response_ok("usage retrieved successfully", &report);
+            report.quota.apply_headers(&mut response);
+            response
+        }
+        Err(err) => response_internal_server_error(err.to_string().as_str()),
+    }
+}
@@ -1,10 +1,12 @@
 use actix_web::{ web, Error, HttpRequest, HttpResponse };
 use actix_web_actors::ws;
-use actix::{ Actor, StreamHandler, Addr, System, AsyncContext};
+use actix::{ Actor, StreamHandler, AsyncContext};
 use actix::prelude::*;
 
 use actix_session::Session;
 
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::time::Duration;
 
 use uuid::Uuid;
@@ -25,22 +27,65 @@ struct WsMessage {
     data: serde_json::Value,
 }
 
+/// A server-initiated message for a connection, already serialised.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct WsNotification(pub String);
+
+/// Open connections by user, so background work can push updates to the
+/// user that started it.
+#[derive(Default)]
+pub struct WsRegistry {
+    connections: Mutex<HashMap<Uuid, HashMap<Uuid, Recipient<WsNotification>>>>,
+}
+
+impl WsRegistry {
+    fn register(&self, user_id: Uuid, connection_id: Uuid, recipient: Recipient<WsNotification>) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.entry(user_id).or_default().insert(connection_id, recipient);
+    }
+
+    fn unregister(&self, user_id: Uuid, connection_id: Uuid) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(user_connections) = connections.get_mut(&user_id) {
+            user_connections.remove(&connection_id);
+            if user_connections.is_empty() {
+                connections.remove(&user_id);
+            }
+        }
+    }
+
+    /// Sends `{ action, data }` to every connection of the user. Users
+    /// without an open connection simply miss the update.
+    pub fn send_to_user(&self, user_id: &Uuid, action: &str, data: serde_json::Value) {
+        let message = json!({ "action": action, "data": data }).to_string();
+        let connections = self.connections.lock().unwrap();
+
+        if let Some(user_connections) = connections.get(user_id) {
+            for recipient in user_connections.values() {
+                recipient.do_send(WsNotification(message.clone()));
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct WsConnection {
     pub id: uuid::Uuid,
     pub connected_at: std::time::SystemTime,
     pub user: Option<User>,
-
+    registry: web::Data<WsRegistry>,
 }
 
 
 impl WsConnection {
-    pub fn new(session: Session) -> Self {
+    pub fn new(session: Session, registry: web::Data<WsRegistry>) -> Self {
         let user = get_user_from_session(&session);
         Self {
             id: uuid::Uuid::new_v4(),
             connected_at: std::time::SystemTime::now(),
             user,
+            registry,
         }
     }
 
@@ -81,10 +126,27 @@ impl WsConnection {
 
 impl Actor for WsConnection {
     type Context = ws::WebsocketContext<Self>;
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(user) = &self.user {
+            self.registry.unregister(user.id, self.id);
+        }
+    }
+}
+
+impl Handler<WsNotification> for WsConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsNotification, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConnection {
     fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(user) = &self.user {
+            self.registry.register(user.id, self.id, ctx.address().recipient());
+        }
         self.start_connection_timer(ctx);
         self.send_welcome_message(ctx);
     }
@@ -135,25 +197,26 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConnection {
 pub async fn websocket_handler(
     req: HttpRequest,
     stream: web::Payload,
-    session: Session
+    session: Session,
+    registry: web::Data<WsRegistry>
 ) -> Result<HttpResponse, Error> {
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("Bearer ") {
-                let token = &auth_str[7..];
-                if validate_token(token) {
-                    let connection = WsConnection::new(session);
-                    
-                    let local_time: DateTime<Local> = connection.connected_at.into();
-                    println!(
-                        "New websocket connection established - ID: {} at: {}", 
-                        connection.id,
-                        local_time.format("%B %d, %Y at %H:%M:%S").to_string()
-                    );
-                    return ws::start(connection, &req, stream);
-                }
-            }
-        }
+    let has_valid_token = req.headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .is_some_and(validate_token);
+
+    // Wallet sign-in keeps the user in the session instead of sending a token.
+    if has_valid_token || wallet_session_user(&session).is_some() {
+        let connection = WsConnection::new(session, registry);
+
+        let local_time: DateTime<Local> = connection.connected_at.into();
+        println!(
+            "New websocket connection established - ID: {} at: {}", 
+            connection.id,
+            local_time.format("%B %d, %Y at %H:%M:%S")
+        );
+        return ws::start(connection, &req, stream);
     }
     Ok(response_unauthorized("unauthorized: invalid or missing authorization token"))
 }
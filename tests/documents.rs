@@ -0,0 +1,55 @@
+//! Chunking and similarity scoring, which need no database or model.
+
+use rust_api::documents::*;
+
+#[test]
+fn chunk_offsets_span_exactly_the_trimmed_content() {
+    let text = "  alpha beta gamma\n\n  delta epsilon zeta eta theta  ";
+    let chunks = chunk_text(text, 16, 4);
+
+    assert!(chunks.len() > 1);
+    for (i, chunk) in chunks.iter().enumerate() {
+        assert_eq!(chunk.index, i);
+        assert!(!chunk.content.is_empty());
+        assert_eq!(chunk.content, chunk.content.trim());
+        assert_eq!(&text[chunk.start..chunk.end], chunk.content);
+    }
+    assert_eq!(chunks[0].start, 2);
+}
+
+#[test]
+fn chunks_cover_the_text_with_overlap() {
+    let text = "one two three four five six seven eight nine ten eleven twelve";
+    let chunks = chunk_text(text, 20, 8);
+
+    assert_eq!(chunks.first().unwrap().start, 0);
+    assert_eq!(chunks.last().unwrap().end, text.len());
+    for pair in chunks.windows(2) {
+        // Each chunk starts at or before the end of the previous one.
+        assert!(pair[1].start <= pair[0].end);
+        assert!(pair[1].start > pair[0].start);
+    }
+}
+
+#[test]
+fn chunks_respect_char_boundaries() {
+    let text = "héllo wörld ünïcödé ñoño çàfé";
+    for chunk in chunk_text(text, 5, 2) {
+        assert_eq!(&text[chunk.start..chunk.end], chunk.content);
+    }
+
+    assert!(chunk_text("   \n\t ", 10, 2).is_empty());
+    assert!(chunk_text("", 10, 2).is_empty());
+}
+
+#[test]
+fn cosine_similarity_of_vectors() {
+    assert!((cosine_similarity(&[1.0, 2.0, 3.0], &[2.0, 4.0, 6.0]) - 1.0).abs() < 1e-9);
+    assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-9);
+    assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-9);
+
+    // Mismatched, empty and zero vectors score 0.
+    assert_eq!(cosine_similarity(&[1.0, 2.0], &[1.0]), 0.0);
+    assert_eq!(cosine_similarity(&[], &[]), 0.0);
+    assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+}
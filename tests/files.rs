@@ -0,0 +1,181 @@
+//! File endpoints against `POSTGRES_TEST_URL`, with blobs on local disk.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Arc;
+
+use actix_session::{ storage::CookieSessionStore, Session, SessionMiddleware };
+use actix_web::{ cookie::{ Cookie, Key }, http::StatusCode, test, web, App, HttpResponse };
+use uuid::Uuid;
+
+use rust_api::files::*;
+use rust_api::local_storage::LocalStorage;
+use rust_api::malware_scan::*;
+use rust_api::media::*;
+use rust_api::models::FileRecord;
+use rust_api::object_store::ObjectStore;
+use rust_api::postgres_db::PostgresDb;
+use rust_api::upload_policy::UploadPolicies;
+use rust_api::uploads::Uploads;
+
+/// Opens a session for the user id in the path.
+async fn login(session: Session, id: web::Path<Uuid>) -> HttpResponse {
+    session.insert("user_id", id.into_inner()).unwrap();
+    session.insert("username", "files-test").unwrap();
+    session.insert("user_role", "user").unwrap();
+    HttpResponse::Ok().finish()
+}
+
+struct Services {
+    db: PostgresDb,
+    store: FileStore,
+    root: std::path::PathBuf,
+}
+
+async fn test_services() -> Option<Services> {
+    let Ok(url) = env::var("POSTGRES_TEST_URL") else {
+        eprintln!("skipping file endpoints: POSTGRES_TEST_URL not set");
+        return None;
+    };
+
+    let db = PostgresDb::new(&url).await.unwrap();
+    let store = FileStore::new(&db, "files-test".to_string(), LinkPolicy::default()).await.unwrap();
+    // Quotas count open upload sessions too.
+    Uploads::new(&db, 4 * 1024 * 1024).await.unwrap();
+    let root = env::temp_dir().join(format!("files-test-{}", Uuid::new_v4()));
+    Some(Services { db, store, root })
+}
+
+macro_rules! app {
+    ($services:expr) => {{
+        let Services { db, store, root } = $services;
+        let media = MediaPipeline::new(&db, MediaConfig::default()).await.unwrap();
+        let storage: Arc<dyn ObjectStore> = Arc::new(LocalStorage::new(root).unwrap());
+        let scanner: Arc<dyn MalwareScanner> = Arc::new(NoopScanner);
+        let policies = UploadPolicies::from_json(r#"{"files": {"default": {"allowed_types": ["text/plain"]}}}"#).unwrap();
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(db))
+                .app_data(web::Data::new(store))
+                .app_data(web::Data::new(media))
+                .app_data(web::Data::new(policies))
+                .app_data(web::Data::<dyn ObjectStore>::from(storage))
+                .app_data(web::Data::<dyn MalwareScanner>::from(scanner))
+                .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+                .route("/login/{id}", web::post().to(login))
+                .route("/files", web::get().to(list_files))
+                .route("/files", web::post().to(upload_files))
+                .route("/files/{id}", web::get().to(get_file))
+        ).await
+    }};
+}
+
+fn record(owner_id: Uuid, file_name: &str) -> FileRecord {
+    let id = Uuid::new_v4();
+    FileRecord {
+        id,
+        owner_id,
+        file_name: file_name.to_string(),
+        content_type: "text/plain".to_string(),
+        size: 1,
+        cache_control: None,
+        metadata: BTreeMap::new(),
+        tags: BTreeMap::new(),
+        scan_status: SCAN_CLEAN.to_string(),
+        container: "files-test".to_string(),
+        blob_name: format!("{}/{}", owner_id, id),
+        created_at: chrono::Utc::now(),
+    }
+}
+
+fn file_names(body: web::Bytes) -> Vec<String> {
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    body["data"]["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["file_name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+macro_rules! session_for {
+    ($app:expr, $id:expr) => {{
+        let req = test::TestRequest::post().uri(&format!("/login/{}", $id)).to_request();
+        let resp = test::call_service(&$app, req).await;
+        resp.response().cookies().next().unwrap().into_owned()
+    }};
+}
+
+fn get(uri: &str, cookie: &Cookie<'static>) -> test::TestRequest {
+    test::TestRequest::get().uri(uri).cookie(cookie.clone())
+}
+
+#[actix_web::test]
+async fn files_of_other_users_are_not_found() {
+    let Some(services) = test_services().await else {
+        return;
+    };
+    let (owner, other) = (Uuid::new_v4(), Uuid::new_v4());
+    let file = record(owner, "mine.txt");
+    services.store.insert(&services.db, &file).await.unwrap();
+    let app = app!(services);
+
+    let owner_cookie = session_for!(app, owner);
+    let other_cookie = session_for!(app, other);
+    let uri = format!("/files/{}", file.id);
+
+    assert_eq!(test::call_service(&app, get(&uri, &owner_cookie).to_request()).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, get(&uri, &other_cookie).to_request()).await.status(), StatusCode::NOT_FOUND);
+    assert!(file_names(test::call_and_read_body(&app, get("/files", &other_cookie).to_request()).await).is_empty());
+}
+
+#[actix_web::test]
+async fn list_prefixes_match_literally() {
+    let Some(services) = test_services().await else {
+        return;
+    };
+    let owner = Uuid::new_v4();
+    for name in ["100%_report.txt", "100abc.txt", "a_b.txt", "axb.txt", "back\\slash.txt", "backslash.txt"] {
+        services.store.insert(&services.db, &record(owner, name)).await.unwrap();
+    }
+    let app = app!(services);
+    let cookie = session_for!(app, owner);
+
+    let list = |prefix: &str| get(&format!("/files?prefix={}", prefix), &cookie);
+    assert_eq!(file_names(test::call_and_read_body(&app, list("100%25").to_request()).await), ["100%_report.txt"]);
+    assert_eq!(file_names(test::call_and_read_body(&app, list("a_").to_request()).await), ["a_b.txt"]);
+    assert_eq!(file_names(test::call_and_read_body(&app, list("back%5C").to_request()).await), ["back\\slash.txt"]);
+    assert_eq!(file_names(test::call_and_read_body(&app, list("").to_request()).await).len(), 6);
+}
+
+#[actix_web::test]
+async fn failed_uploads_keep_none_of_their_files() {
+    let Some(services) = test_services().await else {
+        return;
+    };
+    let root = services.root.clone();
+    let owner = Uuid::new_v4();
+    let app = app!(services);
+    let cookie = session_for!(app, owner);
+
+    let body = "--XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"good.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        fine\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"bad.html\"\r\n\
+        Content-Type: text/html\r\n\r\n\
+        <html></html>\r\n\
+        --XyZ--\r\n";
+    let req = test::TestRequest::post()
+        .uri("/files")
+        .cookie(cookie.clone())
+        .insert_header(("Content-Type", "multipart/form-data; boundary=XyZ"))
+        .set_payload(body)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    assert!(file_names(test::call_and_read_body(&app, get("/files", &cookie).to_request()).await).is_empty());
+    let blobs = root.join("objects").join("files-test").join(owner.to_string());
+    assert!(std::fs::read_dir(&blobs).map_or(true, |mut entries| entries.next().is_none()));
+}
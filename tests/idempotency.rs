@@ -0,0 +1,118 @@
+//! Body handling without a server, plus replays against `REDIS_TEST_URL`.
+
+use std::env;
+use std::time::Duration;
+
+use actix_session::{ storage::CookieSessionStore, SessionMiddleware };
+use actix_web::{ cookie::Key, dev::ServiceResponse, http::StatusCode, test, web, App, HttpResponse };
+use uuid::Uuid;
+
+use rust_api::middleware::*;
+use rust_api::redis_client::*;
+
+/// Echoes the body length with a fresh id, so replays are recognisable.
+async fn echo(body: web::Bytes) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "length": body.len(), "id": Uuid::new_v4() }))
+}
+
+macro_rules! app {
+    ($cache:expr) => {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new($cache))
+                .app_data(web::PayloadConfig::new(4 * 1024 * 1024))
+                .wrap(Idempotency::default().body_limit(1024))
+                .wrap(Auth)
+                .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+                .route("/", web::post().to(echo))
+        ).await
+    };
+}
+
+fn post(key: &str, token: &str, body: Vec<u8>) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri("/")
+        .peer_addr("10.0.0.1:40000".parse().unwrap())
+        .insert_header((IDEMPOTENCY_KEY_HEADER, key))
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .set_payload(body)
+}
+
+async fn json(resp: ServiceResponse) -> serde_json::Value {
+    serde_json::from_slice(&test::read_body(resp).await).unwrap()
+}
+
+#[actix_web::test]
+async fn large_and_multipart_bodies_reach_the_handler_intact() {
+    let config = CacheConfig { connection_timeout: Duration::from_millis(200), ..CacheConfig::default() };
+    let cache = Cache::with_config("redis://127.0.0.1:1", config).await.unwrap();
+    let app = app!(cache);
+
+    let large = post("large", "valid_token", vec![7; 1024 * 1024]).to_request();
+    let resp = test::call_service(&app, large).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(json(resp).await["length"], 1024 * 1024);
+
+    let multipart = post("multipart", "valid_token", vec![1; 300 * 1024])
+        .insert_header(("Content-Type", "multipart/form-data; boundary=abc"))
+        .to_request();
+    let resp = test::call_service(&app, multipart).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(json(resp).await["length"], 300 * 1024);
+
+    let small = post("small", "valid_token", b"{}".to_vec()).to_request();
+    assert_eq!(json(test::call_service(&app, small).await).await["length"], 2);
+}
+
+async fn test_cache() -> Option<Cache> {
+    let Ok(url) = env::var("REDIS_TEST_URL") else {
+        eprintln!("skipping idempotency replays: REDIS_TEST_URL not set");
+        return None;
+    };
+    Some(Cache::new(&url).await.unwrap())
+}
+
+#[actix_web::test]
+async fn repeated_keys_replay_the_first_response() {
+    let Some(cache) = test_cache().await else {
+        return;
+    };
+    let app = app!(cache);
+    let key = Uuid::new_v4().to_string();
+
+    let first = json(test::call_service(&app, post(&key, "valid_token", b"{\"a\":1}".to_vec()).to_request()).await).await;
+    let resp = test::call_service(&app, post(&key, "valid_token", b"{\"a\":1}".to_vec()).to_request()).await;
+    assert_eq!(resp.headers().get("idempotent-replayed").unwrap(), "true");
+    assert_eq!(json(resp).await, first);
+
+    // The same key with another body is refused.
+    let resp = test::call_service(&app, post(&key, "valid_token", b"{\"a\":2}".to_vec()).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // A large body is matched on its length rather than buffered.
+    let large_key = Uuid::new_v4().to_string();
+    let first = json(test::call_service(&app, post(&large_key, "valid_token", vec![1; 4096]).to_request()).await).await;
+    let replay = json(test::call_service(&app, post(&large_key, "valid_token", vec![2; 4096]).to_request()).await).await;
+    assert_eq!(replay, first);
+    let resp = test::call_service(&app, post(&large_key, "valid_token", vec![1; 4097]).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[actix_web::test]
+async fn keys_are_scoped_to_the_authenticated_caller() {
+    let Some(cache) = test_cache().await else {
+        return;
+    };
+    let app = app!(cache);
+    let key = Uuid::new_v4().to_string();
+
+    let first = json(test::call_service(&app, post(&key, "valid_token", b"{}".to_vec()).to_request()).await).await;
+
+    // Forwarded headers don't select another caller's stored response.
+    let req = post(&key, "valid_token", b"{}".to_vec()).insert_header(("X-Forwarded-For", "192.0.2.7")).to_request();
+    assert_eq!(json(test::call_service(&app, req).await).await, first);
+
+    // An unauthenticated request never reaches the stored response.
+    let resp = test::call_service(&app, post(&key, "made-up", b"{}".to_vec()).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
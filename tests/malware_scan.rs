@@ -0,0 +1,88 @@
+//! ClamAV reply parsing, which needs no scanner, and scan results against
+//! `POSTGRES_TEST_URL`.
+
+use std::collections::BTreeMap;
+use std::env;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use uuid::Uuid;
+
+use rust_api::files::*;
+use rust_api::malware_scan::*;
+use rust_api::media::*;
+use rust_api::memory_storage::MemoryStorage;
+use rust_api::models::FileRecord;
+use rust_api::object_store::*;
+use rust_api::postgres_db::PostgresDb;
+use rust_api::upload_policy::UploadPolicy;
+use rust_api::uploads::Uploads;
+
+#[test]
+fn clamav_replies_become_verdicts() {
+    assert_eq!(parse_clamav_reply("stream: OK\0").unwrap(), ScanVerdict::Clean);
+    assert_eq!(parse_clamav_reply("OK").unwrap(), ScanVerdict::Clean);
+    assert_eq!(
+        parse_clamav_reply("stream: Eicar-Signature FOUND\0").unwrap(),
+        ScanVerdict::Infected("Eicar-Signature".to_string())
+    );
+
+    assert!(parse_clamav_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    assert!(parse_clamav_reply("").is_err());
+    assert!(parse_clamav_reply("stream: OKAY").is_err());
+}
+
+/// Flags everything it is given.
+struct EicarScanner;
+
+#[async_trait(?Send)]
+impl MalwareScanner for EicarScanner {
+    fn name(&self) -> &'static str {
+        "eicar"
+    }
+
+    async fn scan(&self, _data: ByteStream) -> Result<ScanVerdict, Box<dyn std::error::Error>> {
+        Ok(ScanVerdict::Infected("Eicar-Signature".to_string()))
+    }
+}
+
+#[actix_web::test]
+async fn infected_files_are_deleted_and_free_their_quota() {
+    let Ok(url) = env::var("POSTGRES_TEST_URL") else {
+        eprintln!("skipping scan results: POSTGRES_TEST_URL not set");
+        return;
+    };
+    let db = PostgresDb::new(&url).await.unwrap();
+    let store = FileStore::new(&db, "scan-test".to_string(), LinkPolicy::default()).await.unwrap();
+    Uploads::new(&db, 4 * 1024 * 1024).await.unwrap();
+    let media = MediaPipeline::new(&db, MediaConfig::default()).await.unwrap();
+    let storage = MemoryStorage::new();
+
+    let (owner_id, id) = (Uuid::new_v4(), Uuid::new_v4());
+    let file = FileRecord {
+        id,
+        owner_id,
+        file_name: "eicar.txt".to_string(),
+        content_type: "text/plain".to_string(),
+        size: 68,
+        cache_control: None,
+        metadata: BTreeMap::new(),
+        tags: BTreeMap::new(),
+        scan_status: SCAN_PENDING.to_string(),
+        container: "scan-test".to_string(),
+        blob_name: format!("{}/{}", owner_id, id),
+        created_at: chrono::Utc::now(),
+    };
+    storage.put(&file.container, &file.blob_name, &PutOptions::new("text/plain"), Bytes::from(vec![b'x'; 68])).await.unwrap();
+    store.insert(&db, &file).await.unwrap();
+
+    scan_file(&EicarScanner, &db, &storage, &media, &file).await.unwrap();
+
+    assert!(store.find(&db, &owner_id, &id).await.unwrap().is_none());
+    assert!(matches!(storage.get(&file.container, &file.blob_name).await, Err(ObjectStoreError::NotFound { .. })));
+
+    let policy = UploadPolicy { quota_bytes: Some(100), ..UploadPolicy::default() };
+    let reservation = policy.reserve_quota(&db, &owner_id, None).await.unwrap().unwrap();
+    assert_eq!(reservation.bytes, 100);
+    reservation.release(&db).await;
+}
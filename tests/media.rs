@@ -0,0 +1,81 @@
+//! Rendition rendering, which needs no database or storage.
+
+use std::io::Cursor;
+
+use image::codecs::png::PngEncoder;
+use image::{ ImageEncoder, ImageFormat, Rgb, RgbImage };
+
+use rust_api::media::*;
+
+const RED: Rgb<u8> = Rgb([255, 0, 0]);
+const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// A 40x20 PNG, red in its top-left corner and blue elsewhere, with an
+/// `eXIf` chunk saying it must be rotated 90° clockwise for display.
+fn rotated_png() -> Vec<u8> {
+    let image = RgbImage::from_fn(40, 20, |x, y| if x < 4 && y < 4 { RED } else { BLUE });
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png).write_image(image.as_raw(), 40, 20, image::ExtendedColorType::Rgb8).unwrap();
+
+    // Big-endian TIFF header, one IFD entry: Orientation (0x0112) = 6.
+    let exif: &[u8] = &[
+        b'M', b'M', 0, 42, 0, 0, 0, 8,
+        0, 1,
+        0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0,
+        0, 0, 0, 0,
+    ];
+    let mut chunk = (exif.len() as u32).to_be_bytes().to_vec();
+    let mut body = b"eXIf".to_vec();
+    body.extend_from_slice(exif);
+    chunk.extend_from_slice(&body);
+    chunk.extend_from_slice(&crc32(&body).to_be_bytes());
+
+    // Decoders only read metadata that comes before the image data.
+    let idat = png.windows(4).position(|window| window == b"IDAT").unwrap() - 4;
+    png.splice(idat..idat, chunk);
+    png
+}
+
+#[test]
+fn renditions_are_upright_webp_and_never_upscaled() {
+    let renditions = render_renditions(&rotated_png(), &[10, 100]).unwrap();
+
+    let sizes: Vec<(&str, u32, u32)> = renditions
+        .iter()
+        .map(|rendition| (rendition.name.as_str(), rendition.width, rendition.height))
+        .collect();
+    assert_eq!(sizes, [("full", 20, 40), ("thumb-10", 5, 10), ("thumb-100", 20, 40)]);
+
+    for rendition in &renditions {
+        assert_eq!(image::guess_format(&rendition.data).unwrap(), ImageFormat::WebP);
+        let decoded = image::load(Cursor::new(&rendition.data), ImageFormat::WebP).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (rendition.width, rendition.height));
+    }
+
+    // Rotated clockwise, the red corner moves to the top right.
+    let full = image::load(Cursor::new(&renditions[0].data), ImageFormat::WebP).unwrap().to_rgb8();
+    assert_eq!(*full.get_pixel(19, 0), RED);
+    assert_eq!(*full.get_pixel(0, 0), BLUE);
+}
+
+#[test]
+fn undecodable_sources_are_rejected() {
+    assert!(render_renditions(b"not an image", &[10]).is_err());
+}
+
+#[test]
+fn renditions_are_stored_next_to_the_original() {
+    assert_eq!(rendition_blob_name("owner/file", "thumb-128"), "owner/file.thumb-128.webp");
+    assert_eq!(rendition_blob_name("owner/file", "full"), "owner/file.full.webp");
+}
@@ -0,0 +1,262 @@
+//! Behaviour every `ObjectStore` driver must share. The memory and local
+//! drivers always run; S3 and Azure run when their `*_TEST_*` variables are
+//! set (e.g. against a local MinIO container).
+
+use std::env;
+
+use bytes::Bytes;
+use futures::stream;
+use md5::{ Digest, Md5 };
+use uuid::Uuid;
+
+use rust_api::azure_storage::AzureStorage;
+use rust_api::local_storage::LocalStorage;
+use rust_api::memory_storage::MemoryStorage;
+use rust_api::object_store::*;
+use rust_api::s3_storage::S3Storage;
+
+async fn read_all(stream: Result<ByteStream, ObjectStoreError>) -> Vec<u8> {
+    collect_bytes(stream.expect("object should be readable")).await.expect("stream should complete").to_vec()
+}
+
+async fn check_put_get_head(store: &dyn ObjectStore, container: &str, run: &str) {
+    let key = format!("{}/docs/hello.txt", run);
+
+    let meta = store.put(container, &key, &PutOptions::new("text/plain"), Bytes::from_static(b"hello world")).await.unwrap();
+    assert_eq!(meta.key, key);
+    assert_eq!(meta.size, 11);
+
+    let head = store.head(container, &key).await.unwrap();
+    assert_eq!(head.size, 11);
+    assert_eq!(head.content_type.as_deref(), Some("text/plain"));
+
+    assert_eq!(read_all(store.get(container, &key).await).await, b"hello world");
+}
+
+async fn check_properties(store: &dyn ObjectStore, container: &str, run: &str) {
+    let key = format!("{}/properties.json", run);
+    let mut options = PutOptions::new("application/json");
+    options.cache_control = Some("public, max-age=3600".to_string());
+    options.metadata.insert("origin".to_string(), "conformance".to_string());
+    options.tags.insert("suite".to_string(), "storage".to_string());
+
+    store.put(container, &key, &options, Bytes::from_static(b"{}")).await.unwrap();
+
+    let head = store.head(container, &key).await.unwrap();
+    assert_eq!(head.content_type.as_deref(), Some("application/json"));
+    assert_eq!(head.cache_control.as_deref(), Some("public, max-age=3600"));
+    assert_eq!(head.metadata.get("origin").map(String::as_str), Some("conformance"));
+}
+
+async fn check_overwrite(store: &dyn ObjectStore, container: &str, run: &str) {
+    let key = format!("{}/overwrite.bin", run);
+
+    store.put(container, &key, &PutOptions::new("application/octet-stream"), Bytes::from_static(b"first version")).await.unwrap();
+    store.put(container, &key, &PutOptions::new("text/csv"), Bytes::from_static(b"second")).await.unwrap();
+
+    let head = store.head(container, &key).await.unwrap();
+    assert_eq!(head.size, 6);
+    assert_eq!(head.content_type.as_deref(), Some("text/csv"));
+    assert_eq!(read_all(store.get(container, &key).await).await, b"second");
+}
+
+async fn check_put_stream(store: &dyn ObjectStore, container: &str, run: &str) {
+    let key = format!("{}/streamed.bin", run);
+    let chunks: Vec<Result<Bytes, ObjectStoreError>> = (0..5u8)
+        .map(|i| Ok(Bytes::from(vec![i; 1000])))
+        .collect();
+
+    let meta = store
+        .put_stream(container, &key, &PutOptions::new("application/octet-stream"), Box::pin(stream::iter(chunks))).await
+        .unwrap();
+    assert_eq!(meta.size, 5000);
+
+    let data = read_all(store.get(container, &key).await).await;
+    assert_eq!(data.len(), 5000);
+    assert_eq!(data[0], 0);
+    assert_eq!(data[4999], 4);
+
+    // Large enough to go up in several parts where the backend uses them.
+    let key = format!("{}/streamed-large.bin", run);
+    let chunks: Vec<Result<Bytes, ObjectStoreError>> = (0..20u8)
+        .map(|i| Ok(Bytes::from(vec![i; 1024 * 1024])))
+        .collect();
+
+    let meta = store
+        .put_stream(container, &key, &PutOptions::new("application/octet-stream"), Box::pin(stream::iter(chunks))).await
+        .unwrap();
+    assert_eq!(meta.size, 20 * 1024 * 1024);
+
+    let data = read_all(store.get(container, &key).await).await;
+    assert_eq!(data.len(), 20 * 1024 * 1024);
+    assert_eq!(data[8 * 1024 * 1024 - 1], 7);
+    assert_eq!(data[8 * 1024 * 1024], 8);
+    assert_eq!(data[data.len() - 1], 19);
+}
+
+async fn check_empty_object(store: &dyn ObjectStore, container: &str, run: &str) {
+    let key = format!("{}/empty", run);
+
+    store.put(container, &key, &PutOptions::new("application/octet-stream"), Bytes::new()).await.unwrap();
+
+    assert_eq!(store.head(container, &key).await.unwrap().size, 0);
+    assert!(read_all(store.get(container, &key).await).await.is_empty());
+}
+
+async fn check_ranges(store: &dyn ObjectStore, container: &str, run: &str) {
+    let key = format!("{}/range.txt", run);
+    store.put(container, &key, &PutOptions::new("text/plain"), Bytes::from_static(b"0123456789")).await.unwrap();
+
+    assert_eq!(read_all(store.get_range(container, &key, 2..5).await).await, b"234");
+    assert_eq!(read_all(store.get_range(container, &key, 0..1).await).await, b"0");
+    assert_eq!(read_all(store.get_range(container, &key, 7..100).await).await, b"789");
+
+    match store.get_range(container, &key, 10..12).await {
+        Err(ObjectStoreError::InvalidRange { size, .. }) => assert_eq!(size, 10),
+        other => panic!("expected InvalidRange, got {:?}", other.map(|_| ())),
+    }
+    assert!(matches!(store.get_range(container, &key, 5..5).await, Err(ObjectStoreError::InvalidRange { .. })));
+}
+
+async fn check_list(store: &dyn ObjectStore, container: &str, run: &str) {
+    for name in ["list/b.txt", "list/a.txt", "list/nested/c.txt", "listing.txt"] {
+        let key = format!("{}/{}", run, name);
+        store.put(container, &key, &PutOptions::new("text/plain"), Bytes::from(name.to_string())).await.unwrap();
+    }
+
+    let keys: Vec<String> = store
+        .list(container, &format!("{}/list/", run)).await
+        .unwrap()
+        .into_iter()
+        .map(|meta| meta.key)
+        .collect();
+    assert_eq!(keys, vec![format!("{}/list/a.txt", run), format!("{}/list/b.txt", run), format!("{}/list/nested/c.txt", run)]);
+
+    let listed = store.list(container, &format!("{}/list", run)).await.unwrap();
+    assert_eq!(listed.len(), 4);
+    assert_eq!(listed[0].size, "list/a.txt".len() as u64);
+
+    assert!(store.list(container, &format!("{}/nothing-here/", run)).await.unwrap().is_empty());
+}
+
+async fn check_missing(store: &dyn ObjectStore, container: &str, run: &str) {
+    let key = format!("{}/missing.txt", run);
+
+    assert!(store.head(container, &key).await.unwrap_err().is_not_found());
+    assert!(store.get(container, &key).await.err().expect("get should fail").is_not_found());
+    assert!(store.get_range(container, &key, 0..1).await.err().expect("range should fail").is_not_found());
+}
+
+async fn check_delete(store: &dyn ObjectStore, container: &str, run: &str) {
+    let key = format!("{}/delete-me.txt", run);
+    store.put(container, &key, &PutOptions::new("text/plain"), Bytes::from_static(b"bye")).await.unwrap();
+
+    store.delete(container, &key).await.unwrap();
+    assert!(store.head(container, &key).await.unwrap_err().is_not_found());
+    assert!(store.list(container, &key).await.unwrap().is_empty());
+
+    // Deleting again is a no-op.
+    store.delete(container, &key).await.unwrap();
+}
+
+async fn check_blocks(store: &dyn ObjectStore, container: &str, run: &str) {
+    let key = format!("{}/blocks.txt", run);
+    let parts: [&[u8]; 3] = [b"alpha-", b"beta-", b"gamma"];
+
+    // Staged out of order and one block re-sent, as a resumed upload would.
+    for index in [2, 0, 1, 0] {
+        let data = Bytes::from_static(parts[index]);
+        let md5: [u8; 16] = Md5::digest(&data).into();
+        store.stage_block(container, &key, &block_id(index), data, md5).await.unwrap();
+    }
+
+    let block_ids: Vec<String> = (0..3).map(block_id).collect();
+    let meta = store.commit_blocks(container, &key, &PutOptions::new("text/plain"), &block_ids).await.unwrap();
+    assert_eq!(meta.size, 16);
+    assert_eq!(read_all(store.get(container, &key).await).await, b"alpha-beta-gamma");
+
+    let aborted = format!("{}/aborted.txt", run);
+    let data = Bytes::from_static(b"never committed");
+    let md5: [u8; 16] = Md5::digest(&data).into();
+    store.stage_block(container, &aborted, &block_id(0), data, md5).await.unwrap();
+    store.abort_blocks(container, &aborted, &[block_id(0)]).await.unwrap();
+    assert!(store.head(container, &aborted).await.unwrap_err().is_not_found());
+}
+
+async fn check_invalid_keys(store: &dyn ObjectStore, container: &str, run: &str) {
+    for key in ["", "/absolute", "trailing/", "a//b", "../escape", format!("{}/../../escape", run).as_str(), "back\\slash"] {
+        let result = store.put(container, key, &PutOptions::new("text/plain"), Bytes::from_static(b"x")).await;
+        assert!(matches!(result, Err(ObjectStoreError::InvalidKey(_))), "key {:?} should be rejected", key);
+    }
+}
+
+async fn run_conformance(store: &dyn ObjectStore, container: &str) {
+    let run = Uuid::new_v4().to_string();
+
+    check_put_get_head(store, container, &run).await;
+    check_properties(store, container, &run).await;
+    check_overwrite(store, container, &run).await;
+    check_put_stream(store, container, &run).await;
+    check_empty_object(store, container, &run).await;
+    check_ranges(store, container, &run).await;
+    check_list(store, container, &run).await;
+    check_missing(store, container, &run).await;
+    check_delete(store, container, &run).await;
+    check_blocks(store, container, &run).await;
+    check_invalid_keys(store, container, &run).await;
+
+    for meta in store.list(container, &run).await.unwrap() {
+        store.delete(container, &meta.key).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn memory_storage_conforms() {
+    run_conformance(&MemoryStorage::new(), "conformance").await;
+}
+
+#[tokio::test]
+async fn local_storage_conforms() {
+    let root = env::temp_dir().join(format!("rust_api-storage-{}", Uuid::new_v4()));
+    let store = LocalStorage::new(&root).unwrap();
+
+    run_conformance(&store, "conformance").await;
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// Needs `S3_TEST_ENDPOINT` and `S3_TEST_BUCKET` (an existing bucket), e.g.
+/// `docker run -p 9000:9000 minio/minio server /data` with the default
+/// `minioadmin` credentials.
+#[tokio::test]
+async fn s3_storage_conforms() {
+    let (Ok(endpoint), Ok(bucket)) = (env::var("S3_TEST_ENDPOINT"), env::var("S3_TEST_BUCKET")) else {
+        eprintln!("skipping S3 conformance: S3_TEST_ENDPOINT / S3_TEST_BUCKET not set");
+        return;
+    };
+
+    let store = S3Storage::new(
+        endpoint,
+        env::var("S3_TEST_REGION").unwrap_or("us-east-1".to_string()),
+        env::var("S3_TEST_ACCESS_KEY").unwrap_or("minioadmin".to_string()),
+        env::var("S3_TEST_SECRET_KEY").unwrap_or("minioadmin".to_string())
+    ).unwrap();
+
+    run_conformance(&store, &bucket).await;
+}
+
+/// Needs `AZURE_TEST_ACCOUNT`, `AZURE_TEST_ACCESS_KEY` and an existing
+/// `AZURE_TEST_CONTAINER`.
+#[tokio::test]
+async fn azure_storage_conforms() {
+    let (Ok(account), Ok(access_key), Ok(container)) = (
+        env::var("AZURE_TEST_ACCOUNT"),
+        env::var("AZURE_TEST_ACCESS_KEY"),
+        env::var("AZURE_TEST_CONTAINER"),
+    ) else {
+        eprintln!("skipping Azure conformance: AZURE_TEST_* not set");
+        return;
+    };
+
+    run_conformance(&AzureStorage::new(account, access_key), &container).await;
+}
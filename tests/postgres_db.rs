@@ -0,0 +1,48 @@
+//! Transactions against `POSTGRES_TEST_URL`.
+
+use std::env;
+
+use uuid::Uuid;
+
+use rust_api::postgres_db::PostgresDb;
+
+async fn test_db() -> Option<PostgresDb> {
+    let Ok(url) = env::var("POSTGRES_TEST_URL") else {
+        eprintln!("skipping transactions: POSTGRES_TEST_URL not set");
+        return None;
+    };
+
+    let db = PostgresDb::new(&url).await.unwrap();
+    db.batch_execute("CREATE TABLE IF NOT EXISTS transaction_test (id UUID PRIMARY KEY)").await.unwrap();
+    Some(db)
+}
+
+async fn exists(db: &PostgresDb, id: &Uuid) -> bool {
+    !db.query("SELECT id FROM transaction_test WHERE id = $1", &[id], |row| Ok(row.try_get::<_, Uuid>("id")?)).await
+        .unwrap()
+        .is_empty()
+}
+
+#[actix_web::test]
+async fn transactions_run_side_by_side_and_roll_back_when_dropped() {
+    let Some(db) = test_db().await else {
+        return;
+    };
+    let (kept, dropped) = (Uuid::new_v4(), Uuid::new_v4());
+
+    // Both stay open at once; neither waits for the other to finish.
+    let first = db.transaction().await.unwrap();
+    let second = db.transaction().await.unwrap();
+    first.execute("INSERT INTO transaction_test (id) VALUES ($1)", &[&kept]).await.unwrap();
+    second.execute("INSERT INTO transaction_test (id) VALUES ($1)", &[&dropped]).await.unwrap();
+    assert!(!exists(&db, &kept).await);
+
+    first.commit().await.unwrap();
+    drop(second);
+
+    assert!(exists(&db, &kept).await);
+    // The dropped insert is rolled back, so its id is free again.
+    db.execute("INSERT INTO transaction_test (id) VALUES ($1)", &[&dropped]).await.unwrap();
+
+    db.execute("DELETE FROM transaction_test WHERE id = ANY($1)", &[&vec![kept, dropped]]).await.unwrap();
+}
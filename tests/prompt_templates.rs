@@ -0,0 +1,93 @@
+//! Placeholder parsing, definition checks and rendering, which need no
+//! database or model.
+
+use serde_json::{ json, Map, Value };
+
+use rust_api::models::*;
+use rust_api::prompt_templates::*;
+
+fn variable(name: &str, kind: VariableType, required: bool, default: Option<Value>) -> TemplateVariable {
+    TemplateVariable { name: name.to_string(), kind, required, default }
+}
+
+fn template(body: &str, variables: Vec<TemplateVariable>) -> PromptTemplate {
+    PromptTemplate {
+        id: uuid::Uuid::new_v4(),
+        name: "greeting".to_string(),
+        version: 1,
+        body: body.to_string(),
+        variables,
+        model: "llama3".to_string(),
+        temperature: None,
+        max_tokens: None,
+        created_at: chrono::Utc::now(),
+    }
+}
+
+fn values(value: Value) -> Map<String, Value> {
+    value.as_object().unwrap().clone()
+}
+
+#[test]
+fn placeholders_are_distinct_and_trimmed() {
+    let names = placeholders("Hi {{ name }}, {{name}} is {{age}} years old.").unwrap();
+    assert_eq!(names.into_iter().collect::<Vec<_>>(), vec!["age", "name"]);
+
+    assert!(placeholders("no placeholders here").unwrap().is_empty());
+    assert!(placeholders("Hi {{name").is_err());
+    assert!(placeholders("Hi {{}}").is_err());
+    assert!(placeholders("Hi {{first name}}").is_err());
+}
+
+#[test]
+fn definitions_must_match_the_body() {
+    let name = variable("name", VariableType::String, true, None);
+    assert!(validate_definition("Hi {{name}}", std::slice::from_ref(&name)).is_ok());
+
+    let errors = validate_definition("Hi {{name}} {{mood}}", &[
+        name.clone(),
+        name.clone(),
+        variable("unused", VariableType::String, false, None),
+    ]).unwrap_err();
+    assert_eq!(errors["name"], json!("variable is declared more than once"));
+    assert_eq!(errors["unused"], json!("variable is declared but never used in body"));
+    assert_eq!(errors["mood"], json!("placeholder is used in body but not declared"));
+
+    let errors = validate_definition("{{count}}", &[
+        variable("count", VariableType::Integer, true, Some(json!(1.5))),
+    ]).unwrap_err();
+    assert_eq!(errors["count"], json!("default value is not of type integer"));
+
+    let errors = validate_definition("Hi {{name", &[name]).unwrap_err();
+    assert!(errors["body"].is_string());
+}
+
+#[test]
+fn render_substitutes_values_and_defaults() {
+    let template = template("{{greeting}}, {{name}}! Tries: {{tries}}. Admin: {{admin}}.{{note}}", vec![
+        variable("greeting", VariableType::String, true, Some(json!("Hello"))),
+        variable("name", VariableType::String, true, None),
+        variable("tries", VariableType::Integer, true, None),
+        variable("admin", VariableType::Boolean, true, Some(json!(false))),
+        variable("note", VariableType::String, false, None),
+    ]);
+
+    let output = render(&template, &values(json!({ "name": "Ada", "tries": 3 }))).unwrap();
+    assert_eq!(output, "Hello, Ada! Tries: 3. Admin: false.");
+
+    let output = render(&template, &values(json!({ "greeting": "Hey", "name": "Ada", "tries": 1, "note": " Hi" }))).unwrap();
+    assert_eq!(output, "Hey, Ada! Tries: 1. Admin: false. Hi");
+}
+
+#[test]
+fn render_reports_missing_extra_and_mistyped_values() {
+    let template = template("{{name}} {{tries}}", vec![
+        variable("name", VariableType::String, true, None),
+        variable("tries", VariableType::Integer, true, None),
+    ]);
+
+    let errors = render(&template, &values(json!({ "tries": "three", "extra": 1 }))).unwrap_err();
+    assert_eq!(errors["name"], json!("variable is required"));
+    assert_eq!(errors["tries"], json!("expected a value of type integer"));
+    assert_eq!(errors["extra"], json!("variable is not defined by this template"));
+}
@@ -0,0 +1,116 @@
+//! The in-memory limiter that runs while Redis is unavailable; no cache is
+//! registered here, so every decision comes from it.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use actix_session::{ storage::CookieSessionStore, Session, SessionMiddleware };
+use actix_web::{ cookie::Key, dev::ServiceResponse, http::StatusCode, test, web, App, HttpResponse };
+use uuid::Uuid;
+
+use rust_api::middleware::*;
+use rust_api::solana_h::WALLET_AUTH_METHOD;
+
+async fn ok() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+/// Opens a wallet session for a fresh user.
+async fn wallet_login(session: Session) -> HttpResponse {
+    session.insert("user_id", Uuid::new_v4()).unwrap();
+    session.insert("username", "wallet").unwrap();
+    session.insert("user_role", "user").unwrap();
+    session.insert("auth_method", WALLET_AUTH_METHOD).unwrap();
+    HttpResponse::Ok().finish()
+}
+
+fn peer(addr: &str) -> SocketAddr {
+    format!("{}:40000", addr).parse().unwrap()
+}
+
+fn header(resp: &ServiceResponse, name: &str) -> String {
+    resp.headers().get(name).unwrap().to_str().unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn sliding_windows_allow_the_limit_then_reject() {
+    let limit = RateLimit::sliding_window("window", 3, Duration::from_secs(60)).unwrap();
+    let app = test::init_service(App::new().wrap(limit).route("/", web::get().to(ok))).await;
+
+    for remaining in ["2", "1", "0"] {
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").peer_addr(peer("10.0.0.1")).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header(&resp, "x-ratelimit-limit"), "3");
+        assert_eq!(header(&resp, "x-ratelimit-remaining"), remaining);
+        assert!(resp.headers().get("retry-after").is_none());
+    }
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/").peer_addr(peer("10.0.0.1")).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&resp, "x-ratelimit-remaining"), "0");
+    let retry_after: u64 = header(&resp, "retry-after").parse().unwrap();
+    assert!((1..=60).contains(&retry_after));
+}
+
+#[actix_web::test]
+async fn token_buckets_refill_over_time() {
+    let limit = RateLimit::token_bucket("bucket", 2, 20.0).unwrap();
+    let app = test::init_service(App::new().wrap(limit).route("/", web::get().to(ok))).await;
+    let request = || test::TestRequest::get().uri("/").peer_addr(peer("10.0.0.2")).to_request();
+
+    assert_eq!(test::call_service(&app, request()).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, request()).await.status(), StatusCode::OK);
+    let resp = test::call_service(&app, request()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&resp, "retry-after"), "1");
+
+    // One token comes back every 50ms.
+    tokio::time::sleep(Duration::from_millis(120)).await;
+    assert_eq!(test::call_service(&app, request()).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn only_authenticated_callers_get_their_own_bucket() {
+    let limit = RateLimit::sliding_window("keys", 1, Duration::from_secs(60)).unwrap();
+    let app = test::init_service(
+        App::new()
+            .wrap(limit)
+            .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+            .route("/", web::get().to(ok))
+            .route("/login", web::post().to(wallet_login))
+    ).await;
+    let from = |addr: &str| test::TestRequest::get().uri("/").peer_addr(peer(addr));
+
+    let resp = test::call_service(&app, from("10.0.0.3").insert_header(("Authorization", "Bearer made-up")).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Unvalidated tokens, API keys and forwarded headers don't buy a new bucket.
+    for req in [
+        from("10.0.0.3").insert_header(("Authorization", "Bearer another-made-up")).to_request(),
+        from("10.0.0.3").insert_header(("X-API-Key", Uuid::new_v4().to_string())).to_request(),
+        from("10.0.0.3").insert_header(("X-Forwarded-For", "192.0.2.7")).to_request(),
+    ] {
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    // A valid token is its own user, and so is a wallet session.
+    let req = from("10.0.0.3").insert_header(("Authorization", "Bearer valid_token")).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let login = test::call_service(&app, test::TestRequest::post().uri("/login").peer_addr(peer("10.0.0.4")).to_request()).await;
+    let cookie = login.response().cookies().next().unwrap().into_owned();
+    let req = from("10.0.0.3").cookie(cookie).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    // Another address has its own bucket.
+    assert_eq!(test::call_service(&app, from("10.0.0.5").to_request()).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn limits_that_would_never_refill_are_rejected() {
+    assert!(RateLimit::token_bucket("bucket", 5, 0.0).is_err());
+    assert!(RateLimit::token_bucket("bucket", 5, -1.0).is_err());
+    assert!(RateLimit::token_bucket("bucket", 5, f64::NAN).is_err());
+    assert!(RateLimit::sliding_window("window", 5, Duration::ZERO).is_err());
+    assert!(RateLimit::token_bucket("bucket", 5, 0.5).is_ok());
+}
@@ -0,0 +1,268 @@
+//! Cache behaviour without a server, plus commands against `REDIS_TEST_URL`.
+
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+
+use rust_api::redis_client::*;
+
+fn unreachable_config() -> CacheConfig {
+    CacheConfig {
+        connection_timeout: Duration::from_millis(200),
+        ..CacheConfig::default()
+    }
+}
+
+#[actix_web::test]
+async fn a_cache_is_built_while_redis_is_down() {
+    let cache = Cache::with_config("redis://127.0.0.1:1", unreachable_config()).await.unwrap();
+
+    let started = Instant::now();
+    assert!(cache.get_value("missing").await.is_err());
+    assert!(cache.set_value("missing", 1, 60).await.is_err());
+    assert!(cache.incr_by("missing", 1, 60).await.is_err());
+    assert!(started.elapsed() < Duration::from_secs(1), "commands must fail fast while disconnected");
+
+    assert!(Cache::with_config("not a url", unreachable_config()).await.is_err());
+}
+
+#[test]
+fn globs_match_like_redis() {
+    assert!(glob_match("session:*", "session:42"));
+    assert!(glob_match("session:*", "session:"));
+    assert!(!glob_match("session:*", "sessions"));
+    assert!(glob_match("user:?:name", "user:7:name"));
+    assert!(!glob_match("user:?:name", "user:77:name"));
+    assert!(glob_match("*:name", "user:1:name"));
+    assert!(glob_match("a*b*c", "axxbyyc"));
+    assert!(!glob_match("a*b*c", "axxbyy"));
+    assert!(glob_match("*", ""));
+    assert!(!glob_match("", "a"));
+}
+
+#[test]
+fn keyspace_flags_are_added_to_the_current_ones() {
+    assert_eq!(keyspace_event_flags(""), "Kg$xe");
+    assert_eq!(keyspace_event_flags("Ex"), "ExKg$e");
+    assert_eq!(keyspace_event_flags("KA"), "KA");
+    assert_eq!(keyspace_event_flags("AE"), "AEK");
+    assert_eq!(keyspace_event_flags("Kg$xe"), "Kg$xe");
+}
+
+async fn test_cache() -> Option<Cache> {
+    let Ok(url) = std::env::var("REDIS_TEST_URL") else {
+        eprintln!("skipping Redis commands: REDIS_TEST_URL not set");
+        return None;
+    };
+
+    let config = CacheConfig {
+        key_prefix: Some(format!("test-{}", uuid::Uuid::new_v4().simple())),
+        ..CacheConfig::default()
+    };
+    Some(Cache::with_config(&url, config).await.unwrap())
+}
+
+/// Two instances sharing a prefix, each with its own L1.
+async fn tiered_caches(capacity: usize) -> Option<(Cache, Cache)> {
+    let Ok(url) = std::env::var("REDIS_TEST_URL") else {
+        eprintln!("skipping L1 tier: REDIS_TEST_URL not set");
+        return None;
+    };
+
+    let config = CacheConfig {
+        key_prefix: Some(format!("test-{}", uuid::Uuid::new_v4().simple())),
+        local: Some(LocalCacheConfig { capacity, max_ttl: Duration::from_secs(60) }),
+        configure_keyspace_events: true,
+        ..CacheConfig::default()
+    };
+    let first = Cache::with_config(&url, config.clone()).await.unwrap();
+    let second = Cache::with_config(&url, config).await.unwrap();
+    // Let both subscribe before anything is written.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    Some((first, second))
+}
+
+#[actix_web::test]
+async fn concurrent_misses_compute_once() {
+    let Some(cache) = test_cache().await else {
+        return;
+    };
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let compute = |calls: Arc<AtomicUsize>| move || async move {
+        calls.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        Ok::<_, Box<dyn std::error::Error>>(vec![1, 2, 3])
+    };
+    let (first, second) = tokio::join!(
+        cache.get_or_compute("report", 60, compute(calls.clone())),
+        cache.get_or_compute("report", 60, compute(calls.clone()))
+    );
+
+    assert_eq!(first.unwrap(), vec![1, 2, 3]);
+    assert_eq!(second.unwrap(), vec![1, 2, 3]);
+    assert_eq!(calls.load(Ordering::SeqCst), 1, "the second caller waits for the first");
+    assert_eq!(cache.get_json::<Vec<i32>>("report").await.unwrap(), Some(vec![1, 2, 3]));
+}
+
+#[actix_web::test]
+async fn stale_values_are_served_while_refreshing() {
+    let Some(cache) = test_cache().await else {
+        return;
+    };
+    let value = |v: &'static str| move || async move { Ok::<_, Box<dyn std::error::Error>>(v.to_string()) };
+
+    assert_eq!(cache.get_or_compute_stale("quote", 1, 30, value("old")).await.unwrap(), "old");
+    assert_eq!(cache.get_or_compute_stale("quote", 1, 30, value("unused")).await.unwrap(), "old");
+
+    tokio::time::sleep(Duration::from_millis(1_100)).await;
+    // Stale: the old value comes back at once and a refresh runs behind it.
+    assert_eq!(cache.get_or_compute_stale("quote", 1, 30, value("new")).await.unwrap(), "old");
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(cache.get_or_compute_stale("quote", 1, 30, value("unused")).await.unwrap(), "new");
+    assert_eq!(cache.get_json::<String>("quote").await.unwrap(), Some("new".to_string()));
+}
+
+#[actix_web::test]
+async fn patterns_delete_only_matching_keys_in_the_namespace() {
+    let Some(cache) = test_cache().await else {
+        return;
+    };
+    let Some(other) = test_cache().await else {
+        return;
+    };
+
+    for i in 0..1_200 {
+        cache.set_value(&format!("session:{}", i), i, 60).await.unwrap();
+    }
+    cache.set_value("sessions", "kept", 60).await.unwrap();
+    other.set_value("session:1", "other namespace", 60).await.unwrap();
+
+    assert_eq!(cache.delete_pattern("session:*").await.unwrap(), 1_200);
+    assert_eq!(cache.get_value("session:1").await.unwrap(), None);
+    assert_eq!(cache.get_value("sessions").await.unwrap(), Some("kept".to_string()));
+    assert_eq!(other.get_value("session:1").await.unwrap(), Some("other namespace".to_string()));
+    assert_eq!(cache.delete_pattern("session:*").await.unwrap(), 0);
+}
+
+#[actix_web::test]
+async fn hashes() {
+    let Some(cache) = test_cache().await else {
+        return;
+    };
+
+    cache.hash_set("user:1", "name", "Ada").await.unwrap();
+    cache.hash_set("user:1", "visits", 1).await.unwrap();
+    assert_eq!(cache.hash_incr_by("user:1", "visits", 4).await.unwrap(), 5);
+    assert_eq!(cache.hash_get("user:1", "name").await.unwrap(), Some("Ada".to_string()));
+    assert_eq!(cache.hash_get("user:1", "missing").await.unwrap(), None);
+
+    let all = cache.hash_get_all("user:1").await.unwrap();
+    assert_eq!(all.len(), 2);
+    assert_eq!(all["visits"], "5");
+
+    assert!(cache.hash_delete("user:1", "name").await.unwrap());
+    assert!(!cache.hash_delete("user:1", "name").await.unwrap());
+    assert!(cache.hash_get_all("missing").await.unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn sorted_sets() {
+    let Some(cache) = test_cache().await else {
+        return;
+    };
+
+    cache.sorted_add("scores", "ada", 10.0).await.unwrap();
+    cache.sorted_add("scores", "bob", 5.0).await.unwrap();
+    cache.sorted_add("scores", "cy", 7.5).await.unwrap();
+    assert_eq!(cache.sorted_incr_by("scores", "bob", 10.0).await.unwrap(), 15.0);
+
+    assert_eq!(
+        cache.sorted_top("scores", 2).await.unwrap(),
+        vec![("bob".to_string(), 15.0), ("ada".to_string(), 10.0)]
+    );
+    assert!(cache.sorted_top("scores", 0).await.unwrap().is_empty());
+    assert_eq!(
+        cache.sorted_range_by_score("scores", 7.0, 12.0).await.unwrap(),
+        vec![("cy".to_string(), 7.5), ("ada".to_string(), 10.0)]
+    );
+
+    assert_eq!(cache.sorted_remove_by_score("scores", 0.0, 8.0).await.unwrap(), 1);
+    assert!(cache.sorted_remove("scores", "ada").await.unwrap());
+    assert!(!cache.sorted_remove("scores", "ada").await.unwrap());
+    assert_eq!(cache.sorted_len("scores").await.unwrap(), 1);
+}
+
+#[actix_web::test]
+async fn queues_are_first_in_first_out() {
+    let Some(cache) = test_cache().await else {
+        return;
+    };
+
+    assert_eq!(cache.queue_push("jobs", "a").await.unwrap(), 1);
+    assert_eq!(cache.queue_push("jobs", "b").await.unwrap(), 2);
+    assert_eq!(cache.queue_push("jobs", 3).await.unwrap(), 3);
+    assert_eq!(cache.queue_range("jobs", 0, -1).await.unwrap(), vec!["a", "b", "3"]);
+    assert_eq!(cache.queue_len("jobs").await.unwrap(), 3);
+
+    assert_eq!(cache.queue_pop("jobs").await.unwrap(), Some("a".to_string()));
+    assert_eq!(cache.queue_range("jobs", 0, 0).await.unwrap(), vec!["b"]);
+    assert_eq!(cache.queue_pop("jobs").await.unwrap(), Some("b".to_string()));
+    assert_eq!(cache.queue_pop("jobs").await.unwrap(), Some("3".to_string()));
+    assert_eq!(cache.queue_pop("jobs").await.unwrap(), None);
+    assert_eq!(cache.queue_len("jobs").await.unwrap(), 0);
+}
+
+#[actix_web::test]
+async fn l1_keeps_only_the_most_recently_used_entries() {
+    let Some((cache, _)) = tiered_caches(2).await else {
+        return;
+    };
+
+    for key in ["a", "b", "c"] {
+        cache.set_value(key, key, 60).await.unwrap();
+    }
+    // Writes notify this instance too; wait for that before reading.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    for key in ["a", "b", "c"] {
+        cache.get_value(key).await.unwrap();
+    }
+    assert_eq!(cache.stats().l1.unwrap().entries, Some(2));
+
+    let before = cache.stats();
+    assert_eq!(cache.get_value("a").await.unwrap(), Some("a".to_string()));
+    let after = cache.stats();
+    assert_eq!(after.l1.as_ref().unwrap().misses, before.l1.as_ref().unwrap().misses + 1, "`a` was evicted");
+    assert_eq!(after.l2.hits, before.l2.hits + 1);
+}
+
+#[actix_web::test]
+async fn l1_entries_are_dropped_when_redis_changes() {
+    let Some((first, second)) = tiered_caches(100).await else {
+        return;
+    };
+
+    first.set_value("greeting", "hello", 60).await.unwrap();
+    assert_eq!(second.get_value("greeting").await.unwrap(), Some("hello".to_string()));
+
+    // Written by the other instance, then by a raw command that bypasses
+    // the cache's helpers entirely.
+    first.set_value("greeting", "hi", 60).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(second.get_value("greeting").await.unwrap(), Some("hi".to_string()));
+
+    first.pipeline::<()>(redis::pipe().set(first.namespaced("greeting"), "hey").ignore()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(second.get_value("greeting").await.unwrap(), Some("hey".to_string()));
+
+    first.incr_by("visits", 1, 60).await.unwrap();
+    assert_eq!(second.get_value("visits").await.unwrap(), Some("1".to_string()));
+    first.incr_by("visits", 1, 60).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(second.get_value("visits").await.unwrap(), Some("2".to_string()));
+
+    first.delete("greeting").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(second.get_value("greeting").await.unwrap(), None);
+}
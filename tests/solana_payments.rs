@@ -0,0 +1,108 @@
+//! Payment intent settlement against `POSTGRES_TEST_URL`. Transfers are fed
+//! to the watcher directly rather than read from a cluster.
+
+use std::env;
+
+use solana_sdk::pubkey::Pubkey;
+use uuid::Uuid;
+
+use rust_api::postgres_db::PostgresDb;
+use rust_api::solana_h::*;
+use rust_api::websocket::WsRegistry;
+
+async fn test_db() -> Option<PostgresDb> {
+    let Ok(url) = env::var("POSTGRES_TEST_URL") else {
+        eprintln!("skipping payment tracking: POSTGRES_TEST_URL not set");
+        return None;
+    };
+
+    Some(PostgresDb::new(&url).await.unwrap())
+}
+
+fn incoming(receiver: &Pubkey, lamports: u64, memo: &str) -> IncomingTransfer {
+    IncomingTransfer {
+        signature: format!("test-{}", Uuid::new_v4().simple()),
+        address: *receiver,
+        sender: Pubkey::new_unique(),
+        lamports,
+        slot: 1,
+        memo: Some(format!("[{}] {}", memo.len(), memo)),
+        block_time: Some(1_700_000_000),
+    }
+}
+
+#[actix_web::test]
+async fn memos_settle_intents_until_the_transfer_is_dropped() {
+    let Some(db) = test_db().await else {
+        return;
+    };
+    // Transfer history joins against linked wallets.
+    WalletStore::new(&db).await.unwrap();
+    let watcher = PaymentWatcher::new(&db, PaymentWatcherConfig::default()).await.unwrap();
+    let sockets = WsRegistry::default();
+    let (user, receiver) = (Uuid::new_v4(), Pubkey::new_unique());
+
+    let intent = watcher.create_intent(&db, &user, &receiver, 1_000).await.unwrap();
+    assert_eq!(intent.status, "pending");
+    assert!(intent.reference.starts_with("pay:"));
+
+    // Underpaying or omitting the reference doesn't settle it.
+    let short = incoming(&receiver, 999, &intent.reference);
+    watcher.record(&db, &sockets, &short).await.unwrap();
+    let unrelated = incoming(&receiver, 5_000, "thanks");
+    watcher.record(&db, &sockets, &unrelated).await.unwrap();
+    assert_eq!(watcher.find_intent(&db, &user, &intent.id).await.unwrap().unwrap().status, "pending");
+
+    let payment = incoming(&receiver, 1_000, &intent.reference);
+    watcher.record(&db, &sockets, &payment).await.unwrap();
+    watcher.record(&db, &sockets, &payment).await.unwrap();
+
+    let paid = watcher.find_intent(&db, &user, &intent.id).await.unwrap().unwrap();
+    assert_eq!(paid.status, "paid");
+    assert_eq!(paid.signature.as_deref(), Some(payment.signature.as_str()));
+    assert!(watcher.find_intent(&db, &Uuid::new_v4(), &intent.id).await.unwrap().is_none());
+
+    let history = watcher.transfers_for_user(&db, &user, 10).await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].lamports, 1_000);
+    assert_eq!(history[0].status, "confirmed");
+
+    // A dropped transfer reopens the intent; seeing it again settles it again.
+    watcher.set_status(&db, &sockets, &payment.signature, &receiver.to_string(), "dropped").await.unwrap();
+    let reopened = watcher.find_intent(&db, &user, &intent.id).await.unwrap().unwrap();
+    assert_eq!(reopened.status, "pending");
+    assert!(reopened.signature.is_none());
+
+    watcher.record(&db, &sockets, &payment).await.unwrap();
+    assert_eq!(watcher.find_intent(&db, &user, &intent.id).await.unwrap().unwrap().status, "paid");
+    assert_eq!(watcher.transfers_for_user(&db, &user, 10).await.unwrap()[0].status, "confirmed");
+
+    db.execute("DELETE FROM payment_intents WHERE user_id = $1", &[&user]).await.unwrap();
+    db.execute("DELETE FROM solana_transfers WHERE address = $1", &[&receiver.to_string()]).await.unwrap();
+}
+
+#[actix_web::test]
+async fn memos_with_several_references_settle_nothing() {
+    let Some(db) = test_db().await else {
+        return;
+    };
+    WalletStore::new(&db).await.unwrap();
+    let watcher = PaymentWatcher::new(&db, PaymentWatcherConfig::default()).await.unwrap();
+    let sockets = WsRegistry::default();
+    let (user, receiver) = (Uuid::new_v4(), Pubkey::new_unique());
+
+    let first = watcher.create_intent(&db, &user, &receiver, 1_000).await.unwrap();
+    let second = watcher.create_intent(&db, &user, &receiver, 1_000).await.unwrap();
+    assert_eq!(payment_reference(&format!("order {} thanks", first.reference)), Some(first.reference.clone()));
+    assert_eq!(payment_reference(&format!("{0} {0}", first.reference)), Some(first.reference.clone()));
+    assert_eq!(payment_reference("pay:not-a-reference"), None);
+
+    let both = incoming(&receiver, 1_000, &format!("{} {}", first.reference, second.reference));
+    watcher.record(&db, &sockets, &both).await.unwrap();
+    for intent in [&first, &second] {
+        assert_eq!(watcher.find_intent(&db, &user, &intent.id).await.unwrap().unwrap().status, "pending");
+    }
+
+    db.execute("DELETE FROM payment_intents WHERE user_id = $1", &[&user]).await.unwrap();
+    db.execute("DELETE FROM solana_transfers WHERE address = $1", &[&receiver.to_string()]).await.unwrap();
+}
@@ -0,0 +1,56 @@
+//! Endpoint failover and error reporting. The unreachable endpoint is a
+//! closed local port, so no network access is needed.
+
+use std::time::Duration;
+
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::signature::Keypair;
+
+use rust_api::solana_h::*;
+
+const UNREACHABLE: &str = "http://127.0.0.1:1";
+
+fn fast_config() -> SolanaConfig {
+    SolanaConfig {
+        timeout: Duration::from_secs(5),
+        max_retries: 1,
+        retry_backoff: Duration::from_millis(10),
+        ..SolanaConfig::default()
+    }
+}
+
+fn client(rpc_clients: Vec<RpcClient>) -> SolanaClient {
+    let receiver = Keypair::new();
+    SolanaClient::with_rpc_clients(rpc_clients, &fast_config(), receiver)
+}
+
+#[actix_web::test]
+async fn requests_fail_over_to_the_next_endpoint() {
+    let client = client(vec![
+        RpcClient::new_with_commitment(UNREACHABLE.to_string(), CommitmentConfig::confirmed()),
+        RpcClient::new_mock("succeeds".to_string()),
+    ]);
+    assert_eq!(client.rpc_url(), UNREACHABLE);
+
+    assert_eq!(client.check_balance().await.unwrap(), 50);
+    // The healthy endpoint stays preferred afterwards.
+    assert_ne!(client.rpc_url(), UNREACHABLE);
+}
+
+#[actix_web::test]
+async fn unreachable_endpoints_are_errors_not_empty_wallets() {
+    let client = client(vec![RpcClient::new_with_commitment(UNREACHABLE.to_string(), CommitmentConfig::confirmed())]);
+
+    match client.check_balance().await {
+        Err(err @ SolanaError::Network(_)) => assert!(err.is_retryable()),
+        other => panic!("expected a network error, got {:?}", other),
+    }
+}
+
+#[actix_web::test]
+async fn commitment_names_are_parsed() {
+    assert_eq!(parse_commitment("finalized").unwrap(), CommitmentConfig::finalized());
+    assert_eq!(parse_commitment(" processed ").unwrap(), CommitmentConfig::processed());
+    assert!(parse_commitment("maybe").is_err());
+}
@@ -0,0 +1,174 @@
+//! SPL Token support against the mocked RPC, with canned account responses
+//! for the requests the mock doesn't answer itself.
+
+use std::collections::HashMap;
+
+use actix_session::{ storage::CookieSessionStore, SessionMiddleware };
+use actix_web::{ cookie::Key, http::StatusCode, test, web, App };
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
+use serde_json::Value;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_request::RpcRequest;
+use solana_program::program_option::COption;
+use solana_program::program_pack::Pack;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{ Keypair, Signer };
+use spl_token::state::Mint;
+
+use rust_api::middleware::*;
+use rust_api::solana_h::*;
+
+/// The `valid_token` user may spend the payer's funds.
+fn mock_client(mocks: HashMap<RpcRequest, Value>) -> SolanaClient {
+    let payer = Keypair::new();
+    SolanaClient::with_rpc_client(RpcClient::new_mock_with_mocks("succeeds".to_string(), mocks), payer)
+        .with_spenders([user_for_token("valid_token").id])
+}
+
+/// A `getAccountInfo` answer for a mint with `decimals`, owned by `owner`.
+fn mint_account(decimals: u8, owner: &Pubkey) -> HashMap<RpcRequest, Value> {
+    let mint = Mint {
+        mint_authority: COption::None,
+        supply: 1_000_000,
+        decimals,
+        is_initialized: true,
+        freeze_authority: COption::None,
+    };
+    let mut data = vec![0; Mint::LEN];
+    Mint::pack(mint, &mut data).unwrap();
+
+    HashMap::from([
+        (
+            RpcRequest::GetAccountInfo,
+            serde_json::json!({
+                "context": { "slot": 1 },
+                "value": {
+                    "lamports": 1_461_600,
+                    "data": [BASE64.encode(&data), "base64"],
+                    "owner": owner.to_string(),
+                    "executable": false,
+                    "rentEpoch": 0,
+                    "space": Mint::LEN,
+                },
+            }),
+        ),
+    ])
+}
+
+#[actix_web::test]
+async fn associated_token_accounts_are_derived_per_owner_and_mint() {
+    let (owner, mint) = (Keypair::new().pubkey(), Keypair::new().pubkey());
+
+    let (expected, _) = Pubkey::find_program_address(
+        &[owner.as_ref(), spl_token::id().as_ref(), mint.as_ref()],
+        &spl_associated_token_account::id()
+    );
+    assert_eq!(associated_token_address(&owner, &mint), expected);
+    assert_ne!(associated_token_address(&owner, &Keypair::new().pubkey()), expected);
+    assert_eq!(token_ui_amount(1_500_000, 6), "1.5");
+}
+
+#[actix_web::test]
+async fn token_accounts_are_listed_with_decimals_and_ui_amounts() {
+    let (owner, mint, address) = (Keypair::new().pubkey(), Keypair::new().pubkey(), Keypair::new().pubkey());
+    let client = mock_client(
+        HashMap::from([
+            (
+                RpcRequest::GetTokenAccountsByOwner,
+                serde_json::json!({
+                    "context": { "slot": 1 },
+                    "value": [{
+                        "pubkey": address.to_string(),
+                        "account": {
+                            "lamports": 2_039_280,
+                            "data": {
+                                "program": "spl-token",
+                                "parsed": {
+                                    "type": "account",
+                                    "info": {
+                                        "isNative": false,
+                                        "mint": mint.to_string(),
+                                        "owner": owner.to_string(),
+                                        "state": "initialized",
+                                        "tokenAmount": {
+                                            "amount": "2500000",
+                                            "decimals": 6,
+                                            "uiAmount": 2.5,
+                                            "uiAmountString": "2.5",
+                                        },
+                                    },
+                                },
+                                "space": 165,
+                            },
+                            "owner": spl_token::id().to_string(),
+                            "executable": false,
+                            "rentEpoch": 0,
+                            "space": 165,
+                        },
+                    }],
+                }),
+            ),
+        ])
+    );
+
+    let accounts = client.token_accounts(&owner).await.unwrap();
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].address, address.to_string());
+    assert_eq!(accounts[0].mint, mint.to_string());
+    assert_eq!(accounts[0].owner, owner.to_string());
+    assert_eq!(accounts[0].amount, 2_500_000);
+    assert_eq!(accounts[0].decimals, 6);
+    assert_eq!(accounts[0].ui_amount, "2.5");
+}
+
+#[actix_web::test]
+async fn token_transfers_check_the_mint_and_its_decimals() {
+    let (mint, to) = (Keypair::new().pubkey(), Keypair::new().pubkey());
+    let commitment = CommitmentConfig::confirmed();
+
+    let client = mock_client(mint_account(6, &spl_token::id()));
+    assert!(matches!(client.transfer_tokens(&mint, &to, 0, 6, commitment).await, Err(TokenError::InvalidAmount(_))));
+    match client.transfer_tokens(&mint, &to, 10, 9, commitment).await {
+        Err(TokenError::DecimalsMismatch { expected, actual }) => {
+            assert_eq!(expected, 6);
+            assert_eq!(actual, 9);
+        }
+        other => panic!("expected a decimals mismatch, got {:?}", other.map(|_| ())),
+    }
+
+    let client = mock_client(mint_account(6, &solana_sdk::system_program::id()));
+    assert!(matches!(client.mint_decimals(&mint).await, Err(TokenError::InvalidMint(_))));
+}
+
+#[actix_web::test]
+async fn token_transfer_endpoint_reports_errors_by_field() {
+    let client = web::Data::new(mock_client(mint_account(6, &spl_token::id())));
+    let app = test::init_service(
+        App::new()
+            .wrap(Auth)
+            .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+            .app_data(client.clone())
+            .route("/api/solana/tokens/transfer", web::post().to(send_token_transfer))
+    ).await;
+    let (mint, to) = (Keypair::new().pubkey().to_string(), Keypair::new().pubkey().to_string());
+
+    for (payload, field) in [
+        (serde_json::json!({ "mint": "nope", "to": to, "amount": 10, "decimals": 6 }), "mint"),
+        (serde_json::json!({ "mint": mint, "to": "nope", "amount": 10, "decimals": 6 }), "to"),
+        (serde_json::json!({ "mint": mint, "to": to, "amount": 10, "decimals": 6, "commitment": "eventually" }), "commitment"),
+        (serde_json::json!({ "mint": mint, "to": to, "amount": 0, "decimals": 6 }), "amount"),
+        (serde_json::json!({ "mint": mint, "to": to, "amount": 10, "decimals": 9 }), "decimals"),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/solana/tokens/transfer")
+            .insert_header(("Authorization", "Bearer valid_token"))
+            .set_json(payload)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["errors"][field].is_string(), "expected an error on {}", field);
+    }
+}
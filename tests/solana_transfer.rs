@@ -0,0 +1,188 @@
+//! Transfers against the mocked RPC, which reports 50 lamports for every
+//! account and accepts any transaction it is sent. Prepared transactions
+//! also need `REDIS_TEST_URL`.
+
+use std::env;
+
+use actix_session::{ storage::CookieSessionStore, SessionMiddleware };
+use actix_web::{ cookie::Key, http::StatusCode, test, web, App };
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
+use serde_json::Value;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::signature::{ Keypair, Signature, Signer };
+use solana_sdk::transaction::Transaction;
+use uuid::Uuid;
+
+use rust_api::middleware::*;
+use rust_api::redis_client::Cache;
+use rust_api::solana_h::*;
+
+/// The `valid_token` user may spend the payer's funds.
+fn mock_client() -> SolanaClient {
+    let payer = Keypair::new();
+    SolanaClient::with_rpc_client(RpcClient::new_mock("succeeds".to_string()), payer)
+        .with_spenders([user_for_token("valid_token").id])
+}
+
+#[actix_web::test]
+async fn transfers_are_signed_by_the_payer() {
+    let client = mock_client();
+    let to = Keypair::new().pubkey();
+
+    let (transaction, _) = client.build_transfer(&to, 10).await.unwrap();
+    assert_eq!(transaction.message.account_keys[0], client.payer());
+    assert!(transaction.message.account_keys.contains(&to));
+    transaction.verify().unwrap();
+
+    let (signature, _) = client.transfer_sol(&to, 10, CommitmentConfig::confirmed()).await.unwrap();
+    assert_ne!(signature, Signature::default());
+}
+
+#[actix_web::test]
+async fn transfers_beyond_the_balance_are_refused() {
+    let client = mock_client();
+    let to = Keypair::new().pubkey();
+
+    match client.build_transfer(&to, 1_000_000_000).await {
+        Err(TransferError::InsufficientFunds { balance, required }) => {
+            assert_eq!(balance, 50);
+            assert!(required > 1_000_000_000);
+        }
+        other => panic!("expected insufficient funds, got {:?}", other.map(|_| ())),
+    }
+    assert!(matches!(client.build_transfer(&to, 0).await, Err(TransferError::InvalidAmount(_))));
+}
+
+#[actix_web::test]
+async fn transfer_endpoint_validates_and_reports_the_signature() {
+    let client = web::Data::new(mock_client());
+    let app = test::init_service(
+        App::new()
+            .wrap(Auth)
+            .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+            .app_data(client.clone())
+            .route("/api/solana/transfer", web::post().to(send_sol_transfer))
+    ).await;
+    let to = Keypair::new().pubkey().to_string();
+
+    let req = test::TestRequest::post()
+        .uri("/api/solana/transfer")
+        .insert_header(("Authorization", "Bearer valid_token"))
+        .set_json(serde_json::json!({ "to": to, "lamports": 10, "commitment": "finalized" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["data"]["from"], client.payer().to_string());
+    assert_eq!(body["data"]["to"], to);
+    assert_eq!(body["data"]["lamports"], 10);
+    assert_eq!(body["data"]["commitment"], "finalized");
+    assert!(body["data"]["signature"].is_string());
+
+    for (payload, field) in [
+        (serde_json::json!({ "to": "nope", "lamports": 10 }), "to"),
+        (serde_json::json!({ "to": to, "lamports": 10, "commitment": "eventually" }), "commitment"),
+        (serde_json::json!({ "to": to, "lamports": 1_000_000_000u64 }), "lamports"),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/solana/transfer")
+            .insert_header(("Authorization", "Bearer valid_token"))
+            .set_json(payload)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["errors"][field].is_string(), "expected an error on {}", field);
+    }
+}
+
+#[actix_web::test]
+async fn only_listed_spenders_can_transfer() {
+    let payer = Keypair::new();
+    let client = SolanaClient::with_rpc_client(RpcClient::new_mock("succeeds".to_string()), payer)
+        .with_spenders([Uuid::new_v4()]);
+    let app = test::init_service(
+        App::new()
+            .wrap(Auth)
+            .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+            .app_data(web::Data::new(client))
+            .route("/api/solana/transfer", web::post().to(send_sol_transfer))
+    ).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/solana/transfer")
+        .insert_header(("Authorization", "Bearer valid_token"))
+        .set_json(serde_json::json!({ "to": Keypair::new().pubkey().to_string(), "lamports": 10 }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+}
+
+fn sign_prepared(encoded: &str, signer: &Keypair) -> Transaction {
+    let mut transaction: Transaction = bincode::deserialize(&BASE64.decode(encoded).unwrap()).unwrap();
+    assert!(!transaction.is_signed());
+
+    let blockhash = transaction.message.recent_blockhash;
+    transaction.sign(&[signer], blockhash);
+    transaction
+}
+
+#[actix_web::test]
+async fn prepared_transactions_are_redeemed_once_when_signed_by_the_wallet() {
+    let Ok(redis_url) = env::var("REDIS_TEST_URL") else {
+        eprintln!("skipping prepared transactions: REDIS_TEST_URL not set");
+        return;
+    };
+    let cache = Cache::new(&redis_url).await.unwrap();
+    let client = mock_client();
+    let (user, wallet) = (Uuid::new_v4(), Keypair::new());
+
+    let requests = [
+        UnsignedTransactionRequest::Memo { memo: "order 42".to_string() },
+        UnsignedTransactionRequest::Transfer { to: Keypair::new().pubkey().to_string(), lamports: 10 },
+    ];
+    for request in &requests {
+        let prepared = prepare_unsigned_transaction(&client, &cache, &user, &wallet.pubkey(), request).await.unwrap();
+        assert_eq!(prepared.wallet, wallet.pubkey().to_string());
+
+        // Only the prepared message, signed by the wallet, is accepted.
+        let mut forged = sign_prepared(&prepared.transaction, &wallet);
+        forged.signatures[0] = Keypair::new().sign_message(&forged.message_data());
+        let forged = BASE64.encode(bincode::serialize(&forged).unwrap());
+        assert!(matches!(
+            redeem_signed_transaction(&cache, &user, &prepared.id, &forged).await,
+            Err(PreparedTxError::Mismatch(_))
+        ));
+        assert!(matches!(
+            redeem_signed_transaction(&cache, &user, &prepared.id, &prepared.transaction).await,
+            Err(PreparedTxError::Mismatch(_))
+        ));
+
+        let signed = BASE64.encode(bincode::serialize(&sign_prepared(&prepared.transaction, &wallet)).unwrap());
+        assert!(matches!(
+            redeem_signed_transaction(&cache, &Uuid::new_v4(), &prepared.id, &signed).await,
+            Err(PreparedTxError::UnknownTransaction)
+        ));
+
+        let (transaction, last_valid_block_height) = redeem_signed_transaction(&cache, &user, &prepared.id, &signed).await.unwrap();
+        assert_eq!(transaction.message.account_keys[0], wallet.pubkey());
+
+        // A failed broadcast puts it back for another attempt.
+        restore_signed_transaction(&cache, &user, &prepared.id, &transaction, last_valid_block_height).await.unwrap();
+        let (transaction, _) = redeem_signed_transaction(&cache, &user, &prepared.id, &signed).await.unwrap();
+        assert!(client.broadcast(&transaction).await.is_ok());
+
+        assert!(matches!(
+            redeem_signed_transaction(&cache, &user, &prepared.id, &signed).await,
+            Err(PreparedTxError::UnknownTransaction)
+        ));
+    }
+
+    let empty = UnsignedTransactionRequest::Memo { memo: String::new() };
+    assert!(matches!(
+        prepare_unsigned_transaction(&client, &cache, &user, &wallet.pubkey(), &empty).await,
+        Err(PreparedTxError::Invalid { field: "memo", .. })
+    ));
+}
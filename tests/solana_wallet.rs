@@ -0,0 +1,312 @@
+//! Wallet endpoints against a mocked RPC, or against a live node such as
+//! `solana-test-validator` when `SOLANA_TEST_RPC_URL` is set. Linking
+//! wallets and signing in with them also needs `POSTGRES_TEST_URL` and
+//! `REDIS_TEST_URL`.
+
+use std::env;
+
+use actix_session::{ storage::CookieSessionStore, SessionMiddleware };
+use actix_web::{ cookie::Key, http::StatusCode, test, web, App };
+use serde_json::Value;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::signature::{ Keypair, Signer };
+use uuid::Uuid;
+
+use rust_api::middleware::*;
+use rust_api::postgres_db::{ is_unique_violation, PostgresDb };
+use rust_api::redis_client::Cache;
+use rust_api::solana_h::*;
+
+/// Balance the mock RPC reports for every account.
+const MOCK_BALANCE: u64 = 50;
+const TOKEN: &str = "valid_token";
+
+/// Returns the client and the balance a fresh, unfunded account will have.
+fn test_client() -> (SolanaClient, u64) {
+    let receiver = Keypair::new();
+
+    match env::var("SOLANA_TEST_RPC_URL") {
+        Ok(url) => (SolanaClient::new(&url, receiver), 0),
+        Err(_) => (SolanaClient::with_rpc_client(RpcClient::new_mock("succeeds".to_string()), receiver), MOCK_BALANCE),
+    }
+}
+
+async fn link_backends() -> Option<(PostgresDb, Cache)> {
+    let (Ok(postgres_url), Ok(redis_url)) = (env::var("POSTGRES_TEST_URL"), env::var("REDIS_TEST_URL")) else {
+        eprintln!("skipping wallet links: POSTGRES_TEST_URL / REDIS_TEST_URL not set");
+        return None;
+    };
+
+    Some((PostgresDb::new(&postgres_url).await.unwrap(), Cache::new(&redis_url).await.unwrap()))
+}
+
+fn bearer(req: test::TestRequest) -> test::TestRequest {
+    req.insert_header(("Authorization", format!("Bearer {}", TOKEN)))
+}
+
+/// Request body proving `keypair` owns its wallet.
+async fn signed_challenge(siws: &SignInWithSolana, cache: &Cache, keypair: &Keypair) -> Value {
+    let challenge = siws.issue(cache, &keypair.pubkey()).await.unwrap();
+
+    serde_json::json!({
+        "pubkey": keypair.pubkey().to_string(),
+        "nonce": challenge.nonce,
+        "signature": keypair.sign_message(challenge.message.as_bytes()).to_string(),
+    })
+}
+
+#[actix_web::test]
+async fn balance_reports_lamports_and_sol() {
+    let (client, expected) = test_client();
+    let app = test::init_service(
+        App::new().app_data(web::Data::new(client)).route("/api/solana/balance/{pubkey}", web::get().to(get_sol_balance))
+    ).await;
+    let pubkey = Keypair::new().pubkey();
+
+    let req = test::TestRequest::get().uri(&format!("/api/solana/balance/{}", pubkey)).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["data"]["pubkey"], pubkey.to_string());
+    assert_eq!(body["data"]["lamports"], expected);
+    assert_eq!(body["data"]["sol"].as_f64().unwrap(), lamports_to_sol(expected));
+}
+
+#[actix_web::test]
+async fn malformed_pubkeys_are_rejected() {
+    let (client, _) = test_client();
+    let app = test::init_service(
+        App::new().app_data(web::Data::new(client)).route("/api/solana/balance/{pubkey}", web::get().to(get_sol_balance))
+    ).await;
+
+    for pubkey in ["not-a-key", "0OIl", "1111111111111111111111111111111111111111111111111"] {
+        let req = test::TestRequest::get().uri(&format!("/api/solana/balance/{}", pubkey)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY, "pubkey {:?} should be rejected", pubkey);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["errors"]["pubkey"].is_string());
+    }
+}
+
+#[actix_web::test]
+async fn wallets_are_linked_per_user() {
+    let Some((db, cache)) = link_backends().await else {
+        return;
+    };
+    let (client, expected) = test_client();
+    let wallets = web::Data::new(WalletStore::new(&db).await.unwrap());
+    let siws = web::Data::new(SignInWithSolana::new(SiwsConfig::default()));
+    let (db, cache) = (web::Data::new(db), web::Data::new(cache));
+
+    // Auth maps the token to a fixed user, so start from a clean slate.
+    let user = user_for_token(TOKEN);
+    for wallet in wallets.list(&db, &cache, &user.id).await.unwrap() {
+        wallets.unlink(&db, &cache, &user.id, &parse_pubkey(&wallet.pubkey).unwrap()).await.unwrap();
+    }
+
+    let other_user = Uuid::new_v4();
+    let other_wallet = Keypair::new().pubkey();
+    wallets.link(&db, &cache, &other_user, &other_wallet, false).await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .wrap(Auth)
+            .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+            .app_data(web::Data::new(client))
+            .app_data(siws.clone())
+            .app_data(wallets.clone())
+            .app_data(db.clone())
+            .app_data(cache.clone())
+            .route("/api/solana/wallet/connect", web::post().to(connect_wallet))
+            .route("/api/solana/wallet", web::get().to(get_wallet))
+            .route("/api/solana/wallets", web::get().to(list_wallets))
+            .route("/api/solana/wallets/{pubkey}", web::delete().to(unlink_wallet))
+            .route("/api/solana/wallets/{pubkey}/primary", web::put().to(set_primary_wallet))
+    ).await;
+
+    let resp = test::call_service(&app, bearer(test::TestRequest::get().uri("/api/solana/wallet")).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let (first_keypair, second_keypair) = (Keypair::new(), Keypair::new());
+    let (first, second) = (first_keypair.pubkey(), second_keypair.pubkey());
+    for (keypair, is_primary) in [(&first_keypair, true), (&second_keypair, false)] {
+        let req = bearer(test::TestRequest::post().uri("/api/solana/wallet/connect"))
+            .set_json(signed_challenge(&siws, &cache, keypair).await)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"]["pubkey"], keypair.pubkey().to_string());
+        assert_eq!(body["data"]["is_primary"], is_primary);
+        assert_eq!(body["data"]["lamports"], expected);
+    }
+
+    let req = bearer(test::TestRequest::post().uri("/api/solana/wallet/connect"))
+        .set_json(serde_json::json!({ "pubkey": "not-a-key", "nonce": "", "signature": "" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Knowing a pubkey isn't enough: the challenge must be signed by its key.
+    let mut forged = signed_challenge(&siws, &cache, &Keypair::new()).await;
+    forged["signature"] = Value::String(Keypair::new().sign_message(b"anything").to_string());
+    let req = bearer(test::TestRequest::post().uri("/api/solana/wallet/connect")).set_json(forged).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = test::call_service(&app, bearer(test::TestRequest::get().uri("/api/solana/wallets")).to_request()).await;
+    let body: Value = test::read_body_json(resp).await;
+    let listed: Vec<&str> = body["data"].as_array().unwrap().iter().map(|w| w["pubkey"].as_str().unwrap()).collect();
+    assert_eq!(listed, vec![first.to_string(), second.to_string()]);
+
+    let req = bearer(test::TestRequest::put().uri(&format!("/api/solana/wallets/{}/primary", second))).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let resp = test::call_service(&app, bearer(test::TestRequest::get().uri("/api/solana/wallet")).to_request()).await;
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["data"]["pubkey"], second.to_string());
+
+    // Unlinking the primary promotes the remaining wallet.
+    let req = bearer(test::TestRequest::delete().uri(&format!("/api/solana/wallets/{}", second))).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+
+    let resp = test::call_service(&app, bearer(test::TestRequest::get().uri("/api/solana/wallet")).to_request()).await;
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["data"]["pubkey"], first.to_string());
+    assert_eq!(body["data"]["is_primary"], true);
+
+    let req = bearer(test::TestRequest::delete().uri(&format!("/api/solana/wallets/{}", second))).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    // Another user's wallet is neither visible nor removable.
+    let req = bearer(test::TestRequest::put().uri(&format!("/api/solana/wallets/{}/primary", other_wallet))).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    let req = bearer(test::TestRequest::delete().uri(&format!("/api/solana/wallets/{}", first))).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+
+    assert!(wallets.unlink(&db, &cache, &other_user, &other_wallet).await.unwrap());
+}
+
+#[actix_web::test]
+async fn wallets_sign_in_with_signed_challenges() {
+    let Some((db, cache)) = link_backends().await else {
+        return;
+    };
+    let wallets = web::Data::new(WalletStore::new(&db).await.unwrap());
+    let siws = web::Data::new(SignInWithSolana::new(SiwsConfig::default()));
+    let (db, cache) = (web::Data::new(db), web::Data::new(cache));
+
+    let app = test::init_service(
+        App::new()
+            .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+            .app_data(siws.clone())
+            .app_data(wallets.clone())
+            .app_data(db.clone())
+            .app_data(cache.clone())
+            .route("/auth/solana/challenge", web::post().to(create_sign_in_challenge))
+            .route("/auth/solana/login", web::post().to(sign_in_with_wallet))
+            .route("/auth/solana/logout", web::post().to(sign_out))
+            .service(web::scope("/api").wrap(Auth).route("/solana/wallets", web::get().to(list_wallets)))
+    ).await;
+
+    let keypair = Keypair::new();
+    let req = test::TestRequest::post()
+        .uri("/auth/solana/challenge")
+        .set_json(serde_json::json!({ "pubkey": keypair.pubkey().to_string() }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let body: Value = test::read_body_json(resp).await;
+    let nonce = body["data"]["nonce"].as_str().unwrap().to_string();
+    let message = body["data"]["message"].as_str().unwrap().to_string();
+    assert!(message.contains(&keypair.pubkey().to_string()));
+    assert!(message.contains(&format!("Nonce: {}", nonce)));
+
+    // A signature from another key is rejected and leaves the challenge usable.
+    let login = |signature: String| serde_json::json!({
+        "pubkey": keypair.pubkey().to_string(),
+        "nonce": nonce,
+        "signature": signature,
+    });
+    let req = test::TestRequest::post()
+        .uri("/auth/solana/login")
+        .set_json(login(Keypair::new().sign_message(message.as_bytes()).to_string()))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let signature = keypair.sign_message(message.as_bytes()).to_string();
+    let req = test::TestRequest::post().uri("/auth/solana/login").set_json(login(signature.clone())).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let cookie = resp.response().cookies().next().expect("login sets a session cookie").into_owned();
+
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["data"]["username"], keypair.pubkey().to_string());
+    assert_eq!(body["data"]["role"], "user");
+
+    // The session authenticates /api without a bearer token.
+    let req = test::TestRequest::get().uri("/api/solana/wallets").cookie(cookie.clone()).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["data"][0]["pubkey"], keypair.pubkey().to_string());
+    assert_eq!(body["data"][0]["is_primary"], true);
+
+    // Challenges are single use.
+    let req = test::TestRequest::post().uri("/auth/solana/login").set_json(login(signature)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let req = test::TestRequest::get().uri("/api/solana/wallets").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::post().uri("/auth/solana/logout").cookie(cookie).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+
+    let owner = wallets.owner(&db, &keypair.pubkey()).await.unwrap().unwrap();
+    assert!(wallets.unlink(&db, &cache, &owner, &keypair.pubkey()).await.unwrap());
+}
+
+#[actix_web::test]
+async fn parse_pubkey_trims_and_validates() {
+    let pubkey = Keypair::new().pubkey();
+
+    assert_eq!(parse_pubkey(&format!("  {}  ", pubkey)), Ok(pubkey));
+    assert!(parse_pubkey("").is_err());
+    assert!(parse_pubkey("abc").is_err());
+}
+
+#[actix_web::test]
+async fn wallets_belong_to_one_user_with_one_primary() {
+    let Some((db, cache)) = link_backends().await else {
+        return;
+    };
+    let wallets = WalletStore::new(&db).await.unwrap();
+    let (user, other_user) = (Uuid::new_v4(), Uuid::new_v4());
+    let (first, second) = (Keypair::new().pubkey(), Keypair::new().pubkey());
+
+    // Concurrent first links must not both try to become primary.
+    let (a, b) = futures::join!(
+        wallets.link(&db, &cache, &user, &first, false),
+        wallets.link(&db, &cache, &user, &second, false)
+    );
+    assert_ne!(a.unwrap().is_primary, b.unwrap().is_primary);
+
+    let err = wallets.link(&db, &cache, &other_user, &first, false).await.unwrap_err();
+    assert!(is_unique_violation(err.as_ref()));
+    assert_eq!(wallets.owner(&db, &first).await.unwrap(), Some(user));
+
+    // Relinking is a no-op, and switching the primary keeps exactly one.
+    wallets.link(&db, &cache, &user, &first, false).await.unwrap();
+    wallets.set_primary(&db, &cache, &user, &second).await.unwrap().unwrap();
+    wallets.set_primary(&db, &cache, &user, &first).await.unwrap().unwrap();
+    let listed = wallets.list(&db, &cache, &user).await.unwrap();
+    assert_eq!(listed.iter().filter(|wallet| wallet.is_primary).count(), 1);
+    assert_eq!(listed[0].pubkey, first.to_string());
+
+    assert!(wallets.unlink(&db, &cache, &user, &first).await.unwrap());
+    assert_eq!(wallets.primary(&db, &cache, &user).await.unwrap().unwrap().pubkey, second.to_string());
+    assert!(wallets.unlink(&db, &cache, &user, &second).await.unwrap());
+}
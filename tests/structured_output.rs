@@ -0,0 +1,45 @@
+//! Schema validation of model output, which needs no model.
+
+use serde_json::json;
+
+use rust_api::structured_output::*;
+
+fn validator() -> jsonschema::Validator {
+    jsonschema::validator_for(
+        &json!({
+            "type": "object",
+            "required": ["name", "tags"],
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer", "minimum": 0 },
+                "tags": { "type": "array", "items": { "type": "string" } },
+            },
+        })
+    ).unwrap()
+}
+
+#[test]
+fn valid_output_is_parsed() {
+    let value = parse_and_validate(&validator(), "\n  {\"name\": \"Ada\", \"age\": 36, \"tags\": []}  \n").unwrap();
+    assert_eq!(value, json!({ "name": "Ada", "age": 36, "tags": [] }));
+}
+
+#[test]
+fn output_that_is_not_json_is_one_violation_at_the_root() {
+    let violations = parse_and_validate(&validator(), "Sure! Here is the JSON: {").unwrap_err();
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].path, "");
+    assert!(violations[0].message.starts_with("output is not valid JSON"));
+}
+
+#[test]
+fn every_failing_path_is_reported() {
+    let violations = parse_and_validate(&validator(), r#"{"age": -1, "tags": ["a", 2]}"#).unwrap_err();
+    let paths: Vec<&str> = violations.iter().map(|v| v.path.as_str()).collect();
+
+    assert_eq!(violations.len(), 3, "{:?}", violations);
+    assert!(paths.contains(&""), "missing `name` is reported on the root");
+    assert!(paths.contains(&"/age"));
+    assert!(paths.contains(&"/tags/1"));
+    assert!(violations.iter().all(|v| !v.schema_path.is_empty() && !v.message.is_empty()));
+}
@@ -0,0 +1,116 @@
+//! Upload policy rules and stream limits, plus quota reservations against
+//! `POSTGRES_TEST_URL`.
+
+use std::env;
+
+use bytes::Bytes;
+use futures::{ stream, StreamExt };
+use uuid::Uuid;
+
+use rust_api::files::{ FileStore, LinkPolicy };
+use rust_api::object_store::*;
+use rust_api::postgres_db::PostgresDb;
+use rust_api::upload_policy::*;
+use rust_api::uploads::Uploads;
+
+#[test]
+fn file_names_are_reduced_to_a_safe_component() {
+    assert_eq!(sanitize_file_name("../../etc/passwd").unwrap(), "passwd");
+    assert_eq!(sanitize_file_name("C:\\Users\\me\\report.pdf").unwrap(), "report.pdf");
+    assert_eq!(sanitize_file_name("  my <best>   photo?.jpg  ").unwrap(), "my best photo.jpg");
+    assert_eq!(sanitize_file_name("..hidden.").unwrap(), "hidden");
+    assert_eq!(sanitize_file_name("bad\u{0}\u{7}name.txt").unwrap(), "badname.txt");
+    assert_eq!(sanitize_file_name("con.txt").unwrap(), "_con.txt");
+    assert_eq!(sanitize_file_name("console.txt").unwrap(), "console.txt");
+    assert!(sanitize_file_name("...").is_err());
+    assert!(sanitize_file_name("dir/").is_err());
+    assert!(sanitize_file_name("\"*?").is_err());
+}
+
+#[test]
+fn types_match_exactly_or_by_wildcard() {
+    let policy = UploadPolicy {
+        allowed_types: vec!["image/*".to_string(), "Application/PDF".to_string()],
+        ..UploadPolicy::default()
+    };
+
+    assert!(policy.allows_type("image/png"));
+    assert!(policy.allows_type("application/pdf; charset=binary"));
+    assert!(policy.allows_type("IMAGE/JPEG"));
+    assert!(!policy.allows_type("application/pdfx"));
+    assert!(!policy.allows_type("imagex/png"));
+    assert!(!policy.allows_type("text/plain"));
+    assert!(UploadPolicy::default().allows_type("anything/at-all"));
+}
+
+#[test]
+fn policies_fall_back_by_role_then_route() {
+    let policies = UploadPolicies::from_json(
+        r#"{"default": {"default": {"max_size": 100}, "admin": {"max_size": 1000}},
+            "files": {"default": {"max_size": 10}, "admin": {"max_size": null}}}"#
+    ).unwrap();
+
+    assert_eq!(policies.policy_for(FILES_ROUTE, "admin").max_size, None);
+    assert_eq!(policies.policy_for(FILES_ROUTE, "user").max_size, Some(10));
+    assert_eq!(policies.policy_for(UPLOADS_ROUTE, "admin").max_size, Some(1000));
+    assert_eq!(policies.policy_for(UPLOADS_ROUTE, "user").max_size, Some(100));
+
+    // Without a configured default, the built-in one applies.
+    let policies = UploadPolicies::from_json(r#"{"files": {"admin": {"max_size": null}}}"#).unwrap();
+    assert_eq!(policies.policy_for(FILES_ROUTE, "user").max_size, UploadPolicy::default().max_size);
+    assert_eq!(policies.policy_for(UPLOADS_ROUTE, "admin").max_size, UploadPolicy::default().max_size);
+}
+
+fn chunks(sizes: &[usize]) -> ByteStream {
+    let chunks: Vec<Result<Bytes, ObjectStoreError>> = sizes
+        .iter()
+        .map(|&size| Ok(Bytes::from(vec![0; size])))
+        .collect();
+    Box::pin(stream::iter(chunks))
+}
+
+#[actix_web::test]
+async fn streams_fail_once_past_the_limit() {
+    let passed: Vec<_> = limit_stream(chunks(&[4, 4, 2]), 10).collect().await;
+    assert!(passed.iter().all(Result::is_ok));
+
+    let mut limited = limit_stream(chunks(&[4, 4, 4, 4]), 10);
+    assert!(limited.next().await.unwrap().is_ok());
+    assert!(limited.next().await.unwrap().is_ok());
+    assert!(matches!(limited.next().await.unwrap(), Err(ObjectStoreError::TooLarge { limit: 10 })));
+}
+
+#[actix_web::test]
+async fn concurrent_reservations_share_the_quota() {
+    let Ok(url) = env::var("POSTGRES_TEST_URL") else {
+        eprintln!("skipping quota reservations: POSTGRES_TEST_URL not set");
+        return;
+    };
+    let db = PostgresDb::new(&url).await.unwrap();
+    FileStore::new(&db, "quota-test".to_string(), LinkPolicy::default()).await.unwrap();
+    Uploads::new(&db, 4 * 1024 * 1024).await.unwrap();
+
+    let policy = UploadPolicy { quota_bytes: Some(100), ..UploadPolicy::default() };
+    let owner = Uuid::new_v4();
+
+    let (first, second) = futures::join!(
+        policy.reserve_quota(&db, &owner, Some(60)),
+        policy.reserve_quota(&db, &owner, Some(60))
+    );
+    let (first, second) = (first.unwrap().unwrap(), second.unwrap().unwrap());
+    let mut granted = [first.bytes, second.bytes];
+    granted.sort();
+    assert_eq!(granted, [40, 60]);
+
+    // Unknown sizes get whatever is left, which is nothing now.
+    assert_eq!(policy.reserve_quota(&db, &owner, None).await.unwrap().unwrap().bytes, 0);
+
+    first.release(&db).await;
+    second.release(&db).await;
+    let reservation = policy.reserve_quota(&db, &owner, None).await.unwrap().unwrap();
+    assert_eq!(reservation.bytes, 100);
+    reservation.release(&db).await;
+
+    let unlimited = UploadPolicy { quota_bytes: None, ..UploadPolicy::default() };
+    assert!(unlimited.reserve_quota(&db, &owner, Some(60)).await.unwrap().is_none());
+}
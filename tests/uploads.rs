@@ -0,0 +1,62 @@
+//! Upload sessions against `POSTGRES_TEST_URL`, with blocks kept in memory.
+
+use std::env;
+use std::sync::Arc;
+
+use actix_session::{ storage::CookieSessionStore, Session, SessionMiddleware };
+use actix_web::{ cookie::Key, http::StatusCode, test, web, App, HttpResponse };
+use serde_json::Value;
+use uuid::Uuid;
+
+use rust_api::files::*;
+use rust_api::memory_storage::MemoryStorage;
+use rust_api::object_store::ObjectStore;
+use rust_api::postgres_db::PostgresDb;
+use rust_api::upload_policy::UploadPolicies;
+use rust_api::uploads::*;
+
+async fn login(session: Session) -> HttpResponse {
+    session.insert("user_id", Uuid::new_v4()).unwrap();
+    session.insert("username", "uploads-test").unwrap();
+    session.insert("user_role", "user").unwrap();
+    HttpResponse::Ok().finish()
+}
+
+#[actix_web::test]
+async fn sizes_that_would_overflow_the_block_count_are_rejected() {
+    let Ok(url) = env::var("POSTGRES_TEST_URL") else {
+        eprintln!("skipping upload sessions: POSTGRES_TEST_URL not set");
+        return;
+    };
+    let db = PostgresDb::new(&url).await.unwrap();
+    let files = FileStore::new(&db, "uploads-test".to_string(), LinkPolicy::default()).await.unwrap();
+    let uploads = Uploads::new(&db, DEFAULT_UPLOAD_BLOCK_SIZE).await.unwrap();
+    let storage: Arc<dyn ObjectStore> = Arc::new(MemoryStorage::new());
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(db))
+            .app_data(web::Data::new(files))
+            .app_data(web::Data::new(uploads))
+            .app_data(web::Data::new(UploadPolicies::default()))
+            .app_data(web::Data::<dyn ObjectStore>::from(storage))
+            .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+            .route("/login", web::post().to(login))
+            .route("/uploads", web::post().to(create_upload))
+    ).await;
+    let login = test::call_service(&app, test::TestRequest::post().uri("/login").to_request()).await;
+    let cookie = login.response().cookies().next().unwrap().into_owned();
+
+    for size in [i64::MAX, i64::MIN, 0, -1] {
+        let req = test::TestRequest::post()
+            .uri("/uploads")
+            .cookie(cookie.clone())
+            .set_json(serde_json::json!({ "file_name": "big.bin", "size": size }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY, "size {}", size);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["errors"]["size"].is_string(), "expected a size error for {}", size);
+    }
+}
@@ -0,0 +1,142 @@
+//! Quota policies and headers, plus usage recording against
+//! `POSTGRES_TEST_URL` and `REDIS_TEST_URL`.
+
+use std::env;
+
+use actix_web::HttpResponse;
+
+use rust_api::models::User;
+use rust_api::ollama::TokenUsage;
+use rust_api::postgres_db::PostgresDb;
+use rust_api::redis_client::Cache;
+use rust_api::usage::*;
+
+fn status(policy: QuotaPolicy, day: UsageCounters, month: UsageCounters) -> QuotaStatus {
+    QuotaStatus { role: "user".to_string(), policy, day, month }
+}
+
+#[test]
+fn policies_fall_back_to_the_default_role() {
+    let limits = UsageLimits::from_json(
+        r#"{"default": {"daily_tokens": 100, "daily_requests": 5, "monthly_requests": null, "monthly_tokens": null},
+            "admin": {"daily_tokens": null, "daily_requests": null, "monthly_requests": null, "monthly_tokens": null}}"#
+    ).unwrap();
+
+    assert_eq!(limits.policy_for("user").daily_tokens, Some(100));
+    assert_eq!(limits.policy_for("admin").daily_tokens, None);
+    assert_eq!(UsageLimits::default().policy_for("anyone").daily_requests, QuotaPolicy::default().daily_requests);
+    assert!(UsageLimits::from_json("[]").is_err());
+}
+
+#[test]
+fn the_first_used_up_quota_is_reported() {
+    let policy = QuotaPolicy {
+        daily_requests: Some(10),
+        daily_tokens: Some(1_000),
+        monthly_requests: Some(100),
+        monthly_tokens: None,
+    };
+    let month = UsageCounters { requests: 50, tokens: 1_000_000 };
+
+    let fine = status(policy.clone(), UsageCounters { requests: 9, tokens: 999 }, month);
+    assert_eq!(fine.exceeded(), None);
+    assert_eq!(fine.daily_requests_remaining(), Some(1));
+    assert_eq!(fine.monthly_tokens_remaining(), None);
+
+    let tokens = status(policy.clone(), UsageCounters { requests: 9, tokens: 1_500 }, month);
+    assert_eq!(tokens.exceeded(), Some("daily token quota exceeded"));
+    assert_eq!(tokens.daily_tokens_remaining(), Some(0));
+
+    let requests = status(policy, UsageCounters { requests: 10, tokens: 1_500 }, month);
+    assert_eq!(requests.exceeded(), Some("daily request quota exceeded"));
+}
+
+#[test]
+fn only_limited_quotas_get_headers() {
+    let policy = QuotaPolicy {
+        daily_requests: Some(10),
+        daily_tokens: None,
+        monthly_requests: Some(100),
+        monthly_tokens: None,
+    };
+    let quota = status(policy, UsageCounters { requests: 3, tokens: 10 }, UsageCounters { requests: 30, tokens: 10 });
+
+    let mut response = HttpResponse::Ok().finish();
+    quota.apply_headers(&mut response);
+    let headers = response.headers();
+    assert_eq!(headers.get("x-quota-daily-requests-remaining").unwrap(), "7");
+    assert_eq!(headers.get("x-quota-monthly-requests-remaining").unwrap(), "70");
+    assert!(headers.get("x-quota-daily-tokens-remaining").is_none());
+    assert!(headers.get("x-quota-monthly-tokens-remaining").is_none());
+}
+
+async fn test_services() -> Option<(PostgresDb, Cache)> {
+    let (Ok(db_url), Ok(redis_url)) = (env::var("POSTGRES_TEST_URL"), env::var("REDIS_TEST_URL")) else {
+        eprintln!("skipping usage recording: POSTGRES_TEST_URL or REDIS_TEST_URL not set");
+        return None;
+    };
+
+    Some((PostgresDb::new(&db_url).await.unwrap(), Cache::new(&redis_url).await.unwrap()))
+}
+
+fn tokens(prompt_tokens: u64, completion_tokens: u64) -> TokenUsage {
+    TokenUsage { prompt_tokens, completion_tokens, duration_ms: 5 }
+}
+
+/// Admits and records one call, the way the LLM handlers do.
+async fn call(tracker: &UsageTracker, cache: &Cache, db: &PostgresDb, user: &User, usage: &TokenUsage) -> QuotaStatus {
+    let (admitted, _) = tracker.admit(cache, db, user).await.unwrap();
+    assert!(admitted);
+    tracker.record(cache, db, user, "llama3", usage).await.unwrap()
+}
+
+#[actix_web::test]
+async fn lost_counters_are_rebuilt_from_recorded_usage() {
+    let Some((db, cache)) = test_services().await else {
+        return;
+    };
+    let tracker = UsageTracker::new(&db, UsageLimits::default()).await.unwrap();
+    let user = User::new("usage-test".to_string(), "user".to_string());
+
+    call(&tracker, &cache, &db, &user, &tokens(10, 20)).await;
+    let status = call(&tracker, &cache, &db, &user, &tokens(1, 2)).await;
+    assert_eq!(status.day.requests, 2);
+    assert_eq!(status.day.tokens, 33);
+
+    // Redis restarted: the next call must not start counting from zero.
+    cache.delete_pattern(&format!("usage:{}:*", user.id)).await.unwrap();
+    let status = call(&tracker, &cache, &db, &user, &tokens(4, 0)).await;
+    assert_eq!(status.day.requests, 3);
+    assert_eq!(status.day.tokens, 37);
+    assert_eq!(status.month.requests, 3);
+    assert_eq!(status.month.tokens, 37);
+
+    // A failed generation still counts as a request, and its zero tokens
+    // leave the counters alone rather than rebuilding them.
+    let status = call(&tracker, &cache, &db, &user, &TokenUsage::default()).await;
+    assert_eq!(status.day.requests, 4);
+    assert_eq!(status.day.tokens, 37);
+    let status = call(&tracker, &cache, &db, &user, &TokenUsage::default()).await;
+    assert_eq!(status.day.requests, 5);
+    assert_eq!(status.month.requests, 5);
+}
+
+#[actix_web::test]
+async fn concurrent_requests_stop_at_the_quota() {
+    let Some((db, cache)) = test_services().await else {
+        return;
+    };
+    let limits = UsageLimits::from_json(
+        r#"{"default": {"daily_requests": 3, "daily_tokens": null, "monthly_requests": null, "monthly_tokens": null}}"#
+    ).unwrap();
+    let tracker = UsageTracker::new(&db, limits).await.unwrap();
+    let user = User::new("usage-test".to_string(), "user".to_string());
+
+    let admitted = futures::future::join_all((0..10).map(|_| tracker.admit(&cache, &db, &user))).await;
+    assert_eq!(admitted.into_iter().filter(|result| result.as_ref().unwrap().0).count(), 3);
+
+    let (admitted, status) = tracker.admit(&cache, &db, &user).await.unwrap();
+    assert!(!admitted);
+    assert_eq!(status.day.requests, 3);
+    assert_eq!(status.exceeded(), Some("daily request quota exceeded"));
+}